@@ -0,0 +1,73 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+
+use radroots_nostr::prelude::RadrootsNostrKeys;
+use radroots_trade::listing::order::TradeOrderStatus;
+use rhi::features::trade_listing::state::{SharedTradeListingState, TradeOrderState};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const EVENTS: usize = 100_000;
+
+fn measure(label: &str, f: impl FnOnce()) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let started = Instant::now();
+    f();
+    let elapsed = started.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!("  {label:<10} {allocations:>8} allocations  {elapsed:?}");
+}
+
+fn main() {
+    let buyer = RadrootsNostrKeys::generate().public_key();
+    let seller = RadrootsNostrKeys::generate().public_key();
+    let state = SharedTradeListingState::default();
+
+    println!("{EVENTS} handler passes: authorize sender, then copy counterparty");
+    let owned_seller = seller.to_hex();
+    let owned_buyer = buyer.to_hex();
+    measure("String", || {
+        for _ in 0..EVENTS {
+            black_box(owned_seller == seller.to_string());
+            black_box(owned_buyer.clone());
+        }
+    });
+
+    let order = TradeOrderState::new(
+        "order-1",
+        "addr",
+        state.intern_pubkey(&owned_buyer),
+        state.intern_pubkey(&owned_seller),
+        TradeOrderStatus::Requested,
+        0,
+    );
+    measure("Arc<str>", || {
+        for _ in 0..EVENTS {
+            black_box(order.is_seller(&seller));
+            black_box(Arc::clone(&order.buyer_pubkey));
+        }
+    });
+}
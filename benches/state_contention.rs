@@ -0,0 +1,72 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use radroots_trade::listing::order::TradeOrderStatus;
+use rhi::config::SnapshotStrategy;
+use rhi::features::trade_listing::{
+    state::{DEFAULT_ORDER_SHARDS, SharedTradeListingState, TradeListingState, TradeOrderState},
+    store::{PersistTrigger, TradeListingStore, persist_state},
+};
+
+const STORED_ORDERS: usize = 2048;
+const ORDERS: usize = 256;
+
+fn order(order_id: String) -> TradeOrderState {
+    TradeOrderState::new(
+        order_id,
+        "addr",
+        "buyer".into(),
+        "seller".into(),
+        TradeOrderStatus::Requested,
+        0,
+    )
+}
+
+// Each task takes the shard lock the way a handler does, records an event, then goes
+// through the write-through store path the subscriber runs after every dispatch.
+async fn run(shards: usize) -> Duration {
+    let mut stored = TradeListingState::default();
+    for i in 0..STORED_ORDERS {
+        stored.insert_order(order(format!("stored-{i}")));
+    }
+    let state = Arc::new(SharedTradeListingState::new(stored, shards));
+    let dir = std::env::temp_dir().join(format!("rhi-bench-{}", uuid::Uuid::new_v4()));
+    let store = TradeListingStore::new(dir.join("state.json"), SnapshotStrategy::WriteThrough);
+    let store = Arc::new(tokio::sync::Mutex::new(store));
+    persist_state(&store, &state, PersistTrigger::Shutdown).await;
+
+    let started = Instant::now();
+    let tasks: Vec<_> = (0..ORDERS)
+        .map(|i| {
+            let state = Arc::clone(&state);
+            let store = Arc::clone(&store);
+            tokio::spawn(async move {
+                let order_id = format!("stored-{}", i * (STORED_ORDERS / ORDERS));
+                state.order_shard_mut(&order_id).await.mark_event_seen(
+                    &order_id,
+                    &format!("evt-{i}"),
+                    64,
+                );
+                persist_state(&store, &state, PersistTrigger::Mutation).await;
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.expect("bench task");
+    }
+    persist_state(&store, &state, PersistTrigger::Shutdown).await;
+    let elapsed = started.elapsed();
+    let _ = std::fs::remove_dir_all(dir);
+    elapsed
+}
+
+#[tokio::main]
+async fn main() {
+    let single = run(1).await;
+    let sharded = run(DEFAULT_ORDER_SHARDS).await;
+    println!("{ORDERS} concurrent order events over {STORED_ORDERS} stored orders, write-through");
+    println!("  1 shard:   {single:?}");
+    println!("  {DEFAULT_ORDER_SHARDS} shards:  {sharded:?}");
+}
@@ -1,6 +1,9 @@
 use std::path::PathBuf;
 
-use clap::{Parser, ValueHint, command};
+use clap::{Parser, Subcommand, ValueHint, command};
+use nostr::RelayUrl;
+
+use crate::config::RelayProfile;
 
 #[derive(Parser, Debug, Clone)]
 #[command(
@@ -26,10 +29,147 @@ pub struct Args {
     )]
     pub identity: Option<PathBuf>,
 
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_hint = ValueHint::FilePath,
+        help = "Path to a separate identity file used to sign emitted trade results (defaults to the daemon identity)",
+    )]
+    pub result_identity: Option<PathBuf>,
+
     #[arg(
         long,
         action = clap::ArgAction::SetTrue,
         help = "Allow generating a new identity file if missing; if not set and identity file is absent, the daemon will fail"
     )]
     pub allow_generate_identity: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "PROFILE",
+        help = "Built-in relay set to use when the configuration does not list any relays"
+    )]
+    pub relay_profile: Option<RelayProfile>,
+
+    #[arg(
+        long = "relay",
+        value_name = "URL",
+        value_parser = RelayUrl::parse,
+        help = "Use this relay instead of the configured ones (repeatable; replaces config relays)"
+    )]
+    pub relays: Vec<RelayUrl>,
+
+    #[arg(
+        long = "add-relay",
+        value_name = "URL",
+        value_parser = RelayUrl::parse,
+        help = "Use this relay in addition to the configured or --relay ones (repeatable)"
+    )]
+    pub add_relays: Vec<RelayUrl>,
+
+    #[arg(
+        long,
+        action = clap::ArgAction::SetTrue,
+        help = "Print the effective configuration (secrets redacted) and exit"
+    )]
+    pub print_effective_config: bool,
+
+    #[arg(
+        long,
+        action = clap::ArgAction::SetTrue,
+        help = "Log outgoing events instead of publishing them; fetching and state changes still run"
+    )]
+    pub dry_run: bool,
+
+    #[arg(
+        long,
+        action = clap::ArgAction::SetTrue,
+        help = "Handle the first trade request, send its reply and exit"
+    )]
+    pub once: bool,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 300,
+        requires = "once",
+        help = "With --once, exit with an error if no request arrives within this many seconds"
+    )]
+    pub once_timeout_secs: u64,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    #[command(subcommand, about = "Manage the daemon identity file")]
+    Identity(IdentityCommand),
+
+    #[command(
+        about = "Feed captured events through the trade listing handlers",
+        long_about = "Read nostr events from a JSONL file (raw events, event journal or \
+            dead-letter entries) and run them through the handlers with fresh in-memory state, \
+            printing each outcome and the resulting order status. Combine with --dry-run to \
+            keep replies off the relays."
+    )]
+    Replay {
+        #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+
+        #[arg(
+            long = "kind",
+            value_name = "KIND",
+            help = "Only replay events of this kind (repeatable)"
+        )]
+        kinds: Vec<u16>,
+
+        #[arg(
+            long,
+            action = clap::ArgAction::SetTrue,
+            help = "Stop at the first event that fails to resolve or handle"
+        )]
+        fail_fast: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum IdentityCommand {
+    #[command(about = "Derive the identity from a NIP-06 mnemonic and write it to --identity")]
+    FromMnemonic {
+        #[arg(
+            long,
+            value_name = "WORDS",
+            help = "BIP-39 mnemonic; read from stdin when omitted"
+        )]
+        mnemonic: Option<String>,
+
+        #[arg(long, value_name = "PASSPHRASE", help = "Optional BIP-39 passphrase")]
+        passphrase: Option<String>,
+
+        #[arg(long, default_value_t = 0, help = "NIP-06 derivation account")]
+        account: u32,
+
+        #[arg(
+            long,
+            action = clap::ArgAction::SetTrue,
+            help = "Overwrite an existing identity file"
+        )]
+        force: bool,
+    },
+
+    #[command(
+        about = "Generate new daemon keys and re-announce the handler under them",
+        long_about = "Generate new daemon keys, back up the old identity file and re-announce the \
+            handler. In-flight orders addressed to the old key are not migrated."
+    )]
+    Rotate {
+        #[arg(
+            long,
+            action = clap::ArgAction::SetTrue,
+            help = "Publish kind-0 metadata on the old key pointing at the new npub"
+        )]
+        announce_on_old_key: bool,
+    },
 }
@@ -1,19 +1,660 @@
+use std::ffi::OsString;
+
+use nostr::RelayUrl;
 use radroots_nostr::prelude::RadrootsNostrMetadata;
 use radroots_runtime::BackoffConfig;
-use serde::{Deserialize, Serialize};
+use radroots_trade::listing::{dvm::TradeListingMessageType, dvm_kinds::TRADE_LISTING_DVM_KINDS};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use thiserror::Error;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Configuration {
     pub logs_dir: String,
-    pub relays: Vec<String>,
+    #[serde(default)]
+    pub relays: Vec<RelayConfig>,
+    #[serde(default)]
+    pub startup_self_ping: bool,
     #[serde(default)]
     pub subscriber: SubscriberConfig,
+    #[serde(default)]
+    pub trade: TradeConfig,
+    #[serde(default)]
+    pub journal: Option<JournalConfig>,
+    #[serde(default)]
+    pub api: Option<ApiConfig>,
+    #[serde(default)]
+    pub events: Option<EventsConfig>,
+    #[serde(default)]
+    pub webhook: Option<WebhookConfig>,
+    #[serde(default)]
+    pub encryption: EncryptionMode,
+}
+
+// `nip04` handles requests carrying an `encrypted` tag. `nip17` additionally accepts
+// NIP-59 gift-wrapped requests addressed to us and gift-wraps every reply.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionMode {
+    #[default]
+    Nip04,
+    Nip17,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("subscriber.lookback_secs requires trade.store to skip already handled requests")]
+    LookbackWithoutStore,
+    #[error("trade.require_payment_for requires trade.payment to issue invoices")]
+    PaymentWithoutConfig,
+}
+
+impl Configuration {
+    // Checks that span sections, run once overrides have been applied.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.subscriber.lookback_secs > 0 && self.trade.store.is_none() {
+            return Err(ConfigError::LookbackWithoutStore);
+        }
+        if !self.trade.require_payment_for.is_empty() && self.trade.payment.is_none() {
+            return Err(ConfigError::PaymentWithoutConfig);
+        }
+        Ok(())
+    }
+
+    // The request kinds this instance subscribes to, and so the ones it advertises.
+    pub fn served_kinds(&self) -> Vec<u16> {
+        served_kinds(&self.trade, &self.subscriber)
+    }
+
+    pub fn resolve_relays(&self, profile: Option<RelayProfile>) -> Vec<RelayConfig> {
+        if !self.relays.is_empty() {
+            return self.relays.clone();
+        }
+        profile
+            .map(|profile| {
+                profile
+                    .relays()
+                    .iter()
+                    .map(|relay| RelayConfig::from(*relay))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "RelayConfigRepr")]
+pub struct RelayConfig {
+    pub url: String,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl From<&str> for RelayConfig {
+    fn from(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            read: true,
+            write: true,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RelayConfigRepr {
+    Url(String),
+    Entry {
+        url: String,
+        #[serde(default = "default_true")]
+        read: bool,
+        #[serde(default = "default_true")]
+        write: bool,
+    },
+}
+
+impl From<RelayConfigRepr> for RelayConfig {
+    fn from(repr: RelayConfigRepr) -> Self {
+        match repr {
+            RelayConfigRepr::Url(url) => RelayConfig::from(url.as_str()),
+            RelayConfigRepr::Entry { url, read, write } => Self { url, read, write },
+        }
+    }
+}
+
+pub fn served_kinds(trade: &TradeConfig, subscriber: &SubscriberConfig) -> Vec<u16> {
+    TRADE_LISTING_DVM_KINDS
+        .iter()
+        .copied()
+        .filter(|kind| trade.is_kind_enabled(*kind) && subscriber.subscribes_to(*kind))
+        .collect()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayProfile {
+    Mainnet,
+    Local,
+}
+
+const MAINNET_RELAYS: &[&str] = &[
+    "wss://relay.damus.io",
+    "wss://nos.lol",
+    "wss://relay.primal.net",
+];
+
+const LOCAL_RELAYS: &[&str] = &["ws://127.0.0.1:8080"];
+
+impl RelayProfile {
+    pub fn relays(self) -> &'static [&'static str] {
+        match self {
+            RelayProfile::Mainnet => MAINNET_RELAYS,
+            RelayProfile::Local => LOCAL_RELAYS,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct SubscriberConfig {
     #[serde(default)]
     pub backoff: BackoffConfig,
+    #[serde(default)]
+    pub backlog_concurrency: Option<usize>,
+    #[serde(default)]
+    pub dead_letter_path: Option<String>,
+    #[serde(default)]
+    pub startup_policy: StartupPolicy,
+    #[serde(default)]
+    pub lookback_secs: u64,
+    #[serde(default)]
+    pub watermark_path: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_trade_kinds")]
+    pub kinds: Option<Vec<u16>>,
+    #[serde(default)]
+    pub idle_reconnect_secs: Option<u64>,
+}
+
+impl SubscriberConfig {
+    pub fn subscribes_to(&self, kind: u16) -> bool {
+        self.kinds
+            .as_ref()
+            .is_none_or(|kinds| kinds.contains(&kind))
+    }
+}
+
+fn deserialize_trade_kinds<'de, D>(deserializer: D) -> Result<Option<Vec<u16>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let kinds = Option::<Vec<u16>>::deserialize(deserializer)?;
+    if kinds.as_ref().is_some_and(Vec::is_empty) {
+        return Err(serde::de::Error::custom(
+            "kinds must list at least one kind; omit it to subscribe to every served kind",
+        ));
+    }
+    if let Some(kind) = kinds
+        .iter()
+        .flatten()
+        .find(|kind| !TRADE_LISTING_DVM_KINDS.contains(kind))
+    {
+        return Err(serde::de::Error::custom(format!(
+            "kind {kind} is not a trade listing DVM kind (known: {TRADE_LISTING_DVM_KINDS:?})"
+        )));
+    }
+    Ok(kinds)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalConfig {
+    pub dir: String,
+    #[serde(default)]
+    pub rotation: JournalRotation,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiConfig {
+    pub bind: String,
+    #[serde(deserialize_with = "deserialize_api_token")]
+    pub token: String,
+}
+
+fn deserialize_api_token<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let token = String::deserialize(deserializer)?;
+    if token.trim().is_empty() {
+        return Err(serde::de::Error::custom("api.token must not be empty"));
+    }
+    Ok(token)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventsConfig {
+    pub sink: EventSinkKind,
+    pub url: String,
+    #[serde(default = "default_events_channel")]
+    pub channel: String,
+    #[serde(default = "default_events_queue_capacity")]
+    pub queue_capacity: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventSinkKind {
+    Redis,
+    Nats,
+}
+
+fn default_events_channel() -> String {
+    "rhi.trade.status".to_string()
+}
+
+fn default_events_queue_capacity() -> usize {
+    256
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub secret: String,
+    #[serde(default = "default_webhook_queue_capacity")]
+    pub queue_capacity: usize,
+    #[serde(default = "default_webhook_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default)]
+    pub backoff: BackoffConfig,
+}
+
+fn default_webhook_queue_capacity() -> usize {
+    256
+}
+
+fn default_webhook_max_attempts() -> u32 {
+    5
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+// Events can reach the notification channel between `subscribe` and the first
+// poll of the stream. `Buffer` processes them in arrival order; `Drop` discards
+// them and logs how many requests were skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupPolicy {
+    #[default]
+    Buffer,
+    Drop,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TradeConfig {
+    #[serde(default)]
+    pub limits: TradeLimitsConfig,
+    #[serde(default)]
+    pub store: Option<TradeStoreConfig>,
+    #[serde(default)]
+    pub mutual_p_tags: Vec<TradeListingMessageType>,
+    #[serde(default)]
+    pub payload_mode: PayloadMode,
+    #[serde(default)]
+    pub emit_completion_reaction: bool,
+    #[serde(
+        default = "default_enabled_stages",
+        deserialize_with = "deserialize_enabled_stages"
+    )]
+    pub enabled_stages: Vec<TradeStage>,
+    #[serde(default)]
+    pub outbox: Option<OutboxConfig>,
+    #[serde(default)]
+    pub reply_expiration_secs: Option<u64>,
+    #[serde(default)]
+    pub listing_cache: ListingCacheConfig,
+    #[serde(default)]
+    pub routing: Vec<RelayRoute>,
+    #[serde(default)]
+    pub rates: Option<RateConfig>,
+    #[serde(default)]
+    pub max_open_orders_per_buyer: Option<usize>,
+    #[serde(default)]
+    pub max_open_orders_per_listing: Option<usize>,
+    #[serde(default)]
+    pub require_payment_for: Vec<TradeStage>,
+    #[serde(default)]
+    pub payment: Option<PaymentConfig>,
+    #[serde(default)]
+    pub require_derived_order_ids: bool,
+    #[serde(default)]
+    pub fetch_retry: FetchRetryConfig,
+    #[serde(default)]
+    pub encrypt_replies_for: Vec<TradeStage>,
+}
+
+impl Default for TradeConfig {
+    fn default() -> Self {
+        Self {
+            limits: TradeLimitsConfig::default(),
+            store: None,
+            mutual_p_tags: Vec::new(),
+            payload_mode: PayloadMode::default(),
+            emit_completion_reaction: false,
+            enabled_stages: default_enabled_stages(),
+            outbox: None,
+            reply_expiration_secs: None,
+            listing_cache: ListingCacheConfig::default(),
+            routing: Vec::new(),
+            rates: None,
+            max_open_orders_per_buyer: None,
+            max_open_orders_per_listing: None,
+            require_payment_for: Vec::new(),
+            payment: None,
+            require_derived_order_ids: false,
+            fetch_retry: FetchRetryConfig::default(),
+            encrypt_replies_for: Vec::new(),
+        }
+    }
+}
+
+impl TradeConfig {
+    pub fn is_message_type_enabled(&self, message_type: TradeListingMessageType) -> bool {
+        !self
+            .disabled_stages()
+            .any(|stage| stage.message_types().contains(&message_type))
+    }
+
+    pub fn is_kind_enabled(&self, kind: u16) -> bool {
+        !self
+            .disabled_stages()
+            .flat_map(|stage| stage.message_types())
+            .any(|message_type| message_type.kind() == kind)
+    }
+
+    pub fn requires_payment(&self, message_type: TradeListingMessageType) -> bool {
+        self.require_payment_for
+            .iter()
+            .any(|stage| stage.message_types().contains(&message_type))
+    }
+
+    pub fn encrypts_reply(&self, message_type: TradeListingMessageType) -> bool {
+        self.encrypt_replies_for
+            .iter()
+            .any(|stage| stage.message_types().contains(&message_type))
+    }
+
+    pub fn routed_relays(&self, message_type: TradeListingMessageType) -> Option<&[String]> {
+        self.routing
+            .iter()
+            .find(|route| route.message_types.contains(&message_type))
+            .map(|route| route.relays.as_slice())
+            .filter(|relays| !relays.is_empty())
+    }
+
+    // Receipts are attestations the buyer may present later, so they never expire.
+    pub fn reply_expiration_secs(&self, message_type: TradeListingMessageType) -> Option<u64> {
+        self.reply_expiration_secs
+            .filter(|_| message_type != TradeListingMessageType::Receipt)
+    }
+
+    fn disabled_stages(&self) -> impl Iterator<Item = TradeStage> + '_ {
+        TradeStage::ALL
+            .into_iter()
+            .filter(|stage| !self.enabled_stages.contains(stage))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentConfig {
+    pub amount_msat: u64,
+    pub invoice_url: String,
+    #[serde(default = "default_invoice_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_invoice_expiry_secs")]
+    pub expiry_secs: u64,
+}
+
+fn default_invoice_timeout_secs() -> u64 {
+    10
+}
+
+fn default_invoice_expiry_secs() -> u64 {
+    600
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateConfig {
+    pub url: String,
+    #[serde(default = "default_rate_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_rate_timeout_secs() -> u64 {
+    10
+}
+
+// Message types without a route are broadcast to every write relay.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayRoute {
+    pub message_types: Vec<TradeListingMessageType>,
+    pub relays: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TradeStage {
+    Validate,
+    Order,
+    Question,
+    Discount,
+    Cancel,
+    Fulfillment,
+    Receipt,
+}
+
+impl TradeStage {
+    pub const ALL: [TradeStage; 7] = [
+        TradeStage::Validate,
+        TradeStage::Order,
+        TradeStage::Question,
+        TradeStage::Discount,
+        TradeStage::Cancel,
+        TradeStage::Fulfillment,
+        TradeStage::Receipt,
+    ];
+
+    pub fn message_types(self) -> &'static [TradeListingMessageType] {
+        use TradeListingMessageType as Mt;
+        match self {
+            TradeStage::Validate => &[Mt::ListingValidateRequest, Mt::ListingValidateResult],
+            TradeStage::Order => &[
+                Mt::OrderRequest,
+                Mt::OrderResponse,
+                Mt::OrderRevision,
+                Mt::OrderRevisionAccept,
+                Mt::OrderRevisionDecline,
+            ],
+            TradeStage::Question => &[Mt::Question, Mt::Answer],
+            TradeStage::Discount => &[
+                Mt::DiscountRequest,
+                Mt::DiscountOffer,
+                Mt::DiscountAccept,
+                Mt::DiscountDecline,
+            ],
+            TradeStage::Cancel => &[Mt::Cancel],
+            TradeStage::Fulfillment => &[Mt::FulfillmentUpdate],
+            TradeStage::Receipt => &[Mt::Receipt],
+        }
+    }
+}
+
+fn default_enabled_stages() -> Vec<TradeStage> {
+    TradeStage::ALL.to_vec()
+}
+
+fn deserialize_enabled_stages<'de, D>(deserializer: D) -> Result<Vec<TradeStage>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let stages = Vec::<TradeStage>::deserialize(deserializer)?;
+    if stages.is_empty() {
+        return Err(serde::de::Error::custom(
+            "enabled_stages must list at least one stage; omit it to enable all",
+        ));
+    }
+    Ok(stages)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxConfig {
+    #[serde(default = "default_outbox_ttl_secs")]
+    pub ttl_secs: u64,
+    #[serde(default = "default_outbox_max_relays")]
+    pub max_relays: usize,
+}
+
+fn default_outbox_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_outbox_max_relays() -> usize {
+    3
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListingCacheConfig {
+    #[serde(default = "default_listing_cache_capacity")]
+    pub capacity: usize,
+    #[serde(default = "default_listing_cache_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+impl Default for ListingCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: default_listing_cache_capacity(),
+            ttl_secs: default_listing_cache_ttl_secs(),
+        }
+    }
+}
+
+fn default_listing_cache_capacity() -> usize {
+    256
+}
+
+fn default_listing_cache_ttl_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FetchRetryConfig {
+    #[serde(default = "default_fetch_attempts")]
+    pub attempts: u32,
+    #[serde(default)]
+    pub backoff: BackoffConfig,
+}
+
+impl Default for FetchRetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: default_fetch_attempts(),
+            backoff: BackoffConfig::default(),
+        }
+    }
+}
+
+fn default_fetch_attempts() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadMode {
+    #[default]
+    Lenient,
+    Strict,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TradeStoreConfig {
+    pub path: String,
+    #[serde(default)]
+    pub snapshot: SnapshotStrategy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum SnapshotStrategy {
+    #[default]
+    WriteThrough,
+    Periodic {
+        interval_secs: u64,
+        max_mutations: u32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TradeLimitsConfig {
+    #[serde(default = "default_max_rounds")]
+    pub max_questions: u32,
+    #[serde(default = "default_max_rounds")]
+    pub max_revisions: u32,
+    #[serde(default = "default_max_rounds")]
+    pub max_discount_rounds: u32,
+    #[serde(default = "default_max_content_bytes")]
+    pub max_content_bytes: usize,
+    #[serde(default = "default_max_decrypted_bytes")]
+    pub max_decrypted_bytes: usize,
+    #[serde(default = "default_max_seen_event_ids")]
+    pub max_seen_event_ids: usize,
+    // Terminal orders untouched this long are dropped with their idempotency keys;
+    // 0 keeps them forever.
+    #[serde(default = "default_order_retention_secs")]
+    pub order_retention_secs: u64,
+}
+
+impl Default for TradeLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_questions: default_max_rounds(),
+            max_revisions: default_max_rounds(),
+            max_discount_rounds: default_max_rounds(),
+            max_content_bytes: default_max_content_bytes(),
+            max_decrypted_bytes: default_max_decrypted_bytes(),
+            max_seen_event_ids: default_max_seen_event_ids(),
+            order_retention_secs: default_order_retention_secs(),
+        }
+    }
+}
+
+fn default_max_rounds() -> u32 {
+    10
+}
+
+fn default_max_content_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_max_decrypted_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_max_seen_event_ids() -> usize {
+    256
+}
+
+fn default_order_retention_secs() -> u64 {
+    30 * 24 * 60 * 60
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,3 +662,508 @@ pub struct Settings {
     pub metadata: RadrootsNostrMetadata,
     pub config: Configuration,
 }
+
+impl Settings {
+    pub fn effective(&self, relay_profile: Option<RelayProfile>) -> Settings {
+        let mut settings = self.clone();
+        settings.config.relays = self.config.resolve_relays(relay_profile);
+        settings
+    }
+
+    // Environment variables override the file: `RHI__CONFIG__LOGS_DIR=/var/log/rhi` sets
+    // `config.logs_dir`. List fields take comma-separated values and string fields keep
+    // the raw value. Fields missing from the file are parsed as JSON scalars, so quote
+    // numeric-looking strings there (`RHI__CONFIG__API__TOKEN='"1234"'`).
+    pub fn with_env_overrides<I>(self, vars: I) -> serde_json::Result<Settings>
+    where
+        I: IntoIterator<Item = (OsString, OsString)>,
+    {
+        let mut value = serde_json::to_value(self)?;
+        for (key, raw) in vars {
+            // Non-UTF-8 entries can't name an override, so they are skipped.
+            let (Ok(key), Ok(raw)) = (key.into_string(), raw.into_string()) else {
+                continue;
+            };
+            if let Some(path) = env_override_path(&key) {
+                set_env_override(&mut value, &path, &raw);
+            }
+        }
+        serde_json::from_value(value)
+    }
+
+    // `--relay` replaces the configured relays outright; `--add-relay` appends to
+    // whatever list is left, skipping relays already present.
+    pub fn with_relay_overrides(mut self, relays: &[RelayUrl], add: &[RelayUrl]) -> Settings {
+        if !relays.is_empty() {
+            self.config.relays = relays
+                .iter()
+                .map(|relay| RelayConfig::from(relay.as_str()))
+                .collect();
+        }
+        for relay in add {
+            if !self
+                .config
+                .relays
+                .iter()
+                .any(|cfg| cfg.url == relay.as_str())
+            {
+                self.config.relays.push(RelayConfig::from(relay.as_str()));
+            }
+        }
+        self
+    }
+
+    pub fn to_redacted_json(&self) -> serde_json::Result<String> {
+        let mut value = serde_json::to_value(self)?;
+        redact_secrets(&mut value);
+        serde_json::to_string_pretty(&value)
+    }
+}
+
+pub const ENV_PREFIX: &str = "RHI";
+const ENV_SEPARATOR: &str = "__";
+
+pub fn env_override_path(key: &str) -> Option<Vec<String>> {
+    let rest = key.strip_prefix(ENV_PREFIX)?.strip_prefix(ENV_SEPARATOR)?;
+    let path: Vec<String> = rest
+        .split(ENV_SEPARATOR)
+        .map(str::to_ascii_lowercase)
+        .collect();
+    (!path.iter().any(String::is_empty)).then_some(path)
+}
+
+fn set_env_override(value: &mut Value, path: &[String], raw: &str) {
+    let Some((leaf, parents)) = path.split_last() else {
+        return;
+    };
+    let mut node = value;
+    for key in parents {
+        if !node.is_object() {
+            *node = Value::Object(Default::default());
+        }
+        node = node
+            .as_object_mut()
+            .expect("node is an object")
+            .entry(key.clone())
+            .or_insert(Value::Null);
+    }
+    if !node.is_object() {
+        *node = Value::Object(Default::default());
+    }
+    let map = node.as_object_mut().expect("node is an object");
+    let parsed = match map.get(leaf) {
+        Some(Value::Array(_)) => Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(parse_env_scalar)
+                .collect(),
+        ),
+        Some(Value::String(_)) => Value::String(raw.to_string()),
+        _ => parse_env_scalar(raw),
+    };
+    map.insert(leaf.clone(), parsed);
+}
+
+fn parse_env_scalar(raw: &str) -> Value {
+    match serde_json::from_str(raw) {
+        Ok(value @ (Value::Bool(_) | Value::Number(_) | Value::String(_))) => value,
+        _ => Value::String(raw.to_string()),
+    }
+}
+
+const REDACTED: &str = "<redacted>";
+const SECRET_KEY_MARKERS: &[&str] = &["secret", "password", "token", "nsec", "private_key"];
+
+fn redact_secrets(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                if !value.is_null() && SECRET_KEY_MARKERS.iter().any(|m| key.contains(m)) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact_secrets(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_secrets),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ApiConfig, ConfigError, Configuration, REDACTED, RelayConfig, RelayProfile, RelayRoute,
+        Settings, SubscriberConfig, TradeConfig, TradeStage, TradeStoreConfig, redact_secrets,
+        served_kinds,
+    };
+    use nostr::RelayUrl;
+    use radroots_nostr::prelude::RadrootsNostrMetadata;
+    use radroots_trade::listing::{
+        dvm::TradeListingMessageType, dvm_kinds::TRADE_LISTING_DVM_KINDS,
+    };
+    use serde_json::json;
+    use std::ffi::OsString;
+
+    fn configuration(relays: &[&str]) -> Configuration {
+        Configuration {
+            logs_dir: "logs".into(),
+            relays: relays
+                .iter()
+                .map(|relay| RelayConfig::from(*relay))
+                .collect(),
+            startup_self_ping: false,
+            subscriber: Default::default(),
+            trade: Default::default(),
+            journal: None,
+            api: None,
+            events: None,
+            webhook: None,
+            encryption: Default::default(),
+        }
+    }
+
+    #[test]
+    fn profile_relays_fill_empty_config() {
+        let config = configuration(&[]);
+        assert_eq!(
+            config.resolve_relays(Some(RelayProfile::Mainnet)),
+            RelayProfile::Mainnet
+                .relays()
+                .iter()
+                .map(|relay| RelayConfig::from(*relay))
+                .collect::<Vec<_>>()
+        );
+        assert!(config.resolve_relays(None).is_empty());
+    }
+
+    #[test]
+    fn config_relays_override_profile() {
+        let config = configuration(&["wss://relay.example.com"]);
+        assert_eq!(
+            config.resolve_relays(Some(RelayProfile::Mainnet)),
+            vec![RelayConfig::from("wss://relay.example.com")]
+        );
+    }
+
+    #[test]
+    fn relay_entries_accept_plain_urls_and_roles() {
+        let relays: Vec<RelayConfig> = serde_json::from_str(
+            r#"[
+                "wss://both.example.com",
+                { "url": "wss://read.example.com", "write": false },
+                { "url": "wss://write.example.com", "read": false, "write": true }
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            relays,
+            vec![
+                RelayConfig::from("wss://both.example.com"),
+                RelayConfig {
+                    url: "wss://read.example.com".into(),
+                    read: true,
+                    write: false,
+                },
+                RelayConfig {
+                    url: "wss://write.example.com".into(),
+                    read: false,
+                    write: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn effective_config_includes_resolved_relays() {
+        let settings = Settings {
+            metadata: RadrootsNostrMetadata::default(),
+            config: configuration(&[]),
+        };
+
+        let printed = settings
+            .effective(Some(RelayProfile::Local))
+            .to_redacted_json()
+            .unwrap();
+        assert!(printed.contains("ws://127.0.0.1:8080"));
+    }
+
+    #[test]
+    fn env_overrides_take_precedence_over_the_file() {
+        let settings = Settings {
+            metadata: RadrootsNostrMetadata::default(),
+            config: configuration(&["wss://file.example.com"]),
+        };
+        let vars = [
+            ("RHI__CONFIG__LOGS_DIR", "/var/log/rhi"),
+            (
+                "RHI__CONFIG__RELAYS",
+                "wss://a.example.com, wss://b.example.com",
+            ),
+            ("RHI__CONFIG__TRADE__LIMITS__MAX_QUESTIONS", "3"),
+            ("RHI__CONFIG__API__BIND", "0.0.0.0:8787"),
+            ("RHI__CONFIG__API__TOKEN", "\"1234\""),
+            ("RHI_CONFIG__LOGS_DIR", "ignored"),
+            ("HOME", "/root"),
+        ]
+        .map(|(key, value)| (OsString::from(key), OsString::from(value)));
+
+        let config = settings.with_env_overrides(vars).unwrap().config;
+        assert_eq!(config.logs_dir, "/var/log/rhi");
+        assert_eq!(
+            config.relays,
+            vec![
+                RelayConfig::from("wss://a.example.com"),
+                RelayConfig::from("wss://b.example.com"),
+            ]
+        );
+        assert_eq!(config.trade.limits.max_questions, 3);
+        let api = config.api.unwrap();
+        assert_eq!(api.bind, "0.0.0.0:8787");
+        assert_eq!(api.token, "1234");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_env_entries_are_skipped() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let settings = Settings {
+            metadata: RadrootsNostrMetadata::default(),
+            config: configuration(&[]),
+        };
+        let vars = [
+            (OsString::from_vec(vec![0xff]), OsString::from("x")),
+            (
+                OsString::from("RHI__CONFIG__LOGS_DIR"),
+                OsString::from_vec(vec![0xff]),
+            ),
+        ];
+
+        let config = settings.with_env_overrides(vars).unwrap().config;
+        assert_eq!(config.logs_dir, "logs");
+    }
+
+    #[test]
+    fn relay_flags_replace_or_extend_the_config_relays() {
+        let settings = || Settings {
+            metadata: RadrootsNostrMetadata::default(),
+            config: configuration(&["wss://file.example.com"]),
+        };
+        let local = RelayUrl::parse("ws://127.0.0.1:7777").unwrap();
+        let extra = RelayUrl::parse("wss://extra.example.com").unwrap();
+        let urls = |settings: Settings| -> Vec<String> {
+            settings
+                .config
+                .relays
+                .into_iter()
+                .map(|relay| relay.url)
+                .collect()
+        };
+
+        let replaced = settings().with_relay_overrides(std::slice::from_ref(&local), &[]);
+        assert_eq!(urls(replaced), [local.as_str()]);
+
+        let extended = settings().with_relay_overrides(&[], &[extra.clone(), extra.clone()]);
+        assert_eq!(urls(extended), ["wss://file.example.com", extra.as_str()]);
+
+        let both = settings()
+            .with_relay_overrides(std::slice::from_ref(&local), std::slice::from_ref(&extra));
+        assert_eq!(urls(both), [local.as_str(), extra.as_str()]);
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let mut value = json!({
+            "logs_dir": "logs",
+            "webhook": { "url": "https://example.com", "secret": "hunter2" },
+            "relays": [{ "url": "wss://relay", "auth_token": "abc" }],
+            "api_password": null,
+        });
+        redact_secrets(&mut value);
+
+        assert_eq!(value["logs_dir"], "logs");
+        assert_eq!(value["webhook"]["url"], "https://example.com");
+        assert_eq!(value["webhook"]["secret"], REDACTED);
+        assert_eq!(value["relays"][0]["auth_token"], REDACTED);
+        assert!(value["api_password"].is_null());
+    }
+
+    #[test]
+    fn all_stages_enabled_by_default() {
+        let trade: TradeConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(trade.enabled_stages, TradeStage::ALL.to_vec());
+        for stage in TradeStage::ALL {
+            for message_type in stage.message_types() {
+                assert!(trade.is_message_type_enabled(*message_type));
+                assert!(trade.is_kind_enabled(message_type.kind()));
+            }
+        }
+    }
+
+    #[test]
+    fn disabled_stages_filter_message_types_and_kinds() {
+        let trade: TradeConfig =
+            serde_json::from_str(r#"{ "enabled_stages": ["validate", "order"] }"#).unwrap();
+
+        assert!(trade.is_message_type_enabled(TradeListingMessageType::OrderRequest));
+        assert!(trade.is_kind_enabled(TradeListingMessageType::OrderRequest.kind()));
+        assert!(!trade.is_message_type_enabled(TradeListingMessageType::FulfillmentUpdate));
+        assert!(!trade.is_kind_enabled(TradeListingMessageType::FulfillmentUpdate.kind()));
+        assert!(!trade.is_message_type_enabled(TradeListingMessageType::Receipt));
+    }
+
+    #[test]
+    fn empty_enabled_stages_are_rejected() {
+        let err = serde_json::from_str::<TradeConfig>(r#"{ "enabled_stages": [] }"#).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("enabled_stages must list at least one stage")
+        );
+    }
+
+    #[test]
+    fn empty_api_token_is_rejected() {
+        let err = serde_json::from_str::<ApiConfig>(r#"{ "bind": "127.0.0.1:0", "token": " " }"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("api.token must not be empty"));
+    }
+
+    #[test]
+    fn lookback_requires_a_store() {
+        let mut config = configuration(&[]);
+        config.subscriber.lookback_secs = 3600;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::LookbackWithoutStore)
+        ));
+
+        config.trade.store = Some(TradeStoreConfig {
+            path: "logs/state.json".into(),
+            snapshot: Default::default(),
+        });
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn required_payment_needs_a_payment_section() {
+        let mut config = configuration(&[]);
+        config.trade.require_payment_for = vec![TradeStage::Order];
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::PaymentWithoutConfig)
+        ));
+
+        let payment = json!({ "amount_msat": 21000, "invoice_url": "http://127.0.0.1:3000" });
+        config.trade.payment = Some(serde_json::from_value(payment).unwrap());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn served_kinds_track_enabled_stages() {
+        let trade: TradeConfig =
+            serde_json::from_str(r#"{ "enabled_stages": ["validate", "order"] }"#).unwrap();
+        let kinds = served_kinds(&trade, &SubscriberConfig::default());
+
+        assert!(kinds.contains(&TradeListingMessageType::OrderRequest.kind()));
+        assert!(kinds.contains(&TradeListingMessageType::ListingValidateRequest.kind()));
+        assert!(!kinds.contains(&TradeListingMessageType::FulfillmentUpdate.kind()));
+        assert!(!kinds.contains(&TradeListingMessageType::Receipt.kind()));
+        assert_eq!(
+            served_kinds(&TradeConfig::default(), &SubscriberConfig::default()).len(),
+            TRADE_LISTING_DVM_KINDS.len()
+        );
+    }
+
+    #[test]
+    fn subscriber_kinds_are_limited_to_trade_kinds() {
+        let kind = TRADE_LISTING_DVM_KINDS[0];
+        let other = TRADE_LISTING_DVM_KINDS[1];
+        let subscriber: SubscriberConfig =
+            serde_json::from_value(json!({ "kinds": [kind] })).unwrap();
+        assert!(subscriber.subscribes_to(kind));
+        assert!(!subscriber.subscribes_to(other));
+        assert!(SubscriberConfig::default().subscribes_to(other));
+
+        let err = serde_json::from_value::<SubscriberConfig>(json!({ "kinds": [1] })).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("kind 1 is not a trade listing DVM kind")
+        );
+
+        let err = serde_json::from_value::<SubscriberConfig>(json!({ "kinds": [] })).unwrap_err();
+        assert!(err.to_string().contains("at least one kind"));
+    }
+
+    #[test]
+    fn payment_is_required_per_stage() {
+        let trade: TradeConfig = serde_json::from_value(json!({
+            "require_payment_for": ["order"],
+            "payment": { "amount_msat": 21000, "invoice_url": "http://127.0.0.1:3000/invoices" }
+        }))
+        .unwrap();
+
+        assert!(trade.requires_payment(TradeListingMessageType::OrderRequest));
+        assert!(!trade.requires_payment(TradeListingMessageType::Question));
+        let payment = trade.payment.unwrap();
+        assert_eq!(payment.amount_msat, 21000);
+        assert_eq!(payment.expiry_secs, 600);
+    }
+
+    #[test]
+    fn replies_are_encrypted_per_stage() {
+        let trade: TradeConfig =
+            serde_json::from_str(r#"{ "encrypt_replies_for": ["fulfillment"] }"#).unwrap();
+
+        assert!(trade.encrypts_reply(TradeListingMessageType::FulfillmentUpdate));
+        assert!(!trade.encrypts_reply(TradeListingMessageType::OrderResponse));
+        assert!(!TradeConfig::default().encrypts_reply(TradeListingMessageType::Receipt));
+    }
+
+    #[test]
+    fn routed_relays_fall_back_when_unmapped() {
+        let trade = TradeConfig {
+            routing: vec![
+                RelayRoute {
+                    message_types: vec![TradeListingMessageType::Receipt],
+                    relays: vec!["wss://private.example.com".into()],
+                },
+                RelayRoute {
+                    message_types: vec![TradeListingMessageType::Cancel],
+                    relays: Vec::new(),
+                },
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            trade.routed_relays(TradeListingMessageType::Receipt),
+            Some(&["wss://private.example.com".to_string()][..])
+        );
+        assert_eq!(trade.routed_relays(TradeListingMessageType::Cancel), None);
+        assert_eq!(
+            trade.routed_relays(TradeListingMessageType::OrderRequest),
+            None
+        );
+    }
+
+    #[test]
+    fn receipts_never_expire() {
+        let trade = TradeConfig {
+            reply_expiration_secs: Some(3600),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            trade.reply_expiration_secs(TradeListingMessageType::OrderResponse),
+            Some(3600)
+        );
+        assert_eq!(
+            trade.reply_expiration_secs(TradeListingMessageType::Receipt),
+            None
+        );
+    }
+}
@@ -0,0 +1,249 @@
+#![forbid(unsafe_code)]
+
+use std::sync::Arc;
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query, Request, State},
+    http::{
+        StatusCode,
+        header::{AUTHORIZATION, CONTENT_TYPE},
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use radroots_trade::listing::order::TradeOrderStatus;
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tracing::{info, warn};
+
+use crate::config::ApiConfig;
+use crate::features::trade_listing::state::{
+    SharedTradeListingState, TradeFulfillmentStage, TradeOrderState,
+};
+use crate::infra::metrics;
+
+#[derive(Clone)]
+struct ApiState {
+    orders: Arc<SharedTradeListingState>,
+    token: Arc<str>,
+}
+
+#[derive(Debug, Serialize)]
+struct OrderView {
+    order_id: String,
+    listing_addr: String,
+    buyer_pubkey: Arc<str>,
+    seller_pubkey: Arc<str>,
+    status: TradeOrderStatus,
+    fulfillment: Option<TradeFulfillmentStage>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl From<TradeOrderState> for OrderView {
+    fn from(order: TradeOrderState) -> Self {
+        Self {
+            order_id: order.order_id,
+            listing_addr: order.listing_addr,
+            buyer_pubkey: order.buyer_pubkey,
+            seller_pubkey: order.seller_pubkey,
+            status: order.status,
+            fulfillment: order.fulfillment,
+            created_at: order.created_at,
+            updated_at: order.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OrdersQuery {
+    status: Option<String>,
+}
+
+pub async fn serve_api(
+    cfg: &ApiConfig,
+    orders: Arc<SharedTradeListingState>,
+) -> std::io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(&cfg.bind).await?;
+    info!("Serving trade order API on {}", listener.local_addr()?);
+    let app = router(orders, &cfg.token);
+    Ok(tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            warn!("trade order API stopped: {e}");
+        }
+    }))
+}
+
+fn router(orders: Arc<SharedTradeListingState>, token: &str) -> Router {
+    let state = ApiState {
+        orders,
+        token: Arc::from(token),
+    };
+    Router::new()
+        .route("/orders", get(list_orders))
+        .route("/orders/{order_id}", get(get_order))
+        .route("/metrics", get(get_metrics))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_bearer,
+        ))
+        .with_state(state)
+}
+
+async fn require_bearer(
+    State(state): State<ApiState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let presented = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(token) if constant_time_eq(token.as_bytes(), state.token.as_bytes()) => {
+            Ok(next.run(request).await)
+        }
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+async fn get_order(
+    State(state): State<ApiState>,
+    Path(order_id): Path<String>,
+) -> Result<Json<OrderView>, StatusCode> {
+    let shard = state.orders.order_shard(&order_id).read().await;
+    let order = shard.get_order(&order_id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(order.clone().into()))
+}
+
+async fn list_orders(
+    State(state): State<ApiState>,
+    Query(query): Query<OrdersQuery>,
+) -> Json<Vec<OrderView>> {
+    let mut orders: Vec<OrderView> = state
+        .orders
+        .orders()
+        .await
+        .into_iter()
+        .filter(|order| match &query.status {
+            Some(status) => format!("{:?}", order.status).eq_ignore_ascii_case(status),
+            None => true,
+        })
+        .map(OrderView::from)
+        .collect();
+    orders.sort_by(|a, b| a.order_id.cmp(&b.order_id));
+    Json(orders)
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    let body = metrics::render_prometheus(&metrics::snapshot());
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::router;
+    use crate::features::trade_listing::state::{
+        SharedTradeListingState, TradeListingState, TradeOrderState,
+    };
+    use axum::{
+        Router,
+        body::{Body, to_bytes},
+        http::{Request, StatusCode, header::AUTHORIZATION},
+    };
+    use radroots_trade::listing::order::TradeOrderStatus;
+    use serde_json::Value;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    fn order(order_id: &str, status: TradeOrderStatus) -> TradeOrderState {
+        let mut order = TradeOrderState::new(
+            order_id,
+            "30402:seller:listing",
+            "buyer".into(),
+            "seller".into(),
+            status,
+            1_700_000_000,
+        );
+        order.updated_at = 1_700_000_100;
+        order
+    }
+
+    fn app() -> Router {
+        let mut state = TradeListingState::default();
+        state.insert_order(order("order-1", TradeOrderStatus::Requested));
+        state.insert_order(order("order-2", TradeOrderStatus::Accepted));
+        router(Arc::new(SharedTradeListingState::new(state, 4)), "secret")
+    }
+
+    async fn get(uri: &str, token: Option<&str>) -> (StatusCode, Value) {
+        let mut request = Request::get(uri);
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let response = app()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn requests_without_the_bearer_token_are_rejected() {
+        assert_eq!(get("/orders", None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            get("/orders", Some("wrong")).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn orders_are_served_by_id_and_filtered_by_status() {
+        let (status, body) = get("/orders/order-1", Some("secret")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["order_id"], "order-1");
+        assert_eq!(body["buyer_pubkey"], "buyer");
+        assert_eq!(body["listing_addr"], "30402:seller:listing");
+        assert_eq!(body["created_at"], 1_700_000_000);
+        assert!(body.get("seen_event_ids").is_none());
+
+        let (status, _) = get("/orders/missing", Some("secret")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (_, body) = get("/orders?status=requested", Some("secret")).await;
+        let ids: Vec<&str> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|order| order["order_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["order-1"]);
+
+        let (_, body) = get("/orders", Some("secret")).await;
+        assert_eq!(body.as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_behind_the_bearer_token() {
+        assert_eq!(get("/metrics", None).await.0, StatusCode::UNAUTHORIZED);
+
+        let request = Request::get("/metrics")
+            .header(AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = app().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("rhi_notifications_lagged_total "));
+    }
+}
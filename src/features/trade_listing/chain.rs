@@ -0,0 +1,148 @@
+#![forbid(unsafe_code)]
+
+use radroots_events::tags::{TAG_D, TAG_E_ROOT};
+use radroots_nostr::prelude::RadrootsNostrEvent;
+use thiserror::Error;
+
+use crate::infra::nostr::tag_lookup;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TradeChainError {
+    #[error("reference event {event_id} has no trade root tag")]
+    MissingRoot { event_id: String },
+    #[error("reference event {event_id} has trade root {found}, expected {expected}")]
+    RootMismatch {
+        event_id: String,
+        expected: String,
+        found: String,
+    },
+    #[error("reference event {event_id} has trade id {found}, expected {expected}")]
+    TradeIdMismatch {
+        event_id: String,
+        expected: String,
+        found: String,
+    },
+}
+
+// The chain tags a stage pushes onto its result: the trade root, the result it
+// follows, and the trade id carried along from the order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainCtx {
+    pub e_root: String,
+    pub e_prev: String,
+    pub trade_id: Option<String>,
+}
+
+// Checks that the referenced result is rooted, and rooted where the stage expects
+// when `expected_root` is known. A forged or mismatched reference fails here,
+// before any result is built on top of it.
+pub fn verify_chain(
+    prev_event: &RadrootsNostrEvent,
+    expected_root: Option<&str>,
+) -> Result<ChainCtx, TradeChainError> {
+    let event_id = prev_event.id.to_hex();
+    let e_root = tag_lookup(prev_event, TAG_E_ROOT)
+        .filter(|root| !root.is_empty())
+        .ok_or_else(|| TradeChainError::MissingRoot {
+            event_id: event_id.clone(),
+        })?;
+    if let Some(expected) = expected_root.filter(|expected| *expected != e_root) {
+        return Err(TradeChainError::RootMismatch {
+            event_id,
+            expected: expected.to_string(),
+            found: e_root,
+        });
+    }
+    Ok(ChainCtx {
+        e_root,
+        e_prev: event_id,
+        trade_id: tag_lookup(prev_event, TAG_D),
+    })
+}
+
+// The request's own chain tags, when the client sent them, are the chain it
+// expects to continue.
+pub fn verify_request_chain(
+    request: &RadrootsNostrEvent,
+    prev_event: &RadrootsNostrEvent,
+) -> Result<ChainCtx, TradeChainError> {
+    let chain = verify_chain(prev_event, tag_lookup(request, TAG_E_ROOT).as_deref())?;
+    match (tag_lookup(request, TAG_D), &chain.trade_id) {
+        (Some(expected), Some(found)) if expected != *found => {
+            Err(TradeChainError::TradeIdMismatch {
+                event_id: chain.e_prev,
+                expected,
+                found: found.clone(),
+            })
+        }
+        _ => Ok(chain),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChainCtx, TradeChainError, verify_chain, verify_request_chain};
+    use nostr::{EventBuilder, Kind, Tag};
+    use radroots_events::tags::{TAG_D, TAG_E_ROOT};
+    use radroots_nostr::prelude::{RadrootsNostrEvent, RadrootsNostrKeys};
+
+    fn event(tags: &[[&str; 2]]) -> RadrootsNostrEvent {
+        EventBuilder::new(Kind::Custom(6302), "")
+            .tags(tags.iter().map(|tag| Tag::parse(*tag).unwrap()))
+            .sign_with_keys(&RadrootsNostrKeys::generate())
+            .unwrap()
+    }
+
+    #[test]
+    fn chain_carries_root_and_trade_id_forward() {
+        let prev = event(&[[TAG_E_ROOT, "listing"], [TAG_D, "trade-1"]]);
+        let chain = verify_chain(&prev, Some("listing")).unwrap();
+        assert_eq!(
+            chain,
+            ChainCtx {
+                e_root: "listing".into(),
+                e_prev: prev.id.to_hex(),
+                trade_id: Some("trade-1".into()),
+            }
+        );
+        assert!(verify_chain(&prev, None).is_ok());
+    }
+
+    #[test]
+    fn broken_chains_fail_with_specific_errors() {
+        let unrooted = event(&[[TAG_D, "trade-1"]]);
+        assert!(matches!(
+            verify_chain(&unrooted, None),
+            Err(TradeChainError::MissingRoot { .. })
+        ));
+
+        let prev = event(&[[TAG_E_ROOT, "listing"], [TAG_D, "trade-1"]]);
+        assert!(matches!(
+            verify_chain(&prev, Some("other-listing")),
+            Err(TradeChainError::RootMismatch { .. })
+        ));
+
+        let request = event(&[[TAG_E_ROOT, "listing"], [TAG_D, "trade-2"]]);
+        assert!(matches!(
+            verify_request_chain(&request, &prev),
+            Err(TradeChainError::TradeIdMismatch { .. })
+        ));
+        let request = event(&[[TAG_E_ROOT, "listing"]]);
+        assert!(verify_request_chain(&request, &prev).is_ok());
+    }
+
+    #[test]
+    fn accept_without_root_is_rejected() {
+        let request = event(&[[TAG_D, "trade-1"]]);
+        let accept = event(&[[TAG_D, "trade-1"]]);
+        match verify_request_chain(&request, &accept) {
+            Err(TradeChainError::MissingRoot { event_id }) => {
+                assert_eq!(event_id, accept.id.to_hex());
+            }
+            other => panic!("expected a missing root, got {other:?}"),
+        }
+
+        let accept = event(&[[TAG_E_ROOT, "listing"], [TAG_D, "trade-1"]]);
+        assert!(verify_request_chain(&request, &accept).is_ok());
+    }
+}
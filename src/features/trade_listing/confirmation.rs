@@ -0,0 +1,89 @@
+#![forbid(unsafe_code)]
+
+use nostr::hashes::{Hash, sha256};
+use serde::Serialize;
+use serde_json::Value;
+
+pub const CONFIRMATION_TAG: &str = "confirmation";
+
+// The confirmation hash is the lowercase hex sha256 of the order payload encoded as
+// compact JSON with object keys sorted lexicographically at every depth. Strings and
+// numbers are written exactly as serde_json encodes them, so the hash covers the
+// order id, quantities, prices and totals as the buyer submitted them.
+pub fn order_confirmation_hash<T: Serialize>(order: &T) -> Result<String, serde_json::Error> {
+    let value = serde_json::to_value(order)?;
+    let mut canonical = String::new();
+    write_canonical(&value, &mut canonical)?;
+    Ok(sha256::Hash::hash(canonical.as_bytes()).to_string())
+}
+
+fn write_canonical(value: &Value, out: &mut String) -> Result<(), serde_json::Error> {
+    match value {
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out)?;
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(key)?);
+                out.push(':');
+                write_canonical(&map[key], out)?;
+            }
+            out.push('}');
+        }
+        scalar => out.push_str(&serde_json::to_string(scalar)?),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::order_confirmation_hash;
+    use serde_json::json;
+
+    fn order() -> serde_json::Value {
+        json!({
+            "total": "10.00",
+            "quantity": 2,
+            "price": "5.00",
+            "order_id": "order-1",
+        })
+    }
+
+    #[test]
+    fn confirmation_hash_is_stable() {
+        let hash = order_confirmation_hash(&order()).unwrap();
+        assert_eq!(hash, order_confirmation_hash(&order()).unwrap());
+        assert_eq!(
+            hash,
+            "69572c5c6d0cc0bb2ea7bcace5e36b78d334f818110a882c57a87e78464400ad"
+        );
+    }
+
+    #[test]
+    fn confirmation_hash_changes_with_any_field() {
+        let base = order_confirmation_hash(&order()).unwrap();
+        for (field, value) in [
+            ("total", json!("11.00")),
+            ("quantity", json!(3)),
+            ("price", json!("5.01")),
+            ("order_id", json!("order-2")),
+        ] {
+            let mut changed = order();
+            changed[field] = value;
+            assert_ne!(order_confirmation_hash(&changed).unwrap(), base, "{field}");
+        }
+    }
+}
@@ -0,0 +1,110 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use radroots_nostr::prelude::RadrootsNostrEvent;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DeadLetterError {
+    #[error("dead-letter io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("dead-letter serde error: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetterEntry {
+    pub event: RadrootsNostrEvent,
+    pub error: String,
+    pub at: u64,
+}
+
+#[derive(Debug)]
+pub struct DeadLetterJournal {
+    path: PathBuf,
+    write_lock: Mutex<()>,
+}
+
+impl DeadLetterJournal {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&self, event: &RadrootsNostrEvent, error: &str) -> Result<(), DeadLetterError> {
+        let entry = DeadLetterEntry {
+            event: event.clone(),
+            error: error.to_string(),
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        let _guard = self.write_lock.lock().unwrap_or_else(|p| p.into_inner());
+        if let Some(parent) = self.path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeadLetterEntry, DeadLetterJournal};
+    use nostr::{EventBuilder, Kind};
+    use radroots_nostr::prelude::RadrootsNostrKeys;
+
+    #[test]
+    fn journal_appends_and_reads_entries() {
+        let path =
+            std::env::temp_dir().join(format!("rhi-dead-letter-{}.jsonl", uuid::Uuid::new_v4()));
+        let journal = DeadLetterJournal::new(&path);
+        let keys = RadrootsNostrKeys::generate();
+        let first = EventBuilder::new(Kind::Custom(5321), "first")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let second = EventBuilder::new(Kind::Custom(5321), "second")
+            .sign_with_keys(&keys)
+            .unwrap();
+
+        journal.append(&first, "relay timeout").unwrap();
+        journal.append(&second, "feedback failed").unwrap();
+
+        let entries: Vec<DeadLetterEntry> = std::fs::read_to_string(journal.path())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].event.id, first.id);
+        assert_eq!(entries[0].error, "relay timeout");
+        assert_eq!(entries[1].event.id, second.id);
+        assert!(entries[1].at > 0);
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,71 +1,189 @@
+use std::str::FromStr;
+
 use radroots_events::listing::RadrootsListing;
+use radroots_nostr::prelude::RadrootsNostrEvent;
+use radroots_trade::listing::order::TradeOrder;
 use radroots_trade::prelude::price_ext::BinPricingTryExt;
-use radroots_trade::prelude::stage::order::{
-    TradeListingOrderRequestPayload, TradeListingOrderResult,
-};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::features::trade_listing::handlers::dvm::TradeListingDvmError;
+
+// An itemized quote for every bin in the order; the line totals sum to `total`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OrderQuote {
+    pub items: Vec<OrderBreakdown>,
+    pub total: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OrderBreakdown {
+    pub tier: String,
+    pub unit_price: Decimal,
+    pub quantity: u64,
+    pub subtotal: Decimal,
+    pub discounts: Vec<AppliedDiscount>,
+    pub total: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AppliedDiscount {
+    pub description: String,
+    pub amount: Decimal,
+}
 
-use crate::features::trade_listing::handlers::order::JobRequestOrderError;
+impl OrderBreakdown {
+    // Bin pricing only exposes the subtotal and the discounted total, so the gap
+    // between the two is itemised as one line for the tier (negative if it adds).
+    pub fn new(
+        tier: String,
+        unit_price: Decimal,
+        quantity: u64,
+        subtotal: Decimal,
+        total: Decimal,
+    ) -> Self {
+        let discount = subtotal - total;
+        let description = if discount > Decimal::ZERO {
+            format!("{tier} discount")
+        } else {
+            format!("{tier} adjustment")
+        };
+        let discounts = if discount.is_zero() {
+            Vec::new()
+        } else {
+            vec![AppliedDiscount {
+                description,
+                amount: discount,
+            }]
+        };
+        Self {
+            tier,
+            unit_price,
+            quantity,
+            subtotal,
+            discounts,
+            total,
+        }
+    }
+}
+
+// Money serialises either as a bare amount or as an object whose `amount` may itself
+// be a money object, as in a price per canonical unit.
+fn money_amount(value: &Value) -> Option<Decimal> {
+    match value {
+        Value::Object(fields) => money_amount(fields.get("amount")?),
+        Value::String(amount) => Decimal::from_str(amount).ok(),
+        Value::Number(amount) => Decimal::from_str(&amount.to_string()).ok(),
+        _ => None,
+    }
+}
+
+fn itemize<T: Serialize>(
+    money: &T,
+    bin_id: &str,
+    field: &str,
+) -> Result<Decimal, TradeListingDvmError> {
+    serde_json::to_value(money)
+        .ok()
+        .as_ref()
+        .and_then(money_amount)
+        .ok_or_else(|| TradeListingDvmError::Pricing(format!("bin {bin_id} has no {field}")))
+}
 
 pub trait ListingOrderCalculator {
-    fn calculate_order(
-        &self,
-        order: &TradeListingOrderRequestPayload,
-    ) -> Result<TradeListingOrderResult, JobRequestOrderError>;
+    fn calculate_order(&self, order: &TradeOrder) -> Result<OrderQuote, TradeListingDvmError>;
 }
 
 impl ListingOrderCalculator for RadrootsListing {
-    fn calculate_order(
-        &self,
-        order: &TradeListingOrderRequestPayload,
-    ) -> Result<TradeListingOrderResult, JobRequestOrderError> {
-        if order.bin_id.trim().is_empty() {
-            return Err(JobRequestOrderError::Unsatisfiable(format!(
-                "requested bin id is empty"
-            )));
+    fn calculate_order(&self, order: &TradeOrder) -> Result<OrderQuote, TradeListingDvmError> {
+        if order.items.is_empty() {
+            return Err(TradeListingDvmError::InvalidOrder);
         }
-
-        if order.bin_count == 0 {
-            return Err(JobRequestOrderError::Unsatisfiable(
-                "requested bin count must be greater than 0".to_string(),
+        let mut items = Vec::with_capacity(order.items.len());
+        for item in &order.items {
+            if item.bin_count == 0 {
+                return Err(TradeListingDvmError::InvalidOrder);
+            }
+            let bin_id = item.bin_id.as_str();
+            let bin = self
+                .bins
+                .iter()
+                .find(|bin| bin.bin_id == bin_id)
+                .ok_or_else(|| {
+                    TradeListingDvmError::Pricing(format!("bin {bin_id} is not listed"))
+                })?;
+            let subtotal = bin
+                .try_subtotal_for_count(item.bin_count)
+                .map_err(|e| TradeListingDvmError::Pricing(format!("bin {bin_id}: {e}")))?;
+            let total = bin
+                .try_total_for_count(item.bin_count)
+                .map_err(|e| TradeListingDvmError::Pricing(format!("bin {bin_id}: {e}")))?;
+            items.push(OrderBreakdown::new(
+                format!("bin {bin_id}"),
+                itemize(&bin.price_per_canonical_unit, bin_id, "unit price")?,
+                item.bin_count as u64,
+                itemize(&subtotal, bin_id, "subtotal")?,
+                itemize(&total, bin_id, "total")?,
             ));
         }
+        let total = items.iter().map(|item| item.total).sum();
+        Ok(OrderQuote { items, total })
+    }
+}
+
+// The quote is priced from the seller's listing, never taken from the buyer's payload.
+pub fn quote_order(
+    listing: &RadrootsNostrEvent,
+    order: &TradeOrder,
+) -> Result<OrderQuote, TradeListingDvmError> {
+    let listing: RadrootsListing = serde_json::from_str(&listing.content)
+        .map_err(|e| TradeListingDvmError::Pricing(format!("unreadable listing: {e}")))?;
+    listing.calculate_order(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+    use serde_json::json;
+
+    use super::{OrderBreakdown, money_amount};
+
+    fn dec(value: &str) -> Decimal {
+        Decimal::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn money_amounts_are_read_from_objects_and_scalars() {
+        let money = json!({ "amount": "4.25", "currency": "USD" });
+        assert_eq!(money_amount(&money), Some(dec("4.25")));
+        assert_eq!(money_amount(&json!(3)), Some(dec("3")));
+        assert_eq!(money_amount(&json!({ "currency": "USD" })), None);
+
+        let unit_price = json!({
+            "amount": { "amount": "0.50", "currency": "USD" },
+            "quantity": { "amount": "1", "unit": "each" }
+        });
+        assert_eq!(money_amount(&unit_price), Some(dec("0.50")));
+    }
+
+    #[test]
+    fn breakdown_itemizes_the_gap_between_subtotal_and_total() {
+        let discounted =
+            OrderBreakdown::new("bin 1kg".into(), dec("4.25"), 4, dec("17.00"), dec("15.30"));
+        assert_eq!(discounted.discounts.len(), 1);
+        assert_eq!(discounted.discounts[0].description, "bin 1kg discount");
+        assert_eq!(discounted.discounts[0].amount, dec("1.70"));
+
+        let undiscounted =
+            OrderBreakdown::new("bin 1kg".into(), dec("4.25"), 4, dec("17.00"), dec("17.00"));
+        assert!(undiscounted.discounts.is_empty());
 
-        let bin = self
-            .bins
-            .iter()
-            .find(|bin| bin.bin_id == order.bin_id)
-            .ok_or_else(|| {
-                JobRequestOrderError::Unsatisfiable(format!(
-                    "requested bin {} not available",
-                    order.bin_id
-                ))
-            })?;
-
-        let out_price = bin.price_per_canonical_unit.clone();
-        let out_subtotal = bin
-            .try_subtotal_for_count(order.bin_count)
-            .map_err(|err| {
-                JobRequestOrderError::Unsatisfiable(format!(
-                    "failed to price requested bin: {err}"
-                ))
-            })?;
-        let out_total = bin
-            .try_total_for_count(order.bin_count)
-            .map_err(|err| {
-                JobRequestOrderError::Unsatisfiable(format!(
-                    "failed to total requested bin: {err}"
-                ))
-            })?;
-
-        let discounts_out = self.discounts.clone().unwrap_or_default();
-
-        Ok(TradeListingOrderResult {
-            bin_id: order.bin_id.clone(),
-            bin_count: order.bin_count,
-            price: out_price,
-            discounts: discounts_out,
-            subtotal: out_subtotal,
-            total: out_total,
-        })
+        let surcharged =
+            OrderBreakdown::new("bin 1kg".into(), dec("4.25"), 4, dec("17.00"), dec("18.00"));
+        assert_eq!(surcharged.discounts[0].description, "bin 1kg adjustment");
+        assert_eq!(surcharged.discounts[0].amount, dec("-1.00"));
     }
 }
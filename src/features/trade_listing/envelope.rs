@@ -0,0 +1,188 @@
+#![forbid(unsafe_code)]
+
+use nostr::hashes::{Hash, sha256};
+use radroots_trade::listing::dvm::TradeListingEnvelope;
+use serde::Serialize;
+
+use crate::features::trade_listing::handlers::dvm::TradeListingDvmError;
+
+pub const TRADE_LISTING_ENVELOPE_VERSION: u8 = 1;
+
+const ENVELOPE_VERSION_FIELD: &str = "version";
+
+pub const MAX_ORDER_ID_LEN: usize = 128;
+
+// Order ids become `d` tag values and state map keys, so they are limited to a
+// short ASCII charset that covers ids like `trade:<root>:<request>`.
+pub fn validate_order_id(order_id: &str) -> Result<(), TradeListingDvmError> {
+    let valid = !order_id.is_empty()
+        && order_id.len() <= MAX_ORDER_ID_LEN
+        && order_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b':' | b'-' | b'_' | b'.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(TradeListingDvmError::InvalidOrder)
+    }
+}
+
+pub const DERIVED_ORDER_ID_PREFIX: &str = "order:";
+
+// `order:` followed by the hex sha256 of the JSON array `[listing_addr, buyer_pubkey,
+// nonce]`, so the same inputs always give the same id and another buyer cannot claim
+// it. The listing address should be the canonical `kind:pubkey:d` coordinate.
+pub fn derive_order_id(listing_addr: &str, buyer_pubkey: &str, nonce: &str) -> String {
+    let preimage = serde_json::json!([listing_addr, buyer_pubkey, nonce]).to_string();
+    let digest = sha256::Hash::hash(preimage.as_bytes());
+    format!("{DERIVED_ORDER_ID_PREFIX}{digest}")
+}
+
+pub fn verify_order_id(
+    order_id: &str,
+    listing_addr: &str,
+    buyer_pubkey: &str,
+    nonce: Option<&str>,
+    required: bool,
+) -> Result<(), TradeListingDvmError> {
+    let verified = match nonce {
+        Some(nonce) => derive_order_id(listing_addr, buyer_pubkey, nonce) == order_id,
+        None => !required,
+    };
+    if verified {
+        Ok(())
+    } else {
+        Err(TradeListingDvmError::UnverifiedOrderId)
+    }
+}
+
+pub fn decode_envelope(
+    content: &str,
+) -> Result<TradeListingEnvelope<serde_json::Value>, TradeListingDvmError> {
+    let mut value: serde_json::Value = serde_json::from_str(content)?;
+    let version = match value
+        .as_object_mut()
+        .and_then(|obj| obj.remove(ENVELOPE_VERSION_FIELD))
+    {
+        None => u64::from(TRADE_LISTING_ENVELOPE_VERSION),
+        Some(version) => version.as_u64().ok_or_else(|| {
+            TradeListingDvmError::InvalidPayload(format!("invalid envelope version: {version}"))
+        })?,
+    };
+    if version == 0 || version > u64::from(TRADE_LISTING_ENVELOPE_VERSION) {
+        return Err(TradeListingDvmError::UnsupportedVersion {
+            version,
+            supported: TRADE_LISTING_ENVELOPE_VERSION,
+        });
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+pub fn encode_envelope<T: Serialize>(
+    envelope: &TradeListingEnvelope<T>,
+) -> Result<String, serde_json::Error> {
+    let mut value = serde_json::to_value(envelope)?;
+    if let Some(obj) = value.as_object_mut() {
+        obj.insert(
+            ENVELOPE_VERSION_FIELD.to_string(),
+            serde_json::Value::from(TRADE_LISTING_ENVELOPE_VERSION),
+        );
+    }
+    serde_json::to_string(&value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DERIVED_ORDER_ID_PREFIX, MAX_ORDER_ID_LEN, TRADE_LISTING_ENVELOPE_VERSION, decode_envelope,
+        derive_order_id, encode_envelope, validate_order_id, verify_order_id,
+    };
+    use crate::features::trade_listing::handlers::dvm::TradeListingDvmError;
+    use radroots_trade::listing::dvm::{TradeListingEnvelope, TradeListingMessageType};
+
+    fn encoded() -> serde_json::Value {
+        let envelope = TradeListingEnvelope::new(
+            TradeListingMessageType::ListingValidateRequest,
+            "30402:seller:listing".to_string(),
+            None,
+            serde_json::json!({}),
+        );
+        serde_json::from_str(&encode_envelope(&envelope).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn encoded_envelope_carries_version() {
+        assert_eq!(
+            encoded()["version"],
+            serde_json::Value::from(TRADE_LISTING_ENVELOPE_VERSION)
+        );
+    }
+
+    #[test]
+    fn v1_envelope_is_accepted() {
+        let envelope = decode_envelope(&encoded().to_string()).unwrap();
+        assert_eq!(envelope.listing_addr, "30402:seller:listing");
+    }
+
+    #[test]
+    fn unversioned_envelope_defaults_to_v1() {
+        let mut value = encoded();
+        value.as_object_mut().unwrap().remove("version");
+        assert!(decode_envelope(&value.to_string()).is_ok());
+    }
+
+    #[test]
+    fn v2_envelope_is_rejected() {
+        let mut value = encoded();
+        value["version"] = serde_json::Value::from(2);
+        let err = decode_envelope(&value.to_string()).unwrap_err();
+        assert!(matches!(
+            err,
+            TradeListingDvmError::UnsupportedVersion {
+                version: 2,
+                supported: TRADE_LISTING_ENVELOPE_VERSION,
+            }
+        ));
+        assert!(err.to_string().contains("supported: 1"));
+    }
+
+    #[test]
+    fn order_ids_are_bounded_to_a_safe_charset() {
+        assert!(validate_order_id("trade:abc123:def456").is_ok());
+        assert!(validate_order_id("order-1_v2.0").is_ok());
+        assert!(validate_order_id(&"a".repeat(MAX_ORDER_ID_LEN)).is_ok());
+
+        for order_id in ["", "order 1", "order\n1", "ordér", "order\"],[\"p"] {
+            assert!(matches!(
+                validate_order_id(order_id),
+                Err(TradeListingDvmError::InvalidOrder)
+            ));
+        }
+        assert!(validate_order_id(&"a".repeat(MAX_ORDER_ID_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn derived_order_ids_bind_listing_buyer_and_nonce() {
+        let listing = "30402:seller:listing";
+        let order_id = derive_order_id(listing, "buyer", "nonce-1");
+        assert!(order_id.starts_with(DERIVED_ORDER_ID_PREFIX));
+        assert_eq!(order_id, derive_order_id(listing, "buyer", "nonce-1"));
+        assert!(validate_order_id(&order_id).is_ok());
+
+        assert_ne!(order_id, derive_order_id(listing, "other", "nonce-1"));
+        assert_ne!(order_id, derive_order_id(listing, "buyer", "nonce-2"));
+        assert_ne!(
+            derive_order_id("a:b", "c", "d"),
+            derive_order_id("a", "b:c", "d")
+        );
+
+        assert!(verify_order_id(&order_id, listing, "buyer", Some("nonce-1"), true).is_ok());
+        assert!(verify_order_id("order-1", listing, "buyer", None, false).is_ok());
+        for (nonce, required) in [(Some("nonce-1"), false), (None, true)] {
+            let err = verify_order_id("order-1", listing, "buyer", nonce, required).unwrap_err();
+            assert!(matches!(err, TradeListingDvmError::UnverifiedOrderId));
+        }
+        let err = verify_order_id(&order_id, listing, "other", Some("nonce-1"), false);
+        assert!(matches!(err, Err(TradeListingDvmError::UnverifiedOrderId)));
+    }
+}
@@ -0,0 +1,283 @@
+#![forbid(unsafe_code)]
+
+use std::sync::Arc;
+#[cfg(any(feature = "redis", feature = "nats"))]
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use radroots_trade::listing::order::TradeOrderStatus;
+#[cfg(feature = "redis")]
+use redis::AsyncCommands;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+
+use crate::config::{EventSinkKind, EventsConfig};
+use crate::features::trade_listing::state::TradeOrderState;
+
+#[derive(Debug, Error)]
+pub enum TradeEventSinkError {
+    #[cfg(feature = "redis")]
+    #[error("redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("nats error: {0}")]
+    Nats(String),
+    #[error("{0} sink support is not compiled in")]
+    Disabled(&'static str),
+    #[error("event queue stopped")]
+    QueueClosed,
+    #[error("publish timed out")]
+    TimedOut,
+    #[error("webhook error: {0}")]
+    Webhook(String),
+    #[error("serde error: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TradeStatusChanged {
+    pub order_id: String,
+    pub listing_addr: String,
+    pub buyer_pubkey: Arc<str>,
+    pub seller_pubkey: Arc<str>,
+    pub from: Option<TradeOrderStatus>,
+    pub to: TradeOrderStatus,
+    pub total: Option<String>,
+    pub at: u64,
+}
+
+impl TradeStatusChanged {
+    pub fn new(order: &TradeOrderState, from: Option<TradeOrderStatus>) -> Self {
+        Self {
+            order_id: order.order_id.clone(),
+            listing_addr: order.listing_addr.clone(),
+            buyer_pubkey: Arc::clone(&order.buyer_pubkey),
+            seller_pubkey: Arc::clone(&order.seller_pubkey),
+            from,
+            to: order.status.clone(),
+            total: order.total.clone(),
+            at: order.updated_at,
+        }
+    }
+}
+
+pub trait TradeEventSink: Send + Sync {
+    fn publish(&self, event: TradeStatusChanged) -> BoxFuture<'_, Result<(), TradeEventSinkError>>;
+}
+
+// Broker publishes are bounded here; the webhook sink bounds its own requests.
+#[cfg(any(feature = "redis", feature = "nats"))]
+const SINK_PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The broker sink is fronted by a bounded queue drained by a background task, so a
+// slow or unreachable broker never holds up a handler.
+pub async fn connect_sink(
+    cfg: &EventsConfig,
+) -> Result<Arc<dyn TradeEventSink>, TradeEventSinkError> {
+    let sink: Arc<dyn TradeEventSink> = match cfg.sink {
+        #[cfg(feature = "redis")]
+        EventSinkKind::Redis => Arc::new(RedisSink::connect(&cfg.url, &cfg.channel).await?),
+        #[cfg(not(feature = "redis"))]
+        EventSinkKind::Redis => return Err(TradeEventSinkError::Disabled("redis")),
+        #[cfg(feature = "nats")]
+        EventSinkKind::Nats => Arc::new(NatsSink::connect(&cfg.url, &cfg.channel).await?),
+        #[cfg(not(feature = "nats"))]
+        EventSinkKind::Nats => return Err(TradeEventSinkError::Disabled("nats")),
+    };
+    Ok(Arc::new(QueuedSink::spawn(sink, cfg.queue_capacity)))
+}
+
+pub struct QueuedSink {
+    queue: mpsc::Sender<TradeStatusChanged>,
+}
+
+impl QueuedSink {
+    pub fn spawn(sink: Arc<dyn TradeEventSink>, capacity: usize) -> Self {
+        let (queue, rx) = mpsc::channel(capacity.max(1));
+        tokio::spawn(drain(rx, sink));
+        Self { queue }
+    }
+}
+
+impl TradeEventSink for QueuedSink {
+    fn publish(&self, event: TradeStatusChanged) -> BoxFuture<'_, Result<(), TradeEventSinkError>> {
+        Box::pin(async move {
+            match self.queue.try_send(event) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(event)) => {
+                    warn!(
+                        "events: queue full, dropping status change for {}",
+                        event.order_id
+                    );
+                    Ok(())
+                }
+                Err(TrySendError::Closed(_)) => Err(TradeEventSinkError::QueueClosed),
+            }
+        })
+    }
+}
+
+async fn drain(mut rx: mpsc::Receiver<TradeStatusChanged>, sink: Arc<dyn TradeEventSink>) {
+    while let Some(event) = rx.recv().await {
+        let order_id = event.order_id.clone();
+        if let Err(e) = sink.publish(event).await {
+            warn!("events: failed to publish status change for {order_id}: {e}");
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+struct RedisSink {
+    conn: redis::aio::MultiplexedConnection,
+    channel: String,
+}
+
+#[cfg(feature = "redis")]
+impl RedisSink {
+    async fn connect(url: &str, channel: &str) -> Result<Self, TradeEventSinkError> {
+        let conn = redis::Client::open(url)?
+            .get_multiplexed_async_connection()
+            .await?;
+        Ok(Self {
+            conn,
+            channel: channel.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "redis")]
+impl TradeEventSink for RedisSink {
+    fn publish(&self, event: TradeStatusChanged) -> BoxFuture<'_, Result<(), TradeEventSinkError>> {
+        Box::pin(async move {
+            let payload = serde_json::to_string(&event)?;
+            let mut conn = self.conn.clone();
+            let publish = conn.publish::<_, _, ()>(&self.channel, payload);
+            tokio::time::timeout(SINK_PUBLISH_TIMEOUT, publish)
+                .await
+                .map_err(|_| TradeEventSinkError::TimedOut)??;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(feature = "nats")]
+struct NatsSink {
+    client: async_nats::Client,
+    subject: String,
+}
+
+#[cfg(feature = "nats")]
+impl NatsSink {
+    async fn connect(url: &str, subject: &str) -> Result<Self, TradeEventSinkError> {
+        let client = async_nats::connect(url)
+            .await
+            .map_err(|e| TradeEventSinkError::Nats(e.to_string()))?;
+        Ok(Self {
+            client,
+            subject: subject.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "nats")]
+impl TradeEventSink for NatsSink {
+    fn publish(&self, event: TradeStatusChanged) -> BoxFuture<'_, Result<(), TradeEventSinkError>> {
+        Box::pin(async move {
+            let payload = serde_json::to_vec(&event)?;
+            let publish = self.client.publish(self.subject.clone(), payload.into());
+            tokio::time::timeout(SINK_PUBLISH_TIMEOUT, publish)
+                .await
+                .map_err(|_| TradeEventSinkError::TimedOut)?
+                .map_err(|e| TradeEventSinkError::Nats(e.to_string()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueuedSink, TradeEventSink, TradeEventSinkError, TradeStatusChanged};
+    use crate::features::trade_listing::state::TradeOrderState;
+    use futures::future::BoxFuture;
+    use radroots_trade::listing::order::TradeOrderStatus;
+    use serde_json::json;
+    use std::{sync::Arc, time::Duration};
+    use tokio::sync::mpsc;
+
+    struct SlowSink(mpsc::UnboundedSender<String>);
+
+    impl TradeEventSink for SlowSink {
+        fn publish(
+            &self,
+            event: TradeStatusChanged,
+        ) -> BoxFuture<'_, Result<(), TradeEventSinkError>> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let _ = self.0.send(event.order_id);
+                Ok(())
+            })
+        }
+    }
+
+    fn change(order_id: &str) -> TradeStatusChanged {
+        TradeStatusChanged {
+            order_id: order_id.into(),
+            listing_addr: "30402:seller:listing".into(),
+            buyer_pubkey: "buyer".into(),
+            seller_pubkey: "seller".into(),
+            from: None,
+            to: TradeOrderStatus::Requested,
+            total: None,
+            at: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn queued_sink_publishes_in_the_background() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let sink = QueuedSink::spawn(Arc::new(SlowSink(tx)), 1);
+
+        let publish = tokio::time::timeout(Duration::from_millis(10), sink.publish(change("a")));
+        publish.await.unwrap().unwrap();
+        assert_eq!(rx.recv().await.unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_instead_of_blocking() {
+        let (queue, mut rx) = mpsc::channel(1);
+        let sink = QueuedSink { queue };
+
+        sink.publish(change("order-1")).await.unwrap();
+        sink.publish(change("order-2")).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().order_id, "order-1");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn status_change_carries_order_identity_and_both_statuses() {
+        let mut order = TradeOrderState::new(
+            "order-1",
+            "30402:seller:listing",
+            "buyer".into(),
+            "seller".into(),
+            TradeOrderStatus::Requested,
+            1,
+        );
+        order.set_status(TradeOrderStatus::Accepted, 2);
+
+        let change = TradeStatusChanged::new(&order, Some(TradeOrderStatus::Requested));
+        let value = serde_json::to_value(&change).unwrap();
+        assert_eq!(value["order_id"], "order-1");
+        assert_eq!(value["buyer_pubkey"], "buyer");
+        assert_eq!(value["seller_pubkey"], "seller");
+        assert_eq!(
+            value["from"],
+            serde_json::to_value(TradeOrderStatus::Requested).unwrap()
+        );
+        assert_eq!(
+            value["to"],
+            serde_json::to_value(TradeOrderStatus::Accepted).unwrap()
+        );
+        assert_eq!(value["at"], json!(2));
+    }
+}
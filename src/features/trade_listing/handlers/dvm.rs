@@ -2,27 +2,26 @@
 
 use std::{sync::Arc, time::Duration};
 
-use radroots_nostr::prelude::{
-    radroots_event_from_nostr,
-    radroots_nostr_build_event,
-    radroots_nostr_build_event_job_feedback,
-    radroots_nostr_fetch_event_by_id,
-    radroots_nostr_parse_pubkey,
-    radroots_nostr_send_event,
-    RadrootsNostrClient,
-    RadrootsNostrEvent,
-    RadrootsNostrFilter,
-    RadrootsNostrKind,
-    RadrootsNostrKeys,
-    RadrootsNostrTag,
+use nostr::{
+    EventBuilder, PublicKey, RelayUrl, Tag, TagKind, Timestamp,
+    nips::{
+        nip04,
+        nip19::{FromBech32, Nip19Coordinate},
+    },
 };
 use radroots_events::kinds::KIND_FARM;
 use radroots_events::listing::RadrootsListingFarmRef;
+use radroots_events::tags::{TAG_E_PREV, TAG_E_ROOT};
+use radroots_nostr::prelude::{
+    RadrootsNostrClient, RadrootsNostrEvent, RadrootsNostrFilter, RadrootsNostrKeys,
+    RadrootsNostrKind, RadrootsNostrTag, radroots_event_from_nostr, radroots_nostr_build_event,
+    radroots_nostr_build_event_job_feedback, radroots_nostr_parse_pubkey,
+};
 use radroots_trade::listing::{
     dvm::{
-        TradeListingEnvelope, TradeListingEnvelopeError, TradeListingMessageType,
-        TradeListingValidateRequest, TradeListingValidateResult, TradeOrderResponse,
-        TradeOrderRevisionResponse, TradeListingCancel, TradeListingAddress,
+        TradeListingAddress, TradeListingCancel, TradeListingEnvelope, TradeListingEnvelopeError,
+        TradeListingMessageType, TradeListingValidateRequest, TradeOrderResponse,
+        TradeOrderRevisionResponse,
     },
     dvm_kinds::is_trade_listing_dvm_kind,
     order::{
@@ -31,12 +30,46 @@ use radroots_trade::listing::{
         TradeReceipt,
     },
     tags::trade_listing_dvm_tags,
-    validation::{validate_listing_event, TradeListingValidationError},
+    validation::{TradeListingValidationError, validate_listing_event},
 };
+use radroots_trade::prelude::stage::fulfillment::TradeListingFulfillmentState;
 use serde::de::DeserializeOwned;
 use thiserror::Error;
-
-use crate::features::trade_listing::state::{TradeListingState, TradeListingStateError, TradeOrderState};
+use tracing::{Instrument, Span, field, info, info_span, warn};
+
+use crate::config::{EncryptionMode, FetchRetryConfig, PayloadMode, TradeConfig};
+use crate::features::trade_listing::{
+    chain::{TradeChainError, verify_request_chain},
+    confirmation::{CONFIRMATION_TAG, order_confirmation_hash},
+    domain::pricing::{OrderQuote, quote_order},
+    envelope::{decode_envelope, encode_envelope, validate_order_id, verify_order_id},
+    events::{TradeEventSink, TradeStatusChanged},
+    handlers::registry::{HandlerRegistry, TradeListingHandlerFuture},
+    invoice::{InvoiceError, InvoiceTerms, SellerInvoice},
+    listing_cache::ListingCache,
+    receipt::{TradeReceiptAttestation, TradeReceiptError, sign_receipt},
+    state::{
+        PendingPayment, SeenEventIds, SharedTradeListingState, TradeFulfillmentStage,
+        TradeListingStateError, TradeOrderRound, TradeOrderState, can_transition,
+    },
+    store::TradeListingStore,
+    validation::{
+        ListingAddressError, ListingAvailabilityError, ListingValidateResultDetail,
+        check_listing_availability,
+    },
+};
+use crate::infra::{
+    event_cache::EventFetchCache,
+    invoices::{InvoiceBackendError, InvoiceIssuer, preimage_payment_hash},
+    journal::{EventJournal, JournalDirection},
+    metrics,
+    nostr::{
+        NostrFetchError, is_transient_fetch_error, log_dry_run_event, nostr_fetch_with_retry,
+        tag_lookup, tag_lookup_in,
+    },
+    outbox::RelayListCache,
+    rates::RateProvider,
+};
 
 #[derive(Debug, Error)]
 pub enum TradeListingDvmError {
@@ -44,37 +77,161 @@ pub enum TradeListingDvmError {
     UnsupportedKind,
     #[error("missing recipient tag")]
     MissingRecipient,
+    #[error("event addresses multiple recipients")]
+    NotRecipient,
     #[error("missing required tag: {0}")]
     MissingTag(&'static str),
     #[error("tag mismatch: {0}")]
     TagMismatch(&'static str),
     #[error("invalid envelope: {0}")]
     InvalidEnvelope(#[from] TradeListingEnvelopeError),
+    #[error("unsupported envelope version {version} (supported: {supported})")]
+    UnsupportedVersion { version: u64, supported: u8 },
     #[error("invalid envelope payload: {0}")]
     InvalidPayload(String),
     #[error("invalid listing address")]
     InvalidListingAddr,
+    #[error("listing address kind {0} is not parameterized-replaceable")]
+    NonReplaceableListingKind(u16),
+    #[error("listing address kind {0} is not a trade listing kind")]
+    UnsupportedListingKind(u16),
     #[error("invalid order request payload")]
     InvalidOrder,
+    #[error("failed to price order: {0}")]
+    Pricing(String),
+    #[error(transparent)]
+    Invoice(#[from] InvoiceError),
+    #[error("order id is not derived from the listing, buyer and order nonce")]
+    UnverifiedOrderId,
     #[error("state error: {0}")]
     State(#[from] TradeListingStateError),
     #[error("nostr error: {0}")]
     Nostr(#[from] radroots_nostr::error::RadrootsNostrError),
+    #[error("relay fetch failed: {0}")]
+    Fetch(#[from] NostrFetchError),
     #[error("serde error: {0}")]
     Serde(#[from] serde_json::Error),
+    #[error("failed to sign event: {0}")]
+    Sign(#[from] nostr::event::builder::Error),
+    #[error("failed to encrypt reply: {0}")]
+    Encrypt(#[from] nip04::Error),
     #[error("unauthorized sender")]
     Unauthorized,
     #[error("listing not validated")]
     ListingNotValidated,
+    #[error("listing has been deleted by its seller")]
+    ListingDeleted,
+    #[error(transparent)]
+    ListingUnavailable(#[from] ListingAvailabilityError),
+    #[error("invalid fulfillment update: {0}")]
+    InvalidFulfillmentUpdate(String),
+    #[error("too many open orders for this {scope} (limit {limit})")]
+    TooManyOpenOrders { scope: &'static str, limit: usize },
+    #[error("failed to sign receipt: {0}")]
+    Receipt(#[from] TradeReceiptError),
+    #[error("payment required but no invoice backend is configured")]
+    PaymentUnavailable,
+    #[error("failed to issue invoice: {0}")]
+    InvoiceBackend(#[from] InvoiceBackendError),
+    #[error("payment preimage does not match an invoice issued for this request")]
+    PaymentNotVerified,
+    #[error("broken trade chain: {0}")]
+    Chain(#[from] TradeChainError),
+}
+
+impl TradeListingDvmError {
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Fetch(err) => is_transient_fetch_error(err),
+            _ => matches!(self, Self::Nostr(_) | Self::InvoiceBackend(_)),
+        }
+    }
+}
+
+pub struct TradeListingRequest {
+    pub event: RadrootsNostrEvent,
+    pub tags: Vec<RadrootsNostrTag>,
+    pub envelope: TradeListingEnvelope<serde_json::Value>,
+    pub listing_addr: TradeListingAddress,
+    pub order_id: Option<String>,
 }
 
+#[derive(Clone)]
+pub struct TradeListingContext {
+    pub client: RadrootsNostrClient,
+    pub keys: RadrootsNostrKeys,
+    pub result_keys: RadrootsNostrKeys,
+    pub state: Arc<SharedTradeListingState>,
+    pub config: Arc<TradeConfig>,
+    pub registry: Arc<HandlerRegistry>,
+    pub store: Option<Arc<tokio::sync::Mutex<TradeListingStore>>>,
+    pub journal: Option<Arc<EventJournal>>,
+    pub outbox: Option<Arc<RelayListCache>>,
+    pub event_cache: Arc<EventFetchCache>,
+    pub listing_cache: Arc<ListingCache>,
+    pub rates: Option<Arc<dyn RateProvider>>,
+    pub invoices: Option<Arc<dyn InvoiceIssuer>>,
+    pub events: Vec<Arc<dyn TradeEventSink>>,
+    pub on_transition: Option<TransitionHook>,
+    pub encryption: EncryptionMode,
+    pub dry_run: bool,
+}
+
+// Runs while the order's shard is write-locked, so hooks should be quick and must
+// not touch the shared state.
+pub type TransitionHook =
+    Arc<dyn Fn(&TradeOrderState, TradeOrderStatus, TradeOrderStatus) + Send + Sync>;
+
 pub async fn handle_event(
     event: RadrootsNostrEvent,
     tags: Vec<RadrootsNostrTag>,
-    keys: RadrootsNostrKeys,
-    client: RadrootsNostrClient,
-    state: Arc<tokio::sync::Mutex<TradeListingState>>,
+    keys: &RadrootsNostrKeys,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
+    match parse_trade_listing_event(event, tags, keys)? {
+        Some(request) => {
+            let span = request_span(&request);
+            dispatch_request(request, ctx).instrument(span).await
+        }
+        None => Ok(()),
+    }
+}
+
+pub fn request_span(request: &TradeListingRequest) -> Span {
+    let span = info_span!(
+        "trade_listing",
+        request_id = %request.event.id,
+        message_type = ?request.envelope.message_type,
+        listing_addr = %request.listing_addr.as_str(),
+        order_id = field::Empty,
+        e_root = field::Empty,
+    );
+    if let Some(order_id) = &request.order_id {
+        span.record("order_id", order_id.as_str());
+    }
+    let tags: Vec<Vec<String>> = request.tags.iter().map(|t| t.as_slice().to_vec()).collect();
+    if let Some(root) = trade_root(&tags) {
+        span.record("e_root", root.as_str());
+    }
+    span
+}
+
+fn trade_root(tags: &[Vec<String>]) -> Option<String> {
+    let e_tags = || {
+        tags.iter()
+            .filter(|t| t.first().map(String::as_str) == Some("e"))
+    };
+    e_tags()
+        .find(|t| t.get(3).map(String::as_str) == Some("root"))
+        .or_else(|| e_tags().next())
+        .and_then(|t| t.get(1).cloned())
+}
+
+pub fn parse_trade_listing_event(
+    event: RadrootsNostrEvent,
+    tags: Vec<RadrootsNostrTag>,
+    keys: &RadrootsNostrKeys,
+) -> Result<Option<TradeListingRequest>, TradeListingDvmError> {
     let kind = match event.kind {
         RadrootsNostrKind::Custom(v) => v,
         _ => return Err(TradeListingDvmError::UnsupportedKind),
@@ -84,219 +241,445 @@ pub async fn handle_event(
     }
 
     if event.pubkey == keys.public_key() {
-        return Ok(());
+        return Ok(None);
     }
 
     let tag_slices: Vec<Vec<String>> = tags.iter().map(|t| t.as_slice().to_vec()).collect();
     let rhi_pubkey = keys.public_key().to_string();
-    if !tag_has_value(&tag_slices, "p", &rhi_pubkey) {
-        return Err(TradeListingDvmError::MissingRecipient);
-    }
+    ensure_sole_recipient(&tag_slices, &rhi_pubkey)?;
 
-    let envelope: TradeListingEnvelope<serde_json::Value> =
-        serde_json::from_str(&event.content)?;
+    let envelope = decode_envelope(&event.content)?;
     envelope.validate()?;
     if envelope.message_type.kind() != kind {
         return Err(TradeListingDvmError::TagMismatch("kind"));
     }
 
-    let listing_addr = tag_value(&tag_slices, "a").ok_or(TradeListingDvmError::MissingTag("a"))?;
-    if listing_addr != envelope.listing_addr {
-        return Err(TradeListingDvmError::TagMismatch("a"));
-    }
+    let listing_addr = tag_lookup_in(&tags, "a").ok_or(TradeListingDvmError::MissingTag("a"))?;
+    ensure_same_listing(&listing_addr, &envelope.listing_addr, "a")?;
 
-    let order_id = envelope.order_id.as_deref();
+    let order_id = envelope.order_id.clone();
+    if let Some(order_id) = order_id.as_deref() {
+        validate_order_id(order_id)?;
+    }
     if envelope.message_type.requires_order_id() {
         let tag_order_id =
-            tag_value(&tag_slices, "d").ok_or(TradeListingDvmError::MissingTag("d"))?;
-        if Some(tag_order_id.as_str()) != order_id {
+            tag_lookup_in(&tags, "d").ok_or(TradeListingDvmError::MissingTag("d"))?;
+        if Some(tag_order_id.as_str()) != order_id.as_deref() {
             return Err(TradeListingDvmError::TagMismatch("d"));
         }
     }
 
-    let listing_addr_parsed =
-        TradeListingAddress::parse(&listing_addr).map_err(|_| TradeListingDvmError::InvalidListingAddr)?;
-    if listing_addr_parsed.kind != 30402 {
-        return Err(TradeListingDvmError::InvalidListingAddr);
-    }
+    let listing_addr_parsed = parse_listing_addr(&listing_addr)?;
 
-    match envelope.message_type {
-        TradeListingMessageType::ListingValidateRequest => {
-            let payload: TradeListingValidateRequest = parse_payload(envelope.payload)?;
-            handle_listing_validate_request(
-                &event,
-                payload,
-                &listing_addr,
-                &client,
-                &state,
-            )
-            .await?;
-        }
-        TradeListingMessageType::OrderRequest => {
-            let payload: TradeOrder = parse_payload(envelope.payload)?;
-            handle_order_request(
-                &event,
-                payload,
-                &listing_addr_parsed,
-                order_id,
-                &client,
-                &state,
-            )
-            .await?;
-        }
-        TradeListingMessageType::OrderResponse => {
-            let payload: TradeOrderResponse = parse_payload(envelope.payload)?;
-            handle_order_response(
-                &event,
-                payload,
-                &listing_addr_parsed,
-                order_id,
-                &client,
-                &state,
-            )
-            .await?;
-        }
-        TradeListingMessageType::OrderRevision => {
-            let payload: TradeOrderRevision = parse_payload(envelope.payload)?;
-            handle_order_revision(
-                &event,
-                payload,
-                &listing_addr_parsed,
-                order_id,
-                &client,
-                &state,
-            )
-            .await?;
-        }
-        TradeListingMessageType::OrderRevisionAccept | TradeListingMessageType::OrderRevisionDecline => {
-            let payload: TradeOrderRevisionResponse = parse_payload(envelope.payload)?;
-            handle_order_revision_response(
-                &event,
-                envelope.message_type,
-                payload,
-                &listing_addr_parsed,
-                order_id,
-                &client,
-                &state,
-            )
-            .await?;
-        }
-        TradeListingMessageType::Question => {
-            let payload: TradeQuestion = parse_payload(envelope.payload)?;
-            handle_question(
-                &event,
-                payload,
-                &listing_addr_parsed,
-                order_id,
-                &client,
-                &state,
-            )
-            .await?;
-        }
-        TradeListingMessageType::Answer => {
-            let payload: TradeAnswer = parse_payload(envelope.payload)?;
-            handle_answer(
-                &event,
-                payload,
-                &listing_addr_parsed,
-                order_id,
-                &client,
-                &state,
-            )
-            .await?;
-        }
-        TradeListingMessageType::DiscountRequest => {
-            let payload: TradeDiscountRequest = parse_payload(envelope.payload)?;
-            handle_discount_request(
-                &event,
-                payload,
-                &listing_addr_parsed,
-                order_id,
-                &client,
-                &state,
-            )
-            .await?;
-        }
-        TradeListingMessageType::DiscountOffer => {
-            let payload: TradeDiscountOffer = parse_payload(envelope.payload)?;
-            handle_discount_offer(
-                &event,
-                payload,
-                &listing_addr_parsed,
-                order_id,
-                &client,
-                &state,
-            )
-            .await?;
+    Ok(Some(TradeListingRequest {
+        event,
+        tags,
+        envelope,
+        listing_addr: listing_addr_parsed,
+        order_id,
+    }))
+}
+
+pub async fn handle_listing_deletion(event: &RadrootsNostrEvent, ctx: &TradeListingContext) {
+    let author = event.pubkey.to_hex();
+    let mut state = ctx.state.listings_mut().await;
+    for tag in event.tags.iter() {
+        let tag = tag.as_slice();
+        if tag.first().map(String::as_str) != Some("a") {
+            continue;
         }
-        TradeListingMessageType::DiscountAccept | TradeListingMessageType::DiscountDecline => {
-            let payload: TradeDiscountDecision = parse_payload(envelope.payload)?;
-            handle_discount_decision(
-                &event,
-                envelope.message_type,
-                payload,
-                &listing_addr_parsed,
-                order_id,
-                &client,
-                &state,
-            )
-            .await?;
+        let Some(Ok(listing_addr)) = tag.get(1).map(|addr| parse_listing_addr(addr)) else {
+            continue;
+        };
+        if listing_addr.seller_pubkey != author {
+            warn!(
+                "trade_listing: ignoring deletion of {} from non-seller {author}",
+                listing_addr.as_str()
+            );
+            continue;
         }
-        TradeListingMessageType::Cancel => {
-            let payload: TradeListingCancel = parse_payload(envelope.payload)?;
-            handle_cancel(
-                &event,
-                payload,
-                &listing_addr_parsed,
-                order_id,
-                &client,
-                &state,
-            )
-            .await?;
+        ctx.listing_cache.invalidate(listing_addr.as_str());
+        if state.invalidate_listing(listing_addr.as_str(), event.created_at.as_u64()) {
+            info!(
+                "trade_listing: listing {} deleted by seller",
+                listing_addr.as_str()
+            );
         }
-        TradeListingMessageType::FulfillmentUpdate => {
-            let payload: TradeFulfillmentUpdate = parse_payload(envelope.payload)?;
-            handle_fulfillment_update(
-                &event,
-                payload,
-                &listing_addr_parsed,
-                order_id,
-                &client,
-                &state,
-            )
-            .await?;
+    }
+}
+
+pub async fn dispatch_request(
+    request: TradeListingRequest,
+    ctx: &TradeListingContext,
+) -> Result<(), TradeListingDvmError> {
+    if !ctx
+        .config
+        .is_message_type_enabled(request.envelope.message_type)
+    {
+        return Ok(());
+    }
+    verify_stage_chain(ctx, &request).await?;
+    if let Some(builder) = required_payment(ctx, &request, unix_now()).await? {
+        return publish_feedback(ctx, builder).await;
+    }
+    ctx.registry.dispatch(ctx.clone(), request).await
+}
+
+// A stage request may carry the chain tags of the result it answers. Its root has to
+// be the order's, and the referenced result has to be rooted there too, so a forged
+// or mismatched reference is turned away before any stage handler runs.
+async fn verify_stage_chain(
+    ctx: &TradeListingContext,
+    request: &TradeListingRequest,
+) -> Result<(), TradeListingDvmError> {
+    if request.envelope.message_type == TradeListingMessageType::OrderRequest {
+        return Ok(());
+    }
+    let Some(order_id) = request.order_id.as_deref() else {
+        return Ok(());
+    };
+    let root = {
+        let state = ctx.state.order_shard(order_id).read().await;
+        state
+            .get_order(order_id)
+            .and_then(|order| order.root_event_id.clone())
+    };
+    let Some(root) = root else {
+        return Ok(());
+    };
+    let event = &request.event;
+    if let Some(found) = tag_lookup(event, TAG_E_ROOT).filter(|found| *found != root) {
+        return Err(TradeChainError::RootMismatch {
+            event_id: event.id.to_hex(),
+            expected: root,
+            found,
         }
-        TradeListingMessageType::Receipt => {
-            let payload: TradeReceipt = parse_payload(envelope.payload)?;
-            handle_receipt(
-                &event,
-                payload,
-                &listing_addr_parsed,
-                order_id,
-                &client,
-                &state,
-            )
-            .await?;
+        .into());
+    }
+    // In nip17 mode results only reach relays inside their gift wrap, so the rumor a
+    // request refers to cannot be fetched; its root tag was checked above.
+    if ctx.encryption == EncryptionMode::Nip17 {
+        return Ok(());
+    }
+    let Some(prev_id) = tag_lookup(event, TAG_E_PREV) else {
+        return Ok(());
+    };
+    let prev = ctx
+        .event_cache
+        .fetch_by_id(
+            &ctx.client,
+            &prev_id,
+            Duration::from_secs(10),
+            &ctx.config.fetch_retry,
+        )
+        .await?;
+    let chain = verify_request_chain(event, &prev)?;
+    if chain.e_root != root {
+        return Err(TradeChainError::RootMismatch {
+            event_id: chain.e_prev,
+            expected: root,
+            found: chain.e_root,
         }
-        TradeListingMessageType::ListingValidateResult => {}
+        .into());
     }
-
     Ok(())
 }
 
+// Each gated request is answered with its own invoice. The client resubmits with
+// `["payment", <preimage hex>]`, which is let through once its sha256 matches the
+// payment hash of an invoice issued to that author for that kind.
+async fn required_payment(
+    ctx: &TradeListingContext,
+    request: &TradeListingRequest,
+    now: u64,
+) -> Result<Option<EventBuilder>, TradeListingDvmError> {
+    let message_type = request.envelope.message_type;
+    if !ctx.config.requires_payment(message_type) {
+        return Ok(None);
+    }
+    let payment = ctx
+        .config
+        .payment
+        .as_ref()
+        .ok_or(TradeListingDvmError::PaymentUnavailable)?;
+    let issuer = ctx
+        .invoices
+        .as_deref()
+        .ok_or(TradeListingDvmError::PaymentUnavailable)?;
+    let payer = request.event.pubkey.to_string();
+    let kind = message_type.kind();
+    // Read from the resolved tags, so a preimage sent in encrypted tags is honoured.
+    if let Some(preimage) = tag_lookup_in(&request.tags, PAYMENT_TAG) {
+        let redeemed = preimage_payment_hash(&preimage)
+            .is_some_and(|hash| ctx.state.redeem_payment(&hash, &payer, kind, now));
+        return redeemed
+            .then_some(None)
+            .ok_or(TradeListingDvmError::PaymentNotVerified);
+    }
+    let memo = format!("rhi request {}", request.event.id);
+    let invoice = issuer.issue(payment.amount_msat, &memo).await?;
+    // Paid invoices stay redeemable for a while past their expiry, so a client that
+    // paid just in time can still resubmit.
+    let pending = PendingPayment {
+        payer,
+        kind,
+        expires_at: now + payment.expiry_secs + PAYMENT_REDEEM_GRACE_SECS,
+    };
+    ctx.state
+        .record_pending_payment(invoice.payment_hash, pending, now);
+    let builder =
+        payment_required_feedback(&request.event, payment.amount_msat, Some(&invoice.bolt11))?;
+    Ok(Some(builder))
+}
+
+fn payment_required_feedback(
+    event: &RadrootsNostrEvent,
+    amount_msat: u64,
+    bolt11: Option<&str>,
+) -> Result<EventBuilder, TradeListingDvmError> {
+    Ok(radroots_nostr_build_event_job_feedback(
+        event,
+        "payment-required",
+        Some(format!("payment of {amount_msat} msat required")),
+        None,
+    )?
+    .tag(amount_tag(amount_msat, bolt11)))
+}
+
+pub(crate) fn register_default_handlers(registry: &mut HandlerRegistry) {
+    registry
+        .register(
+            TradeListingMessageType::ListingValidateRequest,
+            |ctx, request| {
+                Box::pin(async move {
+                    let payload: TradeListingValidateRequest =
+                        parse_payload(request.envelope.payload, ctx.config.payload_mode)?;
+                    let listing_addr = request.listing_addr.as_str().to_string();
+                    handle_listing_validate_request(&request.event, payload, &listing_addr, &ctx)
+                        .await
+                })
+            },
+        )
+        .register(TradeListingMessageType::OrderRequest, |ctx, request| {
+            Box::pin(async move {
+                let mut value = request.envelope.payload;
+                let idempotency_key = take_payload_field(&mut value, IDEMPOTENCY_KEY_FIELD);
+                let nonce = take_payload_field(&mut value, ORDER_NONCE_FIELD);
+                let payload: TradeOrder = parse_payload(value, ctx.config.payload_mode)?;
+                handle_order_request(
+                    &request.event,
+                    payload,
+                    idempotency_key,
+                    nonce.as_deref(),
+                    &request.listing_addr,
+                    request.order_id.as_deref(),
+                    &ctx,
+                )
+                .await
+            })
+        })
+        .register(TradeListingMessageType::OrderResponse, |ctx, request| {
+            Box::pin(async move {
+                let mut value = request.envelope.payload;
+                let invoice = SellerInvoice::take(&mut value);
+                let payload: TradeOrderResponse = parse_payload(value, ctx.config.payload_mode)?;
+                handle_order_response(
+                    &request.event,
+                    payload,
+                    invoice,
+                    &request.listing_addr,
+                    request.order_id.as_deref(),
+                    &ctx,
+                )
+                .await
+            })
+        })
+        .register(TradeListingMessageType::OrderRevision, |ctx, request| {
+            Box::pin(async move {
+                let payload: TradeOrderRevision =
+                    parse_payload(request.envelope.payload, ctx.config.payload_mode)?;
+                handle_order_revision(
+                    &request.event,
+                    payload,
+                    &request.listing_addr,
+                    request.order_id.as_deref(),
+                    &ctx,
+                )
+                .await
+            })
+        })
+        .register(
+            TradeListingMessageType::OrderRevisionAccept,
+            order_revision_response,
+        )
+        .register(
+            TradeListingMessageType::OrderRevisionDecline,
+            order_revision_response,
+        )
+        .register(TradeListingMessageType::Question, |ctx, request| {
+            Box::pin(async move {
+                let payload: TradeQuestion =
+                    parse_payload(request.envelope.payload, ctx.config.payload_mode)?;
+                handle_question(
+                    &request.event,
+                    payload,
+                    &request.listing_addr,
+                    request.order_id.as_deref(),
+                    &ctx,
+                )
+                .await
+            })
+        })
+        .register(TradeListingMessageType::Answer, |ctx, request| {
+            Box::pin(async move {
+                let payload: TradeAnswer =
+                    parse_payload(request.envelope.payload, ctx.config.payload_mode)?;
+                handle_answer(
+                    &request.event,
+                    payload,
+                    &request.listing_addr,
+                    request.order_id.as_deref(),
+                    &ctx,
+                )
+                .await
+            })
+        })
+        .register(TradeListingMessageType::DiscountRequest, |ctx, request| {
+            Box::pin(async move {
+                let payload: TradeDiscountRequest =
+                    parse_payload(request.envelope.payload, ctx.config.payload_mode)?;
+                handle_discount_request(
+                    &request.event,
+                    payload,
+                    &request.listing_addr,
+                    request.order_id.as_deref(),
+                    &ctx,
+                )
+                .await
+            })
+        })
+        .register(TradeListingMessageType::DiscountOffer, |ctx, request| {
+            Box::pin(async move {
+                let payload: TradeDiscountOffer =
+                    parse_payload(request.envelope.payload, ctx.config.payload_mode)?;
+                handle_discount_offer(
+                    &request.event,
+                    payload,
+                    &request.listing_addr,
+                    request.order_id.as_deref(),
+                    &ctx,
+                )
+                .await
+            })
+        })
+        .register(TradeListingMessageType::DiscountAccept, discount_decision)
+        .register(TradeListingMessageType::DiscountDecline, discount_decision)
+        .register(TradeListingMessageType::Cancel, |ctx, request| {
+            Box::pin(async move {
+                let payload: TradeListingCancel =
+                    parse_payload(request.envelope.payload, ctx.config.payload_mode)?;
+                handle_cancel(
+                    &request.event,
+                    payload,
+                    &request.listing_addr,
+                    request.order_id.as_deref(),
+                    &ctx,
+                )
+                .await
+            })
+        })
+        .register(
+            TradeListingMessageType::FulfillmentUpdate,
+            |ctx, request| {
+                Box::pin(async move {
+                    let payload: TradeFulfillmentUpdate =
+                        parse_payload(request.envelope.payload, ctx.config.payload_mode)?;
+                    handle_fulfillment_update(
+                        &request.event,
+                        payload,
+                        &request.listing_addr,
+                        request.order_id.as_deref(),
+                        &ctx,
+                    )
+                    .await
+                })
+            },
+        )
+        .register(TradeListingMessageType::Receipt, |ctx, request| {
+            Box::pin(async move {
+                let payload: TradeReceipt =
+                    parse_payload(request.envelope.payload, ctx.config.payload_mode)?;
+                handle_receipt(
+                    &request.event,
+                    payload,
+                    &request.listing_addr,
+                    request.order_id.as_deref(),
+                    &ctx,
+                )
+                .await
+            })
+        });
+}
+
+fn order_revision_response(
+    ctx: TradeListingContext,
+    request: TradeListingRequest,
+) -> TradeListingHandlerFuture {
+    Box::pin(async move {
+        let payload: TradeOrderRevisionResponse =
+            parse_payload(request.envelope.payload, ctx.config.payload_mode)?;
+        handle_order_revision_response(
+            &request.event,
+            request.envelope.message_type,
+            payload,
+            &request.listing_addr,
+            request.order_id.as_deref(),
+            &ctx,
+        )
+        .await
+    })
+}
+
+fn discount_decision(
+    ctx: TradeListingContext,
+    request: TradeListingRequest,
+) -> TradeListingHandlerFuture {
+    Box::pin(async move {
+        let payload: TradeDiscountDecision =
+            parse_payload(request.envelope.payload, ctx.config.payload_mode)?;
+        handle_discount_decision(
+            &request.event,
+            request.envelope.message_type,
+            payload,
+            &request.listing_addr,
+            request.order_id.as_deref(),
+            &ctx,
+        )
+        .await
+    })
+}
+
 async fn handle_listing_validate_request(
     event: &RadrootsNostrEvent,
     payload: TradeListingValidateRequest,
     listing_addr: &str,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let listing_event = if let Some(ptr) = payload.listing_event {
-        match radroots_nostr_fetch_event_by_id(client, &ptr.id).await {
+        let fetched = ctx
+            .event_cache
+            .fetch_by_id(
+                &ctx.client,
+                &ptr.id,
+                Duration::from_secs(10),
+                &ctx.config.fetch_retry,
+            )
+            .await;
+        match fetched {
             Ok(evt) => Some(evt),
             Err(err) => {
                 let error = match err {
-                    radroots_nostr::error::RadrootsNostrError::EventNotFound(_) => {
+                    NostrFetchError::NotFound(_) => {
                         TradeListingValidationError::ListingEventNotFound {
                             listing_addr: listing_addr.to_string(),
                         }
@@ -305,30 +688,50 @@ async fn handle_listing_validate_request(
                         listing_addr: listing_addr.to_string(),
                     },
                 };
-                send_validate_result(event, client, listing_addr, vec![error]).await?;
+                let payload = ListingValidateResultDetail::new(vec![error]);
+                send_validate_result(event, ctx, listing_addr, payload).await?;
                 return Ok(());
             }
         }
     } else {
-        match fetch_listing_by_addr(client, listing_addr).await {
+        ctx.listing_cache.invalidate(listing_addr);
+        match fetch_listing_by_addr(ctx, listing_addr).await {
             Ok(event) => event,
             Err(_) => {
                 let error = TradeListingValidationError::ListingEventFetchFailed {
                     listing_addr: listing_addr.to_string(),
                 };
-                send_validate_result(event, client, listing_addr, vec![error]).await?;
+                let payload = ListingValidateResultDetail::new(vec![error]);
+                send_validate_result(event, ctx, listing_addr, payload).await?;
                 return Ok(());
             }
         }
     };
 
-    let errors = if let Some(event) = listing_event {
-        let rr_event = radroots_event_from_nostr(&event);
+    let errors = if let Some(listing_event) = listing_event {
+        let addr = parse_listing_addr(listing_addr)?;
+        let deleted = ensure_listing_not_deleted(ctx, listing_addr, &listing_event)
+            .await
+            .is_err();
+        if let Some(err) = listing_address_error(&listing_event, &addr, deleted) {
+            let payload = ListingValidateResultDetail::mismatched(&err);
+            return send_validate_result(event, ctx, listing_addr, payload).await;
+        }
+        if let Err(err) = check_listing_availability(&listing_event, unix_now()) {
+            let payload = ListingValidateResultDetail::unavailable(&err);
+            return send_validate_result(event, ctx, listing_addr, payload).await;
+        }
+        let rr_event = radroots_event_from_nostr(&listing_event);
         match validate_listing_event(&rr_event) {
             Ok(listing) => {
-                let errors = validate_farm_dependencies(client, &listing.listing.farm).await?;
+                let errors = validate_farm_dependencies(
+                    &ctx.client,
+                    &ctx.config.fetch_retry,
+                    &listing.listing.farm,
+                )
+                .await?;
                 if errors.is_empty() {
-                    let mut state = state.lock().await;
+                    let mut state = ctx.state.listings_mut().await;
                     state.mark_listing_validated(listing_addr);
                 }
                 errors
@@ -341,21 +744,18 @@ async fn handle_listing_validate_request(
         }]
     };
 
-    send_validate_result(event, client, listing_addr, errors).await
+    let payload = ListingValidateResultDetail::new(errors);
+    send_validate_result(event, ctx, listing_addr, payload).await
 }
 
 async fn send_validate_result(
     event: &RadrootsNostrEvent,
-    client: &RadrootsNostrClient,
+    ctx: &TradeListingContext,
     listing_addr: &str,
-    errors: Vec<TradeListingValidationError>,
+    payload: ListingValidateResultDetail,
 ) -> Result<(), TradeListingDvmError> {
-    let payload = TradeListingValidateResult {
-        valid: errors.is_empty(),
-        errors,
-    };
     send_envelope(
-        client,
+        ctx,
         event.pubkey.to_string(),
         TradeListingMessageType::ListingValidateResult,
         listing_addr,
@@ -368,65 +768,258 @@ async fn send_validate_result(
 async fn handle_order_request(
     event: &RadrootsNostrEvent,
     payload: TradeOrder,
+    idempotency_key: Option<String>,
+    nonce: Option<&str>,
     listing_addr: &TradeListingAddress,
     order_id: Option<&str>,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let order_id = order_id.ok_or(TradeListingDvmError::MissingTag("d"))?;
-    if payload.order_id != order_id || payload.listing_addr != listing_addr.as_str() {
+    let canonical_addr = listing_addr.as_str().to_string();
+    if payload.order_id != order_id {
         return Err(TradeListingDvmError::InvalidOrder);
     }
+    ensure_same_listing(&canonical_addr, &payload.listing_addr, "listing_addr")?;
 
-    let mut state = state.lock().await;
-    if !state.is_listing_validated(&payload.listing_addr) {
+    if payload.buyer_pubkey != event.pubkey.to_string()
+        || payload.seller_pubkey != listing_addr.seller_pubkey
+    {
+        return Err(TradeListingDvmError::Unauthorized);
+    }
+    verify_order_id(
+        order_id,
+        &canonical_addr,
+        &payload.buyer_pubkey,
+        nonce,
+        ctx.config.require_derived_order_ids,
+    )?;
+
+    if !ctx
+        .state
+        .listings()
+        .read()
+        .await
+        .is_listing_validated(&canonical_addr)
+    {
         return Err(TradeListingDvmError::ListingNotValidated);
     }
+    if let Some(key) = idempotency_key.as_deref() {
+        let existing = ctx
+            .state
+            .idempotent_order_id(&payload.buyer_pubkey, &canonical_addr, key);
+        if let Some(existing) = existing.filter(|existing| existing != order_id) {
+            return answer_idempotent_repeat(ctx, event, order_id, &existing).await;
+        }
+    }
+    if ctx
+        .state
+        .order_shard(order_id)
+        .read()
+        .await
+        .order_exists(order_id)
+    {
+        return Ok(());
+    }
+    ensure_open_order_limits(ctx, &payload.buyer_pubkey, &canonical_addr).await?;
+
+    let listing = fetch_listing_by_addr(ctx, &canonical_addr)
+        .await?
+        .ok_or(TradeListingDvmError::ListingNotValidated)?;
+    ensure_listing_not_deleted(ctx, &canonical_addr, &listing).await?;
+    ensure_listing_coordinate(&listing, listing_addr)?;
+    let seller_pubkey = ensure_listing_author(&listing, &payload.seller_pubkey)?;
+    check_listing_availability(&listing, unix_now())?;
+    let quote = quote_order(&listing, &payload)?;
+
+    let mut state = ctx.state.order_shard_mut(order_id).await;
     if state.order_exists(order_id) {
         return Ok(());
     }
 
-    if payload.buyer_pubkey != event.pubkey.to_string()
-        || payload.seller_pubkey != listing_addr.seller_pubkey
-    {
-        return Err(TradeListingDvmError::Unauthorized);
+    let mut seen = SeenEventIds::default();
+    seen.insert(event.id.to_string(), ctx.config.limits.max_seen_event_ids);
+    let now = unix_now();
+    let confirmation = order_confirmation_hash(&payload)?;
+
+    let mut order = TradeOrderState::new(
+        order_id,
+        canonical_addr.clone(),
+        ctx.state.intern_pubkey(&payload.buyer_pubkey),
+        ctx.state.intern_pubkey(&seller_pubkey),
+        TradeOrderStatus::Requested,
+        now,
+    );
+    order.seen_event_ids = seen;
+    order.root_event_id = Some(event.id.to_string());
+    order.total = Some(quote.total.to_string());
+    order.confirmation = Some(confirmation.clone());
+    order.idempotency_key = idempotency_key;
+    if let Some(existing) = ctx.state.claim_idempotency_key(&order) {
+        drop(state);
+        return answer_idempotent_repeat(ctx, event, order_id, &existing).await;
     }
-
-    let mut seen = std::collections::HashSet::new();
-    seen.insert(event.id.to_string());
-
-    state.insert_order(TradeOrderState {
-        order_id: order_id.to_string(),
-        listing_addr: payload.listing_addr.clone(),
-        buyer_pubkey: payload.buyer_pubkey.clone(),
-        seller_pubkey: payload.seller_pubkey.clone(),
-        status: TradeOrderStatus::Requested,
-        seen_event_ids: seen,
-    });
-
+    let change = TradeStatusChanged::new(&order, None);
+    state.insert_order(order);
     drop(state);
+    emit_status_change(ctx, change).await;
 
-    send_envelope(
-        client,
-        payload.seller_pubkey.clone(),
+    let quoted = QuotedOrder {
+        order: &payload,
+        quote: &quote,
+    };
+    let builder = order_request_event(
+        ctx,
+        event,
+        seller_pubkey,
+        &canonical_addr,
+        order_id,
+        quoted,
+        confirmation,
+    )?;
+    publish_envelope(ctx, TradeListingMessageType::OrderRequest, builder).await
+}
+
+// Builds the order relayed to the seller apart from sending it, so the quote and the
+// chain tags can be checked without a relay. The same goes for `order_response_event`.
+fn order_request_event(
+    ctx: &TradeListingContext,
+    event: &RadrootsNostrEvent,
+    seller_pubkey: String,
+    listing_addr: &str,
+    order_id: &str,
+    quoted: QuotedOrder<'_>,
+    confirmation: String,
+) -> Result<EventBuilder, TradeListingDvmError> {
+    let root_event_id = event.id.to_string();
+    let builder = relayed_envelope_event(
+        ctx,
+        event,
+        seller_pubkey,
         TradeListingMessageType::OrderRequest,
-        &payload.listing_addr,
+        listing_addr,
         Some(order_id),
-        &payload,
+        Some(&root_event_id),
+        &quoted,
+    )?;
+    Ok(with_confirmation(builder, Some(confirmation)))
+}
+
+// The seller gets the itemized quote next to the order. The confirmation hash covers
+// the order alone, so it is checked with `quote` removed.
+#[derive(Clone, serde::Serialize)]
+struct QuotedOrder<'a> {
+    #[serde(flatten)]
+    order: &'a TradeOrder,
+    quote: &'a OrderQuote,
+}
+
+// The seller sees the hash on the relayed request and the buyer on the response, so
+// both sides can check they agreed on the same order.
+fn with_confirmation(builder: EventBuilder, confirmation: Option<String>) -> EventBuilder {
+    match confirmation {
+        Some(hash) => builder.tag(Tag::custom(TagKind::custom(CONFIRMATION_TAG), [hash])),
+        None => builder,
+    }
+}
+
+// Counts are taken shard by shard without a global lock, so concurrent requests
+// can overshoot a limit by the number of requests in flight.
+async fn ensure_open_order_limits(
+    ctx: &TradeListingContext,
+    buyer_pubkey: &str,
+    listing_addr: &str,
+) -> Result<(), TradeListingDvmError> {
+    let config = &ctx.config;
+    if config.max_open_orders_per_buyer.is_none() && config.max_open_orders_per_listing.is_none() {
+        return Ok(());
+    }
+    let (buyer, listing) = ctx
+        .state
+        .open_order_counts(buyer_pubkey, listing_addr)
+        .await;
+    let limits = [
+        ("buyer", buyer, config.max_open_orders_per_buyer),
+        ("listing", listing, config.max_open_orders_per_listing),
+    ];
+    for (scope, open, limit) in limits {
+        if let Some(limit) = limit.filter(|limit| open >= *limit) {
+            return Err(TradeListingDvmError::TooManyOpenOrders { scope, limit });
+        }
+    }
+    Ok(())
+}
+
+// Retried submissions reuse the idempotency key under a fresh order_id; they are
+// answered with the state of the order the key already belongs to.
+async fn answer_idempotent_repeat(
+    ctx: &TradeListingContext,
+    event: &RadrootsNostrEvent,
+    order_id: &str,
+    existing: &str,
+) -> Result<(), TradeListingDvmError> {
+    let status = ctx
+        .state
+        .order_shard(existing)
+        .read()
+        .await
+        .get_order(existing)
+        .map(|order| order.status.clone());
+    info!(
+        "trade_listing: order {order_id} repeats idempotency key of order {existing} \
+         (status {status:?})"
+    );
+    let builder = idempotent_repeat_feedback(event, existing, status.as_ref())?;
+    publish_feedback(ctx, builder).await
+}
+
+fn idempotent_repeat_feedback(
+    event: &RadrootsNostrEvent,
+    existing: &str,
+    status: Option<&TradeOrderStatus>,
+) -> Result<EventBuilder, TradeListingDvmError> {
+    let content = serde_json::json!({ "order_id": existing, "status": status });
+    Ok(
+        radroots_nostr_build_event_job_feedback(event, "success", Some(content.to_string()), None)?
+            .tag(Tag::identifier(existing)),
     )
-    .await
+}
+
+const IDEMPOTENCY_KEY_FIELD: &str = "idempotency_key";
+const ORDER_NONCE_FIELD: &str = "order_nonce";
+
+// Daemon-only request fields are removed before the payload is parsed, so strict
+// payload mode still accepts them.
+fn take_payload_field(payload: &mut serde_json::Value, field: &str) -> Option<String> {
+    match payload.as_object_mut()?.remove(field)? {
+        serde_json::Value::String(key) if !key.is_empty() => Some(key),
+        _ => None,
+    }
+}
+
+// The buyer gets the invoice terms inside the response as well as in the `amount` tag.
+#[derive(Clone, serde::Serialize)]
+struct InvoicedResponse<'a> {
+    #[serde(flatten)]
+    response: &'a TradeOrderResponse,
+    #[serde(flatten)]
+    invoice: Option<&'a InvoiceTerms>,
 }
 
 async fn handle_order_response(
     event: &RadrootsNostrEvent,
     payload: TradeOrderResponse,
+    invoice: Option<SellerInvoice>,
     _listing_addr: &TradeListingAddress,
     order_id: Option<&str>,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let order_id = order_id.ok_or(TradeListingDvmError::MissingTag("d"))?;
-    let mut state = state.lock().await;
+    // A decline has nothing to pay, so only an accepting response keeps its invoice.
+    let invoice = match invoice.filter(|_| payload.accepted) {
+        Some(invoice) => Some(invoice.terms(ctx.rates.as_deref()).await?),
+        None => None,
+    };
+    let mut state = ctx.state.order_shard_mut(order_id).await;
     let event_id = event.id.to_string();
     if state.is_event_seen(order_id, &event_id) {
         return Ok(());
@@ -434,7 +1027,7 @@ async fn handle_order_response(
     let order = state
         .get_order_mut(order_id)
         .ok_or(TradeListingStateError::MissingOrder)?;
-    if order.seller_pubkey != event.pubkey.to_string() {
+    if !order.is_seller(&event.pubkey) {
         return Err(TradeListingDvmError::Unauthorized);
     }
 
@@ -443,23 +1036,69 @@ async fn handle_order_response(
     } else {
         TradeOrderStatus::Declined
     };
-    ensure_transition(order.status.clone(), next_status.clone())?;
-    order.status = next_status;
-    order.seen_event_ids.insert(event_id);
+    ensure_order_transition(order, next_status.clone())?;
+    let change = apply_status(ctx, order, next_status);
+    order
+        .seen_event_ids
+        .insert(event_id, ctx.config.limits.max_seen_event_ids);
 
     let buyer = order.buyer_pubkey.clone();
     let listing_addr_str = order.listing_addr.clone();
+    let root_event_id = order.root_event_id.clone();
+    let confirmation = order.confirmation.clone();
     drop(state);
+    emit_status_change(ctx, change).await;
 
-    send_envelope(
-        client,
-        buyer,
-        TradeListingMessageType::OrderResponse,
+    let response = InvoicedResponse {
+        response: &payload,
+        invoice: invoice.as_ref(),
+    };
+    let builder = order_response_event(
+        ctx,
+        event,
+        buyer.to_string(),
         &listing_addr_str,
+        order_id,
+        root_event_id.as_deref(),
+        response,
+        confirmation,
+    )?;
+    publish_envelope(ctx, TradeListingMessageType::OrderResponse, builder).await
+}
+
+#[allow(clippy::too_many_arguments)]
+fn order_response_event(
+    ctx: &TradeListingContext,
+    event: &RadrootsNostrEvent,
+    buyer_pubkey: String,
+    listing_addr: &str,
+    order_id: &str,
+    root_event_id: Option<&str>,
+    response: InvoicedResponse<'_>,
+    confirmation: Option<String>,
+) -> Result<EventBuilder, TradeListingDvmError> {
+    let builder = relayed_envelope_event(
+        ctx,
+        event,
+        buyer_pubkey,
+        TradeListingMessageType::OrderResponse,
+        listing_addr,
         Some(order_id),
-        &payload,
-    )
-    .await
+        root_event_id,
+        &response,
+    )?;
+    let builder = with_confirmation(builder, confirmation);
+    Ok(match response.invoice {
+        Some(terms) => builder.tag(amount_tag(terms.amount_msat, terms.bolt11.as_deref())),
+        None => builder,
+    })
+}
+
+// NIP-90 amounts are denominated in msat, optionally followed by a bolt11 invoice.
+fn amount_tag(amount_msat: u64, bolt11: Option<&str>) -> Tag {
+    let mut amount = vec![amount_msat.to_string()];
+    amount.extend(bolt11.map(str::to_string));
+    Tag::custom(TagKind::custom("amount"), amount)
 }
 
 async fn handle_order_revision(
@@ -467,14 +1106,13 @@ async fn handle_order_revision(
     payload: TradeOrderRevision,
     listing_addr: &TradeListingAddress,
     order_id: Option<&str>,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let order_id = order_id.ok_or(TradeListingDvmError::MissingTag("d"))?;
     if payload.order_id != order_id {
         return Err(TradeListingDvmError::InvalidOrder);
     }
-    let mut state = state.lock().await;
+    let mut state = ctx.state.order_shard_mut(order_id).await;
     let event_id = event.id.to_string();
     if state.is_event_seen(order_id, &event_id) {
         return Ok(());
@@ -482,24 +1120,29 @@ async fn handle_order_revision(
     let order = state
         .get_order_mut(order_id)
         .ok_or(TradeListingStateError::MissingOrder)?;
-    if order.seller_pubkey != event.pubkey.to_string()
-        || listing_addr.seller_pubkey != order.seller_pubkey
-    {
+    if !order.is_seller(&event.pubkey) || listing_addr.seller_pubkey != *order.seller_pubkey {
         return Err(TradeListingDvmError::Unauthorized);
     }
-    ensure_transition(order.status.clone(), TradeOrderStatus::Revised)?;
-    order.status = TradeOrderStatus::Revised;
-    order.seen_event_ids.insert(event_id);
+    ensure_order_transition(order, TradeOrderStatus::Revised)?;
+    order.record_round(TradeOrderRound::Revision, ctx.config.limits.max_revisions)?;
+    let change = apply_status(ctx, order, TradeOrderStatus::Revised);
+    order
+        .seen_event_ids
+        .insert(event_id, ctx.config.limits.max_seen_event_ids);
     let buyer = order.buyer_pubkey.clone();
     let listing_addr_str = order.listing_addr.clone();
+    let root_event_id = order.root_event_id.clone();
     drop(state);
+    emit_status_change(ctx, change).await;
 
-    send_envelope(
-        client,
-        buyer,
+    send_relayed_envelope(
+        ctx,
+        event,
+        buyer.to_string(),
         TradeListingMessageType::OrderRevision,
         &listing_addr_str,
         Some(order_id),
+        root_event_id.as_deref(),
         &payload,
     )
     .await
@@ -511,11 +1154,10 @@ async fn handle_order_revision_response(
     payload: TradeOrderRevisionResponse,
     _listing_addr: &TradeListingAddress,
     order_id: Option<&str>,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let order_id = order_id.ok_or(TradeListingDvmError::MissingTag("d"))?;
-    let mut state = state.lock().await;
+    let mut state = ctx.state.order_shard_mut(order_id).await;
     let event_id = event.id.to_string();
     if state.is_event_seen(order_id, &event_id) {
         return Ok(());
@@ -523,7 +1165,7 @@ async fn handle_order_revision_response(
     let order = state
         .get_order_mut(order_id)
         .ok_or(TradeListingStateError::MissingOrder)?;
-    if order.buyer_pubkey != event.pubkey.to_string() {
+    if !order.is_buyer(&event.pubkey) {
         return Err(TradeListingDvmError::Unauthorized);
     }
     if message_type == TradeListingMessageType::OrderRevisionAccept && !payload.accepted {
@@ -538,19 +1180,25 @@ async fn handle_order_revision_response(
     } else {
         TradeOrderStatus::Declined
     };
-    ensure_transition(order.status.clone(), next_status.clone())?;
-    order.status = next_status;
-    order.seen_event_ids.insert(event_id);
+    ensure_order_transition(order, next_status.clone())?;
+    let change = apply_status(ctx, order, next_status);
+    order
+        .seen_event_ids
+        .insert(event_id, ctx.config.limits.max_seen_event_ids);
     let seller = order.seller_pubkey.clone();
     let listing_addr_str = order.listing_addr.clone();
+    let root_event_id = order.root_event_id.clone();
     drop(state);
+    emit_status_change(ctx, change).await;
 
-    send_envelope(
-        client,
-        seller,
+    send_relayed_envelope(
+        ctx,
+        event,
+        seller.to_string(),
         message_type,
         &listing_addr_str,
         Some(order_id),
+        root_event_id.as_deref(),
         &payload,
     )
     .await
@@ -561,8 +1209,7 @@ async fn handle_question(
     payload: TradeQuestion,
     _listing_addr: &TradeListingAddress,
     order_id: Option<&str>,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let order_id = order_id.ok_or(TradeListingDvmError::MissingTag("d"))?;
     if let Some(ref payload_order_id) = payload.order_id {
@@ -570,7 +1217,7 @@ async fn handle_question(
             return Err(TradeListingDvmError::InvalidOrder);
         }
     }
-    let mut state = state.lock().await;
+    let mut state = ctx.state.order_shard_mut(order_id).await;
     let event_id = event.id.to_string();
     if state.is_event_seen(order_id, &event_id) {
         return Ok(());
@@ -578,22 +1225,32 @@ async fn handle_question(
     let order = state
         .get_order_mut(order_id)
         .ok_or(TradeListingStateError::MissingOrder)?;
-    if order.buyer_pubkey != event.pubkey.to_string() {
+    if !order.is_buyer(&event.pubkey) {
         return Err(TradeListingDvmError::Unauthorized);
     }
-    ensure_transition(order.status.clone(), TradeOrderStatus::Questioned)?;
-    order.status = TradeOrderStatus::Questioned;
-    order.seen_event_ids.insert(event_id);
+    ensure_order_transition(order, TradeOrderStatus::Questioned)?;
+    order.record_round(TradeOrderRound::Question, ctx.config.limits.max_questions)?;
+    let from = order.status.clone();
+    order.ask_question(unix_now());
+    notify_transition(ctx.on_transition.as_ref(), order, &from);
+    let change = TradeStatusChanged::new(order, Some(from));
+    order
+        .seen_event_ids
+        .insert(event_id, ctx.config.limits.max_seen_event_ids);
     let seller = order.seller_pubkey.clone();
     let listing_addr_str = order.listing_addr.clone();
+    let root_event_id = order.root_event_id.clone();
     drop(state);
+    emit_status_change(ctx, change).await;
 
-    send_envelope(
-        client,
-        seller,
+    send_relayed_envelope(
+        ctx,
+        event,
+        seller.to_string(),
         TradeListingMessageType::Question,
         &listing_addr_str,
         Some(order_id),
+        root_event_id.as_deref(),
         &payload,
     )
     .await
@@ -604,8 +1261,7 @@ async fn handle_answer(
     payload: TradeAnswer,
     listing_addr: &TradeListingAddress,
     order_id: Option<&str>,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let order_id = order_id.ok_or(TradeListingDvmError::MissingTag("d"))?;
     if let Some(ref payload_order_id) = payload.order_id {
@@ -613,7 +1269,7 @@ async fn handle_answer(
             return Err(TradeListingDvmError::InvalidOrder);
         }
     }
-    let mut state = state.lock().await;
+    let mut state = ctx.state.order_shard_mut(order_id).await;
     let event_id = event.id.to_string();
     if state.is_event_seen(order_id, &event_id) {
         return Ok(());
@@ -621,24 +1277,26 @@ async fn handle_answer(
     let order = state
         .get_order_mut(order_id)
         .ok_or(TradeListingStateError::MissingOrder)?;
-    if order.seller_pubkey != event.pubkey.to_string()
-        || listing_addr.seller_pubkey != order.seller_pubkey
-    {
+    if !order.is_seller(&event.pubkey) || listing_addr.seller_pubkey != *order.seller_pubkey {
         return Err(TradeListingDvmError::Unauthorized);
     }
-    ensure_transition(order.status.clone(), TradeOrderStatus::Requested)?;
-    order.status = TradeOrderStatus::Requested;
-    order.seen_event_ids.insert(event_id);
+    order.answer_question()?;
+    order
+        .seen_event_ids
+        .insert(event_id, ctx.config.limits.max_seen_event_ids);
     let buyer = order.buyer_pubkey.clone();
     let listing_addr_str = order.listing_addr.clone();
+    let root_event_id = order.root_event_id.clone();
     drop(state);
 
-    send_envelope(
-        client,
-        buyer,
+    send_relayed_envelope(
+        ctx,
+        event,
+        buyer.to_string(),
         TradeListingMessageType::Answer,
         &listing_addr_str,
         Some(order_id),
+        root_event_id.as_deref(),
         &payload,
     )
     .await
@@ -649,14 +1307,13 @@ async fn handle_discount_request(
     payload: TradeDiscountRequest,
     _listing_addr: &TradeListingAddress,
     order_id: Option<&str>,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let order_id = order_id.ok_or(TradeListingDvmError::MissingTag("d"))?;
     if payload.order_id != order_id {
         return Err(TradeListingDvmError::InvalidOrder);
     }
-    let mut state = state.lock().await;
+    let mut state = ctx.state.order_shard_mut(order_id).await;
     let event_id = event.id.to_string();
     if state.is_event_seen(order_id, &event_id) {
         return Ok(());
@@ -664,20 +1321,29 @@ async fn handle_discount_request(
     let order = state
         .get_order_mut(order_id)
         .ok_or(TradeListingStateError::MissingOrder)?;
-    if order.buyer_pubkey != event.pubkey.to_string() {
+    if !order.is_buyer(&event.pubkey) {
         return Err(TradeListingDvmError::Unauthorized);
     }
-    order.seen_event_ids.insert(event_id);
+    order.record_round(
+        TradeOrderRound::Discount,
+        ctx.config.limits.max_discount_rounds,
+    )?;
+    order
+        .seen_event_ids
+        .insert(event_id, ctx.config.limits.max_seen_event_ids);
     let seller = order.seller_pubkey.clone();
     let listing_addr_str = order.listing_addr.clone();
+    let root_event_id = order.root_event_id.clone();
     drop(state);
 
-    send_envelope(
-        client,
-        seller,
+    send_relayed_envelope(
+        ctx,
+        event,
+        seller.to_string(),
         TradeListingMessageType::DiscountRequest,
         &listing_addr_str,
         Some(order_id),
+        root_event_id.as_deref(),
         &payload,
     )
     .await
@@ -688,14 +1354,13 @@ async fn handle_discount_offer(
     payload: TradeDiscountOffer,
     _listing_addr: &TradeListingAddress,
     order_id: Option<&str>,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let order_id = order_id.ok_or(TradeListingDvmError::MissingTag("d"))?;
     if payload.order_id != order_id {
         return Err(TradeListingDvmError::InvalidOrder);
     }
-    let mut state = state.lock().await;
+    let mut state = ctx.state.order_shard_mut(order_id).await;
     let event_id = event.id.to_string();
     if state.is_event_seen(order_id, &event_id) {
         return Ok(());
@@ -703,22 +1368,28 @@ async fn handle_discount_offer(
     let order = state
         .get_order_mut(order_id)
         .ok_or(TradeListingStateError::MissingOrder)?;
-    if order.seller_pubkey != event.pubkey.to_string() {
+    if !order.is_seller(&event.pubkey) {
         return Err(TradeListingDvmError::Unauthorized);
     }
-    ensure_transition(order.status.clone(), TradeOrderStatus::Revised)?;
-    order.status = TradeOrderStatus::Revised;
-    order.seen_event_ids.insert(event_id);
+    ensure_order_transition(order, TradeOrderStatus::Revised)?;
+    let change = apply_status(ctx, order, TradeOrderStatus::Revised);
+    order
+        .seen_event_ids
+        .insert(event_id, ctx.config.limits.max_seen_event_ids);
     let buyer = order.buyer_pubkey.clone();
     let listing_addr_str = order.listing_addr.clone();
+    let root_event_id = order.root_event_id.clone();
     drop(state);
+    emit_status_change(ctx, change).await;
 
-    send_envelope(
-        client,
-        buyer,
+    send_relayed_envelope(
+        ctx,
+        event,
+        buyer.to_string(),
         TradeListingMessageType::DiscountOffer,
         &listing_addr_str,
         Some(order_id),
+        root_event_id.as_deref(),
         &payload,
     )
     .await
@@ -730,11 +1401,10 @@ async fn handle_discount_decision(
     payload: TradeDiscountDecision,
     _listing_addr: &TradeListingAddress,
     order_id: Option<&str>,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let order_id = order_id.ok_or(TradeListingDvmError::MissingTag("d"))?;
-    let mut state = state.lock().await;
+    let mut state = ctx.state.order_shard_mut(order_id).await;
     let event_id = event.id.to_string();
     if state.is_event_seen(order_id, &event_id) {
         return Ok(());
@@ -742,7 +1412,7 @@ async fn handle_discount_decision(
     let order = state
         .get_order_mut(order_id)
         .ok_or(TradeListingStateError::MissingOrder)?;
-    if order.buyer_pubkey != event.pubkey.to_string() {
+    if !order.is_buyer(&event.pubkey) {
         return Err(TradeListingDvmError::Unauthorized);
     }
     let payload_is_accept = matches!(payload, TradeDiscountDecision::Accept { .. });
@@ -753,39 +1423,52 @@ async fn handle_discount_decision(
     if message_type == TradeListingMessageType::DiscountDecline && !payload_is_decline {
         return Err(TradeListingDvmError::InvalidOrder);
     }
-    let next_status = match message_type {
-        TradeListingMessageType::DiscountAccept => TradeOrderStatus::Accepted,
-        TradeListingMessageType::DiscountDecline => TradeOrderStatus::Requested,
-        _ => order.status.clone(),
-    };
-    ensure_transition(order.status.clone(), next_status.clone())?;
-    order.status = next_status;
-    order.seen_event_ids.insert(event_id);
+    let next_status = discount_decision_status(message_type, &order.status);
+    ensure_order_transition(order, next_status.clone())?;
+    let change = apply_status(ctx, order, next_status);
+    order
+        .seen_event_ids
+        .insert(event_id, ctx.config.limits.max_seen_event_ids);
     let seller = order.seller_pubkey.clone();
     let listing_addr_str = order.listing_addr.clone();
+    let root_event_id = order.root_event_id.clone();
     drop(state);
+    emit_status_change(ctx, change).await;
 
-    send_envelope(
-        client,
-        seller,
+    send_relayed_envelope(
+        ctx,
+        event,
+        seller.to_string(),
         message_type,
         &listing_addr_str,
         Some(order_id),
+        root_event_id.as_deref(),
         &payload,
     )
     .await
 }
 
+// Declining a discount only turns down the offer: the order keeps whatever status
+// it had, so a revised or questioned order is not rolled back to `Requested`.
+fn discount_decision_status(
+    message_type: TradeListingMessageType,
+    current: &TradeOrderStatus,
+) -> TradeOrderStatus {
+    match message_type {
+        TradeListingMessageType::DiscountAccept => TradeOrderStatus::Accepted,
+        _ => current.clone(),
+    }
+}
+
 async fn handle_cancel(
     event: &RadrootsNostrEvent,
     payload: TradeListingCancel,
     _listing_addr: &TradeListingAddress,
     order_id: Option<&str>,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let order_id = order_id.ok_or(TradeListingDvmError::MissingTag("d"))?;
-    let mut state = state.lock().await;
+    let mut state = ctx.state.order_shard_mut(order_id).await;
     let event_id = event.id.to_string();
     if state.is_event_seen(order_id, &event_id) {
         return Ok(());
@@ -793,30 +1476,51 @@ async fn handle_cancel(
     let order = state
         .get_order_mut(order_id)
         .ok_or(TradeListingStateError::MissingOrder)?;
-    let sender = event.pubkey.to_string();
-    if sender != order.buyer_pubkey && sender != order.seller_pubkey {
+    let from_buyer = order.is_buyer(&event.pubkey);
+    if !from_buyer && !order.is_seller(&event.pubkey) {
         return Err(TradeListingDvmError::Unauthorized);
     }
-    ensure_transition(order.status.clone(), TradeOrderStatus::Cancelled)?;
-    order.status = TradeOrderStatus::Cancelled;
-    order.seen_event_ids.insert(event_id);
-    let recipient = if sender == order.buyer_pubkey {
+    ensure_order_transition(order, TradeOrderStatus::Cancelled)?;
+    let change = apply_status(ctx, order, TradeOrderStatus::Cancelled);
+    order
+        .seen_event_ids
+        .insert(event_id, ctx.config.limits.max_seen_event_ids);
+    let recipient = if from_buyer {
         order.seller_pubkey.clone()
     } else {
         order.buyer_pubkey.clone()
     };
     let listing_addr_str = order.listing_addr.clone();
+    let root_event_id = order.root_event_id.clone();
     drop(state);
+    emit_status_change(ctx, change).await;
 
-    send_envelope(
-        client,
-        recipient,
+    send_relayed_envelope(
+        ctx,
+        event,
+        recipient.to_string(),
         TradeListingMessageType::Cancel,
         &listing_addr_str,
         Some(order_id),
+        root_event_id.as_deref(),
         &payload,
     )
-    .await
+    .await?;
+
+    let confirmation = cancel_confirmation(event, order_id)?;
+    publish_feedback(ctx, confirmation).await
+}
+
+fn cancel_confirmation(
+    event: &RadrootsNostrEvent,
+    order_id: &str,
+) -> Result<EventBuilder, TradeListingDvmError> {
+    Ok(radroots_nostr_build_event_job_feedback(
+        event,
+        "success",
+        Some(format!("order {order_id} cancelled")),
+        None,
+    )?)
 }
 
 async fn handle_fulfillment_update(
@@ -824,11 +1528,11 @@ async fn handle_fulfillment_update(
     payload: TradeFulfillmentUpdate,
     _listing_addr: &TradeListingAddress,
     order_id: Option<&str>,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let order_id = order_id.ok_or(TradeListingDvmError::MissingTag("d"))?;
-    let mut state = state.lock().await;
+    validate_fulfillment_update(payload.tracking.as_deref(), payload.eta, unix_now())?;
+    let mut state = ctx.state.order_shard_mut(order_id).await;
     let event_id = event.id.to_string();
     if state.is_event_seen(order_id, &event_id) {
         return Ok(());
@@ -836,22 +1540,29 @@ async fn handle_fulfillment_update(
     let order = state
         .get_order_mut(order_id)
         .ok_or(TradeListingStateError::MissingOrder)?;
-    if order.seller_pubkey != event.pubkey.to_string() {
+    if !order.is_seller(&event.pubkey) {
         return Err(TradeListingDvmError::Unauthorized);
     }
-    ensure_transition(order.status.clone(), TradeOrderStatus::Fulfilled)?;
-    order.status = TradeOrderStatus::Fulfilled;
-    order.seen_event_ids.insert(event_id);
+    ensure_order_transition(order, TradeOrderStatus::Fulfilled)?;
+    order.advance_fulfillment(fulfillment_stage(&payload.state))?;
+    let change = apply_status(ctx, order, TradeOrderStatus::Fulfilled);
+    order
+        .seen_event_ids
+        .insert(event_id, ctx.config.limits.max_seen_event_ids);
     let buyer = order.buyer_pubkey.clone();
     let listing_addr_str = order.listing_addr.clone();
+    let root_event_id = order.root_event_id.clone();
     drop(state);
+    emit_status_change(ctx, change).await;
 
-    send_envelope(
-        client,
-        buyer,
+    send_relayed_envelope(
+        ctx,
+        event,
+        buyer.to_string(),
         TradeListingMessageType::FulfillmentUpdate,
         &listing_addr_str,
         Some(order_id),
+        root_event_id.as_deref(),
         &payload,
     )
     .await
@@ -862,11 +1573,10 @@ async fn handle_receipt(
     payload: TradeReceipt,
     _listing_addr: &TradeListingAddress,
     order_id: Option<&str>,
-    client: &RadrootsNostrClient,
-    state: &Arc<tokio::sync::Mutex<TradeListingState>>,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let order_id = order_id.ok_or(TradeListingDvmError::MissingTag("d"))?;
-    let mut state = state.lock().await;
+    let mut state = ctx.state.order_shard_mut(order_id).await;
     let event_id = event.id.to_string();
     if state.is_event_seen(order_id, &event_id) {
         return Ok(());
@@ -874,95 +1584,581 @@ async fn handle_receipt(
     let order = state
         .get_order_mut(order_id)
         .ok_or(TradeListingStateError::MissingOrder)?;
-    if order.buyer_pubkey != event.pubkey.to_string() {
+    if !order.is_buyer(&event.pubkey) {
         return Err(TradeListingDvmError::Unauthorized);
     }
-    ensure_transition(order.status.clone(), TradeOrderStatus::Completed)?;
-    order.status = TradeOrderStatus::Completed;
-    order.seen_event_ids.insert(event_id);
+    ensure_order_transition(order, TradeOrderStatus::Completed)?;
+    let change = apply_status(ctx, order, TradeOrderStatus::Completed);
+    order
+        .seen_event_ids
+        .insert(event_id, ctx.config.limits.max_seen_event_ids);
+    let buyer = order.buyer_pubkey.clone();
     let seller = order.seller_pubkey.clone();
     let listing_addr_str = order.listing_addr.clone();
+    let root_event_id = order.root_event_id.clone();
+    let total = order.total.clone();
     drop(state);
+    emit_status_change(ctx, change).await;
 
-    send_envelope(
-        client,
-        seller,
+    let attestation = TradeReceiptAttestation {
+        order_id: order_id.to_string(),
+        total,
+        at: unix_now(),
+        buyer_pubkey: buyer.to_string(),
+        seller_pubkey: seller.to_string(),
+    };
+    let signed = sign_receipt(
+        &ctx.result_keys,
+        serde_json::to_value(&payload)?,
+        attestation,
+    )?;
+    send_relayed_envelope(
+        ctx,
+        event,
+        seller.to_string(),
         TradeListingMessageType::Receipt,
         &listing_addr_str,
         Some(order_id),
-        &payload,
+        root_event_id.as_deref(),
+        &signed,
     )
-    .await
+    .await?;
+
+    if ctx.config.emit_completion_reaction {
+        if let Some(root_event_id) = root_event_id {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let res = match completion_reaction(&root_event_id, &buyer) {
+                    Ok(builder) => publish_result(&ctx, builder).await,
+                    Err(err) => Err(err.into()),
+                };
+                if let Err(err) = res {
+                    warn!("trade_listing: failed to publish completion reaction: {err}");
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+const REACTION_KIND: u32 = 7;
+
+fn completion_reaction(
+    root_event_id: &str,
+    buyer_pubkey: &str,
+) -> Result<EventBuilder, radroots_nostr::error::RadrootsNostrError> {
+    let tags = vec![
+        vec!["e".to_string(), root_event_id.to_string()],
+        vec!["p".to_string(), buyer_pubkey.to_string()],
+        vec![
+            "k".to_string(),
+            TradeListingMessageType::OrderRequest.kind().to_string(),
+        ],
+    ];
+    radroots_nostr_build_event(REACTION_KIND, "+".to_string(), tags)
 }
 
 async fn send_envelope<T: serde::Serialize + Clone>(
-    client: &RadrootsNostrClient,
+    ctx: &TradeListingContext,
     recipient_pubkey: String,
     message_type: TradeListingMessageType,
     listing_addr: &str,
     order_id: Option<&str>,
     payload: &T,
 ) -> Result<(), TradeListingDvmError> {
+    let builder = envelope_event(
+        recipient_pubkey,
+        None,
+        message_type,
+        listing_addr,
+        order_id,
+        payload,
+        reply_encryption_keys(ctx, message_type),
+    )?;
+    publish_envelope(ctx, message_type, builder).await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_relayed_envelope<T: serde::Serialize + Clone>(
+    ctx: &TradeListingContext,
+    event: &RadrootsNostrEvent,
+    recipient_pubkey: String,
+    message_type: TradeListingMessageType,
+    listing_addr: &str,
+    order_id: Option<&str>,
+    root_event_id: Option<&str>,
+    payload: &T,
+) -> Result<(), TradeListingDvmError> {
+    let builder = relayed_envelope_event(
+        ctx,
+        event,
+        recipient_pubkey,
+        message_type,
+        listing_addr,
+        order_id,
+        root_event_id,
+        payload,
+    )?;
+    publish_envelope(ctx, message_type, builder).await
+}
+
+// A relayed order message carries the order's chain: the order request as its root
+// and the request it relays as the previous link, which the recipient references
+// when it answers.
+#[allow(clippy::too_many_arguments)]
+fn relayed_envelope_event<T: serde::Serialize + Clone>(
+    ctx: &TradeListingContext,
+    event: &RadrootsNostrEvent,
+    recipient_pubkey: String,
+    message_type: TradeListingMessageType,
+    listing_addr: &str,
+    order_id: Option<&str>,
+    root_event_id: Option<&str>,
+    payload: &T,
+) -> Result<EventBuilder, TradeListingDvmError> {
+    let sender_pubkey = ctx
+        .config
+        .mutual_p_tags
+        .contains(&message_type)
+        .then(|| event.pubkey.to_string());
+    let builder = envelope_event(
+        recipient_pubkey,
+        sender_pubkey,
+        message_type,
+        listing_addr,
+        order_id,
+        payload,
+        reply_encryption_keys(ctx, message_type),
+    )?;
+    Ok(match root_event_id {
+        Some(root) => builder
+            .tag(Tag::custom(TagKind::custom(TAG_E_ROOT), [root]))
+            .tag(Tag::custom(
+                TagKind::custom(TAG_E_PREV),
+                [event.id.to_string()],
+            )),
+        None => builder,
+    })
+}
+
+// Replies are signed by the result key, so that key also encrypts them.
+fn reply_encryption_keys(
+    ctx: &TradeListingContext,
+    message_type: TradeListingMessageType,
+) -> Option<&RadrootsNostrKeys> {
+    ctx.config
+        .encrypts_reply(message_type)
+        .then_some(&ctx.result_keys)
+}
+
+async fn publish_envelope(
+    ctx: &TradeListingContext,
+    message_type: TradeListingMessageType,
+    builder: EventBuilder,
+) -> Result<(), TradeListingDvmError> {
+    let expiration_secs = ctx.config.reply_expiration_secs(message_type);
+    let builder = with_expiration(builder, expiration_secs, unix_now());
+    let relays = ctx.config.routed_relays(message_type);
+    publish_reply(ctx, &ctx.result_keys, builder, relays).await
+}
+
+// In nip17 mode every reply, job feedback included, is gift-wrapped for the requester.
+async fn publish_reply(
+    ctx: &TradeListingContext,
+    keys: &RadrootsNostrKeys,
+    builder: EventBuilder,
+    relays: Option<&[String]>,
+) -> Result<(), TradeListingDvmError> {
+    let event = reply_event(ctx.encryption, keys, builder).await?;
+    publish_event(ctx, event, relays).await
+}
+
+async fn reply_event(
+    encryption: EncryptionMode,
+    keys: &RadrootsNostrKeys,
+    builder: EventBuilder,
+) -> Result<RadrootsNostrEvent, TradeListingDvmError> {
+    match encryption {
+        EncryptionMode::Nip17 => gift_wrap_reply(keys, builder).await,
+        EncryptionMode::Nip04 => sign_result(keys, builder),
+    }
+}
+
+// Wraps the reply for its primary recipient only; a mutual `p` tag stays inside the
+// rumor but the sender does not receive a copy.
+async fn gift_wrap_reply(
+    keys: &RadrootsNostrKeys,
+    builder: EventBuilder,
+) -> Result<RadrootsNostrEvent, TradeListingDvmError> {
+    let rumor = builder.build(keys.public_key());
+    let recipient = rumor
+        .tags
+        .public_keys()
+        .next()
+        .copied()
+        .ok_or(TradeListingDvmError::MissingRecipient)?;
+    Ok(EventBuilder::gift_wrap(keys, &recipient, rumor, []).await?)
+}
+
+fn with_expiration(builder: EventBuilder, expiration_secs: Option<u64>, now: u64) -> EventBuilder {
+    match expiration_secs {
+        Some(secs) => builder.tag(Tag::expiration(Timestamp::from(now.saturating_add(secs)))),
+        None => builder,
+    }
+}
+
+async fn publish_result(
+    ctx: &TradeListingContext,
+    builder: EventBuilder,
+) -> Result<(), TradeListingDvmError> {
+    publish_reply(ctx, &ctx.result_keys, builder, None).await
+}
+
+// Job feedback of every kind is signed with the service key, so clients see one DVM;
+// the result key only signs results.
+async fn publish_feedback(
+    ctx: &TradeListingContext,
+    builder: EventBuilder,
+) -> Result<(), TradeListingDvmError> {
+    publish_reply(ctx, &ctx.keys, builder, None).await
+}
+
+async fn publish_event(
+    ctx: &TradeListingContext,
+    event: RadrootsNostrEvent,
+    relays: Option<&[String]>,
+) -> Result<(), TradeListingDvmError> {
+    deliver_event(ctx, &event, relays).await?;
+    // A stage request chained on one of our results is checked against it, and the
+    // cache spares that check a relay round trip.
+    ctx.event_cache.insert(event);
+    Ok(())
+}
+
+async fn deliver_event(
+    ctx: &TradeListingContext,
+    event: &RadrootsNostrEvent,
+    relays: Option<&[String]>,
+) -> Result<(), TradeListingDvmError> {
+    if ctx.dry_run {
+        log_dry_run_event(event);
+        // Journaled under no relays, so a dry run shows what would have gone out.
+        if let Some(journal) = &ctx.journal {
+            journal.record(JournalDirection::Sent, &[], event);
+        }
+        return Ok(());
+    }
+    // Routed message types go only to their own relays: they skip the outbox, and
+    // relays outside the configured set never join the shared pool.
+    if let Some(relays) = relays {
+        let targets = routed_relay_urls(relays);
+        if !targets.is_empty() {
+            let sent = send_routed(ctx, targets, event).await?;
+            if let Some(journal) = &ctx.journal {
+                journal.record(JournalDirection::Sent, &sent, event);
+            }
+            return Ok(());
+        }
+    }
+    let output = ctx.client.send_event(event).await?;
+    if let Some(journal) = &ctx.journal {
+        let relays: Vec<String> = output.success.iter().map(|url| url.to_string()).collect();
+        journal.record(JournalDirection::Sent, &relays, event);
+    }
+    if let (Some(outbox), None) = (&ctx.outbox, relays) {
+        send_to_recipient_relays(ctx, outbox, event).await;
+    }
+    Ok(())
+}
+
+async fn send_routed(
+    ctx: &TradeListingContext,
+    targets: Vec<RelayUrl>,
+    event: &RadrootsNostrEvent,
+) -> Result<Vec<String>, TradeListingDvmError> {
+    let known = ctx.client.relays().await;
+    let (pooled, extra): (Vec<RelayUrl>, Vec<RelayUrl>) =
+        targets.into_iter().partition(|url| known.contains_key(url));
+    let mut sent = Vec::new();
+    if !pooled.is_empty() {
+        let output = ctx.client.send_event_to(pooled, event).await?;
+        sent.extend(output.success.iter().map(|url| url.to_string()));
+    }
+    if !extra.is_empty() {
+        sent.extend(send_to_extra_relays(ctx, extra, event, "routing").await?);
+    }
+    Ok(sent)
+}
+
+async fn send_to_recipient_relays(
+    ctx: &TradeListingContext,
+    outbox: &RelayListCache,
+    event: &RadrootsNostrEvent,
+) {
+    let known = ctx.client.relays().await;
+    let mut targets = outbox
+        .recipient_relays(&ctx.client, event.tags.public_keys())
+        .await;
+    targets.retain(|relay| !known.contains_key(relay));
+    if targets.is_empty() {
+        return;
+    }
+    match send_to_extra_relays(ctx, targets, event, "outbox").await {
+        Ok(relays) => {
+            if let Some(journal) = &ctx.journal {
+                journal.record(JournalDirection::Sent, &relays, event);
+            }
+        }
+        Err(e) => warn!(
+            "outbox: failed to deliver {} to recipient relays: {e}",
+            event.id
+        ),
+    }
+}
+
+const EXTRA_RELAY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Relays outside the configured set are reached through a short-lived client, so
+// one-off recipients never join the shared pool that every broadcast goes to.
+async fn send_to_extra_relays(
+    ctx: &TradeListingContext,
+    relays: Vec<RelayUrl>,
+    event: &RadrootsNostrEvent,
+    scope: &str,
+) -> Result<Vec<String>, TradeListingDvmError> {
+    let client = RadrootsNostrClient::new(ctx.keys.clone());
+    let mut targets = Vec::with_capacity(relays.len());
+    for relay in relays {
+        match client.add_write_relay(relay.clone()).await {
+            Ok(_) => targets.push(relay),
+            Err(e) => warn!("{scope}: failed to add relay {relay}: {e}"),
+        }
+    }
+    if targets.is_empty() {
+        return Ok(Vec::new());
+    }
+    client.connect().await;
+    client
+        .wait_for_connection(EXTRA_RELAY_CONNECT_TIMEOUT)
+        .await;
+    let output = client.send_event_to(targets, event).await;
+    client.disconnect().await;
+    Ok(output?.success.iter().map(|url| url.to_string()).collect())
+}
+
+// Invalid URLs are skipped; if none remain the event is broadcast to every write
+// relay instead.
+fn routed_relay_urls(relays: &[String]) -> Vec<RelayUrl> {
+    relays
+        .iter()
+        .filter_map(|relay| match RelayUrl::parse(relay) {
+            Ok(url) => Some(url),
+            Err(e) => {
+                warn!("routing: invalid relay {relay}: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+fn sign_result(
+    result_keys: &RadrootsNostrKeys,
+    builder: EventBuilder,
+) -> Result<RadrootsNostrEvent, TradeListingDvmError> {
+    Ok(builder.sign_with_keys(result_keys)?)
+}
+
+fn envelope_event<T: serde::Serialize + Clone>(
+    recipient_pubkey: String,
+    sender_pubkey: Option<String>,
+    message_type: TradeListingMessageType,
+    listing_addr: &str,
+    order_id: Option<&str>,
+    payload: &T,
+    encrypt_with: Option<&RadrootsNostrKeys>,
+) -> Result<EventBuilder, TradeListingDvmError> {
     let envelope = TradeListingEnvelope::new(
         message_type,
         listing_addr.to_string(),
         order_id.map(|v| v.to_string()),
         payload.clone(),
     );
-    let content = serde_json::to_string(&envelope)?;
-    let tags = trade_listing_dvm_tags(recipient_pubkey, listing_addr, order_id);
-    let builder = radroots_nostr_build_event(message_type.kind() as u32, content, tags)?;
-    radroots_nostr_send_event(client, builder).await?;
-    Ok(())
+    let mut content = encode_envelope(&envelope)?;
+    // NIP-04 has a single recipient: a mutual `p` tag still routes the reply to the
+    // sender, but only the primary recipient can read it.
+    if let Some(keys) = encrypt_with {
+        let recipient = radroots_nostr_parse_pubkey(&recipient_pubkey)?;
+        content = nip04::encrypt(keys.secret_key(), &recipient, content)?;
+    }
+    let sender_pubkey = sender_pubkey.filter(|sender| *sender != recipient_pubkey);
+    let mut tags = trade_listing_dvm_tags(recipient_pubkey, listing_addr, order_id);
+    if let Some(sender_pubkey) = sender_pubkey {
+        tags.push(vec!["p".to_string(), sender_pubkey]);
+    }
+    if encrypt_with.is_some() {
+        tags.push(vec!["encrypted".to_string()]);
+    }
+    Ok(radroots_nostr_build_event(
+        message_type.kind() as u32,
+        content,
+        tags,
+    )?)
 }
 
 async fn fetch_listing_by_addr(
+    ctx: &TradeListingContext,
+    listing_addr: &str,
+) -> Result<Option<RadrootsNostrEvent>, TradeListingDvmError> {
+    if let Some(listing) = ctx.listing_cache.get(listing_addr) {
+        return Ok(Some(listing));
+    }
+    let listing = fetch_latest_listing(&ctx.client, &ctx.config.fetch_retry, listing_addr).await?;
+    if let Some(listing) = &listing {
+        ctx.listing_cache.insert(listing_addr, listing.clone());
+    }
+    Ok(listing)
+}
+
+async fn fetch_latest_listing(
     client: &RadrootsNostrClient,
+    retry: &FetchRetryConfig,
     listing_addr: &str,
 ) -> Result<Option<RadrootsNostrEvent>, TradeListingDvmError> {
-    let addr = TradeListingAddress::parse(listing_addr)
-        .map_err(|_| TradeListingDvmError::InvalidListingAddr)?;
+    let addr = parse_listing_addr(listing_addr)?;
     let author = radroots_nostr_parse_pubkey(&addr.seller_pubkey)
         .map_err(|_| TradeListingDvmError::InvalidListingAddr)?;
+    let kind = RadrootsNostrKind::Custom(addr.kind);
     let filter = RadrootsNostrFilter::new()
-        .kind(RadrootsNostrKind::Custom(addr.kind))
+        .kind(kind)
         .author(author)
         .identifier(addr.listing_id);
-    let events = client.fetch_events(filter, Duration::from_secs(10)).await?;
+    let is_listing = |ev: &RadrootsNostrEvent| ev.kind == kind && ev.pubkey == author;
+
+    // Relays answer `limit(1)` with their newest version, so a prolific seller's history
+    // stays on the relay. Some relays ignore limits on addressable filters and return
+    // nothing; those fall back to the full query.
+    let limited = filter.clone().limit(1);
+    let events = nostr_fetch_with_retry(retry, || {
+        fetch_events(client, limited.clone(), Duration::from_secs(10))
+    })
+    .await?;
+    if let Some(latest) = latest_event(events, is_listing) {
+        return Ok(Some(latest));
+    }
+    let events = nostr_fetch_with_retry(retry, || {
+        fetch_events(client, filter.clone(), Duration::from_secs(10))
+    })
+    .await?;
+    Ok(latest_event(events, is_listing))
+}
+
+// Results from several relays arrive merged and unordered. Equal timestamps are
+// settled as NIP-01 does for replaceable events: the lowest id (first in lexical
+// order) is the one relays retain, so it is the canonical version.
+fn latest_event(
+    events: impl IntoIterator<Item = RadrootsNostrEvent>,
+    keep: impl Fn(&RadrootsNostrEvent) -> bool,
+) -> Option<RadrootsNostrEvent> {
     let mut latest: Option<RadrootsNostrEvent> = None;
     for ev in events {
-        if ev.kind != RadrootsNostrKind::Custom(addr.kind) {
+        if !keep(&ev) {
             continue;
         }
-        match &latest {
-            Some(cur) if ev.created_at <= cur.created_at => {}
-            _ => latest = Some(ev),
+        let newer = latest.as_ref().is_none_or(|cur| {
+            ev.created_at > cur.created_at || (ev.created_at == cur.created_at && ev.id < cur.id)
+        });
+        if newer {
+            latest = Some(ev);
         }
     }
-    Ok(latest)
+    latest
+}
+
+async fn ensure_listing_not_deleted(
+    ctx: &TradeListingContext,
+    listing_addr: &str,
+    listing: &RadrootsNostrEvent,
+) -> Result<(), TradeListingDvmError> {
+    let listings = ctx.state.listings().read().await;
+    if listings.is_listing_deleted(listing_addr, listing.created_at.as_u64()) {
+        return Err(TradeListingDvmError::ListingDeleted);
+    }
+    Ok(())
+}
+
+// Returns the listing's signing key, which is the only seller identity later stages
+// trust; the buyer's claimed `seller_pubkey` is just checked against it.
+fn ensure_listing_author(
+    listing: &RadrootsNostrEvent,
+    seller_pubkey: &str,
+) -> Result<String, TradeListingDvmError> {
+    let author = listing.pubkey.to_string();
+    if author == seller_pubkey {
+        Ok(author)
+    } else {
+        Err(TradeListingDvmError::Unauthorized)
+    }
+}
+
+fn listing_address_error(
+    listing: &RadrootsNostrEvent,
+    addr: &TradeListingAddress,
+    deleted: bool,
+) -> Option<ListingAddressError> {
+    if deleted {
+        Some(ListingAddressError::Deleted)
+    } else if ensure_listing_coordinate(listing, addr).is_err() {
+        Some(ListingAddressError::CoordinateMismatch)
+    } else if ensure_listing_author(listing, &addr.seller_pubkey).is_err() {
+        Some(ListingAddressError::AuthorMismatch)
+    } else {
+        None
+    }
+}
+
+fn ensure_listing_coordinate(
+    listing: &RadrootsNostrEvent,
+    addr: &TradeListingAddress,
+) -> Result<(), TradeListingDvmError> {
+    if listing.kind != RadrootsNostrKind::Custom(addr.kind) {
+        return Err(TradeListingDvmError::TagMismatch("listing kind"));
+    }
+    if listing.tags.identifier() != Some(addr.listing_id.as_str()) {
+        return Err(TradeListingDvmError::TagMismatch("listing d"));
+    }
+    Ok(())
 }
 
 async fn fetch_latest_event_by_kind(
     client: &RadrootsNostrClient,
+    retry: &FetchRetryConfig,
     filter: RadrootsNostrFilter,
     kind: RadrootsNostrKind,
 ) -> Result<Option<RadrootsNostrEvent>, TradeListingDvmError> {
-    let events = client.fetch_events(filter, Duration::from_secs(10)).await?;
-    let mut latest: Option<RadrootsNostrEvent> = None;
-    for ev in events {
-        if ev.kind != kind {
-            continue;
-        }
-        match &latest {
-            Some(cur) if ev.created_at <= cur.created_at => {}
-            _ => latest = Some(ev),
-        }
-    }
-    Ok(latest)
+    let events = nostr_fetch_with_retry(retry, || {
+        fetch_events(client, filter.clone(), Duration::from_secs(10))
+    })
+    .await?;
+    Ok(latest_event(events, |ev| ev.kind == kind))
+}
+
+// The client only fails a query when it could not reach the relays; a relay that
+// answered with nothing yields an empty result instead.
+async fn fetch_events(
+    client: &RadrootsNostrClient,
+    filter: RadrootsNostrFilter,
+    timeout: Duration,
+) -> Result<Vec<RadrootsNostrEvent>, NostrFetchError> {
+    let events = client
+        .fetch_events(filter, timeout)
+        .await
+        .map_err(NostrFetchError::Unanswered)?;
+    Ok(events.into_iter().collect())
 }
 
 async fn validate_farm_dependencies(
     client: &RadrootsNostrClient,
+    retry: &FetchRetryConfig,
     farm: &RadrootsListingFarmRef,
 ) -> Result<Vec<TradeListingValidationError>, TradeListingDvmError> {
     let mut errors = Vec::new();
@@ -981,11 +2177,9 @@ async fn validate_farm_dependencies(
         .kind(RadrootsNostrKind::Metadata)
         .author(author.clone());
     let profile_event =
-        match fetch_latest_event_by_kind(client, profile_filter, RadrootsNostrKind::Metadata).await
-        {
-            Ok(event) => event,
-            Err(_) => None,
-        };
+        fetch_latest_event_by_kind(client, retry, profile_filter, RadrootsNostrKind::Metadata)
+            .await
+            .unwrap_or_default();
     let has_profile = profile_event
         .map(|event| {
             let rr_event = radroots_event_from_nostr(&event);
@@ -1003,6 +2197,7 @@ async fn validate_farm_dependencies(
             .identifier(farm_d_tag.to_string());
         let record_event = match fetch_latest_event_by_kind(
             client,
+            retry,
             record_filter,
             RadrootsNostrKind::Custom(KIND_FARM as u16),
         )
@@ -1021,72 +2216,230 @@ async fn validate_farm_dependencies(
     Ok(errors)
 }
 
-fn parse_payload<T: DeserializeOwned>(value: serde_json::Value) -> Result<T, TradeListingDvmError> {
-    serde_json::from_value(value).map_err(|e| TradeListingDvmError::InvalidPayload(e.to_string()))
+pub(crate) const TRADE_LISTING_KIND: u16 = 30402;
+const PARAMETERIZED_REPLACEABLE_KINDS: core::ops::Range<u16> = 30000..40000;
+
+pub fn parse_listing_addr(listing_addr: &str) -> Result<TradeListingAddress, TradeListingDvmError> {
+    let listing_addr = normalize_listing_addr(listing_addr)?;
+    let kind = listing_addr
+        .split(':')
+        .next()
+        .and_then(|kind| kind.parse::<u16>().ok())
+        .ok_or(TradeListingDvmError::InvalidListingAddr)?;
+    if !PARAMETERIZED_REPLACEABLE_KINDS.contains(&kind) {
+        return Err(TradeListingDvmError::NonReplaceableListingKind(kind));
+    }
+    if kind != TRADE_LISTING_KIND {
+        return Err(TradeListingDvmError::UnsupportedListingKind(kind));
+    }
+    TradeListingAddress::parse(&listing_addr).map_err(|_| TradeListingDvmError::InvalidListingAddr)
+}
+
+/// Rewrites `naddr1…` addresses and `npub1…` author segments into the
+/// `kind:pubkey_hex:identifier` coordinate form.
+pub fn normalize_listing_addr(listing_addr: &str) -> Result<String, TradeListingDvmError> {
+    let listing_addr = listing_addr.trim();
+    if listing_addr.starts_with("naddr1") {
+        let naddr = Nip19Coordinate::from_bech32(listing_addr)
+            .map_err(|_| TradeListingDvmError::InvalidListingAddr)?;
+        return Ok(format!(
+            "{}:{}:{}",
+            naddr.coordinate.kind.as_u16(),
+            naddr.coordinate.public_key.to_hex(),
+            naddr.coordinate.identifier
+        ));
+    }
+
+    let mut parts = listing_addr.splitn(3, ':');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(kind), Some(author), Some(identifier)) if author.starts_with("npub1") => {
+            let author =
+                PublicKey::parse(author).map_err(|_| TradeListingDvmError::InvalidListingAddr)?;
+            Ok(format!("{kind}:{}:{identifier}", author.to_hex()))
+        }
+        _ => Ok(listing_addr.to_string()),
+    }
 }
 
-fn tag_value(tags: &[Vec<String>], key: &str) -> Option<String> {
-    tags.iter().find_map(|t| {
-        if t.get(0).map(|k| k.as_str()) == Some(key) {
-            t.get(1).cloned()
-        } else {
-            None
+fn ensure_same_listing(
+    expected: &str,
+    actual: &str,
+    field: &'static str,
+) -> Result<(), TradeListingDvmError> {
+    if listing_addr_kind(expected) != listing_addr_kind(actual) {
+        return Err(TradeListingDvmError::TagMismatch("listing kind"));
+    }
+    if !same_listing_addr(expected, actual) {
+        return Err(TradeListingDvmError::TagMismatch(field));
+    }
+    Ok(())
+}
+
+fn listing_addr_kind(listing_addr: &str) -> Option<u16> {
+    normalize_listing_addr(listing_addr)
+        .ok()?
+        .split(':')
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn same_listing_addr(a: &str, b: &str) -> bool {
+    match (normalize_listing_addr(a), normalize_listing_addr(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn parse_payload<T: DeserializeOwned>(
+    value: serde_json::Value,
+    mode: PayloadMode,
+) -> Result<T, TradeListingDvmError> {
+    match mode {
+        PayloadMode::Lenient => serde_json::from_value(value)
+            .map_err(|e| TradeListingDvmError::InvalidPayload(e.to_string())),
+        PayloadMode::Strict => {
+            let mut unknown = Vec::new();
+            let payload = serde_ignored::deserialize(value, |path| unknown.push(path.to_string()))
+                .map_err(|e| TradeListingDvmError::InvalidPayload(e.to_string()))?;
+            if !unknown.is_empty() {
+                return Err(TradeListingDvmError::InvalidPayload(format!(
+                    "unknown fields: {}",
+                    unknown.join(", ")
+                )));
+            }
+            Ok(payload)
         }
-    })
+    }
+}
+
+fn ensure_sole_recipient(tags: &[Vec<String>], pubkey: &str) -> Result<(), TradeListingDvmError> {
+    if !tag_has_value(tags, "p", pubkey) {
+        return Err(TradeListingDvmError::MissingRecipient);
+    }
+    let conflicting = tags
+        .iter()
+        .filter(|t| t.first().map(String::as_str) == Some("p"))
+        .any(|t| t.get(1).map(String::as_str) != Some(pubkey));
+    if conflicting {
+        return Err(TradeListingDvmError::NotRecipient);
+    }
+    Ok(())
 }
 
+const PAYMENT_TAG: &str = "payment";
+const PAYMENT_REDEEM_GRACE_SECS: u64 = 3600;
+
 fn tag_has_value(tags: &[Vec<String>], key: &str, value: &str) -> bool {
     tags.iter().any(|t| {
         t.get(0).map(|k| k.as_str()) == Some(key) && t.get(1).map(|v| v.as_str()) == Some(value)
     })
 }
 
+const MAX_TRACKING_LEN: usize = 128;
+const MAX_ETA_HORIZON_SECS: u64 = 365 * 24 * 60 * 60;
+
+fn validate_fulfillment_update(
+    tracking: Option<&str>,
+    eta: Option<u64>,
+    now: u64,
+) -> Result<(), TradeListingDvmError> {
+    let invalid = |reason: String| Err(TradeListingDvmError::InvalidFulfillmentUpdate(reason));
+    if let Some(tracking) = tracking {
+        if tracking.trim().is_empty() {
+            return invalid("tracking must not be empty".into());
+        }
+        if tracking.len() > MAX_TRACKING_LEN {
+            return invalid(format!("tracking exceeds {MAX_TRACKING_LEN} bytes"));
+        }
+    }
+    if let Some(eta) = eta {
+        if eta < now {
+            return invalid(format!("eta {eta} is in the past"));
+        }
+        if eta - now > MAX_ETA_HORIZON_SECS {
+            return invalid(format!("eta {eta} is more than a year out"));
+        }
+    }
+    Ok(())
+}
+
+fn apply_status(
+    ctx: &TradeListingContext,
+    order: &mut TradeOrderState,
+    status: TradeOrderStatus,
+) -> TradeStatusChanged {
+    let from = order.status.clone();
+    order.set_status(status, unix_now());
+    notify_transition(ctx.on_transition.as_ref(), order, &from);
+    TradeStatusChanged::new(order, Some(from))
+}
+
+fn notify_transition(
+    hook: Option<&TransitionHook>,
+    order: &TradeOrderState,
+    from: &TradeOrderStatus,
+) {
+    if let Some(hook) = hook {
+        if *from != order.status {
+            hook(order, from.clone(), order.status.clone());
+        }
+    }
+}
+
+async fn emit_status_change(ctx: &TradeListingContext, change: TradeStatusChanged) {
+    if change.from.as_ref() == Some(&change.to) {
+        return;
+    }
+    for sink in &ctx.events {
+        if let Err(err) = sink.publish(change.clone()).await {
+            warn!(
+                "trade_listing: failed to publish status change for {}: {err}",
+                change.order_id
+            );
+        }
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn fulfillment_stage(state: &TradeListingFulfillmentState) -> TradeFulfillmentStage {
+    match state {
+        TradeListingFulfillmentState::Preparing => TradeFulfillmentStage::Preparing,
+        TradeListingFulfillmentState::Shipped => TradeFulfillmentStage::Shipped,
+        TradeListingFulfillmentState::Delivered => TradeFulfillmentStage::Delivered,
+    }
+}
+
+fn ensure_order_transition(
+    order: &TradeOrderState,
+    to: TradeOrderStatus,
+) -> Result<(), TradeListingStateError> {
+    if order.awaiting_answer()
+        && matches!(to, TradeOrderStatus::Accepted | TradeOrderStatus::Declined)
+    {
+        metrics::record_invalid_transition(&order.status, &to);
+        return Err(TradeListingStateError::InvalidTransition {
+            from: order.status.clone(),
+            to,
+        });
+    }
+    ensure_transition(order.status.clone(), to)
+}
+
 fn ensure_transition(
     from: TradeOrderStatus,
     to: TradeOrderStatus,
 ) -> Result<(), TradeListingStateError> {
-    if from == to {
-        return Ok(());
-    }
-    let allowed = match from {
-        TradeOrderStatus::Draft => matches!(to, TradeOrderStatus::Requested),
-        TradeOrderStatus::Validated => matches!(to, TradeOrderStatus::Requested),
-        TradeOrderStatus::Requested => matches!(
-            to,
-            TradeOrderStatus::Accepted
-                | TradeOrderStatus::Declined
-                | TradeOrderStatus::Questioned
-                | TradeOrderStatus::Revised
-                | TradeOrderStatus::Cancelled
-                | TradeOrderStatus::Requested
-        ),
-        TradeOrderStatus::Questioned => matches!(
-            to,
-            TradeOrderStatus::Requested | TradeOrderStatus::Revised | TradeOrderStatus::Cancelled
-        ),
-        TradeOrderStatus::Revised => matches!(
-            to,
-            TradeOrderStatus::Accepted
-                | TradeOrderStatus::Declined
-                | TradeOrderStatus::Cancelled
-                | TradeOrderStatus::Requested
-        ),
-        TradeOrderStatus::Accepted => {
-            matches!(to, TradeOrderStatus::Fulfilled | TradeOrderStatus::Cancelled)
-        }
-        TradeOrderStatus::Declined => false,
-        TradeOrderStatus::Cancelled => false,
-        TradeOrderStatus::Fulfilled => {
-            matches!(
-                to,
-                TradeOrderStatus::Completed | TradeOrderStatus::Fulfilled | TradeOrderStatus::Cancelled
-            )
-        }
-        TradeOrderStatus::Completed => false,
-    };
-    if allowed {
+    if can_transition(&from, &to) {
         Ok(())
     } else {
+        metrics::record_invalid_transition(&from, &to);
         Err(TradeListingStateError::InvalidTransition { from, to })
     }
 }
@@ -1094,18 +2447,285 @@ fn ensure_transition(
 pub async fn handle_error(
     error: TradeListingDvmError,
     event: &RadrootsNostrEvent,
-    client: &RadrootsNostrClient,
+    ctx: &TradeListingContext,
 ) -> Result<(), TradeListingDvmError> {
     let builder =
         radroots_nostr_build_event_job_feedback(event, "error", Some(error.to_string()), None)?;
-    let _ = radroots_nostr_send_event(client, builder).await?;
-    Ok(())
+    publish_feedback(ctx, builder).await
 }
 
 #[cfg(test)]
 mod tests {
-    use super::ensure_transition;
-    use radroots_trade::listing::order::TradeOrderStatus;
+    use super::{
+        CONFIRMATION_TAG, IDEMPOTENCY_KEY_FIELD, InvoicedResponse, ListingAddressError,
+        MAX_ETA_HORIZON_SECS, MAX_TRACKING_LEN, ORDER_NONCE_FIELD, PAYMENT_TAG, QuotedOrder,
+        TradeListingContext, TradeListingDvmError, TradeOrderState, TransitionHook,
+        cancel_confirmation, completion_reaction, decode_envelope, discount_decision_status,
+        ensure_listing_author, ensure_listing_coordinate, ensure_order_transition,
+        ensure_same_listing, ensure_sole_recipient, ensure_transition, envelope_event,
+        handle_event, idempotent_repeat_feedback, latest_event, listing_address_error,
+        normalize_listing_addr, notify_transition, order_request_event, order_response_event,
+        parse_listing_addr, parse_payload, parse_trade_listing_event, payment_required_feedback,
+        reply_event, required_payment, routed_relay_urls, sign_result, tag_has_value,
+        take_payload_field, trade_root, validate_fulfillment_update, with_expiration,
+    };
+    use futures::future::BoxFuture;
+    use nostr::{
+        Coordinate, EventBuilder, Kind, RelayUrl, Tag, TagKind, Timestamp,
+        nips::{
+            nip04,
+            nip19::{Nip19Coordinate, ToBech32},
+        },
+    };
+    use radroots_events::tags::{TAG_E_PREV, TAG_E_ROOT};
+    use radroots_nostr::prelude::{
+        RadrootsNostrClient, RadrootsNostrEvent, RadrootsNostrKeys, radroots_nostr_build_event,
+    };
+    use radroots_trade::listing::{
+        dvm::{TradeListingMessageType, TradeOrderResponse},
+        order::{TradeOrder, TradeOrderStatus},
+    };
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    use crate::config::{EncryptionMode, PayloadMode, TradeConfig, TradeStage};
+    use crate::features::trade_listing::{
+        domain::pricing::quote_order, handlers::registry::HandlerRegistry, invoice::InvoiceTerms,
+        listing_cache::ListingCache, state::SharedTradeListingState,
+    };
+    use crate::infra::{
+        event_cache::EventFetchCache,
+        invoices::{InvoiceBackendError, InvoiceIssuer, IssuedInvoice},
+        metrics,
+        nostr::{NostrPayloadLimits, nostr_unwrap_gift_wrap},
+    };
+
+    // Replies are only logged in dry-run mode, so handlers run without a relay.
+    fn test_context(rhi: &RadrootsNostrKeys) -> TradeListingContext {
+        let trade = TradeConfig::default();
+        TradeListingContext {
+            client: RadrootsNostrClient::new(rhi.clone()),
+            keys: rhi.clone(),
+            result_keys: RadrootsNostrKeys::generate(),
+            state: Arc::new(SharedTradeListingState::default()),
+            listing_cache: Arc::new(ListingCache::new(&trade.listing_cache)),
+            config: Arc::new(trade),
+            registry: Arc::new(HandlerRegistry::default()),
+            store: None,
+            journal: None,
+            outbox: None,
+            event_cache: Arc::new(EventFetchCache::default()),
+            rates: None,
+            invoices: None,
+            events: Vec::new(),
+            on_transition: None,
+            encryption: EncryptionMode::default(),
+            dry_run: true,
+        }
+    }
+
+    type Transitions = Arc<Mutex<Vec<(TradeOrderStatus, TradeOrderStatus)>>>;
+
+    fn chain_context(rhi: &RadrootsNostrKeys, transitions: &Transitions) -> TradeListingContext {
+        let hook: TransitionHook = {
+            let transitions = Arc::clone(transitions);
+            Arc::new(move |_: &TradeOrderState, from, to| {
+                transitions.lock().unwrap().push((from, to));
+            })
+        };
+        TradeListingContext {
+            on_transition: Some(hook),
+            ..test_context(rhi)
+        }
+    }
+
+    fn chain_request(
+        from: &RadrootsNostrKeys,
+        rhi: &RadrootsNostrKeys,
+        message_type: TradeListingMessageType,
+        listing_addr: &str,
+        payload: serde_json::Value,
+    ) -> RadrootsNostrEvent {
+        envelope_event(
+            rhi.public_key().to_string(),
+            None,
+            message_type,
+            listing_addr,
+            Some("order-1"),
+            &payload,
+            None,
+        )
+        .unwrap()
+        .sign_with_keys(from)
+        .unwrap()
+    }
+
+    // `bin-1` holds a dozen at 0.50 each, so one bin costs 6.00.
+    fn priced_listing(seller: &RadrootsNostrKeys) -> RadrootsNostrEvent {
+        let content = json!({
+            "d_tag": "listing-1",
+            "farm": { "pubkey": seller.public_key().to_hex(), "d_tag": "farm-1" },
+            "product": { "key": "eggs", "title": "Eggs", "category": "eggs" },
+            "primary_bin_id": "bin-1",
+            "bins": [{
+                "bin_id": "bin-1",
+                "quantity": { "amount": "12", "unit": "each" },
+                "price_per_canonical_unit": {
+                    "amount": { "amount": "0.50", "currency": "USD" },
+                    "quantity": { "amount": "1", "unit": "each" }
+                }
+            }]
+        });
+        EventBuilder::new(Kind::Custom(30402), content.to_string())
+            .tag(nostr::Tag::identifier("listing-1"))
+            .sign_with_keys(seller)
+            .unwrap()
+    }
+
+    #[test]
+    fn routed_relays_skip_invalid_urls() {
+        let relays = [
+            "wss://private.example.com".to_string(),
+            "not a url".to_string(),
+        ];
+        let urls = routed_relay_urls(&relays);
+        assert_eq!(
+            urls,
+            [RelayUrl::parse("wss://private.example.com").unwrap()]
+        );
+    }
+
+    #[test]
+    fn order_is_quoted_from_the_listing() {
+        let seller = RadrootsNostrKeys::generate();
+        let listing = priced_listing(&seller);
+        let order = |bin_id: &str| -> TradeOrder {
+            serde_json::from_value(json!({
+                "order_id": "order-1",
+                "listing_addr": format!("30402:{}:listing-1", seller.public_key().to_hex()),
+                "buyer_pubkey": "buyer",
+                "seller_pubkey": seller.public_key().to_hex(),
+                "items": [{ "bin_id": bin_id, "bin_count": 2 }],
+                "total": "0.01",
+            }))
+            .unwrap()
+        };
+
+        let quote = quote_order(&listing, &order("bin-1")).unwrap();
+        assert_eq!(quote.total.to_string(), "12.00");
+        let [line] = quote.items.as_slice() else {
+            panic!("expected one line, got {:?}", quote.items);
+        };
+        assert_eq!(line.tier, "bin bin-1");
+        assert_eq!(line.unit_price.to_string(), "0.50");
+        assert_eq!(line.quantity, 2);
+        assert_eq!(line.subtotal.to_string(), "12.00");
+        assert!(line.discounts.is_empty());
+        assert!(matches!(
+            quote_order(&listing, &order("bin-9")),
+            Err(TradeListingDvmError::Pricing(_))
+        ));
+    }
+
+    #[test]
+    fn invoiced_response_carries_the_exact_msat_amount() {
+        let response: TradeOrderResponse =
+            serde_json::from_value(json!({ "accepted": true })).unwrap();
+        let terms = InvoiceTerms::new(21_500, Some("lnbc1".into()));
+        let value = serde_json::to_value(InvoicedResponse {
+            response: &response,
+            invoice: Some(&terms),
+        })
+        .unwrap();
+        assert_eq!(value["accepted"], json!(true));
+        assert_eq!(value["amount_msat"], json!(21_500));
+        assert_eq!(value["amount_sat"], json!(22));
+        assert_eq!(value["bolt11"], json!("lnbc1"));
+    }
+
+    #[test]
+    fn order_events_are_built_without_a_relay() {
+        let rhi = RadrootsNostrKeys::generate();
+        let buyer = RadrootsNostrKeys::generate();
+        let seller = RadrootsNostrKeys::generate();
+        let ctx = chain_context(&rhi, &Transitions::default());
+        let listing_addr = format!("30402:{}:listing-1", seller.public_key().to_hex());
+        let seller_hex = seller.public_key().to_hex();
+        let order: TradeOrder = serde_json::from_value(json!({
+            "order_id": "order-1",
+            "listing_addr": listing_addr,
+            "buyer_pubkey": buyer.public_key().to_hex(),
+            "seller_pubkey": seller_hex,
+            "items": [{ "bin_id": "bin-1", "bin_count": 2 }],
+        }))
+        .unwrap();
+        let quote = quote_order(&priced_listing(&seller), &order).unwrap();
+        let payload = serde_json::to_value(&order).unwrap();
+        let request = chain_request(
+            &buyer,
+            &rhi,
+            TradeListingMessageType::OrderRequest,
+            &listing_addr,
+            payload,
+        );
+
+        let quoted = QuotedOrder {
+            order: &order,
+            quote: &quote,
+        };
+        let relayed = order_request_event(
+            &ctx,
+            &request,
+            seller_hex.clone(),
+            &listing_addr,
+            "order-1",
+            quoted,
+            "hash-1".into(),
+        )
+        .unwrap()
+        .build(rhi.public_key());
+        let tags: Vec<Vec<String>> = relayed.tags.iter().map(|t| t.as_slice().to_vec()).collect();
+        assert_eq!(
+            relayed.kind,
+            Kind::Custom(TradeListingMessageType::OrderRequest.kind())
+        );
+        assert!(tag_has_value(&tags, "p", &seller_hex));
+        assert!(tag_has_value(&tags, "a", &listing_addr));
+        assert!(tag_has_value(&tags, "d", "order-1"));
+        assert!(tag_has_value(&tags, CONFIRMATION_TAG, "hash-1"));
+        assert!(tag_has_value(&tags, TAG_E_ROOT, &request.id.to_string()));
+        assert!(tag_has_value(&tags, TAG_E_PREV, &request.id.to_string()));
+        let envelope = decode_envelope(&relayed.content).unwrap();
+        assert_eq!(envelope.payload["quote"]["total"], json!("12.00"));
+        assert_eq!(envelope.payload["order_id"], json!("order-1"));
+
+        let accepted: TradeOrderResponse =
+            serde_json::from_value(json!({ "accepted": true })).unwrap();
+        let terms = InvoiceTerms::new(21_000, Some("lnbc1".into()));
+        let response = InvoicedResponse {
+            response: &accepted,
+            invoice: Some(&terms),
+        };
+        let buyer_hex = buyer.public_key().to_hex();
+        let relayed = order_response_event(
+            &ctx,
+            &request,
+            buyer_hex.clone(),
+            &listing_addr,
+            "order-1",
+            Some("root-1"),
+            response,
+            None,
+        )
+        .unwrap()
+        .build(rhi.public_key());
+        let tags: Vec<Vec<String>> = relayed.tags.iter().map(|t| t.as_slice().to_vec()).collect();
+        assert!(tag_has_value(&tags, "p", &buyer_hex));
+        assert!(tag_has_value(&tags, TAG_E_ROOT, "root-1"));
+        assert!(tag_has_value(&tags, TAG_E_PREV, &request.id.to_string()));
+        assert!(tags.contains(&vec!["amount".into(), "21000".into(), "lnbc1".into()]));
+        assert!(!tags.iter().any(|tag| tag[0] == CONFIRMATION_TAG));
+    }
 
     #[test]
     fn transition_rejects_accept_after_decline() {
@@ -1113,9 +2733,725 @@ mod tests {
         assert!(err.is_err());
     }
 
+    #[test]
+    fn rejected_transition_increments_metric() {
+        let from = TradeOrderStatus::Completed;
+        let to = TradeOrderStatus::Requested;
+        let before = metrics::snapshot().invalid_transition_count(&from, &to);
+
+        assert!(ensure_transition(from.clone(), to.clone()).is_err());
+
+        let after = metrics::snapshot().invalid_transition_count(&from, &to);
+        assert_eq!(after, before + 1);
+    }
+
     #[test]
     fn transition_allows_revision_after_request() {
         let ok = ensure_transition(TradeOrderStatus::Requested, TradeOrderStatus::Revised);
         assert!(ok.is_ok());
     }
+
+    #[test]
+    fn discount_decline_keeps_the_current_status() {
+        let decline = TradeListingMessageType::DiscountDecline;
+        let accept = TradeListingMessageType::DiscountAccept;
+        for status in [
+            TradeOrderStatus::Requested,
+            TradeOrderStatus::Questioned,
+            TradeOrderStatus::Revised,
+        ] {
+            assert_eq!(discount_decision_status(decline, &status), status);
+            assert_eq!(
+                discount_decision_status(accept, &status),
+                TradeOrderStatus::Accepted
+            );
+        }
+    }
+
+    #[test]
+    fn transition_hook_sees_changed_statuses_only() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let hook: TransitionHook = {
+            let calls = Arc::clone(&calls);
+            Arc::new(move |order: &TradeOrderState, from, to| {
+                calls
+                    .lock()
+                    .unwrap()
+                    .push((order.order_id.clone(), from, to));
+            })
+        };
+        let mut order = order_state();
+        order.status = TradeOrderStatus::Accepted;
+
+        notify_transition(Some(&hook), &order, &TradeOrderStatus::Requested);
+        notify_transition(Some(&hook), &order, &TradeOrderStatus::Accepted);
+        notify_transition(None, &order, &TradeOrderStatus::Requested);
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![(
+                order.order_id.clone(),
+                TradeOrderStatus::Requested,
+                TradeOrderStatus::Accepted
+            )]
+        );
+    }
+
+    #[test]
+    fn cancel_confirmation_targets_cancelling_party() {
+        let rhi = RadrootsNostrKeys::generate();
+        let buyer = RadrootsNostrKeys::generate();
+        let cancel = radroots_nostr_build_event(
+            TradeListingMessageType::Cancel.kind() as u32,
+            String::new(),
+            vec![vec!["p".to_string(), rhi.public_key().to_string()]],
+        )
+        .unwrap()
+        .sign_with_keys(&buyer)
+        .unwrap();
+
+        let confirmation = cancel_confirmation(&cancel, "order-1")
+            .unwrap()
+            .build(rhi.public_key());
+        let tags: Vec<Vec<String>> = confirmation
+            .tags
+            .iter()
+            .map(|t| t.as_slice().to_vec())
+            .collect();
+        assert!(tag_has_value(&tags, "p", &buyer.public_key().to_string()));
+        assert!(tag_has_value(&tags, "e", &cancel.id.to_string()));
+    }
+
+    #[test]
+    fn idempotent_repeat_tells_the_buyer_about_the_existing_order() {
+        let rhi = RadrootsNostrKeys::generate();
+        let buyer = RadrootsNostrKeys::generate();
+        let retry = chain_request(
+            &buyer,
+            &rhi,
+            TradeListingMessageType::OrderRequest,
+            "30402:seller:listing",
+            json!({}),
+        );
+
+        let feedback =
+            idempotent_repeat_feedback(&retry, "order-0", Some(&TradeOrderStatus::Accepted))
+                .unwrap()
+                .build(rhi.public_key());
+        let tags: Vec<Vec<String>> = feedback
+            .tags
+            .iter()
+            .map(|t| t.as_slice().to_vec())
+            .collect();
+        assert!(tag_has_value(&tags, "p", &buyer.public_key().to_string()));
+        assert!(tag_has_value(&tags, "e", &retry.id.to_string()));
+        assert!(tag_has_value(&tags, "d", "order-0"));
+        let content: serde_json::Value = serde_json::from_str(&feedback.content).unwrap();
+        assert_eq!(content["order_id"], "order-0");
+        assert_eq!(content["status"], json!(TradeOrderStatus::Accepted));
+    }
+
+    #[test]
+    fn payment_required_feedback_carries_amount_and_invoice() {
+        let rhi = RadrootsNostrKeys::generate();
+        let buyer = RadrootsNostrKeys::generate();
+        let request = radroots_nostr_build_event(
+            TradeListingMessageType::OrderRequest.kind() as u32,
+            String::new(),
+            vec![vec!["p".to_string(), rhi.public_key().to_string()]],
+        )
+        .unwrap()
+        .sign_with_keys(&buyer)
+        .unwrap();
+
+        let feedback = payment_required_feedback(&request, 21_000, Some("lnbc1"))
+            .unwrap()
+            .build(rhi.public_key());
+        let tags: Vec<Vec<String>> = feedback
+            .tags
+            .iter()
+            .map(|t| t.as_slice().to_vec())
+            .collect();
+        assert!(tag_has_value(&tags, "status", "payment-required"));
+        assert!(tags.contains(&vec!["amount".into(), "21000".into(), "lnbc1".into()]));
+        assert!(tag_has_value(&tags, "e", &request.id.to_string()));
+    }
+
+    // Issues invoices whose preimage is 32 zero bytes.
+    struct ZeroPreimageInvoices;
+
+    impl InvoiceIssuer for ZeroPreimageInvoices {
+        fn issue<'a>(
+            &'a self,
+            _: u64,
+            _: &'a str,
+        ) -> BoxFuture<'a, Result<IssuedInvoice, InvoiceBackendError>> {
+            Box::pin(async {
+                Ok(IssuedInvoice {
+                    bolt11: "lnbc1".into(),
+                    payment_hash:
+                        "66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925".into(),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn gated_requests_need_the_preimage_of_their_invoice() {
+        let rhi = RadrootsNostrKeys::generate();
+        let buyer = RadrootsNostrKeys::generate();
+        let mut ctx = chain_context(&rhi, &Transitions::default());
+        let mut trade = TradeConfig::default();
+        trade.require_payment_for = vec![TradeStage::Order];
+        let payment = json!({ "amount_msat": 21000, "invoice_url": "http://127.0.0.1:3000" });
+        trade.payment = Some(serde_json::from_value(payment).unwrap());
+        ctx.config = Arc::new(trade);
+        ctx.invoices = Some(Arc::new(ZeroPreimageInvoices));
+
+        let listing_addr = format!("30402:{}:listing-1", buyer.public_key().to_hex());
+        let request = |preimage: Option<String>| {
+            let mut builder = envelope_event(
+                rhi.public_key().to_string(),
+                None,
+                TradeListingMessageType::OrderRequest,
+                &listing_addr,
+                Some("order-1"),
+                &json!({}),
+                None,
+            )
+            .unwrap();
+            if let Some(preimage) = preimage {
+                builder = builder.tag(Tag::custom(TagKind::custom(PAYMENT_TAG), [preimage]));
+            }
+            let event = builder.sign_with_keys(&buyer).unwrap();
+            let tags = event.tags.iter().cloned().collect();
+            parse_trade_listing_event(event, tags, &rhi)
+                .unwrap()
+                .unwrap()
+        };
+
+        let feedback = required_payment(&ctx, &request(None), 0)
+            .await
+            .unwrap()
+            .unwrap();
+        let feedback = feedback.build(rhi.public_key());
+        let tags: Vec<Vec<String>> = feedback
+            .tags
+            .iter()
+            .map(|t| t.as_slice().to_vec())
+            .collect();
+        assert!(tags.contains(&vec!["amount".into(), "21000".into(), "lnbc1".into()]));
+
+        let forged = request(Some("11".repeat(32)));
+        assert!(matches!(
+            required_payment(&ctx, &forged, 0).await,
+            Err(TradeListingDvmError::PaymentNotVerified)
+        ));
+        let paid = request(Some("00".repeat(32)));
+        assert!(required_payment(&ctx, &paid, 0).await.unwrap().is_none());
+        assert!(matches!(
+            required_payment(&ctx, &paid, 0).await,
+            Err(TradeListingDvmError::PaymentNotVerified)
+        ));
+
+        // A preimage sent in encrypted tags only shows up among the resolved tags.
+        required_payment(&ctx, &request(None), 0)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut encrypted = request(None);
+        encrypted
+            .tags
+            .push(Tag::custom(TagKind::custom(PAYMENT_TAG), ["00".repeat(32)]));
+        assert!(
+            required_payment(&ctx, &encrypted, 0)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn nip17_replies_are_gift_wrapped_for_the_requester() {
+        let rhi = RadrootsNostrKeys::generate();
+        let buyer = RadrootsNostrKeys::generate();
+        let feedback = || {
+            EventBuilder::new(Kind::JobFeedback, "payment required")
+                .tag(Tag::public_key(buyer.public_key()))
+        };
+        let limits = NostrPayloadLimits {
+            max_content_bytes: 65536,
+            max_decrypted_bytes: 65536,
+        };
+
+        let plain = reply_event(EncryptionMode::Nip04, &rhi, feedback())
+            .await
+            .unwrap();
+        assert_eq!(plain.kind, Kind::JobFeedback);
+
+        let wrap = reply_event(EncryptionMode::Nip17, &rhi, feedback())
+            .await
+            .unwrap();
+        assert_eq!(wrap.kind, Kind::GiftWrap);
+        let inner = nostr_unwrap_gift_wrap(&wrap, &buyer, limits).unwrap();
+        assert_eq!(inner.pubkey, rhi.public_key());
+        assert_eq!(inner.kind, Kind::JobFeedback);
+        assert_eq!(inner.content, "payment required");
+    }
+
+    #[test]
+    fn recipient_must_be_the_only_p_tag() {
+        let p = |pubkey: &str| vec!["p".to_string(), pubkey.to_string()];
+        assert!(ensure_sole_recipient(&[p("rhi")], "rhi").is_ok());
+        assert!(ensure_sole_recipient(&[p("rhi"), p("rhi")], "rhi").is_ok());
+        assert!(matches!(
+            ensure_sole_recipient(&[p("other")], "rhi"),
+            Err(TradeListingDvmError::MissingRecipient)
+        ));
+        assert!(matches!(
+            ensure_sole_recipient(&[p("rhi"), p("other")], "rhi"),
+            Err(TradeListingDvmError::NotRecipient)
+        ));
+    }
+
+    #[test]
+    fn listing_kind_mismatch_between_tag_and_envelope_is_rejected() {
+        let seller = "a".repeat(64);
+        let listing = format!("30402:{seller}:listing");
+        assert!(ensure_same_listing(&listing, &listing, "a").is_ok());
+        assert!(matches!(
+            ensure_same_listing(&listing, &format!("30403:{seller}:listing"), "a"),
+            Err(TradeListingDvmError::TagMismatch("listing kind"))
+        ));
+        assert!(matches!(
+            ensure_same_listing(&listing, &format!("30402:{seller}:other"), "listing_addr"),
+            Err(TradeListingDvmError::TagMismatch("listing_addr"))
+        ));
+    }
+
+    #[test]
+    fn fetched_listing_must_match_address_kind_and_identifier() {
+        let seller = RadrootsNostrKeys::generate();
+        let addr = parse_listing_addr(&format!("30402:{}:listing-1", seller.public_key())).unwrap();
+        let listing = |kind: u32, d: &str| {
+            radroots_nostr_build_event(kind, String::new(), vec![vec!["d".into(), d.into()]])
+                .unwrap()
+                .sign_with_keys(&seller)
+                .unwrap()
+        };
+
+        assert!(ensure_listing_coordinate(&listing(30402, "listing-1"), &addr).is_ok());
+        assert!(matches!(
+            ensure_listing_coordinate(&listing(30403, "listing-1"), &addr),
+            Err(TradeListingDvmError::TagMismatch("listing kind"))
+        ));
+        assert!(matches!(
+            ensure_listing_coordinate(&listing(30402, "listing-2"), &addr),
+            Err(TradeListingDvmError::TagMismatch("listing d"))
+        ));
+    }
+
+    #[test]
+    fn validate_stage_reports_address_mismatches_as_issues() {
+        let seller = RadrootsNostrKeys::generate();
+        let impostor = RadrootsNostrKeys::generate();
+        let addr = parse_listing_addr(&format!("30402:{}:listing-1", seller.public_key())).unwrap();
+        let listing = |keys: &RadrootsNostrKeys, d: &str| {
+            radroots_nostr_build_event(30402, String::new(), vec![vec!["d".into(), d.into()]])
+                .unwrap()
+                .sign_with_keys(keys)
+                .unwrap()
+        };
+
+        assert_eq!(
+            listing_address_error(&listing(&seller, "listing-1"), &addr, false),
+            None
+        );
+        assert_eq!(
+            listing_address_error(&listing(&seller, "listing-1"), &addr, true),
+            Some(ListingAddressError::Deleted)
+        );
+        assert_eq!(
+            listing_address_error(&listing(&seller, "listing-2"), &addr, false),
+            Some(ListingAddressError::CoordinateMismatch)
+        );
+        assert_eq!(
+            listing_address_error(&listing(&impostor, "listing-1"), &addr, false),
+            Some(ListingAddressError::AuthorMismatch)
+        );
+    }
+
+    #[test]
+    fn listing_must_be_authored_by_claimed_seller() {
+        let seller = RadrootsNostrKeys::generate();
+        let impostor = RadrootsNostrKeys::generate();
+        let listing = radroots_nostr_build_event(30402, String::new(), Vec::new())
+            .unwrap()
+            .sign_with_keys(&impostor)
+            .unwrap();
+
+        let author = impostor.public_key().to_string();
+        assert_eq!(ensure_listing_author(&listing, &author).unwrap(), author);
+        assert!(matches!(
+            ensure_listing_author(&listing, &seller.public_key().to_string()),
+            Err(TradeListingDvmError::Unauthorized)
+        ));
+    }
+
+    fn order_state() -> TradeOrderState {
+        TradeOrderState::new(
+            "order-1",
+            "30402:seller:listing",
+            "buyer".into(),
+            "seller".into(),
+            TradeOrderStatus::Requested,
+            0,
+        )
+    }
+
+    #[test]
+    fn order_decision_waits_for_answer_to_pending_question() {
+        let mut order = order_state();
+
+        assert!(ensure_order_transition(&order, TradeOrderStatus::Questioned).is_ok());
+        order.ask_question(0);
+        assert!(ensure_order_transition(&order, TradeOrderStatus::Accepted).is_err());
+
+        order.answer_question().unwrap();
+        assert_eq!(order.status, TradeOrderStatus::Questioned);
+        assert!(ensure_order_transition(&order, TradeOrderStatus::Accepted).is_ok());
+        assert!(ensure_order_transition(&order, TradeOrderStatus::Declined).is_ok());
+    }
+
+    #[test]
+    fn reply_expiration_is_opt_in() {
+        let keys = RadrootsNostrKeys::generate();
+        let expiration = |secs| {
+            let builder = radroots_nostr_build_event(5321, String::new(), Vec::new()).unwrap();
+            with_expiration(builder, secs, 1_700_000_000)
+                .build(keys.public_key())
+                .tags
+                .iter()
+                .map(|t| t.as_slice().to_vec())
+                .find(|t| t.first().map(String::as_str) == Some("expiration"))
+        };
+        assert_eq!(expiration(None), None);
+        assert_eq!(
+            expiration(Some(3600)),
+            Some(vec!["expiration".to_string(), "1700003600".to_string()])
+        );
+    }
+
+    #[test]
+    fn trade_root_prefers_marked_root_e_tag() {
+        let tag = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let tags = vec![
+            tag(&["e", "reply-id", "", "reply"]),
+            tag(&["e", "root-id", "", "root"]),
+        ];
+        assert_eq!(trade_root(&tags).as_deref(), Some("root-id"));
+        assert_eq!(trade_root(&tags[..1]).as_deref(), Some("reply-id"));
+        assert_eq!(trade_root(&[tag(&["p", "pubkey"])]), None);
+    }
+
+    fn envelope_p_tags(sender: Option<String>, recipient: String) -> Vec<String> {
+        let rhi = RadrootsNostrKeys::generate();
+        let event = envelope_event(
+            recipient,
+            sender,
+            TradeListingMessageType::OrderRequest,
+            "30402:seller:listing",
+            Some("order-1"),
+            &serde_json::json!({}),
+            None,
+        )
+        .unwrap()
+        .build(rhi.public_key());
+        event
+            .tags
+            .iter()
+            .map(|t| t.as_slice().to_vec())
+            .filter(|t| t.first().map(String::as_str) == Some("p"))
+            .filter_map(|t| t.get(1).cloned())
+            .collect()
+    }
+
+    #[test]
+    fn relayed_envelope_tags_both_parties_when_enabled() {
+        let buyer = RadrootsNostrKeys::generate().public_key().to_string();
+        let seller = RadrootsNostrKeys::generate().public_key().to_string();
+
+        let p_tags = envelope_p_tags(Some(buyer.clone()), seller.clone());
+        assert_eq!(p_tags.len(), 2);
+        assert!(p_tags.contains(&seller));
+        assert!(p_tags.contains(&buyer));
+    }
+
+    #[test]
+    fn relayed_envelope_tags_only_recipient_when_disabled() {
+        let seller = RadrootsNostrKeys::generate().public_key().to_string();
+
+        assert_eq!(envelope_p_tags(None, seller.clone()), vec![seller.clone()]);
+        assert_eq!(
+            envelope_p_tags(Some(seller.clone()), seller.clone()),
+            vec![seller]
+        );
+    }
+
+    #[test]
+    fn fulfillment_update_accepts_plausible_tracking_and_eta() {
+        let now = 1_700_000_000;
+        assert!(validate_fulfillment_update(None, None, now).is_ok());
+        assert!(
+            validate_fulfillment_update(Some("1Z999AA10123456784"), Some(now + 86_400), now)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn fulfillment_update_rejects_bad_tracking() {
+        let now = 1_700_000_000;
+        let too_long = "x".repeat(MAX_TRACKING_LEN + 1);
+        for tracking in ["", "   ", too_long.as_str()] {
+            assert!(matches!(
+                validate_fulfillment_update(Some(tracking), None, now),
+                Err(TradeListingDvmError::InvalidFulfillmentUpdate(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn fulfillment_update_rejects_implausible_eta() {
+        let now = 1_700_000_000;
+        for eta in [now - 1, now + MAX_ETA_HORIZON_SECS + 1] {
+            assert!(matches!(
+                validate_fulfillment_update(None, Some(eta), now),
+                Err(TradeListingDvmError::InvalidFulfillmentUpdate(_))
+            ));
+        }
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct ProbePayload {
+        order_id: String,
+    }
+
+    #[test]
+    fn lenient_payload_ignores_unknown_fields() {
+        let value = serde_json::json!({ "order_id": "order-1", "future_field": true });
+        let payload: ProbePayload = parse_payload(value, PayloadMode::Lenient).unwrap();
+        assert_eq!(payload.order_id, "order-1");
+    }
+
+    #[test]
+    fn strict_payload_rejects_unknown_fields() {
+        let value = serde_json::json!({ "order_id": "order-1", "future_field": true });
+        let err = parse_payload::<ProbePayload>(value, PayloadMode::Strict).unwrap_err();
+        assert!(
+            matches!(err, TradeListingDvmError::InvalidPayload(msg) if msg.contains("future_field"))
+        );
+
+        let value = serde_json::json!({ "order_id": "order-1" });
+        assert!(parse_payload::<ProbePayload>(value, PayloadMode::Strict).is_ok());
+    }
+
+    #[test]
+    fn idempotency_key_is_taken_before_strict_parsing() {
+        let mut value = serde_json::json!({
+            "order_id": "order-1",
+            "idempotency_key": "k-1",
+            "order_nonce": "n-1",
+        });
+        let key = take_payload_field(&mut value, IDEMPOTENCY_KEY_FIELD);
+        let nonce = take_payload_field(&mut value, ORDER_NONCE_FIELD);
+        assert_eq!(key.as_deref(), Some("k-1"));
+        assert_eq!(nonce.as_deref(), Some("n-1"));
+        assert!(parse_payload::<ProbePayload>(value, PayloadMode::Strict).is_ok());
+
+        let mut value = serde_json::json!({ "order_id": "order-1", "idempotency_key": "" });
+        assert_eq!(take_payload_field(&mut value, IDEMPOTENCY_KEY_FIELD), None);
+        let mut empty = serde_json::json!({});
+        assert_eq!(take_payload_field(&mut empty, IDEMPOTENCY_KEY_FIELD), None);
+    }
+
+    #[test]
+    fn latest_event_picks_the_newest_matching_version() {
+        let seller = RadrootsNostrKeys::generate();
+        let listing = |created_at: u64, kind: u16| {
+            EventBuilder::new(Kind::Custom(kind), format!("v{created_at}"))
+                .custom_created_at(Timestamp::from(created_at))
+                .sign_with_keys(&seller)
+                .unwrap()
+        };
+        let events = vec![
+            listing(200, 30402),
+            listing(300, 30402),
+            listing(100, 30402),
+            listing(400, 1),
+        ];
+
+        let latest = latest_event(events, |ev| ev.kind == Kind::Custom(30402)).unwrap();
+        assert_eq!(latest.content, "v300");
+        assert!(latest_event(Vec::new(), |_| true).is_none());
+    }
+
+    #[test]
+    fn same_timestamp_versions_resolve_to_the_lowest_id() {
+        let seller = RadrootsNostrKeys::generate();
+        let version = |content: &str| {
+            EventBuilder::new(Kind::Custom(30402), content)
+                .custom_created_at(Timestamp::from(1_700_000_000))
+                .sign_with_keys(&seller)
+                .unwrap()
+        };
+        let a = version("a");
+        let b = version("b");
+        let expected = a.id.min(b.id);
+
+        let forward = latest_event(vec![a.clone(), b.clone()], |_| true).unwrap();
+        let backward = latest_event(vec![b, a], |_| true).unwrap();
+        assert_eq!(forward.id, expected);
+        assert_eq!(backward.id, expected);
+    }
+
+    #[test]
+    fn completion_reaction_tags_root_and_buyer() {
+        let rhi = RadrootsNostrKeys::generate();
+        let buyer = RadrootsNostrKeys::generate().public_key().to_string();
+        let root = "a".repeat(64);
+
+        let reaction = completion_reaction(&root, &buyer)
+            .unwrap()
+            .build(rhi.public_key());
+        let tags: Vec<Vec<String>> = reaction
+            .tags
+            .iter()
+            .map(|t| t.as_slice().to_vec())
+            .collect();
+        assert_eq!(reaction.kind.as_u16(), 7);
+        assert!(tag_has_value(&tags, "e", &root));
+        assert!(tag_has_value(&tags, "p", &buyer));
+    }
+
+    #[test]
+    fn results_are_signed_with_result_key() {
+        let result_keys = RadrootsNostrKeys::generate();
+        let subscription_keys = RadrootsNostrKeys::generate();
+        let builder = envelope_event(
+            subscription_keys.public_key().to_string(),
+            None,
+            TradeListingMessageType::OrderResponse,
+            "30402:seller:listing",
+            Some("order-1"),
+            &serde_json::json!({}),
+            None,
+        )
+        .unwrap();
+
+        let event = sign_result(&result_keys, builder).unwrap();
+        assert_eq!(event.pubkey, result_keys.public_key());
+        assert_ne!(event.pubkey, subscription_keys.public_key());
+        assert!(event.verify().is_ok());
+    }
+
+    #[test]
+    fn encrypted_replies_decrypt_for_the_recipient_only() {
+        let result_keys = RadrootsNostrKeys::generate();
+        let buyer = RadrootsNostrKeys::generate();
+        let builder = envelope_event(
+            buyer.public_key().to_string(),
+            None,
+            TradeListingMessageType::FulfillmentUpdate,
+            "30402:seller:listing",
+            Some("order-1"),
+            &serde_json::json!({ "address": "1 Farm Rd" }),
+            Some(&result_keys),
+        )
+        .unwrap();
+        let event = sign_result(&result_keys, builder).unwrap();
+
+        assert!(
+            event
+                .tags
+                .iter()
+                .any(|t| t.as_slice() == ["encrypted".to_string()])
+        );
+        assert!(!event.content.contains("1 Farm Rd"));
+        let cleartext = nip04::decrypt(buyer.secret_key(), &event.pubkey, &event.content).unwrap();
+        assert!(cleartext.contains("1 Farm Rd"));
+        let stranger = RadrootsNostrKeys::generate();
+        let guess = nip04::decrypt(stranger.secret_key(), &event.pubkey, &event.content).ok();
+        assert_ne!(guess, Some(cleartext));
+    }
+
+    #[test]
+    fn listing_addr_rejects_unparseable_address() {
+        assert!(matches!(
+            parse_listing_addr("not-an-address"),
+            Err(TradeListingDvmError::InvalidListingAddr)
+        ));
+    }
+
+    #[test]
+    fn listing_addr_rejects_non_replaceable_kind() {
+        let seller = RadrootsNostrKeys::generate().public_key().to_string();
+        assert!(matches!(
+            parse_listing_addr(&format!("1:{seller}:listing")),
+            Err(TradeListingDvmError::NonReplaceableListingKind(1))
+        ));
+        assert!(matches!(
+            parse_listing_addr(&format!("30023:{seller}:listing")),
+            Err(TradeListingDvmError::UnsupportedListingKind(30023))
+        ));
+    }
+
+    #[test]
+    fn listing_addr_accepts_trade_listing_kind() {
+        let seller = RadrootsNostrKeys::generate().public_key().to_string();
+        let addr = parse_listing_addr(&format!("30402:{seller}:listing")).unwrap();
+        assert_eq!(addr.kind, 30402);
+        assert_eq!(addr.seller_pubkey, seller);
+    }
+
+    #[test]
+    fn listing_addr_accepts_naddr() {
+        let seller = RadrootsNostrKeys::generate().public_key();
+        let coordinate = Coordinate::new(Kind::from(30402), seller).identifier("listing");
+        let naddr = Nip19Coordinate::new(coordinate, Vec::<RelayUrl>::new())
+            .to_bech32()
+            .unwrap();
+
+        let addr = parse_listing_addr(&naddr).unwrap();
+        assert_eq!(addr.kind, 30402);
+        assert_eq!(addr.seller_pubkey, seller.to_hex());
+        assert_eq!(addr.listing_id, "listing");
+    }
+
+    #[test]
+    fn listing_addr_accepts_npub_author() {
+        let seller = RadrootsNostrKeys::generate().public_key();
+        let npub = seller.to_bech32().unwrap();
+        assert_eq!(
+            normalize_listing_addr(&format!("30402:{npub}:listing")).unwrap(),
+            format!("30402:{}:listing", seller.to_hex())
+        );
+        assert_eq!(
+            parse_listing_addr(&format!("30402:{npub}:listing"))
+                .unwrap()
+                .seller_pubkey,
+            seller.to_hex()
+        );
+    }
+
+    #[test]
+    fn listing_addr_rejects_malformed_bech32() {
+        assert!(matches!(
+            parse_listing_addr("naddr1invalid"),
+            Err(TradeListingDvmError::InvalidListingAddr)
+        ));
+        assert!(matches!(
+            parse_listing_addr("30402:npub1invalid:listing"),
+            Err(TradeListingDvmError::InvalidListingAddr)
+        ));
+    }
 }
@@ -1 +1,2 @@
 pub mod dvm;
+pub mod registry;
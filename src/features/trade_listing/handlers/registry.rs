@@ -0,0 +1,118 @@
+#![forbid(unsafe_code)]
+
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use radroots_trade::listing::dvm::TradeListingMessageType;
+
+use crate::features::trade_listing::handlers::dvm::{
+    TradeListingContext, TradeListingDvmError, TradeListingRequest, register_default_handlers,
+};
+
+pub type TradeListingHandlerFuture = BoxFuture<'static, Result<(), TradeListingDvmError>>;
+
+pub type TradeListingHandler = Arc<
+    dyn Fn(TradeListingContext, TradeListingRequest) -> TradeListingHandlerFuture + Send + Sync,
+>;
+
+pub struct HandlerRegistry {
+    handlers: Vec<(TradeListingMessageType, TradeListingHandler)>,
+}
+
+impl HandlerRegistry {
+    pub fn empty() -> Self {
+        Self {
+            handlers: Vec::new(),
+        }
+    }
+
+    pub fn register<F>(&mut self, message_type: TradeListingMessageType, handler: F) -> &mut Self
+    where
+        F: Fn(TradeListingContext, TradeListingRequest) -> TradeListingHandlerFuture
+            + Send
+            + Sync
+            + 'static,
+    {
+        let handler: TradeListingHandler = Arc::new(handler);
+        match self.handlers.iter_mut().find(|(t, _)| *t == message_type) {
+            Some(entry) => entry.1 = handler,
+            None => self.handlers.push((message_type, handler)),
+        }
+        self
+    }
+
+    pub fn remove(
+        &mut self,
+        message_type: &TradeListingMessageType,
+    ) -> Option<TradeListingHandler> {
+        let index = self.handlers.iter().position(|(t, _)| t == message_type)?;
+        Some(self.handlers.remove(index).1)
+    }
+
+    pub fn get(&self, message_type: &TradeListingMessageType) -> Option<&TradeListingHandler> {
+        self.handlers
+            .iter()
+            .find(|(t, _)| t == message_type)
+            .map(|(_, handler)| handler)
+    }
+
+    pub fn contains(&self, message_type: &TradeListingMessageType) -> bool {
+        self.get(message_type).is_some()
+    }
+
+    pub async fn dispatch(
+        &self,
+        ctx: TradeListingContext,
+        request: TradeListingRequest,
+    ) -> Result<(), TradeListingDvmError> {
+        match self.get(&request.envelope.message_type) {
+            Some(handler) => handler(ctx, request).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for HandlerRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        register_default_handlers(&mut registry);
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HandlerRegistry;
+    use radroots_trade::listing::dvm::TradeListingMessageType;
+
+    #[test]
+    fn default_registry_wires_request_handlers() {
+        let registry = HandlerRegistry::default();
+        assert!(registry.contains(&TradeListingMessageType::OrderRequest));
+        assert!(registry.contains(&TradeListingMessageType::DiscountDecline));
+        assert!(!registry.contains(&TradeListingMessageType::ListingValidateResult));
+    }
+
+    #[test]
+    fn registry_overrides_and_removes_handlers() {
+        let mut registry = HandlerRegistry::default();
+        let default = registry
+            .get(&TradeListingMessageType::DiscountOffer)
+            .cloned()
+            .expect("default discount offer handler");
+        registry.register(TradeListingMessageType::DiscountOffer, |_, _| {
+            Box::pin(async { Ok(()) })
+        });
+        let replaced = registry
+            .get(&TradeListingMessageType::DiscountOffer)
+            .expect("custom discount offer handler");
+        assert!(!std::sync::Arc::ptr_eq(&default, replaced));
+
+        assert!(
+            registry
+                .remove(&TradeListingMessageType::DiscountOffer)
+                .is_some()
+        );
+        assert!(!registry.contains(&TradeListingMessageType::DiscountOffer));
+    }
+}
@@ -0,0 +1,271 @@
+#![forbid(unsafe_code)]
+
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::infra::rates::{RateProvider, to_msat};
+
+pub const INVOICE_BOLT11_FIELD: &str = "bolt11";
+pub const INVOICE_AMOUNT_SAT_FIELD: &str = "amount_sat";
+pub const INVOICE_AMOUNT_MSAT_FIELD: &str = "amount_msat";
+pub const INVOICE_AMOUNT_FIELD: &str = "amount";
+pub const INVOICE_CURRENCY_FIELD: &str = "currency";
+pub const INVOICE_FREE_FIELD: &str = "free";
+
+#[derive(Debug, Error)]
+pub enum InvoiceError {
+    #[error("invalid invoice amount: {0}")]
+    InvalidAmount(String),
+}
+
+// Invoice fields a seller may attach to an accepting order response. They are taken
+// out of the payload before it is parsed, so strict payload mode still applies to the
+// response itself.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SellerInvoice {
+    pub bolt11: Option<String>,
+    pub amount_sat: Option<String>,
+    pub amount_msat: Option<String>,
+    pub amount: Option<String>,
+    pub currency: Option<String>,
+    pub free: bool,
+}
+
+impl SellerInvoice {
+    // Returns `None` when the payload carries no invoice field at all.
+    pub fn take(payload: &mut Value) -> Option<Self> {
+        let fields = payload.as_object_mut()?;
+        let mut take = |field: &str| fields.remove(field).map(scalar);
+        let invoice = Self {
+            bolt11: take(INVOICE_BOLT11_FIELD),
+            amount_sat: take(INVOICE_AMOUNT_SAT_FIELD),
+            amount_msat: take(INVOICE_AMOUNT_MSAT_FIELD),
+            amount: take(INVOICE_AMOUNT_FIELD),
+            currency: take(INVOICE_CURRENCY_FIELD),
+            free: take(INVOICE_FREE_FIELD).is_some_and(|free| free == "true"),
+        };
+        (invoice != Self::default()).then_some(invoice)
+    }
+
+    pub fn amount_msat(&self) -> Result<u64, InvoiceError> {
+        parse_amount_msat(
+            self.amount_sat.as_deref(),
+            self.amount_msat.as_deref(),
+            self.free,
+        )
+    }
+
+    // Fiat-denominated invoices (`amount` + `currency`) are quoted through the rate
+    // provider; without one the seller must send amount_sat or amount_msat.
+    pub async fn terms(
+        self,
+        rates: Option<&dyn RateProvider>,
+    ) -> Result<InvoiceTerms, InvoiceError> {
+        let fiat = self
+            .amount
+            .as_deref()
+            .zip(self.currency.as_deref())
+            .filter(|(_, currency)| !currency.eq_ignore_ascii_case("sat"));
+        let amount_msat = match fiat {
+            Some((amount, currency)) => quote_amount_msat(rates, amount, currency).await?,
+            None => self.amount_msat()?,
+        };
+        Ok(InvoiceTerms::new(amount_msat, self.bolt11))
+    }
+}
+
+// What the buyer is asked to pay. The exact msat amount is authoritative; `amount_sat`
+// is rounded up and only meant for display.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InvoiceTerms {
+    pub amount_msat: u64,
+    pub amount_sat: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bolt11: Option<String>,
+}
+
+impl InvoiceTerms {
+    pub fn new(amount_msat: u64, bolt11: Option<String>) -> Self {
+        Self {
+            amount_msat,
+            amount_sat: display_sat(amount_msat),
+            bolt11,
+        }
+    }
+}
+
+pub fn display_sat(amount_msat: u64) -> u64 {
+    amount_msat.div_ceil(1000)
+}
+
+pub async fn quote_amount_msat(
+    rates: Option<&dyn RateProvider>,
+    amount: &str,
+    currency: &str,
+) -> Result<u64, InvoiceError> {
+    let amount = Decimal::from_str(amount)
+        .map_err(|_| InvoiceError::InvalidAmount(format!("malformed amount {amount:?}")))?;
+    let Some(rates) = rates else {
+        return Err(InvoiceError::InvalidAmount(format!(
+            "{currency} amounts need a rate provider; send amount_sat or amount_msat"
+        )));
+    };
+    let rate = rates
+        .rate(currency, "SAT")
+        .await
+        .map_err(|err| InvoiceError::InvalidAmount(format!("no {currency}/SAT rate: {err}")))?;
+    to_msat(amount, rate)
+        .filter(|msat| *msat > 0)
+        .ok_or_else(|| {
+            InvoiceError::InvalidAmount(format!("{amount} {currency} is not a sat amount"))
+        })
+}
+
+// Numbers are kept in their JSON spelling so malformed amounts are reported as sent.
+fn scalar(value: Value) -> String {
+    match value {
+        Value::String(value) => value,
+        other => other.to_string(),
+    }
+}
+
+// Free listings are flagged by the seller with `free: true`; every other invoice
+// must carry a positive amount.
+pub fn parse_amount_msat(
+    amount_sat: Option<&str>,
+    amount_msat: Option<&str>,
+    allow_free: bool,
+) -> Result<u64, InvoiceError> {
+    let amount = match (amount_sat, amount_msat) {
+        (Some(v), _) => v
+            .parse::<u64>()
+            .ok()
+            .and_then(|sat| sat.checked_mul(1000))
+            .ok_or_else(|| InvoiceError::InvalidAmount(format!("malformed amount_sat {v:?}")))?,
+        (None, Some(v)) => v
+            .parse::<u64>()
+            .map_err(|_| InvoiceError::InvalidAmount(format!("malformed amount_msat {v:?}")))?,
+        (None, None) if allow_free => 0,
+        (None, None) => {
+            return Err(InvoiceError::InvalidAmount(
+                "missing amount_sat or amount_msat".to_string(),
+            ));
+        }
+    };
+    if amount == 0 && !allow_free {
+        return Err(InvoiceError::InvalidAmount(
+            "zero amount on a listing that is not free".to_string(),
+        ));
+    }
+    Ok(amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::BoxFuture;
+    use rust_decimal::Decimal;
+    use serde_json::json;
+
+    use super::{
+        InvoiceError, InvoiceTerms, SellerInvoice, display_sat, parse_amount_msat,
+        quote_amount_msat,
+    };
+    use crate::infra::rates::{RateError, RateProvider};
+
+    struct FixedRate(Decimal);
+
+    impl RateProvider for FixedRate {
+        fn rate<'a>(&'a self, _: &'a str, _: &'a str) -> BoxFuture<'a, Result<Decimal, RateError>> {
+            Box::pin(async move { Ok(self.0) })
+        }
+    }
+
+    fn invalid_amount(result: Result<u64, InvoiceError>) -> String {
+        match result {
+            Err(InvoiceError::InvalidAmount(msg)) => msg,
+            other => panic!("expected InvalidAmount, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn amount_prefers_sat_and_falls_back_to_msat() {
+        assert_eq!(
+            parse_amount_msat(Some("21"), Some("5000"), false).unwrap(),
+            21_000
+        );
+        assert_eq!(
+            parse_amount_msat(None, Some("21500"), false).unwrap(),
+            21_500
+        );
+    }
+
+    #[test]
+    fn missing_amount_is_rejected_unless_free() {
+        assert!(invalid_amount(parse_amount_msat(None, None, false)).contains("missing"));
+        assert!(invalid_amount(parse_amount_msat(Some("0"), None, false)).contains("not free"));
+        assert_eq!(parse_amount_msat(None, None, true).unwrap(), 0);
+        assert_eq!(parse_amount_msat(Some("0"), None, true).unwrap(), 0);
+    }
+
+    #[test]
+    fn malformed_amount_is_rejected() {
+        assert!(invalid_amount(parse_amount_msat(Some("abc"), None, true)).contains("amount_sat"));
+        assert!(invalid_amount(parse_amount_msat(None, Some("-1"), true)).contains("amount_msat"));
+    }
+
+    #[test]
+    fn invoice_fields_are_taken_out_of_the_payload() {
+        let mut payload = json!({ "accepted": true, "bolt11": "lnbc1", "amount_sat": 21 });
+        let invoice = SellerInvoice::take(&mut payload).unwrap();
+        assert_eq!(payload, json!({ "accepted": true }));
+        assert_eq!(invoice.bolt11.as_deref(), Some("lnbc1"));
+        assert_eq!(invoice.amount_msat().unwrap(), 21_000);
+
+        let mut payload = json!({ "accepted": true });
+        assert_eq!(SellerInvoice::take(&mut payload), None);
+    }
+
+    #[tokio::test]
+    async fn sub_sat_amounts_keep_msat_precision() {
+        assert_eq!(display_sat(21_500), 22);
+        assert_eq!(display_sat(21_000), 21);
+
+        let mut payload = json!({ "amount_msat": "21500" });
+        let terms = SellerInvoice::take(&mut payload)
+            .unwrap()
+            .terms(None)
+            .await
+            .unwrap();
+        assert_eq!(terms, InvoiceTerms::new(21_500, None));
+        assert_eq!(
+            serde_json::to_value(&terms).unwrap(),
+            json!({ "amount_msat": 21_500, "amount_sat": 22 })
+        );
+    }
+
+    #[tokio::test]
+    async fn fiat_amounts_are_quoted_through_the_rate_provider() {
+        let rates = FixedRate(Decimal::from(1500));
+        assert_eq!(
+            quote_amount_msat(Some(&rates), "2.50", "USD")
+                .await
+                .unwrap(),
+            3_750_000
+        );
+
+        let err = quote_amount_msat(None, "2.50", "USD").await.unwrap_err();
+        assert!(matches!(err, InvoiceError::InvalidAmount(msg) if msg.contains("rate")));
+        assert!(quote_amount_msat(Some(&rates), "two", "USD").await.is_err());
+
+        let mut payload = json!({ "amount": "2.50", "currency": "USD", "bolt11": "lnbc1" });
+        let invoice = SellerInvoice::take(&mut payload).unwrap();
+        assert_eq!(
+            invoice.terms(Some(&rates)).await.unwrap().amount_msat,
+            3_750_000
+        );
+    }
+}
@@ -0,0 +1,143 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use radroots_nostr::prelude::RadrootsNostrEvent;
+
+use crate::config::ListingCacheConfig;
+use crate::infra::metrics;
+
+struct CachedListing {
+    event: RadrootsNostrEvent,
+    fetched_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct ListingCacheEntries {
+    listings: HashMap<String, CachedListing>,
+    clock: u64,
+}
+
+pub struct ListingCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<ListingCacheEntries>,
+}
+
+impl ListingCache {
+    pub fn new(cfg: &ListingCacheConfig) -> Self {
+        Self {
+            capacity: cfg.capacity,
+            ttl: Duration::from_secs(cfg.ttl_secs),
+            entries: Mutex::new(ListingCacheEntries::default()),
+        }
+    }
+
+    pub fn get(&self, listing_addr: &str) -> Option<RadrootsNostrEvent> {
+        let event = self.lookup(listing_addr, Instant::now());
+        metrics::record_listing_cache_lookup(event.is_some());
+        event
+    }
+
+    pub fn insert(&self, listing_addr: &str, event: RadrootsNostrEvent) {
+        self.insert_at(listing_addr, event, Instant::now());
+    }
+
+    pub fn invalidate(&self, listing_addr: &str) {
+        let mut entries = self.entries.lock().unwrap_or_else(|p| p.into_inner());
+        entries.listings.remove(listing_addr);
+    }
+
+    fn lookup(&self, listing_addr: &str, now: Instant) -> Option<RadrootsNostrEvent> {
+        let mut entries = self.entries.lock().unwrap_or_else(|p| p.into_inner());
+        entries.clock += 1;
+        let clock = entries.clock;
+        let entry = entries.listings.get_mut(listing_addr)?;
+        if now.duration_since(entry.fetched_at) >= self.ttl {
+            entries.listings.remove(listing_addr);
+            return None;
+        }
+        entry.last_used = clock;
+        Some(entry.event.clone())
+    }
+
+    fn insert_at(&self, listing_addr: &str, event: RadrootsNostrEvent, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|p| p.into_inner());
+        entries.clock += 1;
+        let clock = entries.clock;
+        if !entries.listings.contains_key(listing_addr) && entries.listings.len() >= self.capacity {
+            let lru = entries
+                .listings
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(addr, _)| addr.clone());
+            if let Some(lru) = lru {
+                entries.listings.remove(&lru);
+            }
+        }
+        entries.listings.insert(
+            listing_addr.to_string(),
+            CachedListing {
+                event,
+                fetched_at: now,
+                last_used: clock,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ListingCache;
+    use crate::config::ListingCacheConfig;
+    use nostr::{EventBuilder, Kind};
+    use radroots_nostr::prelude::{RadrootsNostrEvent, RadrootsNostrKeys};
+    use std::time::{Duration, Instant};
+
+    fn listing() -> RadrootsNostrEvent {
+        EventBuilder::new(Kind::Custom(30402), "listing")
+            .sign_with_keys(&RadrootsNostrKeys::generate())
+            .unwrap()
+    }
+
+    fn cache(capacity: usize) -> ListingCache {
+        ListingCache::new(&ListingCacheConfig {
+            capacity,
+            ttl_secs: 60,
+        })
+    }
+
+    #[test]
+    fn least_recently_used_listing_is_evicted() {
+        let cache = cache(2);
+        let now = Instant::now();
+        cache.insert_at("a", listing(), now);
+        cache.insert_at("b", listing(), now);
+        assert!(cache.lookup("a", now).is_some());
+
+        cache.insert_at("c", listing(), now);
+        assert!(cache.lookup("a", now).is_some());
+        assert!(cache.lookup("b", now).is_none());
+        assert!(cache.lookup("c", now).is_some());
+    }
+
+    #[test]
+    fn listings_expire_and_can_be_invalidated() {
+        let cache = cache(4);
+        let now = Instant::now();
+        cache.insert_at("a", listing(), now);
+        cache.insert_at("b", listing(), now);
+
+        assert!(cache.lookup("a", now + Duration::from_secs(61)).is_none());
+        cache.invalidate("b");
+        assert!(cache.lookup("b", now).is_none());
+    }
+}
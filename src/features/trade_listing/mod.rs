@@ -1,3 +1,22 @@
+pub mod api;
+pub mod chain;
+pub mod confirmation;
+pub mod dead_letter;
+pub mod domain;
+pub mod envelope;
+pub mod events;
 pub mod handlers;
+pub mod invoice;
+pub mod listing_cache;
+pub mod receipt;
 pub mod state;
+pub mod store;
+pub mod stream;
 pub mod subscriber;
+pub mod validation;
+pub mod watermark;
+pub mod webhook;
+
+pub use stream::{
+    TradeListingEvent, TradeListingEventPhase, TradeListingSubscription, subscribe_stream,
+};
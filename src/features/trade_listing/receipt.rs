@@ -0,0 +1,135 @@
+#![forbid(unsafe_code)]
+
+use nostr::{
+    PublicKey,
+    hashes::{Hash, sha256},
+    secp256k1::{Message, schnorr::Signature},
+    util::SECP256K1,
+};
+use radroots_nostr::prelude::RadrootsNostrKeys;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TradeReceiptError {
+    #[error("invalid receipt signature encoding")]
+    InvalidSignatureEncoding,
+    #[error("invalid receipt signer pubkey")]
+    InvalidPubkey,
+    #[error("receipt signature does not verify")]
+    InvalidSignature,
+    #[error("failed to serialize receipt attestation: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TradeReceiptAttestation {
+    pub order_id: String,
+    pub total: Option<String>,
+    pub at: u64,
+    pub buyer_pubkey: String,
+    pub seller_pubkey: String,
+}
+
+impl TradeReceiptAttestation {
+    pub fn canonical_bytes(&self) -> Result<Vec<u8>, TradeReceiptError> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    fn message(&self) -> Result<Message, TradeReceiptError> {
+        let digest = sha256::Hash::hash(&self.canonical_bytes()?);
+        Ok(Message::from_digest(digest.to_byte_array()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedTradeReceipt<T> {
+    #[serde(flatten)]
+    pub receipt: T,
+    pub attestation: TradeReceiptAttestation,
+    pub signature: String,
+}
+
+pub fn sign_receipt<T>(
+    keys: &RadrootsNostrKeys,
+    receipt: T,
+    attestation: TradeReceiptAttestation,
+) -> Result<SignedTradeReceipt<T>, TradeReceiptError> {
+    let signature = keys.sign_schnorr(&attestation.message()?).to_string();
+    Ok(SignedTradeReceipt {
+        receipt,
+        attestation,
+        signature,
+    })
+}
+
+pub fn verify_receipt<T>(
+    result: &SignedTradeReceipt<T>,
+    pubkey: &PublicKey,
+) -> Result<(), TradeReceiptError> {
+    let signature: Signature = result
+        .signature
+        .parse()
+        .map_err(|_| TradeReceiptError::InvalidSignatureEncoding)?;
+    let xonly = pubkey
+        .xonly()
+        .map_err(|_| TradeReceiptError::InvalidPubkey)?;
+    SECP256K1
+        .verify_schnorr(&signature, &result.attestation.message()?, &xonly)
+        .map_err(|_| TradeReceiptError::InvalidSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TradeReceiptAttestation, TradeReceiptError, sign_receipt, verify_receipt};
+    use radroots_nostr::prelude::RadrootsNostrKeys;
+    use serde_json::json;
+
+    fn attestation() -> TradeReceiptAttestation {
+        TradeReceiptAttestation {
+            order_id: "order-1".into(),
+            total: Some("12.50".into()),
+            at: 1_700_000_000,
+            buyer_pubkey: "buyer".into(),
+            seller_pubkey: "seller".into(),
+        }
+    }
+
+    #[test]
+    fn canonical_bytes_are_stable() {
+        assert_eq!(
+            String::from_utf8(attestation().canonical_bytes().unwrap()).unwrap(),
+            r#"{"order_id":"order-1","total":"12.50","at":1700000000,"buyer_pubkey":"buyer","seller_pubkey":"seller"}"#
+        );
+    }
+
+    #[test]
+    fn signed_receipt_verifies_against_daemon_key() {
+        let keys = RadrootsNostrKeys::generate();
+        let signed = sign_receipt(&keys, json!({ "note": "thanks" }), attestation()).unwrap();
+
+        assert!(verify_receipt(&signed, &keys.public_key()).is_ok());
+
+        let encoded = serde_json::to_value(&signed).unwrap();
+        assert_eq!(encoded["note"], "thanks");
+        assert_eq!(encoded["attestation"]["order_id"], "order-1");
+    }
+
+    #[test]
+    fn tampered_or_foreign_receipts_are_rejected() {
+        let keys = RadrootsNostrKeys::generate();
+        let mut signed = sign_receipt(&keys, json!({}), attestation()).unwrap();
+
+        let other = RadrootsNostrKeys::generate();
+        assert!(matches!(
+            verify_receipt(&signed, &other.public_key()),
+            Err(TradeReceiptError::InvalidSignature)
+        ));
+
+        signed.attestation.total = Some("0.01".into());
+        assert!(matches!(
+            verify_receipt(&signed, &keys.public_key()),
+            Err(TradeReceiptError::InvalidSignature)
+        ));
+    }
+}
@@ -1,22 +1,294 @@
 #![forbid(unsafe_code)]
 
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet, VecDeque, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 
+use nostr::PublicKey;
 use radroots_trade::listing::order::TradeOrderStatus;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{RwLock, RwLockWriteGuard};
 
-#[derive(Clone, Debug)]
+pub const DEFAULT_ORDER_SHARDS: usize = 16;
+
+pub const ORDER_STATUSES: [TradeOrderStatus; 10] = [
+    TradeOrderStatus::Draft,
+    TradeOrderStatus::Validated,
+    TradeOrderStatus::Requested,
+    TradeOrderStatus::Questioned,
+    TradeOrderStatus::Revised,
+    TradeOrderStatus::Accepted,
+    TradeOrderStatus::Declined,
+    TradeOrderStatus::Cancelled,
+    TradeOrderStatus::Fulfilled,
+    TradeOrderStatus::Completed,
+];
+
+// Staying in the same status is always allowed so repeated messages are idempotent.
+pub fn can_transition(from: &TradeOrderStatus, to: &TradeOrderStatus) -> bool {
+    use TradeOrderStatus::*;
+    if from == to {
+        return true;
+    }
+    match from {
+        Draft | Validated => matches!(to, Requested),
+        Requested => matches!(to, Accepted | Declined | Questioned | Revised | Cancelled),
+        Questioned => matches!(to, Accepted | Declined | Revised | Cancelled),
+        Revised => matches!(to, Accepted | Declined | Cancelled),
+        Accepted => matches!(to, Fulfilled | Cancelled),
+        Fulfilled => matches!(to, Completed | Cancelled),
+        Declined | Cancelled | Completed => false,
+    }
+}
+
+pub fn is_terminal_status(status: &TradeOrderStatus) -> bool {
+    matches!(
+        status,
+        TradeOrderStatus::Declined | TradeOrderStatus::Cancelled | TradeOrderStatus::Completed
+    )
+}
+
+// Adjacency list of every status to the other statuses it may move to.
+pub fn transition_table() -> Vec<(TradeOrderStatus, Vec<TradeOrderStatus>)> {
+    ORDER_STATUSES
+        .iter()
+        .map(|from| {
+            let next = ORDER_STATUSES
+                .iter()
+                .filter(|to| *to != from && can_transition(from, to))
+                .cloned()
+                .collect();
+            (from.clone(), next)
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TradeOrderState {
     pub order_id: String,
     pub listing_addr: String,
-    pub buyer_pubkey: String,
-    pub seller_pubkey: String,
+    pub buyer_pubkey: Arc<str>,
+    pub seller_pubkey: Arc<str>,
     pub status: TradeOrderStatus,
-    pub seen_event_ids: HashSet<String>,
+    pub seen_event_ids: SeenEventIds,
+    #[serde(default)]
+    pub rounds: TradeOrderRounds,
+    #[serde(default)]
+    pub fulfillment: Option<TradeFulfillmentStage>,
+    #[serde(default)]
+    pub root_event_id: Option<String>,
+    #[serde(default)]
+    pub answered: bool,
+    #[serde(default)]
+    pub total: Option<String>,
+    #[serde(default)]
+    pub confirmation: Option<String>,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub updated_at: u64,
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
 }
 
-#[derive(Debug, Default)]
+// Only recent redeliveries matter for dedup, so the oldest ids are dropped once the
+// cap is reached. Serialized as a list, oldest first.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Vec<String>", into = "Vec<String>")]
+pub struct SeenEventIds {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl SeenEventIds {
+    pub fn contains(&self, event_id: &str) -> bool {
+        self.ids.contains(event_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub fn insert(&mut self, event_id: String, limit: usize) -> bool {
+        if !self.ids.insert(event_id.clone()) {
+            return false;
+        }
+        self.order.push_back(event_id);
+        while self.order.len() > limit.max(1) {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+impl From<Vec<String>> for SeenEventIds {
+    fn from(event_ids: Vec<String>) -> Self {
+        let mut seen = Self::default();
+        for event_id in event_ids {
+            seen.insert(event_id, usize::MAX);
+        }
+        seen
+    }
+}
+
+impl From<SeenEventIds> for Vec<String> {
+    fn from(seen: SeenEventIds) -> Self {
+        seen.order.into()
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TradeOrderRounds {
+    pub questions: u32,
+    pub revisions: u32,
+    pub discounts: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TradeOrderRound {
+    Question,
+    Revision,
+    Discount,
+}
+
+impl core::fmt::Display for TradeOrderRound {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TradeOrderRound::Question => write!(f, "question"),
+            TradeOrderRound::Revision => write!(f, "revision"),
+            TradeOrderRound::Discount => write!(f, "discount"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TradeFulfillmentStage {
+    Preparing,
+    Shipped,
+    Delivered,
+}
+
+impl TradeFulfillmentStage {
+    fn can_follow(self, prev: Option<TradeFulfillmentStage>) -> bool {
+        use TradeFulfillmentStage::*;
+        match prev {
+            None => matches!(self, Preparing | Shipped),
+            Some(prev) if prev == self => true,
+            Some(Preparing) => self == Shipped,
+            Some(Shipped) => self == Delivered,
+            Some(Delivered) => false,
+        }
+    }
+}
+
+impl TradeOrderState {
+    pub fn new(
+        order_id: impl Into<String>,
+        listing_addr: impl Into<String>,
+        buyer_pubkey: Arc<str>,
+        seller_pubkey: Arc<str>,
+        status: TradeOrderStatus,
+        at: u64,
+    ) -> Self {
+        Self {
+            order_id: order_id.into(),
+            listing_addr: listing_addr.into(),
+            buyer_pubkey,
+            seller_pubkey,
+            status,
+            seen_event_ids: SeenEventIds::default(),
+            rounds: TradeOrderRounds::default(),
+            fulfillment: None,
+            root_event_id: None,
+            answered: false,
+            total: None,
+            confirmation: None,
+            created_at: at,
+            updated_at: at,
+            idempotency_key: None,
+        }
+    }
+
+    pub fn is_buyer(&self, pubkey: &PublicKey) -> bool {
+        pubkey_hex_matches(&self.buyer_pubkey, pubkey)
+    }
+
+    pub fn is_seller(&self, pubkey: &PublicKey) -> bool {
+        pubkey_hex_matches(&self.seller_pubkey, pubkey)
+    }
+
+    pub fn awaiting_answer(&self) -> bool {
+        self.status == TradeOrderStatus::Questioned && !self.answered
+    }
+
+    pub fn set_status(&mut self, status: TradeOrderStatus, at: u64) {
+        self.status = status;
+        self.updated_at = at;
+    }
+
+    pub fn ask_question(&mut self, at: u64) {
+        self.set_status(TradeOrderStatus::Questioned, at);
+        self.answered = false;
+    }
+
+    pub fn answer_question(&mut self) -> Result<(), TradeListingStateError> {
+        if !self.awaiting_answer() {
+            return Err(TradeListingStateError::NoPendingQuestion);
+        }
+        self.answered = true;
+        Ok(())
+    }
+
+    pub fn advance_fulfillment(
+        &mut self,
+        stage: TradeFulfillmentStage,
+    ) -> Result<(), TradeListingStateError> {
+        if !stage.can_follow(self.fulfillment) {
+            return Err(TradeListingStateError::InvalidFulfillmentTransition {
+                from: self.fulfillment,
+                to: stage,
+            });
+        }
+        self.fulfillment = Some(stage);
+        Ok(())
+    }
+
+    pub fn record_round(
+        &mut self,
+        round: TradeOrderRound,
+        limit: u32,
+    ) -> Result<(), TradeListingStateError> {
+        let count = match round {
+            TradeOrderRound::Question => &mut self.rounds.questions,
+            TradeOrderRound::Revision => &mut self.rounds.revisions,
+            TradeOrderRound::Discount => &mut self.rounds.discounts,
+        };
+        if *count >= limit {
+            return Err(TradeListingStateError::TooManyRounds { round, limit });
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct TradeListingState {
     validated_listings: HashSet<String>,
+    deleted_listings: HashMap<String, u64>,
+    gift_wraps: HashMap<String, u64>,
     orders: HashMap<String, TradeOrderState>,
 }
 
@@ -29,10 +301,68 @@ impl TradeListingState {
         self.validated_listings.contains(listing_addr)
     }
 
+    // Records a deletion issued at `deleted_at` and drops the listing's validation.
+    // Returns whether the listing had been validated.
+    pub fn invalidate_listing(&mut self, listing_addr: &str, deleted_at: u64) -> bool {
+        let latest = self
+            .deleted_listings
+            .entry(listing_addr.to_string())
+            .or_default();
+        *latest = (*latest).max(deleted_at);
+        self.validated_listings.remove(listing_addr)
+    }
+
+    // A deletion only covers the versions published at or before it; the seller can
+    // republish the listing under the same address afterwards.
+    pub fn is_listing_deleted(&self, listing_addr: &str, listing_created_at: u64) -> bool {
+        self.deleted_listings
+            .get(listing_addr)
+            .is_some_and(|deleted_at| listing_created_at <= *deleted_at)
+    }
+
+    pub fn is_gift_wrap_handled(&self, wrap_id: &str) -> bool {
+        self.gift_wraps.contains_key(wrap_id)
+    }
+
+    // Keyed by wrap id with the wrap's (backdated) created_at; wraps created before
+    // `oldest` fall out of every subscription window and are forgotten.
+    pub fn record_gift_wrap(&mut self, wrap_id: String, created_at: u64, oldest: u64) {
+        self.gift_wraps.retain(|_, seen_at| *seen_at >= oldest);
+        if created_at >= oldest {
+            self.gift_wraps.insert(wrap_id, created_at);
+        }
+    }
+
     pub fn order_exists(&self, order_id: &str) -> bool {
         self.orders.contains_key(order_id)
     }
 
+    pub fn orders(&self) -> impl Iterator<Item = &TradeOrderState> {
+        self.orders.values()
+    }
+
+    pub fn open_orders_for_buyer(&self, buyer_pubkey: &str) -> usize {
+        self.open_orders()
+            .filter(|order| &*order.buyer_pubkey == buyer_pubkey)
+            .count()
+    }
+
+    pub fn open_orders_for_listing(&self, listing_addr: &str) -> usize {
+        self.open_orders()
+            .filter(|order| order.listing_addr == listing_addr)
+            .count()
+    }
+
+    fn open_orders(&self) -> impl Iterator<Item = &TradeOrderState> {
+        self.orders
+            .values()
+            .filter(|order| !is_terminal_status(&order.status))
+    }
+
+    pub fn get_order(&self, order_id: &str) -> Option<&TradeOrderState> {
+        self.orders.get(order_id)
+    }
+
     pub fn get_order_mut(&mut self, order_id: &str) -> Option<&mut TradeOrderState> {
         self.orders.get_mut(order_id)
     }
@@ -41,9 +371,9 @@ impl TradeListingState {
         self.orders.insert(order.order_id.clone(), order);
     }
 
-    pub fn mark_event_seen(&mut self, order_id: &str, event_id: &str) -> bool {
+    pub fn mark_event_seen(&mut self, order_id: &str, event_id: &str, limit: usize) -> bool {
         if let Some(state) = self.orders.get_mut(order_id) {
-            state.seen_event_ids.insert(event_id.to_string())
+            state.seen_event_ids.insert(event_id.to_string(), limit)
         } else {
             false
         }
@@ -55,12 +385,389 @@ impl TradeListingState {
             .map(|state| state.seen_event_ids.contains(event_id))
             .unwrap_or(false)
     }
+
+    // Folds in a separately persisted section; on a repeated order id the section
+    // merged last wins.
+    pub fn merge(&mut self, other: TradeListingState) {
+        self.validated_listings.extend(other.validated_listings);
+        for (listing_addr, deleted_at) in other.deleted_listings {
+            let latest = self.deleted_listings.entry(listing_addr).or_default();
+            *latest = (*latest).max(deleted_at);
+        }
+        self.gift_wraps.extend(other.gift_wraps);
+        self.orders.extend(other.orders);
+    }
+}
+
+// Listing sets live behind their own lock; orders are spread over shards keyed by
+// order_id. Each lock guards a `TradeListingState`, so handlers keep the same API.
+// Idempotency keys span shards, so they are indexed separately and rebuilt on load.
+// Writers go through `listings_mut`/`order_shard_mut`, which mark the section dirty
+// so only changed sections are persisted.
+#[derive(Debug)]
+pub struct SharedTradeListingState {
+    listings: RwLock<TradeListingState>,
+    shards: Vec<RwLock<TradeListingState>>,
+    pubkeys: Mutex<PubkeyInterner>,
+    idempotency_keys: Mutex<HashMap<IdempotencyScope, String>>,
+    pending_payments: Mutex<HashMap<String, PendingPayment>>,
+    listings_dirty: AtomicBool,
+    dirty_shards: Vec<AtomicBool>,
+}
+
+// An invoice issued for a gated request, keyed by its payment hash. It unlocks one
+// request of `kind` from `payer`, and is dropped once `expires_at` has passed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingPayment {
+    pub payer: String,
+    pub kind: u16,
+    pub expires_at: u64,
+}
+
+// The sections changed since the previous snapshot: the listing sets, and the order
+// shards by index.
+#[derive(Debug, Default)]
+pub struct TradeListingSnapshot {
+    pub listings: Option<TradeListingState>,
+    pub shards: Vec<(usize, TradeListingState)>,
+}
+
+impl TradeListingSnapshot {
+    pub fn is_empty(&self) -> bool {
+        self.listings.is_none() && self.shards.is_empty()
+    }
+}
+
+type IdempotencyScope = (Arc<str>, String, String);
+
+impl SharedTradeListingState {
+    pub fn new(state: TradeListingState, shards: usize) -> Self {
+        let shards = shards.max(1);
+        let mut order_shards: Vec<TradeListingState> =
+            (0..shards).map(|_| TradeListingState::default()).collect();
+        let mut pubkeys = PubkeyInterner::default();
+        let mut idempotency_keys = HashMap::new();
+        for (order_id, mut order) in state.orders {
+            order.buyer_pubkey = pubkeys.intern(&order.buyer_pubkey);
+            order.seller_pubkey = pubkeys.intern(&order.seller_pubkey);
+            if let Some(scope) = idempotency_scope(&order) {
+                idempotency_keys.insert(scope, order_id.clone());
+            }
+            order_shards[shard_index(&order_id, shards)]
+                .orders
+                .insert(order_id, order);
+        }
+        Self {
+            listings: RwLock::new(TradeListingState {
+                validated_listings: state.validated_listings,
+                deleted_listings: state.deleted_listings,
+                gift_wraps: state.gift_wraps,
+                orders: HashMap::new(),
+            }),
+            shards: order_shards.into_iter().map(RwLock::new).collect(),
+            pubkeys: Mutex::new(pubkeys),
+            idempotency_keys: Mutex::new(idempotency_keys),
+            pending_payments: Mutex::new(HashMap::new()),
+            // Everything starts dirty, so the first write lays out every section.
+            listings_dirty: AtomicBool::new(true),
+            dirty_shards: (0..shards).map(|_| AtomicBool::new(true)).collect(),
+        }
+    }
+
+    pub fn idempotent_order_id(
+        &self,
+        buyer_pubkey: &str,
+        listing_addr: &str,
+        key: &str,
+    ) -> Option<String> {
+        let keys = self
+            .idempotency_keys
+            .lock()
+            .unwrap_or_else(|p| p.into_inner());
+        keys.get(&(
+            Arc::from(buyer_pubkey),
+            listing_addr.to_string(),
+            key.to_string(),
+        ))
+        .cloned()
+    }
+
+    // Returns the order that already holds the order's idempotency key, if any;
+    // otherwise records the key against this order.
+    pub fn claim_idempotency_key(&self, order: &TradeOrderState) -> Option<String> {
+        let scope = idempotency_scope(order)?;
+        let mut keys = self
+            .idempotency_keys
+            .lock()
+            .unwrap_or_else(|p| p.into_inner());
+        match keys.get(&scope) {
+            Some(existing) if *existing != order.order_id => Some(existing.clone()),
+            Some(_) => None,
+            None => {
+                keys.insert(scope, order.order_id.clone());
+                None
+            }
+        }
+    }
+
+    // Pending invoices are kept in memory only; one issued before a restart has to be
+    // requested again.
+    pub fn record_pending_payment(&self, payment_hash: String, payment: PendingPayment, now: u64) {
+        let mut pending = self
+            .pending_payments
+            .lock()
+            .unwrap_or_else(|p| p.into_inner());
+        pending.retain(|_, payment| payment.expires_at > now);
+        pending.insert(payment_hash, payment);
+    }
+
+    // Consumes the pending invoice, so each payment unlocks a single request.
+    pub fn redeem_payment(&self, payment_hash: &str, payer: &str, kind: u16, now: u64) -> bool {
+        let mut pending = self
+            .pending_payments
+            .lock()
+            .unwrap_or_else(|p| p.into_inner());
+        match pending.get(payment_hash) {
+            Some(payment)
+                if payment.payer == payer && payment.kind == kind && payment.expires_at > now =>
+            {
+                pending.remove(payment_hash);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn intern_pubkey(&self, pubkey: &str) -> Arc<str> {
+        let mut pubkeys = self.pubkeys.lock().unwrap_or_else(|p| p.into_inner());
+        pubkeys.intern(pubkey)
+    }
+
+    pub fn listings(&self) -> &RwLock<TradeListingState> {
+        &self.listings
+    }
+
+    pub async fn listings_mut(&self) -> RwLockWriteGuard<'_, TradeListingState> {
+        let guard = self.listings.write().await;
+        self.listings_dirty.store(true, Ordering::Release);
+        guard
+    }
+
+    pub fn order_shard(&self, order_id: &str) -> &RwLock<TradeListingState> {
+        &self.shards[shard_index(order_id, self.shards.len())]
+    }
+
+    pub async fn order_shard_mut(&self, order_id: &str) -> RwLockWriteGuard<'_, TradeListingState> {
+        let index = shard_index(order_id, self.shards.len());
+        let guard = self.shards[index].write().await;
+        self.dirty_shards[index].store(true, Ordering::Release);
+        guard
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.listings_dirty.load(Ordering::Acquire)
+            || self
+                .dirty_shards
+                .iter()
+                .any(|dirty| dirty.load(Ordering::Acquire))
+    }
+
+    pub async fn orders(&self) -> Vec<TradeOrderState> {
+        let mut orders = Vec::new();
+        for shard in &self.shards {
+            orders.extend(shard.read().await.orders().cloned());
+        }
+        orders
+    }
+
+    pub async fn open_order_counts(
+        &self,
+        buyer_pubkey: &str,
+        listing_addr: &str,
+    ) -> (usize, usize) {
+        let mut counts = (0, 0);
+        for shard in &self.shards {
+            let shard = shard.read().await;
+            counts.0 += shard.open_orders_for_buyer(buyer_pubkey);
+            counts.1 += shard.open_orders_for_listing(listing_addr);
+        }
+        counts
+    }
+
+    // Drops terminal orders untouched for `retention_secs` and releases their
+    // idempotency keys with them.
+    pub async fn prune_orders(&self, now: u64, retention_secs: u64) -> usize {
+        let mut pruned = 0;
+        for (shard, dirty) in self.shards.iter().zip(&self.dirty_shards) {
+            let mut shard = shard.write().await;
+            let expired: Vec<String> = shard
+                .orders
+                .values()
+                .filter(|order| {
+                    is_terminal_status(&order.status)
+                        && order.updated_at.saturating_add(retention_secs) <= now
+                })
+                .map(|order| order.order_id.clone())
+                .collect();
+            if expired.is_empty() {
+                continue;
+            }
+            dirty.store(true, Ordering::Release);
+            let mut keys = self
+                .idempotency_keys
+                .lock()
+                .unwrap_or_else(|p| p.into_inner());
+            for order_id in expired {
+                let Some(order) = shard.orders.remove(&order_id) else {
+                    continue;
+                };
+                if let Some(scope) = idempotency_scope(&order) {
+                    if keys.get(&scope) == Some(&order.order_id) {
+                        keys.remove(&scope);
+                    }
+                }
+                pruned += 1;
+            }
+        }
+        pruned
+    }
+
+    // Each flag is cleared before its section is read. Writers set the flag while
+    // holding the write lock, so a change that lands after the read keeps the flag
+    // set for the next snapshot.
+    pub async fn dirty_snapshot(&self) -> TradeListingSnapshot {
+        let mut snapshot = TradeListingSnapshot::default();
+        if self.listings_dirty.swap(false, Ordering::AcqRel) {
+            let listings = self.listings.read().await;
+            snapshot.listings = Some(TradeListingState {
+                validated_listings: listings.validated_listings.clone(),
+                deleted_listings: listings.deleted_listings.clone(),
+                gift_wraps: listings.gift_wraps.clone(),
+                orders: HashMap::new(),
+            });
+        }
+        for (index, (shard, dirty)) in self.shards.iter().zip(&self.dirty_shards).enumerate() {
+            if dirty.swap(false, Ordering::AcqRel) {
+                let orders = shard.read().await.orders.clone();
+                let shard = TradeListingState {
+                    orders,
+                    ..Default::default()
+                };
+                snapshot.shards.push((index, shard));
+            }
+        }
+        snapshot
+    }
+
+    // Puts back the sections of a snapshot that could not be written.
+    pub fn mark_dirty(&self, snapshot: &TradeListingSnapshot) {
+        if snapshot.listings.is_some() {
+            self.listings_dirty.store(true, Ordering::Release);
+        }
+        for (index, _) in &snapshot.shards {
+            self.dirty_shards[*index].store(true, Ordering::Release);
+        }
+    }
+
+    pub fn mark_all_dirty(&self) {
+        self.listings_dirty.store(true, Ordering::Release);
+        for dirty in &self.dirty_shards {
+            dirty.store(true, Ordering::Release);
+        }
+    }
+}
+
+impl Default for SharedTradeListingState {
+    fn default() -> Self {
+        Self::new(TradeListingState::default(), DEFAULT_ORDER_SHARDS)
+    }
+}
+
+fn idempotency_scope(order: &TradeOrderState) -> Option<IdempotencyScope> {
+    let key = order.idempotency_key.clone()?;
+    Some((
+        Arc::clone(&order.buyer_pubkey),
+        order.listing_addr.clone(),
+        key,
+    ))
+}
+
+const MIN_INTERNED_PUBKEYS: usize = 64;
+
+// Holds only weak references, so a pubkey is freed once no order or handler uses it.
+// Dead entries are swept whenever the table doubles since the last sweep, which keeps
+// its size proportional to the live pubkeys.
+#[derive(Debug)]
+struct PubkeyInterner {
+    entries: HashMap<Box<str>, Weak<str>>,
+    sweep_at: usize,
+}
+
+impl Default for PubkeyInterner {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            sweep_at: MIN_INTERNED_PUBKEYS,
+        }
+    }
+}
+
+impl PubkeyInterner {
+    fn intern(&mut self, pubkey: &str) -> Arc<str> {
+        if let Some(interned) = self.entries.get(pubkey).and_then(Weak::upgrade) {
+            return interned;
+        }
+        let interned: Arc<str> = Arc::from(pubkey);
+        self.entries
+            .insert(pubkey.into(), Arc::downgrade(&interned));
+        if self.entries.len() >= self.sweep_at {
+            self.entries
+                .retain(|_, interned| interned.strong_count() > 0);
+            self.sweep_at = (self.entries.len() * 2).max(MIN_INTERNED_PUBKEYS);
+        }
+        interned
+    }
+}
+
+fn pubkey_hex_matches(hex: &str, pubkey: &PublicKey) -> bool {
+    let hex = hex.as_bytes();
+    hex.len() == 64
+        && pubkey.to_bytes().iter().enumerate().all(|(i, byte)| {
+            hex_nibble(hex[2 * i]) == Some(byte >> 4)
+                && hex_nibble(hex[2 * i + 1]) == Some(byte & 0x0f)
+        })
+}
+
+fn hex_nibble(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    }
+}
+
+fn shard_index(order_id: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    order_id.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TradeListingStateError {
     MissingOrder,
-    InvalidTransition { from: TradeOrderStatus, to: TradeOrderStatus },
+    InvalidTransition {
+        from: TradeOrderStatus,
+        to: TradeOrderStatus,
+    },
+    TooManyRounds {
+        round: TradeOrderRound,
+        limit: u32,
+    },
+    NoPendingQuestion,
+    InvalidFulfillmentTransition {
+        from: Option<TradeFulfillmentStage>,
+        to: TradeFulfillmentStage,
+    },
 }
 
 impl core::fmt::Display for TradeListingStateError {
@@ -70,6 +777,15 @@ impl core::fmt::Display for TradeListingStateError {
             TradeListingStateError::InvalidTransition { from, to } => {
                 write!(f, "invalid order transition: {from:?} -> {to:?}")
             }
+            TradeListingStateError::TooManyRounds { round, limit } => {
+                write!(f, "too many {round} rounds (limit {limit})")
+            }
+            TradeListingStateError::NoPendingQuestion => {
+                write!(f, "no question is awaiting an answer")
+            }
+            TradeListingStateError::InvalidFulfillmentTransition { from, to } => {
+                write!(f, "invalid fulfillment transition: {from:?} -> {to:?}")
+            }
         }
     }
 }
@@ -78,8 +794,14 @@ impl std::error::Error for TradeListingStateError {}
 
 #[cfg(test)]
 mod tests {
-    use super::{TradeListingState, TradeOrderState};
+    use super::{
+        MIN_INTERNED_PUBKEYS, ORDER_STATUSES, PendingPayment, SeenEventIds,
+        SharedTradeListingState, TradeFulfillmentStage, TradeListingState, TradeListingStateError,
+        TradeOrderRound, TradeOrderState, can_transition, is_terminal_status, transition_table,
+    };
+    use radroots_nostr::prelude::RadrootsNostrKeys;
     use radroots_trade::listing::order::TradeOrderStatus;
+    use std::sync::Arc;
 
     #[test]
     fn state_tracks_listings_and_events() {
@@ -88,17 +810,471 @@ mod tests {
         state.mark_listing_validated("addr");
         assert!(state.is_listing_validated("addr"));
 
-        let order = TradeOrderState {
-            order_id: "order-1".into(),
-            listing_addr: "addr".into(),
-            buyer_pubkey: "buyer".into(),
-            seller_pubkey: "seller".into(),
-            status: TradeOrderStatus::Requested,
-            seen_event_ids: Default::default(),
-        };
+        let order = TradeOrderState::new(
+            "order-1",
+            "addr",
+            "buyer".into(),
+            "seller".into(),
+            TradeOrderStatus::Requested,
+            0,
+        );
         state.insert_order(order);
         assert!(!state.is_event_seen("order-1", "evt"));
-        assert!(state.mark_event_seen("order-1", "evt"));
+        assert!(state.mark_event_seen("order-1", "evt", 8));
+        assert!(!state.mark_event_seen("order-1", "evt", 8));
         assert!(state.is_event_seen("order-1", "evt"));
     }
+
+    #[test]
+    fn invalidated_listings_are_marked_deleted() {
+        let mut state = TradeListingState::default();
+        assert!(!state.is_listing_deleted("addr", 100));
+
+        state.mark_listing_validated("addr");
+        assert!(state.invalidate_listing("addr", 200));
+        assert!(!state.is_listing_validated("addr"));
+        assert!(state.is_listing_deleted("addr", 100));
+        assert!(state.is_listing_deleted("addr", 200));
+        assert!(!state.is_listing_deleted("addr", 201));
+
+        assert!(!state.invalidate_listing("addr", 150));
+        assert!(!state.is_listing_deleted("addr", 201));
+    }
+
+    #[test]
+    fn handled_gift_wraps_are_kept_until_they_leave_the_window() {
+        let mut state = TradeListingState::default();
+        state.record_gift_wrap("wrap-1".into(), 100, 50);
+        state.record_gift_wrap("stale".into(), 40, 50);
+        assert!(state.is_gift_wrap_handled("wrap-1"));
+        assert!(!state.is_gift_wrap_handled("stale"));
+
+        let json = serde_json::to_string(&state).unwrap();
+        let mut restored: TradeListingState = serde_json::from_str(&json).unwrap();
+        assert!(restored.is_gift_wrap_handled("wrap-1"));
+
+        restored.record_gift_wrap("wrap-2".into(), 300, 200);
+        assert!(!restored.is_gift_wrap_handled("wrap-1"));
+        assert!(restored.is_gift_wrap_handled("wrap-2"));
+    }
+
+    fn order() -> TradeOrderState {
+        TradeOrderState::new(
+            "order-1",
+            "addr",
+            "buyer".into(),
+            "seller".into(),
+            TradeOrderStatus::Requested,
+            0,
+        )
+    }
+
+    #[test]
+    fn seen_event_ids_keep_only_the_most_recent() {
+        let mut seen = SeenEventIds::default();
+        for i in 0..5 {
+            assert!(seen.insert(format!("evt-{i}"), 3));
+        }
+        assert_eq!(seen.len(), 3);
+        assert!(!seen.contains("evt-1"));
+        assert!(seen.contains("evt-2") && seen.contains("evt-4"));
+
+        let legacy: SeenEventIds = serde_json::from_str(r#"["a", "b"]"#).unwrap();
+        assert!(legacy.contains("a") && legacy.contains("b"));
+        let json = serde_json::to_value(&seen).unwrap();
+        assert_eq!(json, serde_json::json!(["evt-2", "evt-3", "evt-4"]));
+    }
+
+    #[tokio::test]
+    async fn sharded_state_round_trips_through_snapshot() {
+        let mut state = TradeListingState::default();
+        state.mark_listing_validated("addr");
+        for i in 0..8 {
+            let mut order = order();
+            order.order_id = format!("order-{i}");
+            state.insert_order(order);
+        }
+
+        let shared = SharedTradeListingState::new(state, 4);
+        assert!(shared.listings().read().await.is_listing_validated("addr"));
+        assert!(
+            shared
+                .order_shard("order-3")
+                .read()
+                .await
+                .order_exists("order-3")
+        );
+        shared
+            .order_shard_mut("order-3")
+            .await
+            .mark_event_seen("order-3", "evt", 8);
+
+        let first = shared.dirty_snapshot().await;
+        let mut snapshot = first.listings.unwrap();
+        assert_eq!(first.shards.len(), 4);
+        for (_, shard) in first.shards {
+            snapshot.merge(shard);
+        }
+        assert!(snapshot.is_listing_validated("addr"));
+        assert!((0..8).all(|i| snapshot.order_exists(&format!("order-{i}"))));
+        assert!(snapshot.is_event_seen("order-3", "evt"));
+        assert!(!shared.is_dirty());
+    }
+
+    #[tokio::test]
+    async fn dirty_snapshot_holds_only_the_written_sections() {
+        let shared = SharedTradeListingState::new(TradeListingState::default(), 4);
+        assert!(!shared.dirty_snapshot().await.is_empty());
+        assert!(shared.dirty_snapshot().await.is_empty());
+
+        shared
+            .order_shard_mut("order-1")
+            .await
+            .insert_order(order());
+        let snapshot = shared.dirty_snapshot().await;
+        assert!(snapshot.listings.is_none());
+        assert_eq!(snapshot.shards.len(), 1);
+        assert!(snapshot.shards[0].1.order_exists("order-1"));
+
+        shared.mark_dirty(&snapshot);
+        assert_eq!(shared.dirty_snapshot().await.shards.len(), 1);
+
+        shared.listings_mut().await.mark_listing_validated("addr");
+        let snapshot = shared.dirty_snapshot().await;
+        assert!(snapshot.listings.unwrap().is_listing_validated("addr"));
+        assert!(snapshot.shards.is_empty());
+    }
+
+    #[test]
+    fn interned_pubkeys_share_one_allocation_and_match_by_bytes() {
+        let buyer = RadrootsNostrKeys::generate().public_key();
+        let seller = RadrootsNostrKeys::generate().public_key();
+        let mut state = TradeListingState::default();
+        for i in 0..2 {
+            let mut order = order();
+            order.order_id = format!("order-{i}");
+            order.buyer_pubkey = buyer.to_hex().into();
+            order.seller_pubkey = seller.to_hex().into();
+            state.insert_order(order);
+        }
+
+        let shared = SharedTradeListingState::new(state, 1);
+        let shard = shared.order_shard("order-0").try_read().unwrap();
+        let (a, b) = (&shard.orders["order-0"], &shard.orders["order-1"]);
+        assert!(Arc::ptr_eq(&a.buyer_pubkey, &b.buyer_pubkey));
+        assert!(Arc::ptr_eq(
+            &a.buyer_pubkey,
+            &shared.intern_pubkey(&buyer.to_hex())
+        ));
+        assert!(a.is_buyer(&buyer) && !a.is_buyer(&seller));
+        assert!(a.is_seller(&seller) && !a.is_seller(&buyer));
+    }
+
+    #[test]
+    fn unused_pubkeys_are_swept_from_the_intern_table() {
+        let shared = SharedTradeListingState::new(TradeListingState::default(), 1);
+        let kept = shared.intern_pubkey("kept");
+        for i in 0..1000 {
+            drop(shared.intern_pubkey(&format!("dropped-{i}")));
+        }
+
+        assert!(shared.pubkeys.lock().unwrap().entries.len() <= MIN_INTERNED_PUBKEYS);
+        assert!(Arc::ptr_eq(&kept, &shared.intern_pubkey("kept")));
+    }
+
+    #[tokio::test]
+    async fn pruning_drops_old_terminal_orders_and_their_keys() {
+        let mut state = TradeListingState::default();
+        let mut done = order();
+        done.order_id = "done".into();
+        done.idempotency_key = Some("key-1".into());
+        done.set_status(TradeOrderStatus::Completed, 100);
+        let mut open = order();
+        open.order_id = "open".into();
+        state.insert_order(done);
+        state.insert_order(open);
+        let shared = SharedTradeListingState::new(state, 4);
+        let _ = shared.dirty_snapshot().await;
+
+        assert_eq!(shared.prune_orders(150, 100).await, 0);
+        assert_eq!(shared.prune_orders(200, 100).await, 1);
+        assert!(!shared.order_shard("done").read().await.order_exists("done"));
+        assert!(shared.order_shard("open").read().await.order_exists("open"));
+        assert_eq!(shared.idempotent_order_id("buyer", "addr", "key-1"), None);
+        assert!(shared.is_dirty());
+    }
+
+    #[test]
+    fn idempotency_keys_are_scoped_to_buyer_and_listing() {
+        let mut state = TradeListingState::default();
+        let mut first = order();
+        first.idempotency_key = Some("key-1".into());
+        state.insert_order(first);
+        let shared = SharedTradeListingState::new(state, 4);
+        assert_eq!(
+            shared
+                .idempotent_order_id("buyer", "addr", "key-1")
+                .as_deref(),
+            Some("order-1")
+        );
+
+        let mut retry = order();
+        retry.order_id = "order-2".into();
+        retry.idempotency_key = Some("key-1".into());
+        assert_eq!(
+            shared.claim_idempotency_key(&retry).as_deref(),
+            Some("order-1")
+        );
+
+        retry.buyer_pubkey = "other-buyer".into();
+        assert_eq!(shared.claim_idempotency_key(&retry), None);
+        assert_eq!(
+            shared
+                .idempotent_order_id("other-buyer", "addr", "key-1")
+                .as_deref(),
+            Some("order-2")
+        );
+
+        retry.idempotency_key = None;
+        retry.order_id = "order-3".into();
+        assert_eq!(shared.claim_idempotency_key(&retry), None);
+    }
+
+    #[test]
+    fn pending_payments_redeem_once_for_their_payer_and_kind() {
+        let shared = SharedTradeListingState::new(TradeListingState::default(), 1);
+        let pending = |payer: &str| PendingPayment {
+            payer: payer.into(),
+            kind: 5321,
+            expires_at: 100,
+        };
+        shared.record_pending_payment("hash-1".into(), pending("buyer"), 0);
+        assert!(!shared.redeem_payment("hash-1", "other-buyer", 5321, 10));
+        assert!(!shared.redeem_payment("hash-1", "buyer", 5322, 10));
+        assert!(!shared.redeem_payment("hash-2", "buyer", 5321, 10));
+        assert!(shared.redeem_payment("hash-1", "buyer", 5321, 10));
+        assert!(!shared.redeem_payment("hash-1", "buyer", 5321, 10));
+
+        shared.record_pending_payment("hash-2".into(), pending("buyer"), 0);
+        assert!(!shared.redeem_payment("hash-2", "buyer", 5321, 100));
+        shared.record_pending_payment("hash-3".into(), pending("buyer"), 200);
+        assert_eq!(shared.pending_payments.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn answers_require_a_pending_question() {
+        let mut order = order();
+        assert_eq!(
+            order.answer_question(),
+            Err(TradeListingStateError::NoPendingQuestion)
+        );
+
+        order.ask_question(0);
+        assert!(order.awaiting_answer());
+        assert!(order.answer_question().is_ok());
+        assert_eq!(order.status, TradeOrderStatus::Questioned);
+        assert!(!order.awaiting_answer());
+        assert_eq!(
+            order.answer_question(),
+            Err(TradeListingStateError::NoPendingQuestion)
+        );
+
+        order.ask_question(0);
+        assert!(order.awaiting_answer());
+    }
+
+    fn assert_round_cap(round: TradeOrderRound) {
+        let mut order = order();
+        assert!(order.record_round(round, 2).is_ok());
+        assert!(order.record_round(round, 2).is_ok());
+        assert_eq!(
+            order.record_round(round, 2),
+            Err(TradeListingStateError::TooManyRounds { round, limit: 2 })
+        );
+    }
+
+    #[test]
+    fn question_rounds_are_capped() {
+        assert_round_cap(TradeOrderRound::Question);
+    }
+
+    #[test]
+    fn revision_rounds_are_capped() {
+        assert_round_cap(TradeOrderRound::Revision);
+    }
+
+    #[test]
+    fn discount_rounds_are_capped() {
+        assert_round_cap(TradeOrderRound::Discount);
+    }
+
+    #[test]
+    fn round_caps_are_tracked_independently() {
+        let mut order = order();
+        assert!(order.record_round(TradeOrderRound::Question, 1).is_ok());
+        assert!(order.record_round(TradeOrderRound::Revision, 1).is_ok());
+        assert!(order.record_round(TradeOrderRound::Discount, 1).is_ok());
+        assert!(order.record_round(TradeOrderRound::Question, 1).is_err());
+    }
+
+    #[test]
+    fn fulfillment_stages_advance_in_order() {
+        let mut order = order();
+        assert!(
+            order
+                .advance_fulfillment(TradeFulfillmentStage::Preparing)
+                .is_ok()
+        );
+        assert!(
+            order
+                .advance_fulfillment(TradeFulfillmentStage::Preparing)
+                .is_ok()
+        );
+        assert!(
+            order
+                .advance_fulfillment(TradeFulfillmentStage::Shipped)
+                .is_ok()
+        );
+        assert!(
+            order
+                .advance_fulfillment(TradeFulfillmentStage::Shipped)
+                .is_ok()
+        );
+        assert!(
+            order
+                .advance_fulfillment(TradeFulfillmentStage::Delivered)
+                .is_ok()
+        );
+        assert_eq!(order.fulfillment, Some(TradeFulfillmentStage::Delivered));
+    }
+
+    #[test]
+    fn fulfillment_stages_reject_out_of_order_updates() {
+        let mut order = order();
+        assert_eq!(
+            order.advance_fulfillment(TradeFulfillmentStage::Delivered),
+            Err(TradeListingStateError::InvalidFulfillmentTransition {
+                from: None,
+                to: TradeFulfillmentStage::Delivered,
+            })
+        );
+
+        order
+            .advance_fulfillment(TradeFulfillmentStage::Preparing)
+            .unwrap();
+        assert!(
+            order
+                .advance_fulfillment(TradeFulfillmentStage::Delivered)
+                .is_err()
+        );
+
+        order
+            .advance_fulfillment(TradeFulfillmentStage::Shipped)
+            .unwrap();
+        order
+            .advance_fulfillment(TradeFulfillmentStage::Delivered)
+            .unwrap();
+        assert!(
+            order
+                .advance_fulfillment(TradeFulfillmentStage::Shipped)
+                .is_err()
+        );
+        assert_eq!(order.fulfillment, Some(TradeFulfillmentStage::Delivered));
+    }
+
+    #[test]
+    fn transition_table_matches_every_status_pair() {
+        use TradeOrderStatus::*;
+        let allowed = [
+            (Draft, Requested),
+            (Validated, Requested),
+            (Requested, Accepted),
+            (Requested, Declined),
+            (Requested, Questioned),
+            (Requested, Revised),
+            (Requested, Cancelled),
+            (Questioned, Accepted),
+            (Questioned, Declined),
+            (Questioned, Revised),
+            (Questioned, Cancelled),
+            (Revised, Accepted),
+            (Revised, Declined),
+            (Revised, Cancelled),
+            (Accepted, Fulfilled),
+            (Accepted, Cancelled),
+            (Fulfilled, Completed),
+            (Fulfilled, Cancelled),
+        ];
+        for from in &ORDER_STATUSES {
+            for to in &ORDER_STATUSES {
+                let expected = from == to || allowed.iter().any(|(a, b)| a == from && b == to);
+                assert_eq!(can_transition(from, to), expected, "{from:?} -> {to:?}");
+            }
+        }
+
+        let table = transition_table();
+        assert_eq!(table.len(), ORDER_STATUSES.len());
+        let edges: usize = table.iter().map(|(_, next)| next.len()).sum();
+        assert_eq!(edges, allowed.len());
+        for (from, next) in &table {
+            assert!(!next.contains(from));
+            assert!(next.iter().all(|to| can_transition(from, to)));
+        }
+        // Answers and revision declines never send an order back to Requested.
+        for (from, next) in &table {
+            if matches!(from, Questioned | Revised) {
+                assert!(!next.contains(&Requested), "{from:?} -> Requested");
+            }
+        }
+    }
+
+    #[test]
+    fn order_statuses_cover_every_variant() {
+        for status in &ORDER_STATUSES {
+            match status {
+                TradeOrderStatus::Draft
+                | TradeOrderStatus::Validated
+                | TradeOrderStatus::Requested
+                | TradeOrderStatus::Questioned
+                | TradeOrderStatus::Revised
+                | TradeOrderStatus::Accepted
+                | TradeOrderStatus::Declined
+                | TradeOrderStatus::Cancelled
+                | TradeOrderStatus::Fulfilled
+                | TradeOrderStatus::Completed => {}
+            }
+        }
+        for (i, a) in ORDER_STATUSES.iter().enumerate() {
+            assert!(!ORDER_STATUSES[i + 1..].contains(a));
+        }
+    }
+
+    #[tokio::test]
+    async fn open_order_counts_skip_terminal_orders() {
+        let mut state = TradeListingState::default();
+        let statuses = [
+            TradeOrderStatus::Requested,
+            TradeOrderStatus::Accepted,
+            TradeOrderStatus::Cancelled,
+            TradeOrderStatus::Completed,
+        ];
+        for (i, status) in statuses.into_iter().enumerate() {
+            let mut order = order();
+            order.order_id = format!("order-{i}");
+            order.status = status;
+            state.insert_order(order);
+        }
+        let mut other = order();
+        other.order_id = "other".into();
+        other.listing_addr = "other-addr".into();
+        state.insert_order(other);
+
+        assert_eq!(state.open_orders_for_buyer("buyer"), 3);
+        assert_eq!(state.open_orders_for_listing("addr"), 2);
+        assert!(is_terminal_status(&TradeOrderStatus::Declined));
+        assert!(!is_terminal_status(&TradeOrderStatus::Fulfilled));
+
+        let shared = SharedTradeListingState::new(state, 4);
+        assert_eq!(shared.open_order_counts("buyer", "addr").await, (3, 2));
+    }
 }
@@ -0,0 +1,367 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+use tracing::warn;
+
+use crate::config::SnapshotStrategy;
+use crate::features::trade_listing::state::{
+    SharedTradeListingState, TradeListingSnapshot, TradeListingState,
+};
+
+#[derive(Debug, Error)]
+pub enum TradeListingStoreError {
+    #[error("store io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("store serde error: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+// The listing sets are kept at `path` and each order shard in a sibling
+// `<stem>.shard-NN.json`, so a mutation rewrites only the sections it touched.
+#[derive(Debug)]
+pub struct TradeListingStore {
+    path: PathBuf,
+    strategy: SnapshotStrategy,
+    pending: u32,
+    last_flush: Instant,
+    loaded_shards: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PersistTrigger {
+    Mutation,
+    Tick,
+    Shutdown,
+}
+
+impl TradeListingStore {
+    pub fn new(path: impl Into<PathBuf>, strategy: SnapshotStrategy) -> Self {
+        Self {
+            path: path.into(),
+            strategy,
+            pending: 0,
+            last_flush: Instant::now(),
+            loaded_shards: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn shard_path(&self, index: usize) -> PathBuf {
+        self.path.with_extension(format!("shard-{index:02}.json"))
+    }
+
+    // The listing sets come from the main file and the orders from the shard files.
+    pub fn load(&mut self) -> Result<TradeListingState, TradeListingStoreError> {
+        let mut state: TradeListingState = match fs::read(&self.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => TradeListingState::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let mut shards = self.shard_files()?;
+        shards.sort();
+        for path in &shards {
+            state.merge(serde_json::from_slice(&fs::read(path)?)?);
+        }
+        self.loaded_shards = shards;
+        Ok(state)
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.pending > 0
+    }
+
+    // Counts a mutation and reports whether the strategy wants it written now.
+    pub fn record_mutation(&mut self) -> bool {
+        self.pending = self.pending.saturating_add(1);
+        match self.strategy {
+            SnapshotStrategy::WriteThrough => true,
+            SnapshotStrategy::Periodic { max_mutations, .. } => {
+                self.pending >= max_mutations || self.interval_elapsed()
+            }
+        }
+    }
+
+    pub fn flush_due(&self) -> bool {
+        self.interval_elapsed()
+    }
+
+    pub fn write(&mut self, snapshot: &TradeListingSnapshot) -> Result<(), TradeListingStoreError> {
+        if let Some(parent) = self.path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        if let Some(listings) = &snapshot.listings {
+            write_atomic(&self.path, listings)?;
+        }
+        let mut written = HashSet::new();
+        for (index, shard) in &snapshot.shards {
+            let path = self.shard_path(*index);
+            write_atomic(&path, shard)?;
+            written.insert(path);
+        }
+        // Shard files left over from a different shard count would otherwise be
+        // merged back in on the next load.
+        for path in std::mem::take(&mut self.loaded_shards) {
+            if !written.contains(&path) {
+                let _ = fs::remove_file(path);
+            }
+        }
+        self.pending = 0;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    fn shard_files(&self) -> Result<Vec<PathBuf>, TradeListingStoreError> {
+        let Some(stem) = self.path.file_stem().and_then(|stem| stem.to_str()) else {
+            return Ok(Vec::new());
+        };
+        let prefix = format!("{stem}.shard-");
+        let dir = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let is_shard = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".json"));
+            if is_shard {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn interval_elapsed(&self) -> bool {
+        match self.strategy {
+            SnapshotStrategy::WriteThrough => true,
+            SnapshotStrategy::Periodic { interval_secs, .. } => {
+                self.last_flush.elapsed() >= Duration::from_secs(interval_secs)
+            }
+        }
+    }
+}
+
+fn write_atomic(path: &Path, state: &TradeListingState) -> Result<(), TradeListingStoreError> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(state)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Writes the sections changed since the last write on the blocking pool. Outside
+// shutdown a write already in flight is not waited for: the changed sections stay
+// dirty and go out with the next mutation or tick, which debounces bursts.
+pub async fn persist_state(
+    store: &Arc<tokio::sync::Mutex<TradeListingStore>>,
+    state: &SharedTradeListingState,
+    trigger: PersistTrigger,
+) {
+    let mut store = match trigger {
+        PersistTrigger::Shutdown => Arc::clone(store).lock_owned().await,
+        _ => match Arc::clone(store).try_lock_owned() {
+            Ok(store) => store,
+            Err(_) => return,
+        },
+    };
+    let due = match trigger {
+        PersistTrigger::Mutation => store.record_mutation(),
+        PersistTrigger::Tick => state.is_dirty() && store.flush_due(),
+        PersistTrigger::Shutdown => true,
+    };
+    if !due {
+        return;
+    }
+    let snapshot = state.dirty_snapshot().await;
+    if snapshot.is_empty() {
+        return;
+    }
+    let written = tokio::task::spawn_blocking(move || {
+        let path = store.path().to_path_buf();
+        store.write(&snapshot).map_err(|err| (err, path, snapshot))
+    })
+    .await;
+    match written {
+        Ok(Ok(())) => {}
+        Ok(Err((err, path, snapshot))) => {
+            warn!(
+                "trade_listing: failed to persist state to {}: {err}",
+                path.display()
+            );
+            state.mark_dirty(&snapshot);
+        }
+        Err(err) => {
+            warn!("trade_listing: state persistence task failed: {err}");
+            state.mark_all_dirty();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use radroots_trade::listing::order::TradeOrderStatus;
+
+    use super::{PersistTrigger, TradeListingStore, persist_state};
+    use crate::config::SnapshotStrategy;
+    use crate::features::trade_listing::state::{
+        SharedTradeListingState, TradeListingSnapshot, TradeListingState, TradeOrderState,
+    };
+
+    fn dir() -> PathBuf {
+        std::env::temp_dir().join(format!("rhi-store-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn store(dir: &PathBuf, strategy: SnapshotStrategy) -> TradeListingStore {
+        TradeListingStore::new(dir.join("state.json"), strategy)
+    }
+
+    fn shared(
+        store: TradeListingStore,
+    ) -> (
+        Arc<tokio::sync::Mutex<TradeListingStore>>,
+        SharedTradeListingState,
+    ) {
+        let state = SharedTradeListingState::new(TradeListingState::default(), 4);
+        (Arc::new(tokio::sync::Mutex::new(store)), state)
+    }
+
+    fn order(order_id: &str) -> TradeOrderState {
+        TradeOrderState::new(
+            order_id,
+            "addr",
+            "buyer".into(),
+            "seller".into(),
+            TradeOrderStatus::Requested,
+            0,
+        )
+    }
+
+    fn periodic() -> SnapshotStrategy {
+        SnapshotStrategy::Periodic {
+            interval_secs: 3600,
+            max_mutations: 3,
+        }
+    }
+
+    #[tokio::test]
+    async fn write_through_persists_every_mutation() {
+        let dir = dir();
+        let (store, state) = shared(store(&dir, SnapshotStrategy::WriteThrough));
+        state.listings_mut().await.mark_listing_validated("addr");
+
+        persist_state(&store, &state, PersistTrigger::Mutation).await;
+        assert!(!store.lock().await.is_dirty());
+        assert!(!state.is_dirty());
+        let loaded = store.lock().await.load().unwrap();
+        assert!(loaded.is_listing_validated("addr"));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn periodic_flushes_after_max_mutations() {
+        let dir = dir();
+        let (store, state) = shared(store(&dir, periodic()));
+
+        state.listings_mut().await.mark_listing_validated("a");
+        persist_state(&store, &state, PersistTrigger::Mutation).await;
+        state.listings_mut().await.mark_listing_validated("b");
+        persist_state(&store, &state, PersistTrigger::Mutation).await;
+        persist_state(&store, &state, PersistTrigger::Tick).await;
+        assert!(!store.lock().await.load().unwrap().is_listing_validated("a"));
+
+        state.listings_mut().await.mark_listing_validated("c");
+        persist_state(&store, &state, PersistTrigger::Mutation).await;
+        let loaded = store.lock().await.load().unwrap();
+        assert!(loaded.is_listing_validated("a"));
+        assert!(loaded.is_listing_validated("c"));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn periodic_flushes_pending_state_on_shutdown() {
+        let dir = dir();
+        let (store, state) = shared(store(&dir, periodic()));
+        state.listings_mut().await.mark_listing_validated("addr");
+
+        persist_state(&store, &state, PersistTrigger::Mutation).await;
+        assert!(store.lock().await.is_dirty());
+        persist_state(&store, &state, PersistTrigger::Shutdown).await;
+        assert!(!store.lock().await.is_dirty());
+        assert!(
+            store
+                .lock()
+                .await
+                .load()
+                .unwrap()
+                .is_listing_validated("addr")
+        );
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn only_dirty_shards_are_rewritten() {
+        let dir = dir();
+        let (store, state) = shared(store(&dir, SnapshotStrategy::WriteThrough));
+        persist_state(&store, &state, PersistTrigger::Mutation).await;
+        let shard_paths: Vec<_> = (0..4)
+            .map(|i| store.try_lock().unwrap().shard_path(i))
+            .collect();
+        for path in &shard_paths {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        state
+            .order_shard_mut("order-1")
+            .await
+            .insert_order(order("order-1"));
+        persist_state(&store, &state, PersistTrigger::Mutation).await;
+        assert_eq!(shard_paths.iter().filter(|path| path.exists()).count(), 1);
+        assert!(store.lock().await.load().unwrap().order_exists("order-1"));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn stale_shard_files_are_removed() {
+        let dir = dir();
+        let mut store = store(&dir, SnapshotStrategy::WriteThrough);
+        let mut stale = TradeListingSnapshot::default();
+        stale.shards.push((9, TradeListingState::default()));
+        store.write(&stale).unwrap();
+        let _ = store.load().unwrap();
+        store.write(&TradeListingSnapshot::default()).unwrap();
+        assert!(!store.shard_path(9).exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn missing_snapshot_loads_empty_state() {
+        let mut store = store(&dir(), SnapshotStrategy::WriteThrough);
+        assert!(!store.load().unwrap().is_listing_validated("addr"));
+    }
+}
@@ -0,0 +1,437 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use futures::stream::{self, BoxStream, StreamExt};
+use nostr::{RelayMessage, RelayUrl, SubscriptionId};
+use radroots_nostr::prelude::{
+    RadrootsNostrClient, RadrootsNostrEvent, RadrootsNostrFilter, RadrootsNostrKeys,
+    RadrootsNostrKind, RadrootsNostrRelayPoolNotification,
+};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::config::StartupPolicy;
+use crate::features::trade_listing::handlers::dvm::{
+    TradeListingDvmError, TradeListingRequest, parse_trade_listing_event,
+};
+use crate::infra::{
+    journal::{EventJournal, JournalDirection},
+    metrics,
+    nostr::{NostrPayloadLimits, nostr_tags_resolve, nostr_unwrap_gift_wrap},
+};
+
+const RECENT_EVENT_IDS_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TradeListingEventPhase {
+    Stored,
+    Live,
+}
+
+// Requests that arrived gift-wrapped carry the wrap they came in, so it can be
+// remembered as handled and dead-lettered still encrypted.
+pub enum TradeListingEvent {
+    Request {
+        request: TradeListingRequest,
+        phase: TradeListingEventPhase,
+        gift_wrap: Option<RadrootsNostrEvent>,
+    },
+    Rejected {
+        event: RadrootsNostrEvent,
+        error: TradeListingDvmError,
+        gift_wrap: Option<RadrootsNostrEvent>,
+    },
+    Deletion(RadrootsNostrEvent),
+}
+
+impl TradeListingEvent {
+    pub fn gift_wrap(&self) -> Option<&RadrootsNostrEvent> {
+        match self {
+            Self::Request { gift_wrap, .. } | Self::Rejected { gift_wrap, .. } => {
+                gift_wrap.as_ref()
+            }
+            Self::Deletion(_) => None,
+        }
+    }
+}
+
+pub struct TradeListingSubscription {
+    pub ids: Vec<SubscriptionId>,
+    pub events: BoxStream<'static, TradeListingEvent>,
+}
+
+pub async fn subscribe_stream(
+    client: &RadrootsNostrClient,
+    keys: RadrootsNostrKeys,
+    filters: Vec<RadrootsNostrFilter>,
+    limits: NostrPayloadLimits,
+    startup: StartupPolicy,
+    journal: Option<Arc<EventJournal>>,
+) -> Result<TradeListingSubscription> {
+    let notifications = client.notifications();
+    let mut ids = Vec::with_capacity(filters.len());
+    for filter in filters {
+        ids.push(client.subscribe(filter, None).await?.val);
+    }
+
+    let events = notification_stream(notifications, ids.clone(), keys, limits, startup, journal);
+    Ok(TradeListingSubscription { ids, events })
+}
+
+fn notification_stream(
+    notifications: broadcast::Receiver<RadrootsNostrRelayPoolNotification>,
+    subscription_ids: Vec<SubscriptionId>,
+    keys: RadrootsNostrKeys,
+    limits: NostrPayloadLimits,
+    startup: StartupPolicy,
+    journal: Option<Arc<EventJournal>>,
+) -> BoxStream<'static, TradeListingEvent> {
+    let state = StreamState {
+        notifications,
+        subscription_ids,
+        keys,
+        limits,
+        startup: Some(startup),
+        journal,
+        eose: EoseTracker::default(),
+        recent: RecentEventIds::new(RECENT_EVENT_IDS_CAPACITY),
+    };
+    stream::unfold(state, |mut state| async move {
+        let event = state.next_event().await?;
+        Some((event, state))
+    })
+    .boxed()
+}
+
+struct StreamState {
+    notifications: broadcast::Receiver<RadrootsNostrRelayPoolNotification>,
+    subscription_ids: Vec<SubscriptionId>,
+    keys: RadrootsNostrKeys,
+    limits: NostrPayloadLimits,
+    startup: Option<StartupPolicy>,
+    journal: Option<Arc<EventJournal>>,
+    eose: EoseTracker,
+    recent: RecentEventIds,
+}
+
+impl StreamState {
+    async fn next_event(&mut self) -> Option<TradeListingEvent> {
+        if self.startup.take() == Some(StartupPolicy::Drop) {
+            self.drop_startup_backlog();
+        }
+        loop {
+            let notification = match self.notifications.recv().await {
+                Ok(notification) => notification,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("trade_listing: notification receiver lagged, skipped {skipped} events");
+                    metrics::record_notifications_lagged(skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            let (relay_url, subscription_id, event) = match notification {
+                RadrootsNostrRelayPoolNotification::Event {
+                    relay_url,
+                    subscription_id,
+                    event,
+                } => (relay_url, subscription_id, event),
+                RadrootsNostrRelayPoolNotification::Message {
+                    relay_url,
+                    message: RelayMessage::EndOfStoredEvents(subscription_id),
+                } => {
+                    if self.subscription_ids.contains(&subscription_id) {
+                        self.eose.mark_eose(relay_url, subscription_id.into_owned());
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            if !self.subscription_ids.contains(&subscription_id) {
+                continue;
+            }
+            if !self.recent.insert(event.id.to_string()) {
+                continue;
+            }
+            if let Some(journal) = &self.journal {
+                journal.record(JournalDirection::Received, &[relay_url.to_string()], &event);
+            }
+
+            let event = (*event).clone();
+            if event.kind == RadrootsNostrKind::EventDeletion {
+                return Some(TradeListingEvent::Deletion(event));
+            }
+            let (event, gift_wrap) = if event.kind == RadrootsNostrKind::GiftWrap {
+                match nostr_unwrap_gift_wrap(&event, &self.keys, self.limits) {
+                    Ok(inner) => (inner, Some(event)),
+                    Err(err) => {
+                        warn!(
+                            "trade_listing: failed to unwrap gift wrap {}: {err}",
+                            event.id
+                        );
+                        continue;
+                    }
+                }
+            } else {
+                (event, None)
+            };
+            let tags = match nostr_tags_resolve(&event, &self.keys, self.limits) {
+                Ok(tags) => tags,
+                Err(err) => {
+                    warn!("trade_listing: failed to resolve tags: {err}");
+                    continue;
+                }
+            };
+
+            match parse_trade_listing_event(event.clone(), tags, &self.keys) {
+                Ok(Some(request)) => {
+                    let phase = self.eose.phase(&relay_url, &subscription_id);
+                    return Some(TradeListingEvent::Request {
+                        request,
+                        phase,
+                        gift_wrap,
+                    });
+                }
+                Ok(None) => {}
+                Err(
+                    TradeListingDvmError::MissingRecipient | TradeListingDvmError::UnsupportedKind,
+                ) => {}
+                Err(TradeListingDvmError::NotRecipient) => {
+                    warn!(
+                        "trade_listing: ignoring event {} that also tags other recipients",
+                        event.id
+                    );
+                }
+                Err(error) => {
+                    return Some(TradeListingEvent::Rejected {
+                        event,
+                        error,
+                        gift_wrap,
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl StreamState {
+    fn drop_startup_backlog(&mut self) {
+        let mut dropped = 0usize;
+        for _ in 0..self.notifications.len() {
+            match self.notifications.try_recv() {
+                Ok(RadrootsNostrRelayPoolNotification::Event {
+                    subscription_id, ..
+                }) => {
+                    if self.subscription_ids.contains(&subscription_id) {
+                        dropped += 1;
+                    }
+                }
+                Ok(RadrootsNostrRelayPoolNotification::Message {
+                    relay_url,
+                    message: RelayMessage::EndOfStoredEvents(subscription_id),
+                }) => {
+                    if self.subscription_ids.contains(&subscription_id) {
+                        self.eose.mark_eose(relay_url, subscription_id.into_owned());
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        if dropped > 0 {
+            warn!("trade_listing: dropped {dropped} events received during startup");
+        }
+    }
+}
+
+#[derive(Default)]
+struct EoseTracker {
+    done: HashSet<(RelayUrl, SubscriptionId)>,
+}
+
+impl EoseTracker {
+    fn mark_eose(&mut self, relay_url: RelayUrl, subscription_id: SubscriptionId) {
+        self.done.insert((relay_url, subscription_id));
+    }
+
+    fn phase(
+        &self,
+        relay_url: &RelayUrl,
+        subscription_id: &SubscriptionId,
+    ) -> TradeListingEventPhase {
+        if self
+            .done
+            .contains(&(relay_url.clone(), subscription_id.clone()))
+        {
+            TradeListingEventPhase::Live
+        } else {
+            TradeListingEventPhase::Stored
+        }
+    }
+}
+
+struct RecentEventIds {
+    capacity: usize,
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl RecentEventIds {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ids: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn insert(&mut self, id: String) -> bool {
+        if self.ids.contains(&id) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id.clone());
+        self.order.push_back(id);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        EoseTracker, RecentEventIds, TradeListingEvent, TradeListingEventPhase, notification_stream,
+    };
+    use futures::StreamExt;
+    use nostr::{EventBuilder, Kind, RelayUrl, SubscriptionId};
+    use radroots_nostr::prelude::{RadrootsNostrKeys, RadrootsNostrRelayPoolNotification};
+    use tokio::sync::broadcast;
+
+    use crate::config::StartupPolicy;
+    use crate::infra::{metrics, nostr::NostrPayloadLimits};
+
+    const LIMITS: NostrPayloadLimits = NostrPayloadLimits {
+        max_content_bytes: 65_536,
+        max_decrypted_bytes: 65_536,
+    };
+
+    fn early_deletions(
+        tx: &broadcast::Sender<RadrootsNostrRelayPoolNotification>,
+        sub: &SubscriptionId,
+        keys: &RadrootsNostrKeys,
+        label: &str,
+        count: usize,
+    ) {
+        let relay_url = RelayUrl::parse("wss://relay.example.com").unwrap();
+        for i in 0..count {
+            let event = EventBuilder::new(Kind::EventDeletion, format!("{label}-{i}"))
+                .sign_with_keys(keys)
+                .unwrap();
+            tx.send(RadrootsNostrRelayPoolNotification::Event {
+                relay_url: relay_url.clone(),
+                subscription_id: sub.clone(),
+                event: Box::new(event),
+            })
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn buffered_startup_processes_events_sent_right_after_subscribe() {
+        let (tx, rx) = broadcast::channel(16);
+        let sub = SubscriptionId::new("trade");
+        let keys = RadrootsNostrKeys::generate();
+        early_deletions(&tx, &sub, &keys, "early", 2);
+
+        let mut events =
+            notification_stream(rx, vec![sub], keys, LIMITS, StartupPolicy::Buffer, None);
+        for _ in 0..2 {
+            assert!(matches!(
+                events.next().await,
+                Some(TradeListingEvent::Deletion(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn lagged_receiver_keeps_streaming_until_closed() {
+        let (tx, rx) = broadcast::channel(2);
+        let sub = SubscriptionId::new("trade");
+        let keys = RadrootsNostrKeys::generate();
+        early_deletions(&tx, &sub, &keys, "burst", 4);
+        let lagged_before = metrics::snapshot().notifications_lagged;
+
+        let mut events =
+            notification_stream(rx, vec![sub], keys, LIMITS, StartupPolicy::Buffer, None);
+        for expected in ["burst-2", "burst-3"] {
+            let Some(TradeListingEvent::Deletion(event)) = events.next().await else {
+                panic!("stream ended after lag");
+            };
+            assert_eq!(event.content, expected);
+        }
+        assert!(metrics::snapshot().notifications_lagged >= lagged_before + 2);
+
+        drop(tx);
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn drop_startup_discards_events_sent_before_first_poll() {
+        let (tx, rx) = broadcast::channel(16);
+        let sub = SubscriptionId::new("trade");
+        let keys = RadrootsNostrKeys::generate();
+        early_deletions(&tx, &sub, &keys, "early", 2);
+
+        let mut events = notification_stream(
+            rx,
+            vec![sub.clone()],
+            keys.clone(),
+            LIMITS,
+            StartupPolicy::Drop,
+            None,
+        );
+        let mut next = events.next();
+        assert!(futures::poll!(&mut next).is_pending());
+        early_deletions(&tx, &sub, &keys, "live", 1);
+        let Some(TradeListingEvent::Deletion(event)) = next.await else {
+            panic!("expected live deletion");
+        };
+        assert_eq!(event.content, "live-0");
+    }
+
+    #[test]
+    fn recent_event_ids_dedupe_and_evict() {
+        let mut recent = RecentEventIds::new(2);
+        assert!(recent.insert("a".into()));
+        assert!(!recent.insert("a".into()));
+        assert!(recent.insert("b".into()));
+        assert!(recent.insert("c".into()));
+        assert!(recent.insert("a".into()));
+        assert!(!recent.insert("c".into()));
+    }
+
+    #[test]
+    fn events_are_stored_until_relay_sends_eose() {
+        let relay_a = RelayUrl::parse("wss://a.example.com").unwrap();
+        let relay_b = RelayUrl::parse("wss://b.example.com").unwrap();
+        let sub = SubscriptionId::new("trade");
+        let other_sub = SubscriptionId::new("deletions");
+        let mut eose = EoseTracker::default();
+
+        assert_eq!(eose.phase(&relay_a, &sub), TradeListingEventPhase::Stored);
+        eose.mark_eose(relay_a.clone(), sub.clone());
+        assert_eq!(eose.phase(&relay_a, &sub), TradeListingEventPhase::Live);
+        assert_eq!(eose.phase(&relay_b, &sub), TradeListingEventPhase::Stored);
+        assert_eq!(
+            eose.phase(&relay_a, &other_sub),
+            TradeListingEventPhase::Stored
+        );
+    }
+}
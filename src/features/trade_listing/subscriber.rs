@@ -1,55 +1,229 @@
 #![forbid(unsafe_code)]
 
-use std::{sync::Arc, time::Duration};
+use std::{any::Any, collections::HashMap, pin::Pin, sync::Arc, time::Duration};
 
-use anyhow::{anyhow, Result};
+use anyhow::{Result, anyhow};
+use futures::StreamExt;
+use nostr::{Alphabet, RelayMessage, SingleLetterTag, SubscriptionId, Timestamp};
 use radroots_nostr::prelude::{
-    radroots_nostr_filter_new_events,
-    radroots_nostr_tags_resolve,
-    RadrootsNostrClient,
-    RadrootsNostrFilter,
-    RadrootsNostrKind,
-    RadrootsNostrKeys,
-    RadrootsNostrRelayPoolNotification,
+    RadrootsNostrClient, RadrootsNostrEvent, RadrootsNostrFilter, RadrootsNostrKeys,
+    RadrootsNostrKind, RadrootsNostrRelayPoolNotification, radroots_nostr_filter_new_events,
 };
-use tokio::sync::watch;
-use tokio::time::sleep;
-use tracing::{info, warn};
-
-use radroots_trade::listing::dvm_kinds::TRADE_LISTING_DVM_KINDS;
+use tokio::sync::{Semaphore, broadcast, watch};
+use tokio::task::{Id as TaskId, JoinError, JoinSet};
+use tokio::time::{Instant, Sleep, sleep};
+use tracing::{Instrument, error, info, info_span, warn};
 
+use crate::config::{EncryptionMode, SubscriberConfig, TradeConfig, served_kinds};
 use crate::features::trade_listing::{
-    handlers::dvm::{handle_error, handle_event, TradeListingDvmError},
-    state::TradeListingState,
+    dead_letter::DeadLetterJournal,
+    events::TradeEventSink,
+    handlers::{
+        dvm::{
+            TRADE_LISTING_KIND, TradeListingContext, TradeListingDvmError, TransitionHook,
+            dispatch_request, handle_error, handle_listing_deletion, request_span, unix_now,
+        },
+        registry::HandlerRegistry,
+    },
+    listing_cache::ListingCache,
+    state::{DEFAULT_ORDER_SHARDS, SharedTradeListingState, TradeListingState},
+    store::{PersistTrigger, TradeListingStore, TradeListingStoreError, persist_state},
+    stream::{TradeListingEvent, TradeListingEventPhase, subscribe_stream},
+    watermark::Watermark,
+};
+use crate::infra::{
+    event_cache::EventFetchCache,
+    invoices::invoice_issuer,
+    journal::EventJournal,
+    metrics,
+    nostr::{NostrPayloadLimits, SHUTDOWN_STEP_TIMEOUT, bounded_shutdown_step},
+    outbox::RelayListCache,
+    rates::rate_provider,
 };
 
+const STORE_FLUSH_TICK: Duration = Duration::from_secs(1);
+const ORDER_PRUNE_TICK: Duration = Duration::from_secs(60 * 60);
+const IDLE_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+// NIP-59 backdates gift wraps by up to two days to hide when they were sent.
+const GIFT_WRAP_BACKDATE_SECS: u64 = 2 * 24 * 60 * 60;
+// Handled wraps are remembered for twice the backdate window, which covers every
+// wrap a subscription can still fetch again.
+const GIFT_WRAP_RETENTION_SECS: u64 = 2 * GIFT_WRAP_BACKDATE_SECS;
+
+// Order state and its store outlive individual subscriptions, so a relay pool reset
+// resumes with every order handled so far.
+#[derive(Clone)]
+pub struct TradeListingShared {
+    pub state: Arc<SharedTradeListingState>,
+    pub store: Option<Arc<tokio::sync::Mutex<TradeListingStore>>>,
+}
+
+impl TradeListingShared {
+    pub fn load(trade_cfg: &TradeConfig) -> Result<Self, TradeListingStoreError> {
+        let mut store = trade_cfg
+            .store
+            .as_ref()
+            .map(|cfg| TradeListingStore::new(&cfg.path, cfg.snapshot));
+        let state = match &mut store {
+            Some(store) => store.load()?,
+            None => TradeListingState::default(),
+        };
+        Ok(Self {
+            state: Arc::new(SharedTradeListingState::new(state, DEFAULT_ORDER_SHARDS)),
+            store: store.map(|store| Arc::new(tokio::sync::Mutex::new(store))),
+        })
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SubscriberRuntime {
+    pub journal: Option<Arc<EventJournal>>,
+    pub events: Vec<Arc<dyn TradeEventSink>>,
+    pub on_transition: Option<TransitionHook>,
+    pub encryption: EncryptionMode,
+    pub dry_run: bool,
+    pub once: bool,
+}
+
+// A watermark or lookback re-delivers requests that may already have been handled
+// before a restart; the persisted order state keeps those replays idempotent, which is
+// why a lookback requires a store (`Configuration::validate`).
+fn resume_since(watermark: Option<&Watermark>, lookback_secs: u64) -> Option<Timestamp> {
+    if let Some(created_at) = watermark.and_then(Watermark::get) {
+        return Some(Timestamp::from(created_at));
+    }
+    match lookback_secs {
+        0 => None,
+        secs => Some(Timestamp::from(
+            Timestamp::now().as_u64().saturating_sub(secs),
+        )),
+    }
+}
+
+fn new_events_filter(filter: RadrootsNostrFilter, since: Option<Timestamp>) -> RadrootsNostrFilter {
+    match since {
+        Some(since) => filter.since(since),
+        None => radroots_nostr_filter_new_events(filter),
+    }
+}
+
+fn gift_wrap_filter(keys: &RadrootsNostrKeys, since: Option<Timestamp>) -> RadrootsNostrFilter {
+    let since = since.unwrap_or_else(Timestamp::now).as_u64();
+    RadrootsNostrFilter::new()
+        .kind(RadrootsNostrKind::GiftWrap)
+        .pubkey(keys.public_key())
+        .since(Timestamp::from(
+            since.saturating_sub(GIFT_WRAP_BACKDATE_SECS),
+        ))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn subscriber(
     client: RadrootsNostrClient,
     keys: RadrootsNostrKeys,
+    result_keys: RadrootsNostrKeys,
+    trade_cfg: Arc<TradeConfig>,
+    registry: Arc<HandlerRegistry>,
+    subscriber_cfg: &SubscriberConfig,
+    runtime: &SubscriberRuntime,
+    shared: &TradeListingShared,
     mut stop_rx: watch::Receiver<bool>,
 ) -> Result<()> {
-    info!(
-        "Starting subscriber for trade listing DVM kinds: {:?}",
-        TRADE_LISTING_DVM_KINDS
-    );
+    let enabled_kinds = served_kinds(&trade_cfg, subscriber_cfg);
+    info!("Starting subscriber for trade listing DVM kinds: {enabled_kinds:?}");
 
-    let kinds: Vec<RadrootsNostrKind> = TRADE_LISTING_DVM_KINDS
+    let kinds: Vec<RadrootsNostrKind> = enabled_kinds
         .iter()
         .map(|kind| RadrootsNostrKind::Custom(*kind))
         .collect();
-    let filter = radroots_nostr_filter_new_events(RadrootsNostrFilter::new().kinds(kinds));
+    let watermark = subscriber_cfg
+        .watermark_path
+        .as_ref()
+        .map(|path| Watermark::load(path).map(Arc::new))
+        .transpose()?;
+    let since = resume_since(watermark.as_deref(), subscriber_cfg.lookback_secs);
+    let filter = new_events_filter(RadrootsNostrFilter::new().kinds(kinds), since);
+    let deletion_filter = new_events_filter(
+        RadrootsNostrFilter::new()
+            .kind(RadrootsNostrKind::EventDeletion)
+            .custom_tag(
+                SingleLetterTag::lowercase(Alphabet::K),
+                TRADE_LISTING_KIND.to_string(),
+            ),
+        since,
+    );
 
     if *stop_rx.borrow() {
         return Ok(());
     }
 
-    let subscription = client.subscribe(filter, None).await?;
+    let limits = NostrPayloadLimits {
+        max_content_bytes: trade_cfg.limits.max_content_bytes,
+        max_decrypted_bytes: trade_cfg.limits.max_decrypted_bytes,
+    };
+    let mut filters = vec![filter, deletion_filter];
+    if runtime.encryption == EncryptionMode::Nip17 {
+        filters.push(gift_wrap_filter(&keys, since));
+    }
+    let mut subscription = subscribe_stream(
+        &client,
+        keys.clone(),
+        filters,
+        limits,
+        subscriber_cfg.startup_policy,
+        runtime.journal.clone(),
+    )
+    .await?;
 
-    let state = Arc::new(tokio::sync::Mutex::new(TradeListingState::default()));
-    let mut notifications = client.notifications();
+    let outbox = trade_cfg.outbox.as_ref().map(|cfg| {
+        Arc::new(RelayListCache::new(
+            Duration::from_secs(cfg.ttl_secs),
+            cfg.max_relays,
+        ))
+    });
+    let listing_cache = Arc::new(ListingCache::new(&trade_cfg.listing_cache));
+    let rates = rate_provider(trade_cfg.rates.as_ref())?;
+    let invoices = invoice_issuer(trade_cfg.payment.as_ref())?;
+    let ctx = TradeListingContext {
+        client: client.clone(),
+        keys: keys.clone(),
+        result_keys,
+        state: Arc::clone(&shared.state),
+        config: trade_cfg,
+        registry,
+        store: shared.store.clone(),
+        journal: runtime.journal.clone(),
+        outbox,
+        event_cache: Arc::new(EventFetchCache::default()),
+        listing_cache,
+        rates,
+        invoices,
+        events: runtime.events.clone(),
+        on_transition: runtime.on_transition.clone(),
+        encryption: runtime.encryption,
+        dry_run: runtime.dry_run,
+    };
+    let mut flush_tick = tokio::time::interval(STORE_FLUSH_TICK);
+    let mut prune_tick = tokio::time::interval(ORDER_PRUNE_TICK);
+    let order_retention_secs = ctx.config.limits.order_retention_secs;
 
+    let dead_letter = subscriber_cfg
+        .dead_letter_path
+        .as_ref()
+        .map(|path| Arc::new(DeadLetterJournal::new(path)));
+    let backlog = subscriber_cfg
+        .backlog_concurrency
+        .map(|permits| Arc::new(Semaphore::new(permits.max(1))));
+    let mut tasks = JoinSet::new();
+    let mut task_events: HashMap<TaskId, String> = HashMap::new();
     let mut stop_requested = false;
     let mut notifications_closed = false;
+    let idle_limit = subscriber_cfg
+        .idle_reconnect_secs
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    let mut idle = idle_limit.map(|limit| Box::pin(sleep(limit)));
+    let mut idle_expired = false;
 
     loop {
         tokio::select! {
@@ -57,59 +231,388 @@ pub async fn subscriber(
                 stop_requested = true;
                 break;
             }
-            msg = notifications.recv() => {
-                let n = match msg {
-                    Ok(n) => n,
-                    Err(_) => {
-                        notifications_closed = true;
-                        break;
+            () = wait_idle(&mut idle) => {
+                if relays_answer(&client, &keys, IDLE_PROBE_TIMEOUT).await {
+                    if let (Some(idle), Some(limit)) = (idle.as_mut(), idle_limit) {
+                        idle.as_mut().reset(Instant::now() + limit);
                     }
+                    continue;
+                }
+                idle_expired = true;
+                break;
+            }
+            Some(joined) = tasks.join_next_with_id(), if !tasks.is_empty() => {
+                reap_handler(joined, &mut task_events, &tasks);
+            }
+            _ = flush_tick.tick(), if ctx.store.is_some() || watermark.is_some() => {
+                persist(&ctx, PersistTrigger::Tick).await;
+                flush_watermark(watermark.as_deref());
+            }
+            _ = prune_tick.tick(), if order_retention_secs > 0 => {
+                let pruned = ctx.state.prune_orders(unix_now(), order_retention_secs).await;
+                if pruned > 0 {
+                    info!("trade_listing: pruned {pruned} finished orders");
+                }
+            }
+            item = subscription.events.next() => {
+                let Some(item) = item else {
+                    notifications_closed = true;
+                    break;
                 };
+                if let (Some(idle), Some(limit)) = (idle.as_mut(), idle_limit) {
+                    idle.as_mut().reset(Instant::now() + limit);
+                }
+                // Every subscribe fetches gift wraps two days back, so reconnects see
+                // the wraps handled before them again.
+                if let Some(wrap) = item.gift_wrap() {
+                    let wrap_id = wrap.id.to_string();
+                    if ctx.state.listings().read().await.is_gift_wrap_handled(&wrap_id) {
+                        continue;
+                    }
+                }
 
-                if let RadrootsNostrRelayPoolNotification::Event { event, .. } = n {
-                    let event = (*event).clone();
-                    let keys = keys.clone();
-                    let client = client.clone();
-                    let state = Arc::clone(&state);
-
-                    tokio::spawn(async move {
-                        if cfg!(debug_assertions) {
-                            sleep(Duration::from_millis(200)).await;
-                        }
-
-                        let resolved_tags = match radroots_nostr_tags_resolve(&event, &keys) {
-                            Ok(tags) => tags,
-                            Err(err) => {
-                                warn!("trade_listing: failed to resolve tags: {err}");
-                                return;
-                            }
+                let ctx = ctx.clone();
+                let dead_letter = dead_letter.clone();
+                let event = match &item {
+                    TradeListingEvent::Request { request, .. } => &request.event,
+                    TradeListingEvent::Rejected { event, .. } => event,
+                    TradeListingEvent::Deletion(event) => event,
+                };
+                let event_id = event.id.to_string();
+                // Rejected events are never handled, so they don't move the watermark.
+                let mark = match &item {
+                    TradeListingEvent::Rejected { .. } => None,
+                    _ => watermark
+                        .as_ref()
+                        .map(|w| w.track(event.created_at.as_u64(), unix_now())),
+                };
+                // `--once` waits for a live request; backlog replays, rejections and
+                // deletions don't count.
+                let live_request = matches!(
+                    item,
+                    TradeListingEvent::Request {
+                        phase: TradeListingEventPhase::Live,
+                        ..
+                    }
+                );
+                let handle = match item {
+                    TradeListingEvent::Request {
+                        request,
+                        phase,
+                        gift_wrap,
+                    } => {
+                        let backlog = match phase {
+                            TradeListingEventPhase::Stored => backlog.clone(),
+                            TradeListingEventPhase::Live => None,
                         };
+                        let span = request_span(&request);
+                        let task = async move {
+                            let _permit = match backlog {
+                                Some(backlog) => backlog.acquire_owned().await.ok(),
+                                None => None,
+                            };
+                            if cfg!(debug_assertions) {
+                                sleep(Duration::from_millis(200)).await;
+                            }
 
-                        if let Err(err) =
-                            handle_event(event.clone(), resolved_tags, keys, client.clone(), state).await
-                        {
-                            match err {
-                                TradeListingDvmError::MissingRecipient
-                                | TradeListingDvmError::UnsupportedKind => {}
-                                other => {
-                                    if let Err(err) = handle_error(other, &event, &client).await {
-                                        warn!("trade_listing: failed to send error feedback: {err}");
-                                    }
+                            let event = request.event.clone();
+                            let res = dispatch_request(request, &ctx).await;
+                            let handled = res.as_ref().err().is_none_or(|err| !err.is_transient());
+                            if let Some(wrap) = gift_wrap.as_ref().filter(|_| handled) {
+                                record_gift_wrap(&ctx, wrap).await;
+                            }
+                            persist(&ctx, PersistTrigger::Mutation).await;
+                            match (res, mark) {
+                                (Ok(()), Some(mark)) => mark.handled(),
+                                (Ok(()), None) => {}
+                                (Err(err), _) => {
+                                    let wrap = gift_wrap.as_ref();
+                                    let dead_letter = dead_letter.as_deref();
+                                    report_failure(err, &event, wrap, &ctx, dead_letter).await;
                                 }
                             }
-                        }
-                    });
+                        };
+                        tasks.spawn(task.instrument(span))
+                    }
+                    TradeListingEvent::Rejected {
+                        event,
+                        error,
+                        gift_wrap,
+                    } => {
+                        let span = info_span!("trade_listing", request_id = %event.id);
+                        let task = async move {
+                            let handled = !error.is_transient();
+                            let dead_letter = dead_letter.as_deref();
+                            report_failure(error, &event, gift_wrap.as_ref(), &ctx, dead_letter)
+                                .await;
+                            if let Some(wrap) = gift_wrap.as_ref().filter(|_| handled) {
+                                record_gift_wrap(&ctx, wrap).await;
+                                persist(&ctx, PersistTrigger::Mutation).await;
+                            }
+                        };
+                        tasks.spawn(task.instrument(span))
+                    }
+                    TradeListingEvent::Deletion(event) => {
+                        tasks.spawn(async move {
+                            handle_listing_deletion(&event, &ctx).await;
+                            persist(&ctx, PersistTrigger::Mutation).await;
+                            if let Some(mark) = mark {
+                                mark.handled();
+                            }
+                        })
+                    }
+                };
+                task_events.insert(handle.id(), event_id);
+                metrics::set_handlers_in_flight(tasks.len());
+                if runtime.once && live_request {
+                    break;
                 }
             }
         }
     }
 
-    client.unsubscribe(&subscription.val).await;
+    let unsubscribe = async {
+        for id in &subscription.ids {
+            client.unsubscribe(id).await;
+        }
+    };
+    bounded_shutdown_step(
+        "trade_listing: unsubscribe",
+        SHUTDOWN_STEP_TIMEOUT,
+        unsubscribe,
+    )
+    .await;
+    if !tasks.is_empty() {
+        info!("trade_listing: draining {} in-flight handlers", tasks.len());
+    }
+    while let Some(joined) = tasks.join_next_with_id().await {
+        reap_handler(joined, &mut task_events, &tasks);
+    }
+    persist(&ctx, PersistTrigger::Shutdown).await;
+    flush_watermark(watermark.as_deref());
     if stop_requested {
         return Ok(());
     }
     if notifications_closed {
         return Err(anyhow!("trade_listing subscriber notifications closed"));
     }
+    if idle_expired {
+        // A half-open socket never errors, so the relays are dropped here and the
+        // retry loop reconnects them from scratch.
+        let disconnect = client.disconnect();
+        bounded_shutdown_step(
+            "trade_listing: disconnect",
+            SHUTDOWN_STEP_TIMEOUT,
+            disconnect,
+        )
+        .await;
+        return Err(anyhow!(
+            "trade_listing relays sent nothing for {:?} and did not answer a probe; reconnecting",
+            idle_limit.unwrap_or_default()
+        ));
+    }
     Ok(())
 }
+
+// A quiet relay sends nothing after EOSE, so silence alone doesn't mean the socket is
+// dead. Before reconnecting, the relays are asked for nothing; an EOSE back shows the
+// connection still answers.
+async fn relays_answer(
+    client: &RadrootsNostrClient,
+    keys: &RadrootsNostrKeys,
+    timeout: Duration,
+) -> bool {
+    let notifications = client.notifications();
+    let probe = RadrootsNostrFilter::new()
+        .author(keys.public_key())
+        .limit(0);
+    let Ok(output) = client.subscribe(probe, None).await else {
+        return false;
+    };
+    let answered = tokio::time::timeout(timeout, wait_for_eose(notifications, &output.val))
+        .await
+        .unwrap_or(false);
+    client.unsubscribe(&output.val).await;
+    answered
+}
+
+async fn wait_for_eose(
+    mut notifications: broadcast::Receiver<RadrootsNostrRelayPoolNotification>,
+    subscription_id: &SubscriptionId,
+) -> bool {
+    loop {
+        match notifications.recv().await {
+            Ok(RadrootsNostrRelayPoolNotification::Message {
+                message: RelayMessage::EndOfStoredEvents(id),
+                ..
+            }) if *id == *subscription_id => return true,
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return false,
+        }
+    }
+}
+
+async fn wait_idle(idle: &mut Option<Pin<Box<Sleep>>>) {
+    match idle {
+        Some(idle) => idle.as_mut().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn reap_handler(
+    joined: Result<(TaskId, ()), JoinError>,
+    task_events: &mut HashMap<TaskId, String>,
+    tasks: &JoinSet<()>,
+) {
+    metrics::set_handlers_in_flight(tasks.len());
+    let err = match joined {
+        Ok((id, ())) => {
+            task_events.remove(&id);
+            return;
+        }
+        Err(err) => err,
+    };
+    let event_id = task_events
+        .remove(&err.id())
+        .unwrap_or_else(|| "unknown".to_string());
+    if !err.is_panic() {
+        warn!(event_id = %event_id, "trade_listing: handler task cancelled: {err}");
+        return;
+    }
+    metrics::record_handler_panic();
+    let message = panic_message(err.into_panic());
+    error!(event_id = %event_id, "trade_listing: handler panicked: {message}");
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => (*message).to_string(),
+            Err(_) => "non-string panic payload".to_string(),
+        },
+    }
+}
+
+async fn record_gift_wrap(ctx: &TradeListingContext, wrap: &RadrootsNostrEvent) {
+    let oldest = unix_now().saturating_sub(GIFT_WRAP_RETENTION_SECS);
+    let created_at = wrap.created_at.as_u64();
+    ctx.state
+        .listings_mut()
+        .await
+        .record_gift_wrap(wrap.id.to_string(), created_at, oldest);
+}
+
+// Requests that came gift-wrapped are dead-lettered as the wrap, so the journal never
+// holds their plaintext and replay can unwrap them like the live stream does.
+async fn report_failure(
+    err: TradeListingDvmError,
+    event: &RadrootsNostrEvent,
+    gift_wrap: Option<&RadrootsNostrEvent>,
+    ctx: &TradeListingContext,
+    dead_letter: Option<&DeadLetterJournal>,
+) {
+    let transient = err.is_transient();
+    let message = err.to_string();
+    let failure = match handle_error(err, event, ctx).await {
+        Ok(()) if !transient => return,
+        Ok(()) => message,
+        Err(feedback_err) => {
+            warn!("trade_listing: failed to send error feedback: {feedback_err}");
+            format!("{message}; feedback failed: {feedback_err}")
+        }
+    };
+    let Some(journal) = dead_letter else {
+        return;
+    };
+    if let Err(err) = journal.append(gift_wrap.unwrap_or(event), &failure) {
+        warn!(
+            "trade_listing: failed to record dead letter in {}: {err}",
+            journal.path().display()
+        );
+    }
+}
+
+fn flush_watermark(watermark: Option<&Watermark>) {
+    let Some(watermark) = watermark else {
+        return;
+    };
+    if let Err(err) = watermark.flush() {
+        warn!(
+            "trade_listing: failed to persist watermark to {}: {err}",
+            watermark.path().display()
+        );
+    }
+}
+
+async fn persist(ctx: &TradeListingContext, trigger: PersistTrigger) {
+    if let Some(store) = &ctx.store {
+        persist_state(store, &ctx.state, trigger).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use nostr::{RelayMessage, RelayUrl, SubscriptionId};
+    use radroots_nostr::prelude::RadrootsNostrRelayPoolNotification;
+    use tokio::{sync::broadcast, task::JoinSet, time::timeout};
+
+    use super::{reap_handler, wait_for_eose, wait_idle};
+    use crate::infra::metrics;
+
+    fn eose(subscription_id: &SubscriptionId) -> RadrootsNostrRelayPoolNotification {
+        RadrootsNostrRelayPoolNotification::Message {
+            relay_url: RelayUrl::parse("wss://relay.example.com").unwrap(),
+            message: RelayMessage::eose(subscription_id.clone()),
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_watchdog_fires_only_when_enabled() {
+        let mut disabled = None;
+        assert!(
+            timeout(Duration::from_millis(20), wait_idle(&mut disabled))
+                .await
+                .is_err()
+        );
+
+        let mut idle = Some(Box::pin(tokio::time::sleep(Duration::from_millis(5))));
+        assert!(
+            timeout(Duration::from_secs(1), wait_idle(&mut idle))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_probe_needs_an_eose_for_its_own_subscription() {
+        let probe = SubscriptionId::new("probe");
+        let (tx, rx) = broadcast::channel(8);
+        tx.send(eose(&SubscriptionId::new("trade"))).unwrap();
+        tx.send(eose(&probe)).unwrap();
+        assert!(wait_for_eose(rx, &probe).await);
+
+        let (tx, rx) = broadcast::channel(8);
+        tx.send(eose(&SubscriptionId::new("trade"))).unwrap();
+        drop(tx);
+        assert!(!wait_for_eose(rx, &probe).await);
+    }
+
+    #[tokio::test]
+    async fn panicking_handler_is_reaped_and_counted() {
+        let before = metrics::snapshot().handler_panics;
+        let mut tasks = JoinSet::new();
+        let mut task_events = HashMap::new();
+        let handle = tasks.spawn(async { panic!("handler exploded") });
+        task_events.insert(handle.id(), "event-1".to_string());
+
+        let joined = tasks.join_next_with_id().await.expect("joined task");
+        assert!(joined.as_ref().is_err_and(|err| err.is_panic()));
+        reap_handler(joined, &mut task_events, &tasks);
+
+        assert!(task_events.is_empty());
+        assert!(metrics::snapshot().handler_panics > before);
+    }
+}
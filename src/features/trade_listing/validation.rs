@@ -0,0 +1,334 @@
+#![forbid(unsafe_code)]
+
+use radroots_nostr::prelude::RadrootsNostrEvent;
+use radroots_trade::listing::{
+    dvm::TradeListingValidateResult, validation::TradeListingValidationError,
+};
+use serde::Serialize;
+use thiserror::Error;
+
+// Sent in place of the bare `TradeListingValidateResult`: `valid` and `errors` keep
+// their shape for existing clients, and `issues` adds one entry per error.
+#[derive(Debug, Serialize)]
+pub struct ListingValidateResultDetail {
+    #[serde(flatten)]
+    pub result: TradeListingValidateResult,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ListingValidateResultDetail {
+    pub fn new(errors: Vec<TradeListingValidationError>) -> Self {
+        let issues = errors.iter().map(ValidationIssue::from_error).collect();
+        Self {
+            result: TradeListingValidateResult {
+                valid: errors.is_empty(),
+                errors,
+            },
+            issues,
+        }
+    }
+
+    pub fn unavailable(error: &ListingAvailabilityError) -> Self {
+        Self::rejected(error.issue())
+    }
+
+    pub fn mismatched(error: &ListingAddressError) -> Self {
+        Self::rejected(error.issue())
+    }
+
+    fn rejected(issue: ValidationIssue) -> Self {
+        let mut detail = Self::new(Vec::new());
+        detail.result.valid = false;
+        detail.issues.push(issue);
+        detail
+    }
+}
+
+// The fetched listing must be the one the address names. A mismatch is a finding
+// about the listing, so the validate stage reports it through `issues`.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ListingAddressError {
+    #[error("listing kind or identifier does not match the address")]
+    CoordinateMismatch,
+    #[error("listing is not signed by the seller in the address")]
+    AuthorMismatch,
+    #[error("listing has been deleted by its seller")]
+    Deleted,
+}
+
+impl ListingAddressError {
+    fn issue(&self) -> ValidationIssue {
+        let (code, field) = match self {
+            Self::CoordinateMismatch => ("listing_coordinate_mismatch", Some("listing_addr")),
+            Self::AuthorMismatch => ("listing_author_mismatch", Some("pubkey")),
+            Self::Deleted => ("listing_deleted", None),
+        };
+        ValidationIssue {
+            code: code.to_string(),
+            field: field.map(str::to_string),
+            message: self.to_string(),
+        }
+    }
+}
+
+// Listing availability is not part of `validate_listing_event`, so it is checked
+// here and reported only through `issues`.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ListingAvailabilityError {
+    #[error("listing expired at {expired_at}")]
+    Expired { expired_at: u64 },
+    #[error("listing is not available (status: {status})")]
+    Unavailable { status: String },
+}
+
+impl ListingAvailabilityError {
+    fn issue(&self) -> ValidationIssue {
+        let (code, field) = match self {
+            Self::Expired { .. } => ("listing_expired", "expiration"),
+            Self::Unavailable { .. } => ("listing_unavailable", "status"),
+        };
+        ValidationIssue {
+            code: code.to_string(),
+            field: Some(field.to_string()),
+            message: self.to_string(),
+        }
+    }
+}
+
+// Honors the NIP-40 `expiration` tag and the NIP-99 `status` tag; any status other
+// than `active` (such as `sold`) stops new orders.
+pub fn check_listing_availability(
+    listing: &RadrootsNostrEvent,
+    now: u64,
+) -> Result<(), ListingAvailabilityError> {
+    for tag in listing.tags.iter() {
+        match tag.as_slice() {
+            [name, value, ..] if name == "expiration" => {
+                if let Some(expired_at) = value.parse::<u64>().ok().filter(|at| *at <= now) {
+                    return Err(ListingAvailabilityError::Expired { expired_at });
+                }
+            }
+            [name, value, ..] if name == "status" && !value.eq_ignore_ascii_case("active") => {
+                return Err(ListingAvailabilityError::Unavailable {
+                    status: value.clone(),
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// `code` is stable across releases, whatever the upstream variant is called.
+// `field` names the listing tag or reference a client should highlight, when the
+// error points at one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ValidationIssue {
+    pub code: String,
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl ValidationIssue {
+    pub fn from_error(error: &TradeListingValidationError) -> Self {
+        let (code, field) = issue_code(error);
+        Self {
+            code: code.to_string(),
+            field: field.map(str::to_string),
+            message: error.to_string(),
+        }
+    }
+}
+
+// Spelled out per variant, with no catch-all, so a variant added upstream has to be
+// given a code here before this builds.
+fn issue_code(error: &TradeListingValidationError) -> (&'static str, Option<&'static str>) {
+    use TradeListingValidationError::*;
+    match error {
+        InvalidKind { .. } => ("invalid_kind", Some("kind")),
+        MissingListingId => ("missing_listing_id", Some("d")),
+        ListingEventNotFound { .. } => ("listing_event_not_found", Some("listing_event")),
+        ListingEventFetchFailed { .. } => ("listing_event_fetch_failed", Some("listing_event")),
+        ParseError { .. } => ("parse_error", None),
+        InvalidSeller => ("invalid_seller", Some("pubkey")),
+        MissingFarmProfile => ("missing_farm_profile", Some("farm")),
+        MissingFarmRecord => ("missing_farm_record", Some("farm")),
+        MissingTitle => ("missing_title", Some("title")),
+        MissingDescription => ("missing_description", Some("description")),
+        MissingProductType => ("missing_product_type", Some("product_type")),
+        MissingBins => ("missing_bins", Some("bins")),
+        MissingPrimaryBin => ("missing_primary_bin", Some("primary_bin")),
+        InvalidBin => ("invalid_bin", Some("bins")),
+        MissingPrice => ("missing_price", Some("price")),
+        InvalidPrice => ("invalid_price", Some("price")),
+        MissingInventory => ("missing_inventory", Some("inventory")),
+        InvalidInventory => ("invalid_inventory", Some("inventory")),
+        MissingAvailability => ("missing_availability", Some("availability")),
+        MissingLocation => ("missing_location", Some("location")),
+        MissingDeliveryMethod => ("missing_delivery_method", Some("delivery_method")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{
+        ListingAddressError, ListingAvailabilityError, ListingValidateResultDetail,
+        ValidationIssue, check_listing_availability,
+    };
+    use nostr::{EventBuilder, Kind, Tag};
+    use radroots_nostr::prelude::{RadrootsNostrEvent, RadrootsNostrKeys};
+    use radroots_trade::listing::{
+        parse::TradeListingParseError, validation::TradeListingValidationError,
+    };
+    use serde_json::json;
+
+    fn listing(tags: &[[&str; 2]]) -> RadrootsNostrEvent {
+        EventBuilder::new(Kind::Custom(30402), "listing")
+            .tags(tags.iter().map(|tag| Tag::parse(*tag).unwrap()))
+            .sign_with_keys(&RadrootsNostrKeys::generate())
+            .unwrap()
+    }
+
+    #[test]
+    fn every_validation_error_has_its_own_code() {
+        use TradeListingValidationError::*;
+        let addr = || "30402:seller:listing".to_string();
+        let cases = [
+            (InvalidKind { kind: 1 }, "invalid_kind", Some("kind")),
+            (MissingListingId, "missing_listing_id", Some("d")),
+            (
+                ListingEventNotFound {
+                    listing_addr: addr(),
+                },
+                "listing_event_not_found",
+                Some("listing_event"),
+            ),
+            (
+                ListingEventFetchFailed {
+                    listing_addr: addr(),
+                },
+                "listing_event_fetch_failed",
+                Some("listing_event"),
+            ),
+            (
+                ParseError {
+                    error: TradeListingParseError::InvalidJson("eof".into()),
+                },
+                "parse_error",
+                None,
+            ),
+            (InvalidSeller, "invalid_seller", Some("pubkey")),
+            (MissingFarmProfile, "missing_farm_profile", Some("farm")),
+            (MissingFarmRecord, "missing_farm_record", Some("farm")),
+            (MissingTitle, "missing_title", Some("title")),
+            (
+                MissingDescription,
+                "missing_description",
+                Some("description"),
+            ),
+            (
+                MissingProductType,
+                "missing_product_type",
+                Some("product_type"),
+            ),
+            (MissingBins, "missing_bins", Some("bins")),
+            (
+                MissingPrimaryBin,
+                "missing_primary_bin",
+                Some("primary_bin"),
+            ),
+            (InvalidBin, "invalid_bin", Some("bins")),
+            (MissingPrice, "missing_price", Some("price")),
+            (InvalidPrice, "invalid_price", Some("price")),
+            (MissingInventory, "missing_inventory", Some("inventory")),
+            (InvalidInventory, "invalid_inventory", Some("inventory")),
+            (
+                MissingAvailability,
+                "missing_availability",
+                Some("availability"),
+            ),
+            (MissingLocation, "missing_location", Some("location")),
+            (
+                MissingDeliveryMethod,
+                "missing_delivery_method",
+                Some("delivery_method"),
+            ),
+        ];
+
+        let mut codes = HashSet::new();
+        for (error, code, field) in cases {
+            let issue = ValidationIssue::from_error(&error);
+            assert_eq!(issue.code, code);
+            assert_eq!(issue.field.as_deref(), field);
+            assert_eq!(issue.message, error.to_string());
+            assert!(codes.insert(code), "duplicate code {code}");
+        }
+    }
+
+    #[test]
+    fn detail_keeps_the_existing_result_fields() {
+        let detail = ListingValidateResultDetail::new(vec![
+            TradeListingValidationError::MissingFarmProfile,
+            TradeListingValidationError::ListingEventNotFound {
+                listing_addr: "30402:seller:listing".to_string(),
+            },
+        ]);
+        let value = serde_json::to_value(&detail).unwrap();
+
+        assert_eq!(value["valid"], false);
+        assert_eq!(value["errors"].as_array().unwrap().len(), 2);
+        assert_eq!(value["issues"][0]["code"], "missing_farm_profile");
+        assert_eq!(value["issues"][0]["field"], "farm");
+        assert_eq!(value["issues"][1]["code"], "listing_event_not_found");
+        assert_eq!(value["issues"][1]["field"], "listing_event");
+
+        let valid = serde_json::to_value(ListingValidateResultDetail::new(Vec::new())).unwrap();
+        assert_eq!(valid["valid"], true);
+        assert_eq!(valid["issues"], json!([]));
+    }
+
+    #[test]
+    fn expired_and_sold_listings_are_unavailable() {
+        let now = 1_700_000_000;
+        assert!(check_listing_availability(&listing(&[["d", "listing"]]), now).is_ok());
+        assert!(check_listing_availability(&listing(&[["status", "active"]]), now).is_ok());
+        assert!(check_listing_availability(&listing(&[["expiration", "1700000001"]]), now).is_ok());
+
+        let err = check_listing_availability(&listing(&[["expiration", "1699999999"]]), now);
+        assert_eq!(
+            err,
+            Err(ListingAvailabilityError::Expired {
+                expired_at: 1_699_999_999
+            })
+        );
+        let err = check_listing_availability(&listing(&[["status", "sold"]]), now).unwrap_err();
+        assert_eq!(
+            err,
+            ListingAvailabilityError::Unavailable {
+                status: "sold".into()
+            }
+        );
+
+        let detail = serde_json::to_value(ListingValidateResultDetail::unavailable(&err)).unwrap();
+        assert_eq!(detail["valid"], false);
+        assert_eq!(detail["issues"][0]["code"], "listing_unavailable");
+        assert_eq!(detail["issues"][0]["field"], "status");
+    }
+
+    #[test]
+    fn address_mismatches_are_reported_as_issues() {
+        let detail = ListingValidateResultDetail::mismatched(&ListingAddressError::AuthorMismatch);
+        let value = serde_json::to_value(&detail).unwrap();
+
+        assert_eq!(value["valid"], false);
+        assert_eq!(value["errors"], json!([]));
+        assert_eq!(value["issues"][0]["code"], "listing_author_mismatch");
+        assert_eq!(value["issues"][0]["field"], "pubkey");
+
+        let deleted = ListingValidateResultDetail::mismatched(&ListingAddressError::Deleted);
+        assert_eq!(deleted.issues[0].code, "listing_deleted");
+        assert_eq!(deleted.issues[0].field, None);
+    }
+}
@@ -0,0 +1,209 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum WatermarkError {
+    #[error("watermark io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("watermark serde error: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WatermarkFile {
+    created_at: u64,
+}
+
+#[derive(Debug, Default)]
+struct WatermarkState {
+    latest: u64,
+    persisted: u64,
+    in_flight: BTreeMap<u64, usize>,
+}
+
+impl WatermarkState {
+    // Handlers finish out of order, so the mark never moves past the oldest event
+    // still in flight. Subscriptions resume at the mark inclusively and rely on the
+    // order state to ignore events that were already handled.
+    fn mark(&self) -> u64 {
+        match self.in_flight.keys().next() {
+            Some(oldest) => self.latest.min(*oldest),
+            None => self.latest,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Watermark {
+    path: PathBuf,
+    state: Mutex<WatermarkState>,
+}
+
+impl Watermark {
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, WatermarkError> {
+        let path = path.into();
+        let persisted = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<WatermarkFile>(&bytes)?.created_at,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path,
+            state: Mutex::new(WatermarkState {
+                latest: persisted,
+                persisted,
+                in_flight: BTreeMap::new(),
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        let mark = self.lock().mark();
+        (mark > 0).then_some(mark)
+    }
+
+    // `created_at` is chosen by the sender, so it is clamped to `now`: a future-dated
+    // event must not move the mark past requests that have yet to arrive.
+    pub fn track(self: &Arc<Self>, created_at: u64, now: u64) -> WatermarkGuard {
+        let created_at = created_at.min(now);
+        *self.lock().in_flight.entry(created_at).or_default() += 1;
+        WatermarkGuard {
+            watermark: Arc::clone(self),
+            created_at,
+            handled: false,
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        let state = self.lock();
+        state.mark() > state.persisted
+    }
+
+    pub fn flush(&self) -> Result<bool, WatermarkError> {
+        let mut state = self.lock();
+        let mark = state.mark();
+        if mark <= state.persisted {
+            return Ok(false);
+        }
+        if let Some(parent) = self.path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(
+            &tmp,
+            serde_json::to_vec(&WatermarkFile { created_at: mark })?,
+        )?;
+        fs::rename(&tmp, &self.path)?;
+        state.persisted = mark;
+        Ok(true)
+    }
+
+    fn finish(&self, created_at: u64, handled: bool) {
+        let mut state = self.lock();
+        if let Some(count) = state.in_flight.get_mut(&created_at) {
+            *count -= 1;
+            if *count == 0 {
+                state.in_flight.remove(&created_at);
+            }
+        }
+        if handled {
+            state.latest = state.latest.max(created_at);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WatermarkState> {
+        self.state.lock().unwrap_or_else(|p| p.into_inner())
+    }
+}
+
+// Releases the event on drop, so a panicking handler cannot pin the watermark. Only
+// events marked as handled advance it.
+#[derive(Debug)]
+pub struct WatermarkGuard {
+    watermark: Arc<Watermark>,
+    created_at: u64,
+    handled: bool,
+}
+
+impl WatermarkGuard {
+    pub fn handled(mut self) {
+        self.handled = true;
+    }
+}
+
+impl Drop for WatermarkGuard {
+    fn drop(&mut self) {
+        self.watermark.finish(self.created_at, self.handled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Watermark;
+
+    const NOW: u64 = 1_000;
+
+    fn watermark() -> Arc<Watermark> {
+        let path =
+            std::env::temp_dir().join(format!("rhi-watermark-{}.json", uuid::Uuid::new_v4()));
+        Arc::new(Watermark::load(path).unwrap())
+    }
+
+    #[test]
+    fn missing_file_has_no_watermark() {
+        let watermark = watermark();
+        assert_eq!(watermark.get(), None);
+        assert!(!watermark.flush().unwrap());
+    }
+
+    #[test]
+    fn watermark_waits_for_older_in_flight_events() {
+        let watermark = watermark();
+        let older = watermark.track(100, NOW);
+        watermark.track(200, NOW).handled();
+        assert_eq!(watermark.get(), Some(100));
+
+        older.handled();
+        assert_eq!(watermark.get(), Some(200));
+        assert!(watermark.is_dirty());
+        assert!(watermark.flush().unwrap());
+        assert!(!watermark.is_dirty());
+
+        let reloaded = Watermark::load(watermark.path()).unwrap();
+        assert_eq!(reloaded.get(), Some(200));
+
+        let _ = std::fs::remove_file(watermark.path());
+    }
+
+    #[test]
+    fn future_dated_events_are_clamped_to_now() {
+        let watermark = watermark();
+        watermark.track(NOW + 86_400, NOW).handled();
+        assert_eq!(watermark.get(), Some(NOW));
+    }
+
+    #[test]
+    fn unhandled_events_do_not_advance_the_watermark() {
+        let watermark = watermark();
+        watermark.track(100, NOW).handled();
+        drop(watermark.track(200, NOW));
+        assert_eq!(watermark.get(), Some(100));
+    }
+}
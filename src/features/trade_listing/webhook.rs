@@ -0,0 +1,102 @@
+#![forbid(unsafe_code)]
+
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use nostr::hashes::{
+    Hash, HashEngine,
+    hmac::{Hmac, HmacEngine},
+    sha256,
+};
+use radroots_runtime::{Backoff, BackoffConfig};
+use tracing::warn;
+
+use crate::config::WebhookConfig;
+use crate::features::trade_listing::events::{
+    TradeEventSink, TradeEventSinkError, TradeStatusChanged,
+};
+
+pub const SIGNATURE_HEADER: &str = "X-Rhi-Signature";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Posts each status change, retrying with backoff up to `max_attempts`. Run it
+// behind a `QueuedSink` so the retries never hold up a handler.
+pub struct WebhookSink {
+    client: reqwest::Client,
+    url: String,
+    secret: String,
+    max_attempts: u32,
+    backoff: BackoffConfig,
+}
+
+impl WebhookSink {
+    pub fn new(cfg: &WebhookConfig) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: cfg.url.clone(),
+            secret: cfg.secret.clone(),
+            max_attempts: cfg.max_attempts,
+            backoff: cfg.backoff.clone(),
+        }
+    }
+}
+
+impl TradeEventSink for WebhookSink {
+    fn publish(&self, event: TradeStatusChanged) -> BoxFuture<'_, Result<(), TradeEventSinkError>> {
+        Box::pin(async move {
+            let body = serde_json::to_vec(&event)?;
+            let signature = sign_payload(self.secret.as_bytes(), &body);
+            let mut backoff = Backoff::new(self.backoff.clone());
+            let mut attempt = 1;
+            loop {
+                let res = self
+                    .client
+                    .post(&self.url)
+                    .timeout(REQUEST_TIMEOUT)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .header(SIGNATURE_HEADER, &signature)
+                    .body(body.clone())
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                match res {
+                    Ok(_) => return Ok(()),
+                    Err(e) if attempt < self.max_attempts => {
+                        let delay = backoff.next_delay();
+                        warn!(
+                            "webhook: attempt {attempt} for {} failed, retrying in {delay:?}: {e}",
+                            event.order_id
+                        );
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    Err(e) => {
+                        return Err(TradeEventSinkError::Webhook(format!(
+                            "giving up after {attempt} attempts: {e}"
+                        )));
+                    }
+                }
+            }
+        })
+    }
+}
+
+pub fn sign_payload(secret: &[u8], body: &[u8]) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret);
+    engine.input(body);
+    format!("sha256={}", Hmac::<sha256::Hash>::from_engine(engine))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sign_payload;
+
+    #[test]
+    fn payload_signature_is_hmac_sha256() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign_payload(b"Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
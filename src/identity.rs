@@ -0,0 +1,259 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail};
+use nostr::{Keys, nips::nip06::FromMnemonic, nips::nip19::ToBech32};
+use radroots_identity::RadrootsIdentity;
+use radroots_nostr::prelude::RadrootsNostrMetadata;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{
+    add_relays, cli::IdentityCommand, cli_args, config::Settings, publish_announcements, rhi::Rhi,
+};
+
+pub const DEFAULT_IDENTITY_PATH: &str = "identity.json";
+
+pub async fn run_identity_command(
+    command: &IdentityCommand,
+    settings: &Settings,
+    args: &cli_args,
+) -> Result<()> {
+    let path = args
+        .identity
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_IDENTITY_PATH));
+    let path = path.as_path();
+    match command {
+        IdentityCommand::FromMnemonic {
+            mnemonic,
+            passphrase,
+            account,
+            force,
+        } => {
+            let mnemonic = match mnemonic {
+                Some(mnemonic) => mnemonic.clone(),
+                None => read_mnemonic_from_stdin()?,
+            };
+            let keys = keys_from_mnemonic(&mnemonic, passphrase.as_deref(), *account)?;
+            write_identity(path, &keys, *force)?;
+            println!("{}", keys.public_key());
+            Ok(())
+        }
+        IdentityCommand::Rotate {
+            announce_on_old_key,
+        } => rotate_identity(path, settings, args, *announce_on_old_key).await,
+    }
+}
+
+// Rotation only moves the daemon identity. Orders negotiated under the old key stay
+// addressed to it and are not migrated to the new pubkey.
+async fn rotate_identity(
+    path: &Path,
+    settings: &Settings,
+    args: &cli_args,
+    announce_on_old_key: bool,
+) -> Result<()> {
+    let path_buf = path.to_path_buf();
+    let old_keys = RadrootsIdentity::load_or_generate(Some(&path_buf), false)?
+        .keys()
+        .clone();
+    let backup = backup_path(path, unix_now());
+    if args.dry_run {
+        info!(
+            "dry run: not rotating {} (backup would go to {})",
+            path.display(),
+            backup.display()
+        );
+        return Ok(());
+    }
+    fs::copy(path, &backup).with_context(|| format!("back up {}", path.display()))?;
+    info!("Backed up identity to {}", backup.display());
+
+    write_identity(path, &Keys::generate(), true)?;
+    let identity = RadrootsIdentity::load_or_generate(Some(&path_buf), false)?;
+    let new_npub = identity.keys().public_key().to_bech32()?;
+
+    let relays = settings.effective(args.relay_profile).config.relays;
+    if relays.is_empty() {
+        warn!("No relays configured; announcements will be published on next start");
+    } else {
+        let client = Rhi::new(identity.keys().clone()).client;
+        add_relays(&client, &relays).await?;
+        client.connect().await;
+        client.wait_for_connection(Duration::from_secs(5)).await;
+        publish_announcements(&client, &identity, settings, &relays, args.dry_run).await;
+        client.disconnect().await;
+
+        if announce_on_old_key {
+            let old_client = Rhi::new(old_keys).client;
+            add_relays(&old_client, &relays).await?;
+            old_client.connect().await;
+            old_client.wait_for_connection(Duration::from_secs(5)).await;
+            let moved = moved_metadata(&settings.metadata, &new_npub);
+            if let Err(e) = old_client.set_metadata(&moved).await {
+                warn!("Failed to publish moved notice on the old key: {e}");
+            }
+            old_client.disconnect().await;
+        }
+    }
+
+    println!("{new_npub}");
+    Ok(())
+}
+
+fn moved_metadata(metadata: &RadrootsNostrMetadata, new_npub: &str) -> RadrootsNostrMetadata {
+    let mut moved = metadata.clone();
+    moved.about = Some(format!("This service has moved to {new_npub}"));
+    moved.custom_field("moved_to", new_npub)
+}
+
+fn backup_path(path: &Path, at: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{at}.bak"));
+    PathBuf::from(name)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn keys_from_mnemonic(mnemonic: &str, passphrase: Option<&str>, account: u32) -> Result<Keys> {
+    Keys::from_mnemonic_with_account(mnemonic.trim(), passphrase, Some(account))
+        .context("derive keys from mnemonic")
+}
+
+fn read_mnemonic_from_stdin() -> Result<String> {
+    let mut line = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .context("read mnemonic from stdin")?;
+    if line.trim().is_empty() {
+        bail!("no mnemonic provided");
+    }
+    Ok(line)
+}
+
+fn write_identity(path: &Path, keys: &Keys, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        bail!(
+            "identity file {} already exists (use --force to overwrite)",
+            path.display()
+        );
+    }
+    let contents = serde_json::to_string_pretty(&json!({
+        "secret_key": keys.secret_key().to_secret_hex(),
+        "public_key": keys.public_key().to_hex(),
+    }))?;
+    let tmp = path.with_extension("tmp");
+    // A leftover tmp file may carry looser permissions, so the key is always written
+    // into a freshly created file that is owner-only from the start.
+    match fs::remove_file(&tmp) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(err).with_context(|| format!("remove {}", tmp.display()));
+        }
+        _ => {}
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .with_context(|| format!("write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("write {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backup_path, keys_from_mnemonic, moved_metadata, write_identity};
+    use radroots_nostr::prelude::RadrootsNostrMetadata;
+    use std::path::Path;
+
+    const MNEMONIC: &str =
+        "leader monkey parrot ring guide accident before fence cannon height naive bean";
+
+    #[test]
+    fn derives_nip06_test_vector() {
+        let keys = keys_from_mnemonic(MNEMONIC, None, 0).unwrap();
+        assert_eq!(
+            keys.secret_key().to_secret_hex(),
+            "7f7ff03d123792d6ac594bfa67bf6d0c0ab55b6b1fdb6249303fe861f1ccba9a"
+        );
+        assert_eq!(
+            keys.public_key().to_hex(),
+            "17162c921dc4d2518f9a101db33695df1afb56ab82f5ff3e5da6eec3ca5cd917"
+        );
+        assert_ne!(
+            keys_from_mnemonic(MNEMONIC, None, 1).unwrap().public_key(),
+            keys.public_key()
+        );
+    }
+
+    #[test]
+    fn moved_metadata_points_at_new_key() {
+        let metadata = RadrootsNostrMetadata::new().name("rhi");
+        let moved = moved_metadata(&metadata, "npub1new");
+        assert_eq!(moved.name.as_deref(), Some("rhi"));
+        assert_eq!(moved.custom["moved_to"], "npub1new");
+        assert!(moved.about.unwrap().contains("npub1new"));
+    }
+
+    #[test]
+    fn backup_path_keeps_original_name() {
+        assert_eq!(
+            backup_path(Path::new("data/identity.json"), 42),
+            Path::new("data/identity.json.42.bak")
+        );
+    }
+
+    #[test]
+    fn identity_file_is_not_overwritten_without_force() {
+        let path = std::env::temp_dir().join(format!("rhi-identity-{}.json", uuid::Uuid::new_v4()));
+        let keys = keys_from_mnemonic(MNEMONIC, None, 0).unwrap();
+        write_identity(&path, &keys, false).unwrap();
+        assert!(write_identity(&path, &keys, false).is_err());
+        write_identity(&path, &keys, true).unwrap();
+
+        let value: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(value["public_key"], keys.public_key().to_hex());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn identity_file_is_owner_only_even_over_a_stale_tmp() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("rhi-identity-{}.json", uuid::Uuid::new_v4()));
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, "stale").unwrap();
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let keys = keys_from_mnemonic(MNEMONIC, None, 0).unwrap();
+        write_identity(&path, &keys, false).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!tmp.exists());
+
+        let _ = std::fs::remove_file(path);
+    }
+}
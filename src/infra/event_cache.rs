@@ -0,0 +1,171 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use radroots_nostr::prelude::{RadrootsNostrClient, RadrootsNostrEvent};
+use tokio::sync::OnceCell;
+
+use crate::{
+    config::FetchRetryConfig,
+    infra::nostr::{NostrFetchError, nostr_fetch_event_by_id_fast, nostr_fetch_with_retry},
+};
+
+pub const EVENT_FETCH_CACHE_TTL: Duration = Duration::from_secs(30);
+
+struct CachedFetch {
+    created_at: Instant,
+    cell: Arc<OnceCell<RadrootsNostrEvent>>,
+}
+
+impl CachedFetch {
+    fn is_live(&self, now: Instant, ttl: Duration) -> bool {
+        if self.cell.initialized() {
+            return now.duration_since(self.created_at) < ttl;
+        }
+        Arc::strong_count(&self.cell) > 1
+    }
+}
+
+pub struct EventFetchCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, CachedFetch>>,
+}
+
+impl EventFetchCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn fetch_by_id(
+        &self,
+        client: &RadrootsNostrClient,
+        id: &str,
+        timeout: Duration,
+        retry: &FetchRetryConfig,
+    ) -> Result<RadrootsNostrEvent, NostrFetchError> {
+        self.get_or_fetch(id, || {
+            nostr_fetch_with_retry(retry, || nostr_fetch_event_by_id_fast(client, id, timeout))
+        })
+        .await
+    }
+
+    // Seeds the cache with an event we already hold, such as one we just published.
+    pub fn insert(&self, event: RadrootsNostrEvent) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap_or_else(|p| p.into_inner());
+        entries.retain(|_, entry| entry.is_live(now, self.ttl));
+        entries.insert(
+            event.id.to_hex(),
+            CachedFetch {
+                created_at: now,
+                cell: Arc::new(OnceCell::new_with(Some(event))),
+            },
+        );
+    }
+
+    async fn get_or_fetch<F, Fut, E>(&self, id: &str, fetch: F) -> Result<RadrootsNostrEvent, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<RadrootsNostrEvent, E>>,
+    {
+        let cell = self.cell(id, Instant::now());
+        cell.get_or_try_init(fetch).await.cloned()
+    }
+
+    fn cell(&self, id: &str, now: Instant) -> Arc<OnceCell<RadrootsNostrEvent>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|p| p.into_inner());
+        entries.retain(|_, entry| entry.is_live(now, self.ttl));
+        let entry = entries
+            .entry(id.to_string())
+            .or_insert_with(|| CachedFetch {
+                created_at: now,
+                cell: Arc::new(OnceCell::new()),
+            });
+        Arc::clone(&entry.cell)
+    }
+}
+
+impl Default for EventFetchCache {
+    fn default() -> Self {
+        Self::new(EVENT_FETCH_CACHE_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventFetchCache;
+    use nostr::{EventBuilder, Kind};
+    use radroots_nostr::prelude::{RadrootsNostrEvent, RadrootsNostrKeys};
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    fn event() -> RadrootsNostrEvent {
+        EventBuilder::new(Kind::TextNote, "listing")
+            .sign_with_keys(&RadrootsNostrKeys::generate())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn concurrent_fetches_for_the_same_id_share_one_request() {
+        let cache = EventFetchCache::new(Duration::from_secs(30));
+        let fetches = &AtomicUsize::new(0);
+        let expected = &event();
+        let fetch = || async move {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok::<_, ()>(expected.clone())
+        };
+
+        let (a, b, c) = tokio::join!(
+            cache.get_or_fetch("evt", fetch),
+            cache.get_or_fetch("evt", fetch),
+            cache.get_or_fetch("evt", fetch),
+        );
+        assert_eq!(a.unwrap().id, expected.id);
+        assert_eq!(b.unwrap().id, expected.id);
+        assert_eq!(c.unwrap().id, expected.id);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        cache.get_or_fetch("evt", fetch).await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_fetches_are_not_cached() {
+        let cache = EventFetchCache::new(Duration::from_secs(30));
+        let expected = &event();
+
+        let failed = cache.get_or_fetch("evt", || async { Err::<RadrootsNostrEvent, _>(()) });
+        assert!(failed.await.is_err());
+        let fetched = cache
+            .get_or_fetch("evt", || async move { Ok::<_, ()>(expected.clone()) })
+            .await
+            .unwrap();
+        assert_eq!(fetched.id, expected.id);
+    }
+
+    #[tokio::test]
+    async fn inserted_events_are_served_without_a_fetch() {
+        let cache = EventFetchCache::new(Duration::from_secs(30));
+        let expected = event();
+        cache.insert(expected.clone());
+
+        let fetched = cache
+            .get_or_fetch(&expected.id.to_hex(), || async {
+                Err::<RadrootsNostrEvent, _>(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(fetched.id, expected.id);
+    }
+}
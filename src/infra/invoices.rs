@@ -0,0 +1,133 @@
+#![forbid(unsafe_code)]
+
+use std::{sync::Arc, time::Duration};
+
+use futures::future::BoxFuture;
+use nostr::hashes::{Hash, sha256};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::PaymentConfig;
+
+#[derive(Debug, Error)]
+pub enum InvoiceBackendError {
+    #[error("invoice request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invoice backend returned an invalid payment hash: {0}")]
+    InvalidPaymentHash(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct IssuedInvoice {
+    pub bolt11: String,
+    pub payment_hash: String,
+}
+
+pub trait InvoiceIssuer: Send + Sync {
+    fn issue<'a>(
+        &'a self,
+        amount_msat: u64,
+        memo: &'a str,
+    ) -> BoxFuture<'a, Result<IssuedInvoice, InvoiceBackendError>>;
+}
+
+// Posts `{"amount_msat": 21000, "memo": "...", "expiry_secs": 600}` to `{url}` and
+// expects `{"bolt11": "lnbc...", "payment_hash": "<64 hex chars>"}` back.
+pub struct HttpInvoiceIssuer {
+    client: reqwest::Client,
+    url: String,
+    expiry_secs: u64,
+}
+
+#[derive(Serialize)]
+struct InvoiceRequest<'a> {
+    amount_msat: u64,
+    memo: &'a str,
+    expiry_secs: u64,
+}
+
+impl HttpInvoiceIssuer {
+    pub fn new(cfg: &PaymentConfig) -> Result<Self, InvoiceBackendError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(cfg.timeout_secs))
+            .build()?;
+        Ok(Self {
+            client,
+            url: cfg.invoice_url.clone(),
+            expiry_secs: cfg.expiry_secs,
+        })
+    }
+}
+
+pub fn invoice_issuer(
+    cfg: Option<&PaymentConfig>,
+) -> Result<Option<Arc<dyn InvoiceIssuer>>, InvoiceBackendError> {
+    Ok(match cfg {
+        Some(cfg) => Some(Arc::new(HttpInvoiceIssuer::new(cfg)?)),
+        None => None,
+    })
+}
+
+impl InvoiceIssuer for HttpInvoiceIssuer {
+    fn issue<'a>(
+        &'a self,
+        amount_msat: u64,
+        memo: &'a str,
+    ) -> BoxFuture<'a, Result<IssuedInvoice, InvoiceBackendError>> {
+        Box::pin(async move {
+            let request = InvoiceRequest {
+                amount_msat,
+                memo,
+                expiry_secs: self.expiry_secs,
+            };
+            let mut invoice: IssuedInvoice = self
+                .client
+                .post(&self.url)
+                .json(&request)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            if decode_hex(&invoice.payment_hash).is_none_or(|hash| hash.len() != 32) {
+                return Err(InvoiceBackendError::InvalidPaymentHash(
+                    invoice.payment_hash,
+                ));
+            }
+            invoice.payment_hash.make_ascii_lowercase();
+            Ok(invoice)
+        })
+    }
+}
+
+// The payment hash a preimage unlocks: the sha256 of its 32 bytes, as lowercase hex.
+pub fn preimage_payment_hash(preimage: &str) -> Option<String> {
+    let bytes = decode_hex(preimage.trim())?;
+    (bytes.len() == 32).then(|| sha256::Hash::hash(&bytes).to_string())
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::preimage_payment_hash;
+
+    #[test]
+    fn preimages_hash_to_their_payment_hash() {
+        assert_eq!(
+            preimage_payment_hash(&"00".repeat(32)).as_deref(),
+            Some("66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925")
+        );
+        assert_eq!(preimage_payment_hash(&"00".repeat(31)), None);
+        assert_eq!(preimage_payment_hash(&"zz".repeat(32)), None);
+        assert_eq!(preimage_payment_hash("paid"), None);
+    }
+}
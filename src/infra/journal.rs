@@ -0,0 +1,157 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    io::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use radroots_nostr::prelude::RadrootsNostrEvent;
+use serde::Serialize;
+use tracing::warn;
+use tracing_appender::rolling::{InitError, RollingFileAppender, Rotation};
+
+use crate::config::{JournalConfig, JournalRotation};
+
+const JOURNAL_FILE_PREFIX: &str = "events.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalDirection {
+    Received,
+    Sent,
+}
+
+#[derive(Debug, Serialize)]
+struct JournalRecord<'a> {
+    direction: JournalDirection,
+    relays: &'a [String],
+    at: u64,
+    event: &'a RadrootsNostrEvent,
+}
+
+pub struct EventJournal {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl EventJournal {
+    pub fn new(cfg: &JournalConfig) -> Result<Self, InitError> {
+        let rotation = match cfg.rotation {
+            JournalRotation::Minutely => Rotation::MINUTELY,
+            JournalRotation::Hourly => Rotation::HOURLY,
+            JournalRotation::Daily => Rotation::DAILY,
+            JournalRotation::Never => Rotation::NEVER,
+        };
+        let appender = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix(JOURNAL_FILE_PREFIX)
+            .build(&cfg.dir)?;
+        Ok(Self::from_writer(appender))
+    }
+
+    pub fn from_writer(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    pub fn record(
+        &self,
+        direction: JournalDirection,
+        relays: &[String],
+        event: &RadrootsNostrEvent,
+    ) {
+        let record = JournalRecord {
+            direction,
+            relays,
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            event,
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(err) => {
+                warn!("event journal: failed to encode event {}: {err}", event.id);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut writer = self.writer.lock().unwrap_or_else(|p| p.into_inner());
+        if let Err(err) = writer.write_all(&line) {
+            warn!("event journal: failed to write event {}: {err}", event.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventJournal, JournalDirection};
+    use crate::config::{JournalConfig, JournalRotation};
+    use nostr::{EventBuilder, Kind};
+    use radroots_nostr::prelude::RadrootsNostrKeys;
+    use serde_json::Value;
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records_direction_relays_and_raw_event() {
+        let buf = SharedBuf::default();
+        let journal = EventJournal::from_writer(buf.clone());
+        let keys = RadrootsNostrKeys::generate();
+        let event = EventBuilder::new(Kind::Custom(5321), "hello")
+            .sign_with_keys(&keys)
+            .unwrap();
+
+        journal.record(
+            JournalDirection::Received,
+            &["wss://relay.example.com".to_string()],
+            &event,
+        );
+        journal.record(JournalDirection::Sent, &[], &event);
+
+        let bytes = buf.0.lock().unwrap().clone();
+        let lines: Vec<Value> = String::from_utf8(bytes)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["direction"], "received");
+        assert_eq!(lines[0]["relays"][0], "wss://relay.example.com");
+        assert_eq!(lines[0]["event"]["id"], event.id.to_hex());
+        assert_eq!(lines[1]["direction"], "sent");
+        assert!(lines[1]["at"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn unusable_journal_dir_is_an_error() {
+        let file = std::env::temp_dir().join(format!("rhi-journal-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&file, b"").unwrap();
+        let cfg = JournalConfig {
+            dir: file.join("nested").to_string_lossy().into_owned(),
+            rotation: JournalRotation::Never,
+        };
+
+        assert!(EventJournal::new(&cfg).is_err());
+
+        let _ = std::fs::remove_file(file);
+    }
+}
@@ -0,0 +1,159 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+static INVALID_TRANSITIONS: LazyLock<Mutex<BTreeMap<String, u64>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+static NOTIFICATIONS_LAGGED: AtomicU64 = AtomicU64::new(0);
+static HANDLERS_IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+static HANDLER_PANICS: AtomicU64 = AtomicU64::new(0);
+static LISTING_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static LISTING_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub invalid_transitions: BTreeMap<String, u64>,
+    pub notifications_lagged: u64,
+    pub handlers_in_flight: u64,
+    pub handler_panics: u64,
+    pub listing_cache_hits: u64,
+    pub listing_cache_misses: u64,
+}
+
+impl MetricsSnapshot {
+    pub fn invalid_transition_count<S: Debug>(&self, from: &S, to: &S) -> u64 {
+        self.invalid_transitions
+            .get(&transition_key(from, to))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+pub fn record_invalid_transition<S: Debug>(from: &S, to: &S) {
+    let mut counters = INVALID_TRANSITIONS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    *counters.entry(transition_key(from, to)).or_default() += 1;
+}
+
+pub fn record_notifications_lagged(skipped: u64) {
+    NOTIFICATIONS_LAGGED.fetch_add(skipped, Ordering::Relaxed);
+}
+
+pub fn set_handlers_in_flight(count: usize) {
+    HANDLERS_IN_FLIGHT.store(count as u64, Ordering::Relaxed);
+}
+
+pub fn record_handler_panic() {
+    HANDLER_PANICS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_listing_cache_lookup(hit: bool) {
+    let counter = if hit {
+        &LISTING_CACHE_HITS
+    } else {
+        &LISTING_CACHE_MISSES
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn snapshot() -> MetricsSnapshot {
+    let invalid_transitions = INVALID_TRANSITIONS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone();
+    MetricsSnapshot {
+        invalid_transitions,
+        notifications_lagged: NOTIFICATIONS_LAGGED.load(Ordering::Relaxed),
+        handlers_in_flight: HANDLERS_IN_FLIGHT.load(Ordering::Relaxed),
+        handler_panics: HANDLER_PANICS.load(Ordering::Relaxed),
+        listing_cache_hits: LISTING_CACHE_HITS.load(Ordering::Relaxed),
+        listing_cache_misses: LISTING_CACHE_MISSES.load(Ordering::Relaxed),
+    }
+}
+
+fn transition_key<S: Debug>(from: &S, to: &S) -> String {
+    format!("{from:?}->{to:?}")
+}
+
+// Prometheus text exposition. `rhi_notifications_lagged_total` counts relay
+// notifications dropped because the subscriber fell behind the broadcast channel;
+// if it keeps rising, lower the handler load or narrow the relay filters.
+pub fn render_prometheus(snapshot: &MetricsSnapshot) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        out.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+        ));
+    };
+    metric(
+        "rhi_notifications_lagged_total",
+        "counter",
+        "Relay notifications skipped because the subscriber lagged.",
+        snapshot.notifications_lagged,
+    );
+    metric(
+        "rhi_handlers_in_flight",
+        "gauge",
+        "Trade handlers currently running.",
+        snapshot.handlers_in_flight,
+    );
+    metric(
+        "rhi_handler_panics_total",
+        "counter",
+        "Trade handlers that panicked.",
+        snapshot.handler_panics,
+    );
+    metric(
+        "rhi_listing_cache_hits_total",
+        "counter",
+        "Listing lookups served from the cache.",
+        snapshot.listing_cache_hits,
+    );
+    metric(
+        "rhi_listing_cache_misses_total",
+        "counter",
+        "Listing lookups that went to relays.",
+        snapshot.listing_cache_misses,
+    );
+    out.push_str(
+        "# HELP rhi_invalid_transitions_total Rejected order status transitions.\n\
+         # TYPE rhi_invalid_transitions_total counter\n",
+    );
+    for (transition, count) in &snapshot.invalid_transitions {
+        out.push_str(&format!(
+            "rhi_invalid_transitions_total{{transition=\"{transition}\"}} {count}\n"
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MetricsSnapshot, render_prometheus};
+
+    #[test]
+    fn prometheus_output_includes_lag_counter() {
+        let mut snapshot = MetricsSnapshot {
+            notifications_lagged: 42,
+            ..Default::default()
+        };
+        snapshot
+            .invalid_transitions
+            .insert("Requested->Completed".to_string(), 2);
+
+        let text = render_prometheus(&snapshot);
+        assert!(text.contains("# TYPE rhi_notifications_lagged_total counter\n"));
+        assert!(text.contains("\nrhi_notifications_lagged_total 42\n"));
+        assert!(
+            text.contains("rhi_invalid_transitions_total{transition=\"Requested->Completed\"} 2\n")
+        );
+    }
+}
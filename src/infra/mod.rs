@@ -1 +1,9 @@
 #![forbid(unsafe_code)]
+pub mod event_cache;
+pub mod invoices;
+pub mod journal;
+pub mod metrics;
+pub mod nostr;
+pub mod outbox;
+pub mod rates;
+pub mod relays;
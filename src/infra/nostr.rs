@@ -0,0 +1,529 @@
+#![forbid(unsafe_code)]
+
+use std::{future::Future, time::Duration};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use nostr::{
+    Event, EventId, JsonUtil, Kind, Tags, UnsignedEvent,
+    nips::{nip04, nip44},
+};
+use radroots_nostr::{
+    error::RadrootsNostrError,
+    prelude::{
+        RadrootsNostrClient, RadrootsNostrEvent, RadrootsNostrFilter, RadrootsNostrKeys,
+        RadrootsNostrTag,
+    },
+};
+use radroots_runtime::Backoff;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::config::FetchRetryConfig;
+
+#[derive(Debug, Error)]
+pub enum NostrTagsResolveError {
+    #[error("event content is {size} bytes (limit {limit})")]
+    ContentTooLarge { size: usize, limit: usize },
+    #[error("decrypted content is {size} bytes (limit {limit})")]
+    DecryptedTooLarge { size: usize, limit: usize },
+    #[error("failed to decrypt content: {0}")]
+    Decrypt(String),
+    #[error("invalid decrypted tags: {0}")]
+    InvalidTags(String),
+    #[error("encrypted event is not addressed solely to us")]
+    NotRecipient,
+    #[error("invalid gift wrap: {0}")]
+    InvalidGiftWrap(String),
+    #[error("gift wrap rumor author does not match the seal signer")]
+    SealSenderMismatch,
+    #[error("decrypted listing {found} is not by the author of listing {original}")]
+    ForkedListingAuthor { original: String, found: String },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NostrPayloadLimits {
+    pub max_content_bytes: usize,
+    pub max_decrypted_bytes: usize,
+}
+
+pub fn nostr_tags_resolve(
+    event: &RadrootsNostrEvent,
+    keys: &RadrootsNostrKeys,
+    limits: NostrPayloadLimits,
+) -> Result<Vec<RadrootsNostrTag>, NostrTagsResolveError> {
+    let size = event.content.len();
+    if size > limits.max_content_bytes {
+        return Err(NostrTagsResolveError::ContentTooLarge {
+            size,
+            limit: limits.max_content_bytes,
+        });
+    }
+
+    let is_encrypted =
+        |tag: &RadrootsNostrTag| tag.as_slice().first().map(String::as_str) == Some("encrypted");
+    if !event.tags.iter().any(is_encrypted) {
+        return Ok(event.tags.iter().cloned().collect());
+    }
+    let our_pubkey = keys.public_key();
+    let mut recipients = event.tags.public_keys().peekable();
+    if recipients.peek().is_none() || recipients.any(|pubkey| *pubkey != our_pubkey) {
+        return Err(NostrTagsResolveError::NotRecipient);
+    }
+
+    let cleartext = nip04::decrypt(keys.secret_key(), &event.pubkey, &event.content)
+        .map_err(|e| NostrTagsResolveError::Decrypt(e.to_string()))?;
+    let size = cleartext.len();
+    if size > limits.max_decrypted_bytes {
+        return Err(NostrTagsResolveError::DecryptedTooLarge {
+            size,
+            limit: limits.max_decrypted_bytes,
+        });
+    }
+
+    let decrypted: Vec<Vec<String>> = serde_json::from_str(&cleartext)
+        .map_err(|e| NostrTagsResolveError::InvalidTags(e.to_string()))?;
+    ensure_same_listing_author(&event.tags, &decrypted)?;
+    let mut tags: Vec<RadrootsNostrTag> = event
+        .tags
+        .iter()
+        .filter(|tag| !is_encrypted(tag))
+        .cloned()
+        .collect();
+    for tag in decrypted {
+        tags.push(
+            RadrootsNostrTag::parse(tag)
+                .map_err(|e| NostrTagsResolveError::InvalidTags(e.to_string()))?,
+        );
+    }
+    Ok(tags)
+}
+
+// Encrypted tags may repeat the listing address, but never fork the request onto a
+// listing by another seller than the one named in the clear.
+fn ensure_same_listing_author(
+    tags: &Tags,
+    decrypted: &[Vec<String>],
+) -> Result<(), NostrTagsResolveError> {
+    let Some(original) = tags.iter().find_map(|tag| listing_addr(tag.as_slice())) else {
+        return Ok(());
+    };
+    for found in decrypted.iter().filter_map(|tag| listing_addr(tag)) {
+        if listing_author(found) != listing_author(original) {
+            return Err(NostrTagsResolveError::ForkedListingAuthor {
+                original: original.to_string(),
+                found: found.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn listing_addr(tag: &[String]) -> Option<&str> {
+    match tag {
+        [name, addr, ..] if name == "a" => Some(addr.as_str()),
+        _ => None,
+    }
+}
+
+fn listing_author(addr: &str) -> Option<&str> {
+    addr.split(':').nth(1)
+}
+
+// Unwraps a NIP-59 gift wrap into the rumor it carries. Rumors are unsigned by
+// design, so the returned event borrows the seal's signature: the seal proves the
+// sender, but the event does not `verify()` and must never be republished.
+pub fn nostr_unwrap_gift_wrap(
+    event: &RadrootsNostrEvent,
+    keys: &RadrootsNostrKeys,
+    limits: NostrPayloadLimits,
+) -> Result<RadrootsNostrEvent, NostrTagsResolveError> {
+    let size = event.content.len();
+    if size > limits.max_content_bytes {
+        return Err(NostrTagsResolveError::ContentTooLarge {
+            size,
+            limit: limits.max_content_bytes,
+        });
+    }
+    let invalid = |e: &dyn std::fmt::Display| NostrTagsResolveError::InvalidGiftWrap(e.to_string());
+
+    let seal_json = nip44::decrypt(keys.secret_key(), &event.pubkey, &event.content)
+        .map_err(|e| NostrTagsResolveError::Decrypt(e.to_string()))?;
+    let seal = Event::from_json(&seal_json).map_err(|e| invalid(&e))?;
+    if seal.kind != Kind::Seal {
+        return Err(invalid(&format!("expected a seal, got kind {}", seal.kind)));
+    }
+    seal.verify().map_err(|e| invalid(&e))?;
+
+    let rumor_json = nip44::decrypt(keys.secret_key(), &seal.pubkey, &seal.content)
+        .map_err(|e| NostrTagsResolveError::Decrypt(e.to_string()))?;
+    let size = rumor_json.len();
+    if size > limits.max_decrypted_bytes {
+        return Err(NostrTagsResolveError::DecryptedTooLarge {
+            size,
+            limit: limits.max_decrypted_bytes,
+        });
+    }
+    let mut rumor = UnsignedEvent::from_json(&rumor_json).map_err(|e| invalid(&e))?;
+    if rumor.pubkey != seal.pubkey {
+        return Err(NostrTagsResolveError::SealSenderMismatch);
+    }
+    rumor.ensure_id();
+    let id = rumor.id.ok_or_else(|| invalid(&"rumor has no id"))?;
+    Ok(Event::new(
+        id,
+        rumor.pubkey,
+        rumor.created_at,
+        rumor.kind,
+        rumor.tags,
+        rumor.content,
+        seal.sig,
+    ))
+}
+
+// First value of the first `key` tag, the lookup every trade stage uses for its
+// chain tags. A first `key` tag without a value means no value, not a later one.
+pub fn tag_lookup(event: &RadrootsNostrEvent, key: &str) -> Option<String> {
+    tag_lookup_in(event.tags.as_slice(), key)
+}
+
+// The same lookup over tags resolved apart from their event, such as decrypted ones.
+pub fn tag_lookup_in(tags: &[RadrootsNostrTag], key: &str) -> Option<String> {
+    tags.iter()
+        .map(|tag| tag.as_slice())
+        .find(|tag| tag.first().is_some_and(|name| name == key))
+        .and_then(|tag| tag.get(1).cloned())
+}
+
+pub const SHUTDOWN_STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Unsubscribing or disconnecting waits on relays, and a dead one can stall that
+// forever; shutdown gives the step `timeout` and moves on. Returns false on timeout.
+pub async fn bounded_shutdown_step(step: &str, timeout: Duration, fut: impl Future) -> bool {
+    match tokio::time::timeout(timeout, fut).await {
+        Ok(_) => true,
+        Err(_) => {
+            warn!("{step} timed out after {timeout:?}; continuing shutdown");
+            false
+        }
+    }
+}
+
+pub fn log_dry_run_event(event: &RadrootsNostrEvent) {
+    let tags: Vec<&[String]> = event.tags.iter().map(|tag| tag.as_slice()).collect();
+    info!(
+        id = %event.id,
+        kind = %event.kind,
+        ?tags,
+        content = %event.content,
+        "dry run: not publishing event"
+    );
+}
+
+#[derive(Debug, Error)]
+pub enum NostrFetchError {
+    #[error("event not found: {0}")]
+    NotFound(String),
+    #[error("invalid event id: {0}")]
+    InvalidId(String),
+    #[error("no relay answered within {0:?}")]
+    TimedOut(Duration),
+    #[error("no relay answered: {0}")]
+    Unanswered(RadrootsNostrError),
+}
+
+pub async fn nostr_fetch_event_by_id_fast(
+    client: &RadrootsNostrClient,
+    id: &str,
+    timeout: Duration,
+) -> Result<RadrootsNostrEvent, NostrFetchError> {
+    let event_id = EventId::parse(id).map_err(|_| NostrFetchError::InvalidId(id.to_string()))?;
+    let filter = RadrootsNostrFilter::new().id(event_id).limit(1);
+
+    let mut fetches: FuturesUnordered<_> = client
+        .relays()
+        .await
+        .into_iter()
+        .filter(|(_, relay)| relay.flags().has_read())
+        .map(|(url, _)| {
+            let filter = filter.clone();
+            async move { client.fetch_events_from([url], filter, timeout).await }
+        })
+        .collect();
+
+    // Only report a relay error when no relay answered at all, so callers can retry
+    // it; a relay that answered without the event means it was not found.
+    let first_valid = async {
+        let mut answered = false;
+        let mut last_err = None;
+        while let Some(res) = fetches.next().await {
+            let events = match res {
+                Ok(events) => events,
+                Err(err) => {
+                    last_err = Some(err);
+                    continue;
+                }
+            };
+            answered = true;
+            if let Some(event) = events
+                .into_iter()
+                .find(|ev| ev.id == event_id && ev.verify().is_ok())
+            {
+                return Ok(event);
+            }
+        }
+        match last_err {
+            Some(err) if !answered => Err(NostrFetchError::Unanswered(err)),
+            _ => Err(NostrFetchError::NotFound(id.to_string())),
+        }
+    };
+
+    match tokio::time::timeout(timeout, first_valid).await {
+        Ok(res) => res,
+        Err(_) => Err(NostrFetchError::TimedOut(timeout)),
+    }
+}
+
+// Only relays that failed to answer may do better on the next attempt. A relay that
+// answered without the event, or an id that does not parse, is final.
+pub fn is_transient_fetch_error(err: &NostrFetchError) -> bool {
+    match err {
+        NostrFetchError::TimedOut(_) | NostrFetchError::Unanswered(_) => true,
+        NostrFetchError::NotFound(_) | NostrFetchError::InvalidId(_) => false,
+    }
+}
+
+pub async fn nostr_fetch_with_retry<T, F, Fut>(
+    retry: &FetchRetryConfig,
+    mut fetch: F,
+) -> Result<T, NostrFetchError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, NostrFetchError>>,
+{
+    let mut backoff = Backoff::new(retry.backoff.clone());
+    let mut attempt = 1;
+    loop {
+        match fetch().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < retry.attempts && is_transient_fetch_error(&err) => {
+                let delay = backoff.next_delay();
+                warn!("relay fetch attempt {attempt} failed, retrying in {delay:?}: {err}");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicU32, Ordering},
+        time::Duration,
+    };
+
+    use super::{
+        NostrFetchError, NostrPayloadLimits, NostrTagsResolveError, bounded_shutdown_step,
+        nostr_fetch_with_retry, nostr_tags_resolve, nostr_unwrap_gift_wrap, tag_lookup,
+    };
+    use crate::config::FetchRetryConfig;
+    use nostr::{EventBuilder, Kind, Tag, nips::nip04};
+    use radroots_nostr::prelude::RadrootsNostrKeys;
+
+    fn encrypted_request(
+        sender: &RadrootsNostrKeys,
+        recipient: &RadrootsNostrKeys,
+        cleartext: &str,
+    ) -> nostr::Event {
+        let content =
+            nip04::encrypt(sender.secret_key(), &recipient.public_key(), cleartext).unwrap();
+        EventBuilder::new(Kind::Custom(5321), content)
+            .tag(Tag::parse(["p", &recipient.public_key().to_hex()]).unwrap())
+            .tag(Tag::parse(["encrypted"]).unwrap())
+            .sign_with_keys(sender)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn hung_shutdown_step_is_abandoned() {
+        let timeout = std::time::Duration::from_millis(10);
+        assert!(bounded_shutdown_step("unsubscribe", timeout, async {}).await);
+        let hung = std::future::pending::<()>();
+        assert!(!bounded_shutdown_step("unsubscribe", timeout, hung).await);
+    }
+
+    #[test]
+    fn tag_lookup_returns_the_first_value_of_a_key() {
+        let event = EventBuilder::new(Kind::Custom(6302), "")
+            .tag(Tag::parse(["d", "trade-1"]).unwrap())
+            .tag(Tag::parse(["d", "trade-2"]).unwrap())
+            .tag(Tag::parse(["encrypted"]).unwrap())
+            .tag(Tag::parse(["encrypted", "nip04"]).unwrap())
+            .sign_with_keys(&RadrootsNostrKeys::generate())
+            .unwrap();
+
+        assert_eq!(tag_lookup(&event, "d").as_deref(), Some("trade-1"));
+        assert_eq!(tag_lookup(&event, "encrypted"), None);
+        assert_eq!(tag_lookup(&event, "e"), None);
+    }
+
+    #[tokio::test]
+    async fn gift_wrapped_request_unwraps_to_the_real_sender() {
+        let sender = RadrootsNostrKeys::generate();
+        let recipient = RadrootsNostrKeys::generate();
+        let rumor = EventBuilder::new(Kind::Custom(5321), "{}")
+            .tag(Tag::parse(["p", &recipient.public_key().to_hex()]).unwrap())
+            .build(sender.public_key());
+        let wrap = EventBuilder::gift_wrap(&sender, &recipient.public_key(), rumor, [])
+            .await
+            .unwrap();
+        let limits = NostrPayloadLimits {
+            max_content_bytes: 65536,
+            max_decrypted_bytes: 65536,
+        };
+
+        assert_ne!(wrap.pubkey, sender.public_key());
+        let inner = nostr_unwrap_gift_wrap(&wrap, &recipient, limits).unwrap();
+        assert_eq!(inner.pubkey, sender.public_key());
+        assert_eq!(inner.kind, Kind::Custom(5321));
+        assert_eq!(inner.content, "{}");
+
+        let stranger = RadrootsNostrKeys::generate();
+        let err = nostr_unwrap_gift_wrap(&wrap, &stranger, limits).unwrap_err();
+        assert!(matches!(err, NostrTagsResolveError::Decrypt(_)));
+    }
+
+    #[test]
+    fn decrypted_tags_replace_encrypted_marker() {
+        let sender = RadrootsNostrKeys::generate();
+        let recipient = RadrootsNostrKeys::generate();
+        let event = encrypted_request(&sender, &recipient, r#"[["d","order-1"]]"#);
+        let limits = NostrPayloadLimits {
+            max_content_bytes: 1024,
+            max_decrypted_bytes: 1024,
+        };
+
+        let tags = nostr_tags_resolve(&event, &recipient, limits).unwrap();
+        let tags: Vec<Vec<String>> = tags.iter().map(|t| t.as_slice().to_vec()).collect();
+        assert!(tags.iter().any(|t| t[0] == "p"));
+        assert!(tags.iter().any(|t| t[0] == "d" && t[1] == "order-1"));
+        assert!(!tags.iter().any(|t| t[0] == "encrypted"));
+    }
+
+    #[test]
+    fn decrypted_tags_cannot_fork_onto_another_sellers_listing() {
+        let sender = RadrootsNostrKeys::generate();
+        let recipient = RadrootsNostrKeys::generate();
+        let limits = NostrPayloadLimits {
+            max_content_bytes: 1024,
+            max_decrypted_bytes: 1024,
+        };
+        let seller = "a".repeat(64);
+        let other = "b".repeat(64);
+        let listing = format!("30402:{seller}:listing");
+        let with_listing = |cleartext: &str| {
+            let event = encrypted_request(&sender, &recipient, cleartext);
+            EventBuilder::new(event.kind, event.content.clone())
+                .tags(event.tags.iter().cloned())
+                .tag(Tag::parse(["a", &listing]).unwrap())
+                .sign_with_keys(&sender)
+                .unwrap()
+        };
+
+        let same = with_listing(&format!(r#"[["a","30402:{seller}:other"]]"#));
+        assert!(nostr_tags_resolve(&same, &recipient, limits).is_ok());
+
+        let forked = with_listing(&format!(r#"[["a","30402:{other}:listing"]]"#));
+        let err = nostr_tags_resolve(&forked, &recipient, limits).unwrap_err();
+        assert!(matches!(
+            err,
+            NostrTagsResolveError::ForkedListingAuthor { .. }
+        ));
+    }
+
+    #[test]
+    fn encrypted_event_for_multiple_recipients_is_not_decrypted() {
+        let sender = RadrootsNostrKeys::generate();
+        let recipient = RadrootsNostrKeys::generate();
+        let other = RadrootsNostrKeys::generate();
+        let limits = NostrPayloadLimits {
+            max_content_bytes: 1024,
+            max_decrypted_bytes: 1024,
+        };
+        let event = encrypted_request(&sender, &recipient, r#"[["d","order-1"]]"#);
+        let event = EventBuilder::new(event.kind, event.content.clone())
+            .tags(event.tags.iter().cloned())
+            .tag(Tag::parse(["p", &other.public_key().to_hex()]).unwrap())
+            .sign_with_keys(&sender)
+            .unwrap();
+
+        let err = nostr_tags_resolve(&event, &recipient, limits).unwrap_err();
+        assert!(matches!(err, NostrTagsResolveError::NotRecipient));
+    }
+
+    #[test]
+    fn oversized_decrypted_payload_is_rejected() {
+        let sender = RadrootsNostrKeys::generate();
+        let recipient = RadrootsNostrKeys::generate();
+        let padding = "x".repeat(2048);
+        let cleartext = format!(r#"[["d","{padding}"]]"#);
+        let event = encrypted_request(&sender, &recipient, &cleartext);
+        let limits = NostrPayloadLimits {
+            max_content_bytes: event.content.len(),
+            max_decrypted_bytes: 1024,
+        };
+
+        let err = nostr_tags_resolve(&event, &recipient, limits).unwrap_err();
+        assert!(matches!(
+            err,
+            NostrTagsResolveError::DecryptedTooLarge { limit: 1024, .. }
+        ));
+    }
+
+    #[test]
+    fn oversized_raw_content_is_rejected_before_decrypting() {
+        let sender = RadrootsNostrKeys::generate();
+        let recipient = RadrootsNostrKeys::generate();
+        let event = encrypted_request(&sender, &recipient, r#"[["d","order-1"]]"#);
+        let limits = NostrPayloadLimits {
+            max_content_bytes: 8,
+            max_decrypted_bytes: 1024,
+        };
+
+        let err = nostr_tags_resolve(&event, &recipient, limits).unwrap_err();
+        assert!(matches!(
+            err,
+            NostrTagsResolveError::ContentTooLarge { limit: 8, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn not_found_fetches_are_not_retried() {
+        let calls = AtomicU32::new(0);
+        let res: Result<(), _> = nostr_fetch_with_retry(&FetchRetryConfig::default(), || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async { Err(NostrFetchError::NotFound("abc".to_string())) }
+        })
+        .await;
+        assert!(matches!(res, Err(NostrFetchError::NotFound(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn unanswered_fetches_are_retried_until_they_succeed() {
+        let calls = AtomicU32::new(0);
+        let res = nostr_fetch_with_retry(&FetchRetryConfig::default(), || {
+            let attempt = calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match attempt {
+                    0 => Err(NostrFetchError::TimedOut(Duration::from_secs(10))),
+                    _ => Ok(attempt),
+                }
+            }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
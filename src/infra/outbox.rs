@@ -0,0 +1,187 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use nostr::{Kind, PublicKey, RelayUrl};
+use radroots_nostr::prelude::{RadrootsNostrClient, RadrootsNostrEvent, RadrootsNostrFilter};
+use tracing::warn;
+
+const RELAY_LIST_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct CachedRelayList {
+    fetched_at: Instant,
+    read: Vec<RelayUrl>,
+}
+
+pub struct RelayListCache {
+    ttl: Duration,
+    max_relays: usize,
+    entries: Mutex<HashMap<PublicKey, CachedRelayList>>,
+}
+
+impl RelayListCache {
+    pub fn new(ttl: Duration, max_relays: usize) -> Self {
+        Self {
+            ttl,
+            max_relays,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Lookups for every recipient run concurrently, and the merged set is capped at
+    // `max_relays` so one reply never fans out to an unbounded number of relays.
+    pub async fn recipient_relays<'a>(
+        &self,
+        client: &RadrootsNostrClient,
+        recipients: impl IntoIterator<Item = &'a PublicKey>,
+    ) -> Vec<RelayUrl> {
+        let lookups = recipients
+            .into_iter()
+            .map(|pubkey| self.read_relays(client, pubkey));
+        merge_relays(join_all(lookups).await, self.max_relays)
+    }
+
+    pub async fn read_relays(
+        &self,
+        client: &RadrootsNostrClient,
+        pubkey: &PublicKey,
+    ) -> Vec<RelayUrl> {
+        if let Some(relays) = self.cached(pubkey, Instant::now()) {
+            return relays;
+        }
+        let filter = RadrootsNostrFilter::new()
+            .kind(Kind::RelayList)
+            .author(*pubkey)
+            .limit(1);
+        let events = match client.fetch_events(filter, RELAY_LIST_FETCH_TIMEOUT).await {
+            Ok(events) => events,
+            Err(e) => {
+                // Failures are cached too, so an unreachable lookup is not retried
+                // on every reply until the entry expires.
+                warn!("outbox: failed to fetch relay list for {pubkey}: {e}");
+                self.insert(*pubkey, Vec::new(), Instant::now());
+                return Vec::new();
+            }
+        };
+        let read = events
+            .into_iter()
+            .max_by_key(|event| event.created_at)
+            .map(|event| nip65_read_relays(&event, self.max_relays))
+            .unwrap_or_default();
+        self.insert(*pubkey, read.clone(), Instant::now());
+        read
+    }
+
+    fn cached(&self, pubkey: &PublicKey, now: Instant) -> Option<Vec<RelayUrl>> {
+        let entries = self.entries.lock().unwrap_or_else(|p| p.into_inner());
+        let entry = entries.get(pubkey)?;
+        (now.duration_since(entry.fetched_at) < self.ttl).then(|| entry.read.clone())
+    }
+
+    fn insert(&self, pubkey: PublicKey, read: Vec<RelayUrl>, now: Instant) {
+        let mut entries = self.entries.lock().unwrap_or_else(|p| p.into_inner());
+        entries.retain(|_, entry| now.duration_since(entry.fetched_at) < self.ttl);
+        entries.insert(
+            pubkey,
+            CachedRelayList {
+                fetched_at: now,
+                read,
+            },
+        );
+    }
+}
+
+fn merge_relays(lists: Vec<Vec<RelayUrl>>, max_relays: usize) -> Vec<RelayUrl> {
+    let mut merged: Vec<RelayUrl> = Vec::new();
+    for relay in lists.into_iter().flatten() {
+        if merged.len() >= max_relays {
+            break;
+        }
+        if !merged.contains(&relay) {
+            merged.push(relay);
+        }
+    }
+    merged
+}
+
+pub fn nip65_read_relays(event: &RadrootsNostrEvent, max_relays: usize) -> Vec<RelayUrl> {
+    event
+        .tags
+        .iter()
+        .map(|tag| tag.as_slice())
+        .filter(|tag| tag.first().map(String::as_str) == Some("r"))
+        .filter(|tag| matches!(tag.get(2).map(String::as_str), None | Some("read")))
+        .filter_map(|tag| RelayUrl::parse(tag.get(1)?).ok())
+        .take(max_relays)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RelayListCache, merge_relays, nip65_read_relays};
+    use nostr::{EventBuilder, Kind, RelayUrl, Tag};
+    use radroots_nostr::prelude::RadrootsNostrKeys;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn read_relays_skip_write_only_entries() {
+        let keys = RadrootsNostrKeys::generate();
+        let tags = [
+            vec!["r", "wss://both.example.com"],
+            vec!["r", "wss://read.example.com", "read"],
+            vec!["r", "wss://write.example.com", "write"],
+            vec!["r", "not a url"],
+        ]
+        .into_iter()
+        .map(|tag| Tag::parse(tag).unwrap());
+        let event = EventBuilder::new(Kind::RelayList, "")
+            .tags(tags)
+            .sign_with_keys(&keys)
+            .unwrap();
+
+        let relays = nip65_read_relays(&event, 8);
+        assert_eq!(
+            relays,
+            vec![
+                RelayUrl::parse("wss://both.example.com").unwrap(),
+                RelayUrl::parse("wss://read.example.com").unwrap(),
+            ]
+        );
+        assert_eq!(nip65_read_relays(&event, 1).len(), 1);
+    }
+
+    #[test]
+    fn cached_relay_lists_expire_after_ttl() {
+        let cache = RelayListCache::new(Duration::from_secs(60), 3);
+        let pubkey = RadrootsNostrKeys::generate().public_key();
+        let relay = RelayUrl::parse("wss://read.example.com").unwrap();
+        let now = Instant::now();
+
+        cache.insert(pubkey, vec![relay.clone()], now);
+        assert_eq!(cache.cached(&pubkey, now), Some(vec![relay]));
+        assert_eq!(cache.cached(&pubkey, now + Duration::from_secs(61)), None);
+    }
+
+    #[test]
+    fn merged_recipient_relays_are_deduplicated_and_capped() {
+        let relay = |host: &str| RelayUrl::parse(&format!("wss://{host}.example.com")).unwrap();
+        let lists = vec![
+            vec![relay("a"), relay("b")],
+            vec![relay("b"), relay("c"), relay("d")],
+        ];
+
+        assert_eq!(
+            merge_relays(lists.clone(), 8),
+            vec![relay("a"), relay("b"), relay("c"), relay("d")]
+        );
+        assert_eq!(
+            merge_relays(lists, 3),
+            vec![relay("a"), relay("b"), relay("c")]
+        );
+    }
+}
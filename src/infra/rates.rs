@@ -0,0 +1,127 @@
+#![forbid(unsafe_code)]
+
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use futures::future::BoxFuture;
+use rust_decimal::{Decimal, prelude::ToPrimitive};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::config::RateConfig;
+
+#[derive(Debug, Error)]
+pub enum RateError {
+    #[error("rate request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid rate for {from}/{to}: {value}")]
+    InvalidRate {
+        from: String,
+        to: String,
+        value: String,
+    },
+}
+
+// `rate(from, to)` is the amount of `to` that one unit of `from` buys.
+pub trait RateProvider: Send + Sync {
+    fn rate<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, Result<Decimal, RateError>>;
+}
+
+// Queries `GET {url}?from=USD&to=SAT` and expects `{"rate": "<decimal>"}`; the rate may
+// also be a JSON number.
+pub struct HttpRateProvider {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct RateResponse {
+    rate: serde_json::Value,
+}
+
+impl HttpRateProvider {
+    pub fn new(cfg: &RateConfig) -> Result<Self, RateError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(cfg.timeout_secs))
+            .build()?;
+        Ok(Self {
+            client,
+            url: cfg.url.clone(),
+        })
+    }
+}
+
+pub fn rate_provider(cfg: Option<&RateConfig>) -> Result<Option<Arc<dyn RateProvider>>, RateError> {
+    Ok(match cfg {
+        Some(cfg) => Some(Arc::new(HttpRateProvider::new(cfg)?)),
+        None => None,
+    })
+}
+
+impl RateProvider for HttpRateProvider {
+    fn rate<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, Result<Decimal, RateError>> {
+        Box::pin(async move {
+            let response: RateResponse = self
+                .client
+                .get(&self.url)
+                .query(&[("from", from), ("to", to)])
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            parse_rate(&response.rate).ok_or_else(|| RateError::InvalidRate {
+                from: from.to_string(),
+                to: to.to_string(),
+                value: response.rate.to_string(),
+            })
+        })
+    }
+}
+
+fn parse_rate(value: &serde_json::Value) -> Option<Decimal> {
+    let rate = match value {
+        serde_json::Value::String(s) => Decimal::from_str(s).ok()?,
+        serde_json::Value::Number(n) => Decimal::from_str(&n.to_string()).ok()?,
+        _ => return None,
+    };
+    (rate > Decimal::ZERO).then_some(rate)
+}
+
+// Converts `amount` of a currency into msat using a sats-per-unit rate, rounding to
+// the nearest msat.
+pub fn to_msat(amount: Decimal, sats_per_unit: Decimal) -> Option<u64> {
+    amount
+        .checked_mul(sats_per_unit)?
+        .checked_mul(Decimal::ONE_THOUSAND)?
+        .round()
+        .to_u64()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+    use serde_json::json;
+
+    use super::{parse_rate, to_msat};
+
+    #[test]
+    fn rates_parse_from_strings_and_numbers() {
+        assert_eq!(
+            parse_rate(&json!("1612.5")),
+            Decimal::from_str("1612.5").ok()
+        );
+        assert_eq!(parse_rate(&json!(1500)), Some(Decimal::from(1500)));
+        assert_eq!(parse_rate(&json!("0")), None);
+        assert_eq!(parse_rate(&json!(null)), None);
+    }
+
+    #[test]
+    fn fiat_amounts_convert_to_msat_without_truncation() {
+        let usd = Decimal::from_str("12.34").unwrap();
+        let sats_per_usd = Decimal::from_str("1612.9").unwrap();
+        assert_eq!(to_msat(usd, sats_per_usd), Some(19_903_186));
+        assert_eq!(to_msat(Decimal::from(-1), sats_per_usd), None);
+    }
+}
@@ -0,0 +1,206 @@
+#![forbid(unsafe_code)]
+
+use std::{collections::HashMap, time::Duration};
+
+use radroots_nostr::{
+    error::RadrootsNostrError,
+    prelude::{RadrootsNostrClient, RadrootsNostrKeys, radroots_nostr_build_event},
+};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayConnectionStatus {
+    pub status: String,
+    pub connected: bool,
+}
+
+pub type RelayStatusMap = HashMap<String, RelayConnectionStatus>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayStatusChange {
+    pub url: String,
+    pub from: Option<RelayConnectionStatus>,
+    pub to: Option<RelayConnectionStatus>,
+}
+
+pub fn relay_status_changes(
+    prev: &RelayStatusMap,
+    next: &RelayStatusMap,
+) -> Vec<RelayStatusChange> {
+    let mut changes: Vec<RelayStatusChange> = next
+        .iter()
+        .filter(|(url, status)| prev.get(*url) != Some(*status))
+        .map(|(url, status)| RelayStatusChange {
+            url: url.clone(),
+            from: prev.get(url).cloned(),
+            to: Some(status.clone()),
+        })
+        .chain(
+            prev.iter()
+                .filter(|(url, _)| !next.contains_key(*url))
+                .map(|(url, status)| RelayStatusChange {
+                    url: url.clone(),
+                    from: Some(status.clone()),
+                    to: None,
+                }),
+        )
+        .collect();
+    changes.sort_by(|a, b| a.url.cmp(&b.url));
+    changes
+}
+
+pub async fn monitor_relay_status(
+    client: RadrootsNostrClient,
+    status_tx: watch::Sender<RelayStatusMap>,
+    mut stop_rx: watch::Receiver<bool>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        if *stop_rx.borrow() {
+            break;
+        }
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = stop_rx.changed() => break,
+        }
+
+        let next: RelayStatusMap = client
+            .relays()
+            .await
+            .into_iter()
+            .map(|(url, relay)| {
+                let status = relay.status();
+                (
+                    url.to_string(),
+                    RelayConnectionStatus {
+                        status: status.to_string(),
+                        connected: status.is_connected(),
+                    },
+                )
+            })
+            .collect();
+
+        for change in relay_status_changes(&status_tx.borrow(), &next) {
+            log_relay_status_change(&change);
+        }
+        status_tx.send_replace(next);
+    }
+}
+
+const SELF_PING_KIND: u32 = 20_990;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayWriteResult {
+    pub url: String,
+    pub error: Option<String>,
+}
+
+impl RelayWriteResult {
+    pub fn accepted(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+pub fn relay_write_results(
+    accepted: impl IntoIterator<Item = String>,
+    rejected: impl IntoIterator<Item = (String, String)>,
+) -> Vec<RelayWriteResult> {
+    let mut results: Vec<RelayWriteResult> = accepted
+        .into_iter()
+        .map(|url| RelayWriteResult { url, error: None })
+        .chain(rejected.into_iter().map(|(url, error)| RelayWriteResult {
+            url,
+            error: Some(error),
+        }))
+        .collect();
+    results.sort_by(|a, b| a.url.cmp(&b.url));
+    results
+}
+
+pub async fn relay_self_ping(
+    client: &RadrootsNostrClient,
+    keys: &RadrootsNostrKeys,
+) -> Result<Vec<RelayWriteResult>, RadrootsNostrError> {
+    let tags = vec![vec!["p".to_string(), keys.public_key().to_hex()]];
+    let builder = radroots_nostr_build_event(SELF_PING_KIND, "rhi self-ping", tags)?;
+    let output = client.send_event_builder(builder).await?;
+    let results = relay_write_results(
+        output.success.into_iter().map(|url| url.to_string()),
+        output
+            .failed
+            .into_iter()
+            .map(|(url, error)| (url.to_string(), error)),
+    );
+    for result in &results {
+        match &result.error {
+            None => info!("relay {}: self-ping accepted", result.url),
+            Some(error) => warn!("relay {}: self-ping rejected: {error}", result.url),
+        }
+    }
+    Ok(results)
+}
+
+fn log_relay_status_change(change: &RelayStatusChange) {
+    let from = change
+        .from
+        .as_ref()
+        .map(|s| s.status.as_str())
+        .unwrap_or("unknown");
+    match &change.to {
+        Some(to) if to.connected => info!("relay {}: {from} -> {}", change.url, to.status),
+        Some(to) => warn!("relay {}: {from} -> {}", change.url, to.status),
+        None => warn!("relay {}: {from} -> removed", change.url),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RelayConnectionStatus, RelayStatusMap, relay_status_changes, relay_write_results};
+
+    fn status(status: &str, connected: bool) -> RelayConnectionStatus {
+        RelayConnectionStatus {
+            status: status.to_string(),
+            connected,
+        }
+    }
+
+    #[test]
+    fn relay_status_changes_report_transitions() {
+        let prev: RelayStatusMap = [
+            ("wss://a".to_string(), status("Connected", true)),
+            ("wss://b".to_string(), status("Connected", true)),
+            ("wss://c".to_string(), status("Connecting", false)),
+        ]
+        .into();
+        let next: RelayStatusMap = [
+            ("wss://a".to_string(), status("Connected", true)),
+            ("wss://b".to_string(), status("Disconnected", false)),
+            ("wss://d".to_string(), status("Connected", true)),
+        ]
+        .into();
+
+        let changes = relay_status_changes(&prev, &next);
+        let urls: Vec<&str> = changes.iter().map(|c| c.url.as_str()).collect();
+        assert_eq!(urls, ["wss://b", "wss://c", "wss://d"]);
+        assert_eq!(changes[0].to, Some(status("Disconnected", false)));
+        assert_eq!(changes[1].to, None);
+        assert_eq!(changes[2].from, None);
+    }
+
+    #[test]
+    fn relay_write_results_report_each_relay() {
+        let results = relay_write_results(
+            ["wss://b".to_string(), "wss://a".to_string()],
+            [("wss://c".to_string(), "blocked: read-only".to_string())],
+        );
+
+        let urls: Vec<&str> = results.iter().map(|r| r.url.as_str()).collect();
+        assert_eq!(urls, ["wss://a", "wss://b", "wss://c"]);
+        assert!(results[0].accepted());
+        assert!(results[1].accepted());
+        assert!(!results[2].accepted());
+        assert_eq!(results[2].error.as_deref(), Some("blocked: read-only"));
+    }
+}
@@ -1,7 +1,9 @@
 pub mod adapters;
 pub mod cli;
 pub mod config;
+pub mod identity;
 pub mod infra;
+pub mod replay;
 pub mod rhi;
 
 pub mod features {
@@ -10,20 +12,31 @@ pub mod features {
 
 pub use cli::Args as cli_args;
 
-use anyhow::Result;
-use std::time::Duration;
+use anyhow::{Context, Result, bail};
+use std::{sync::Arc, time::Duration};
 
 use crate::{
+    config::RelayConfig,
+    features::trade_listing::{
+        api::serve_api,
+        events::{QueuedSink, TradeEventSink, connect_sink},
+        handlers::{dvm::TransitionHook, registry::HandlerRegistry},
+        subscriber::{SubscriberRuntime, TradeListingShared},
+        webhook::WebhookSink,
+    },
+    infra::{
+        journal::EventJournal,
+        nostr::{SHUTDOWN_STEP_TIMEOUT, bounded_shutdown_step, log_dry_run_event},
+        relays::relay_self_ping,
+    },
     rhi::{Rhi, start_subscriber},
 };
+use nostr::EventBuilder;
 use radroots_identity::RadrootsIdentity;
 use radroots_nostr::prelude::{
-    radroots_nostr_publish_application_handler,
-    radroots_nostr_publish_identity_profile,
-    RadrootsNostrApplicationHandlerSpec,
-    RadrootsNostrMetadata,
+    RadrootsNostrApplicationHandlerSpec, RadrootsNostrClient, RadrootsNostrMetadata,
+    radroots_nostr_publish_application_handler, radroots_nostr_publish_identity_profile,
 };
-use radroots_trade::listing::dvm_kinds::TRADE_LISTING_DVM_KINDS;
 use tracing::{info, warn};
 
 fn metadata_has_fields(md: &RadrootsNostrMetadata) -> bool {
@@ -40,70 +53,106 @@ fn metadata_has_fields(md: &RadrootsNostrMetadata) -> bool {
 }
 
 pub async fn run_rhi(settings: &config::Settings, args: &cli_args) -> Result<()> {
+    run_rhi_with_transition_hook(settings, args, None).await
+}
+
+/// Runs rhi with a hook that is called after every order status transition.
+pub async fn run_rhi_with_transition_hook(
+    settings: &config::Settings,
+    args: &cli_args,
+    on_transition: Option<TransitionHook>,
+) -> Result<()> {
     let identity = RadrootsIdentity::load_or_generate(
         args.identity.as_ref(),
         args.allow_generate_identity,
     )?;
     let keys = identity.keys().clone();
+    let result_keys = match &args.result_identity {
+        Some(path) => RadrootsIdentity::load_or_generate(Some(path), false)?
+            .keys()
+            .clone(),
+        None => keys.clone(),
+    };
 
     let rhi = Rhi::new(keys.clone());
     let client = rhi.client.clone();
-    let relays = settings.config.relays.clone();
-
-    for relay in &relays {
-        client.add_relay(relay).await?;
-    }
-
-    let md = settings.metadata.clone();
-    let has_metadata = metadata_has_fields(&md);
+    let relays = settings.effective(args.relay_profile).config.relays;
+    add_relays(&client, &relays).await?;
 
     if !relays.is_empty() {
         client.connect().await;
         client.wait_for_connection(Duration::from_secs(5)).await;
-        let profile_published = match radroots_nostr_publish_identity_profile(&client, &identity).await
-        {
-            Ok(Some(_)) => true,
-            Ok(None) => false,
-            Err(e) => {
-                warn!("Failed to publish identity profile: {e}");
-                false
-            }
-        };
-        if has_metadata && !profile_published {
-            if let Err(e) = client.set_metadata(&md).await {
-                warn!("Failed to publish metadata on startup: {e}");
-            } else {
-                info!("Published metadata on startup");
+        if settings.config.startup_self_ping && args.dry_run {
+            info!("dry run: skipping startup self-ping");
+        } else if settings.config.startup_self_ping {
+            if let Err(e) = relay_self_ping(&client, &keys).await {
+                warn!("Failed to send startup self-ping: {e}");
             }
         }
+        publish_announcements(&client, &identity, settings, &relays, args.dry_run).await;
+    }
 
-        let handler_kinds = TRADE_LISTING_DVM_KINDS
-            .iter()
-            .map(|kind| *kind as u32)
-            .collect();
-        let handler_spec = RadrootsNostrApplicationHandlerSpec {
-            kinds: handler_kinds,
-            identifier: None,
-            metadata: Some(md.clone()),
-            extra_tags: Vec::new(),
-            relays: relays.clone(),
-            nostrconnect_url: None,
-        };
-        if let Err(e) = radroots_nostr_publish_application_handler(&client, &handler_spec).await {
-            warn!("Failed to publish NIP-89 announcement: {e}");
-        } else {
-            info!("Published NIP-89 announcement");
+    let mut events = Vec::new();
+    if let Some(cfg) = &settings.config.events {
+        match connect_sink(cfg).await {
+            Ok(sink) => events.push(sink),
+            Err(e) => warn!("Failed to connect {:?} trade event sink: {e}", cfg.sink),
         }
     }
+    if let Some(cfg) = &settings.config.webhook {
+        let sink = QueuedSink::spawn(Arc::new(WebhookSink::new(cfg)), cfg.queue_capacity);
+        events.push(Arc::new(sink) as Arc<dyn TradeEventSink>);
+    }
+    let journal = settings
+        .config
+        .journal
+        .as_ref()
+        .map(|cfg| EventJournal::new(cfg).map(Arc::new))
+        .transpose()
+        .context("open event journal")?;
+    let shared = TradeListingShared::load(&settings.config.trade).context("load trade state")?;
+    // The API outlives subscriber reconnects; failing to bind it leaves event
+    // processing running.
+    let api = match &settings.config.api {
+        Some(cfg) => match serve_api(cfg, Arc::clone(&shared.state)).await {
+            Ok(api) => Some(api),
+            Err(e) => {
+                warn!("Failed to start trade order API on {}: {e}", cfg.bind);
+                None
+            }
+        },
+        None => None,
+    };
+    let runtime = SubscriberRuntime {
+        journal,
+        events,
+        on_transition,
+        encryption: settings.config.encryption,
+        dry_run: args.dry_run,
+        once: args.once,
+    };
 
     let handle = start_subscriber(
         client.clone(),
         keys.clone(),
-        settings.config.subscriber.backoff.clone(),
+        result_keys,
+        settings.config.subscriber.clone(),
+        settings.config.trade.clone(),
+        Arc::new(HandlerRegistry::default()),
+        runtime,
+        shared,
     )
     .await;
 
     let stop_handle = handle.clone();
+    let once_deadline = async {
+        if args.once {
+            tokio::time::sleep(Duration::from_secs(args.once_timeout_secs)).await;
+        } else {
+            std::future::pending::<()>().await;
+        }
+    };
+    let mut timed_out = false;
 
     tokio::select! {
         _ = radroots_runtime::shutdown_signal() => {
@@ -111,10 +160,105 @@ pub async fn run_rhi(settings: &config::Settings, args: &cli_args) -> Result<()>
             stop_handle.stop();
         }
         _ = handle.stopped() => {}
+        _ = once_deadline => {
+            timed_out = true;
+            stop_handle.stop();
+        }
     }
 
-    client.unsubscribe_all().await;
-    client.disconnect().await;
+    let unsubscribe = client.unsubscribe_all();
+    bounded_shutdown_step("unsubscribe", SHUTDOWN_STEP_TIMEOUT, unsubscribe).await;
+    bounded_shutdown_step("disconnect", SHUTDOWN_STEP_TIMEOUT, client.disconnect()).await;
+    if let Some(api) = api {
+        api.abort();
+    }
 
+    if timed_out {
+        bail!(
+            "no trade request received within {}s",
+            args.once_timeout_secs
+        );
+    }
     Ok(())
 }
+
+pub(crate) async fn add_relays(client: &RadrootsNostrClient, relays: &[RelayConfig]) -> Result<()> {
+    for relay in relays {
+        match (relay.read, relay.write) {
+            (true, true) => client.add_relay(&relay.url).await?,
+            (true, false) => client.add_read_relay(&relay.url).await?,
+            (false, true) => client.add_write_relay(&relay.url).await?,
+            (false, false) => {
+                warn!(
+                    "Skipping relay {} with neither read nor write enabled",
+                    relay.url
+                );
+                continue;
+            }
+        };
+    }
+    Ok(())
+}
+
+pub(crate) async fn publish_announcements(
+    client: &RadrootsNostrClient,
+    identity: &RadrootsIdentity,
+    settings: &config::Settings,
+    relays: &[RelayConfig],
+    dry_run: bool,
+) {
+    let md = settings.metadata.clone();
+    let has_metadata = metadata_has_fields(&md);
+    let handler_kinds = settings
+        .config
+        .served_kinds()
+        .into_iter()
+        .map(u32::from)
+        .collect();
+    let handler_spec = RadrootsNostrApplicationHandlerSpec {
+        kinds: handler_kinds,
+        identifier: None,
+        metadata: Some(md.clone()),
+        extra_tags: Vec::new(),
+        relays: relays
+            .iter()
+            .filter(|relay| relay.read)
+            .map(|relay| relay.url.clone())
+            .collect(),
+        nostrconnect_url: None,
+    };
+    if dry_run {
+        match EventBuilder::metadata(&md).sign_with_keys(identity.keys()) {
+            Ok(event) => log_dry_run_event(&event),
+            Err(e) => warn!("Failed to build metadata event: {e}"),
+        }
+        info!(
+            kinds = ?handler_spec.kinds,
+            relays = ?handler_spec.relays,
+            "dry run: not publishing NIP-89 announcement"
+        );
+        return;
+    }
+
+    let profile_published = match radroots_nostr_publish_identity_profile(client, identity).await {
+        Ok(Some(_)) => true,
+        Ok(None) => false,
+        Err(e) => {
+            warn!("Failed to publish identity profile: {e}");
+            false
+        }
+    };
+    if has_metadata && !profile_published {
+        if let Err(e) = client.set_metadata(&md).await {
+            warn!("Failed to publish metadata on startup: {e}");
+        } else {
+            info!("Published metadata on startup");
+        }
+    }
+
+    if let Err(e) = radroots_nostr_publish_application_handler(client, &handler_spec).await {
+        warn!("Failed to publish NIP-89 announcement: {e}");
+    } else {
+        info!("Published NIP-89 announcement");
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::{Context, Result};
-use rhi::{cli_args, config, run_rhi};
+use rhi::{cli, cli_args, config, identity, replay, run_rhi};
 use std::process::ExitCode;
 use tracing::info;
 
@@ -16,13 +16,48 @@ async fn main() -> ExitCode {
 }
 
 async fn run() -> Result<()> {
+    // Logging is initialised while the file is loaded, so the logs dir override is
+    // resolved up front; every other override is applied to the loaded settings below.
+    let env_logs_dir = std::env::var("RHI__CONFIG__LOGS_DIR").ok();
     let (args, settings): (cli_args, config::Settings) =
         radroots_runtime::parse_and_load_path_with_init(
             |a: &cli_args| Some(a.config.as_path()),
-            |cfg: &config::Settings| cfg.config.logs_dir.as_str(),
+            |cfg: &config::Settings| {
+                env_logs_dir
+                    .as_deref()
+                    .unwrap_or(cfg.config.logs_dir.as_str())
+            },
             None,
         )
         .context("load configuration")?;
+    let settings = settings
+        .with_env_overrides(std::env::vars_os())
+        .context("apply RHI__ environment overrides")?
+        .with_relay_overrides(&args.relays, &args.add_relays);
+    settings
+        .config
+        .validate()
+        .context("invalid configuration")?;
+
+    if args.print_effective_config {
+        let effective = settings.effective(args.relay_profile);
+        println!("{}", effective.to_redacted_json()?);
+        return Ok(());
+    }
+
+    match &args.command {
+        Some(cli::Command::Identity(command)) => {
+            return identity::run_identity_command(command, &settings, &args).await;
+        }
+        Some(cli::Command::Replay {
+            file,
+            kinds,
+            fail_fast,
+        }) => {
+            return replay::run_replay(file, kinds, *fail_fast, &settings, &args).await;
+        }
+        None => {}
+    }
 
     info!("Starting");
 
@@ -0,0 +1,247 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use radroots_identity::RadrootsIdentity;
+use radroots_nostr::prelude::{RadrootsNostrEvent, RadrootsNostrKeys, RadrootsNostrKind};
+use serde_json::Value;
+
+use crate::{
+    add_relays, cli_args,
+    config::Settings,
+    features::trade_listing::{
+        handlers::{
+            dvm::{TradeListingContext, handle_event},
+            registry::HandlerRegistry,
+        },
+        listing_cache::ListingCache,
+        state::SharedTradeListingState,
+    },
+    infra::{
+        event_cache::EventFetchCache,
+        invoices::invoice_issuer,
+        nostr::{
+            NostrPayloadLimits, NostrTagsResolveError, nostr_tags_resolve, nostr_unwrap_gift_wrap,
+        },
+        rates::rate_provider,
+    },
+    rhi::Rhi,
+};
+
+pub async fn run_replay(
+    file: &Path,
+    kinds: &[u16],
+    fail_fast: bool,
+    settings: &Settings,
+    args: &cli_args,
+) -> Result<()> {
+    let events = read_replay_events(file)?;
+    let identity = RadrootsIdentity::load_or_generate(args.identity.as_ref(), false)?;
+    let keys = identity.keys().clone();
+    let result_keys = match &args.result_identity {
+        Some(path) => RadrootsIdentity::load_or_generate(Some(path), false)?
+            .keys()
+            .clone(),
+        None => keys.clone(),
+    };
+
+    let client = Rhi::new(keys.clone()).client;
+    let relays = settings.effective(args.relay_profile).config.relays;
+    add_relays(&client, &relays).await?;
+    if !relays.is_empty() {
+        client.connect().await;
+        client.wait_for_connection(Duration::from_secs(5)).await;
+    }
+
+    let trade_cfg = settings.config.trade.clone();
+    let limits = NostrPayloadLimits {
+        max_content_bytes: trade_cfg.limits.max_content_bytes,
+        max_decrypted_bytes: trade_cfg.limits.max_decrypted_bytes,
+    };
+    let ctx = TradeListingContext {
+        client: client.clone(),
+        keys: keys.clone(),
+        result_keys,
+        state: Arc::new(SharedTradeListingState::default()),
+        listing_cache: Arc::new(ListingCache::new(&trade_cfg.listing_cache)),
+        config: Arc::new(trade_cfg),
+        registry: Arc::new(HandlerRegistry::default()),
+        store: None,
+        journal: None,
+        outbox: None,
+        event_cache: Arc::new(EventFetchCache::default()),
+        rates: rate_provider(settings.config.trade.rates.as_ref())
+            .context("build rate provider")?,
+        invoices: invoice_issuer(settings.config.trade.payment.as_ref())
+            .context("build invoice issuer")?,
+        events: Vec::new(),
+        on_transition: None,
+        encryption: settings.config.encryption,
+        dry_run: args.dry_run,
+    };
+
+    let mut failures = 0;
+    for event in events {
+        let wrap_id = event.id;
+        let event = match unwrap_replayed(event, &keys, limits) {
+            Ok(event) => event,
+            Err(err) => {
+                failures += 1;
+                let kind = RadrootsNostrKind::GiftWrap;
+                println!("{wrap_id} kind {kind}: error: failed to unwrap gift wrap: {err}");
+                if fail_fast {
+                    break;
+                }
+                continue;
+            }
+        };
+        let wanted = |kind: &u16| event.kind == RadrootsNostrKind::Custom(*kind);
+        if !kinds.is_empty() && !kinds.iter().any(wanted) {
+            continue;
+        }
+        let outcome = match nostr_tags_resolve(&event, &keys, limits) {
+            Ok(tags) => handle_event(event.clone(), tags, &keys, &ctx)
+                .await
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(format!("failed to resolve tags: {err}")),
+        };
+        let order_id = event.tags.identifier();
+        let status = match order_id {
+            Some(order_id) => ctx
+                .state
+                .order_shard(order_id)
+                .read()
+                .await
+                .get_order(order_id)
+                .map(|order| format!(" order {order_id} -> {:?}", order.status)),
+            None => None,
+        };
+        match outcome {
+            Ok(()) => {
+                let status = status.unwrap_or_default();
+                println!("{} kind {}: ok{status}", event.id, event.kind);
+            }
+            Err(err) => {
+                failures += 1;
+                println!("{} kind {}: error: {err}", event.id, event.kind);
+                if fail_fast {
+                    break;
+                }
+            }
+        }
+    }
+
+    client.disconnect().await;
+    if failures > 0 {
+        bail!("{failures} replayed events failed");
+    }
+    Ok(())
+}
+
+// Gift-wrapped requests, as the journal and the dead letter record them, are replayed
+// as the rumor inside, the same way the live stream hands them to the handlers.
+fn unwrap_replayed(
+    event: RadrootsNostrEvent,
+    keys: &RadrootsNostrKeys,
+    limits: NostrPayloadLimits,
+) -> Result<RadrootsNostrEvent, NostrTagsResolveError> {
+    if event.kind != RadrootsNostrKind::GiftWrap {
+        return Ok(event);
+    }
+    nostr_unwrap_gift_wrap(&event, keys, limits)
+}
+
+fn read_replay_events(path: &Path) -> Result<Vec<RadrootsNostrEvent>> {
+    let file = fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut events = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = parse_replay_line(&line)
+            .with_context(|| format!("{}:{}: invalid event", path.display(), index + 1))?;
+        events.extend(event);
+    }
+    Ok(events)
+}
+
+// Accepts raw events as well as event journal and dead-letter records, which wrap the
+// event under an `event` key. Events the daemon sent itself are skipped.
+fn parse_replay_line(line: &str) -> Result<Option<RadrootsNostrEvent>, serde_json::Error> {
+    let mut value: Value = serde_json::from_str(line)?;
+    if value.get("direction").and_then(Value::as_str) == Some("sent") {
+        return Ok(None);
+    }
+    if let Some(event) = value.get_mut("event") {
+        value = event.take();
+    }
+    serde_json::from_value(value).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_replay_line, unwrap_replayed};
+    use nostr::{EventBuilder, Kind, Tag};
+    use radroots_nostr::prelude::RadrootsNostrKeys;
+    use serde_json::json;
+
+    use crate::infra::nostr::NostrPayloadLimits;
+
+    #[test]
+    fn replay_lines_accept_raw_journal_and_dead_letter_records() {
+        let event = EventBuilder::new(Kind::Custom(5321), "order")
+            .sign_with_keys(&RadrootsNostrKeys::generate())
+            .unwrap();
+        let raw = serde_json::to_string(&event).unwrap();
+        let received = json!({"direction": "received", "relays": [], "at": 1, "event": event});
+        let dead_letter = json!({"event": event, "error": "relay timeout", "at": 1});
+        let sent = json!({"direction": "sent", "relays": [], "at": 1, "event": event});
+
+        for line in [raw, received.to_string(), dead_letter.to_string()] {
+            assert_eq!(
+                parse_replay_line(&line).unwrap().map(|e| e.id),
+                Some(event.id)
+            );
+        }
+        assert!(parse_replay_line(&sent.to_string()).unwrap().is_none());
+        assert!(parse_replay_line("{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn gift_wraps_are_replayed_as_their_rumor() {
+        let buyer = RadrootsNostrKeys::generate();
+        let rhi = RadrootsNostrKeys::generate();
+        let limits = NostrPayloadLimits {
+            max_content_bytes: 65_536,
+            max_decrypted_bytes: 65_536,
+        };
+        let rumor = EventBuilder::new(Kind::Custom(5321), "order")
+            .tag(Tag::public_key(rhi.public_key()))
+            .build(buyer.public_key());
+        let wrap = EventBuilder::gift_wrap(&buyer, &rhi.public_key(), rumor, [])
+            .await
+            .unwrap();
+        let line = json!({"event": wrap, "error": "relay timeout", "at": 1}).to_string();
+
+        let replayed = parse_replay_line(&line).unwrap().unwrap();
+        let request = unwrap_replayed(replayed, &rhi, limits).unwrap();
+        assert_eq!(request.kind, Kind::Custom(5321));
+        assert_eq!(request.pubkey, buyer.public_key());
+
+        let plain = EventBuilder::new(Kind::Custom(5321), "order")
+            .sign_with_keys(&buyer)
+            .unwrap();
+        assert_eq!(
+            unwrap_replayed(plain.clone(), &rhi, limits).unwrap().id,
+            plain.id
+        );
+    }
+}
@@ -1,7 +1,14 @@
 use std::time::{Duration, Instant};
 
 use radroots_nostr::prelude::{RadrootsNostrClient, RadrootsNostrKeys};
-use radroots_runtime::{Backoff, BackoffConfig};
+use radroots_runtime::Backoff;
+
+use crate::config::{SubscriberConfig, TradeConfig};
+use crate::features::trade_listing::{
+    handlers::registry::HandlerRegistry,
+    subscriber::{SubscriberRuntime, TradeListingShared},
+};
+use crate::infra::relays::{RelayStatusMap, monitor_relay_status};
 
 pub struct Rhi {
     pub(crate) _started: Instant,
@@ -21,8 +28,20 @@ impl Rhi {
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RhiStatus {
+    Starting,
+    Running,
+    Retrying { attempt: u32, next_delay: Duration },
+    Stopped,
+}
+
+const RELAY_STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
 pub struct RhiHandle {
     stop_tx: Arc<Mutex<Option<tokio::sync::watch::Sender<bool>>>>,
+    status_rx: tokio::sync::watch::Receiver<RhiStatus>,
+    relays_rx: tokio::sync::watch::Receiver<RelayStatusMap>,
     join: Option<tokio::task::JoinHandle<()>>,
 }
 
@@ -30,12 +49,22 @@ impl Clone for RhiHandle {
     fn clone(&self) -> Self {
         Self {
             stop_tx: Arc::clone(&self.stop_tx),
+            status_rx: self.status_rx.clone(),
+            relays_rx: self.relays_rx.clone(),
             join: None, // don’t clone the JoinHandle!
         }
     }
 }
 
 impl RhiHandle {
+    pub fn status(&self) -> RhiStatus {
+        self.status_rx.borrow().clone()
+    }
+
+    pub fn relay_statuses(&self) -> RelayStatusMap {
+        self.relays_rx.borrow().clone()
+    }
+
     pub fn stop(&self) {
         if let Some(tx) = self.stop_tx.try_lock().ok().and_then(|mut opt| opt.take()) {
             let _ = tx.send(true);
@@ -52,12 +81,28 @@ impl RhiHandle {
 pub async fn start_subscriber(
     client: RadrootsNostrClient,
     keys: RadrootsNostrKeys,
-    backoff_cfg: BackoffConfig,
+    result_keys: RadrootsNostrKeys,
+    subscriber_cfg: SubscriberConfig,
+    trade_cfg: TradeConfig,
+    registry: Arc<HandlerRegistry>,
+    runtime: SubscriberRuntime,
+    shared: TradeListingShared,
 ) -> RhiHandle {
     let (stop_tx, mut stop_rx) = tokio::sync::watch::channel(false);
+    let (status_tx, status_rx) = tokio::sync::watch::channel(RhiStatus::Starting);
+    let (relays_tx, relays_rx) = tokio::sync::watch::channel(RelayStatusMap::new());
+    let trade_cfg = Arc::new(trade_cfg);
+
+    tokio::spawn(monitor_relay_status(
+        client.clone(),
+        relays_tx,
+        stop_rx.clone(),
+        RELAY_STATUS_INTERVAL,
+    ));
 
     let join = tokio::spawn(async move {
-        let mut backoff = Backoff::new(backoff_cfg);
+        let mut backoff = Backoff::new(subscriber_cfg.backoff);
+        let mut attempt = 0;
         loop {
             if *stop_rx.borrow() {
                 break;
@@ -69,9 +114,16 @@ pub async fn start_subscriber(
                 _ = stop_rx.changed() => break,
             }
 
+            status_tx.send_replace(RhiStatus::Running);
             let res = crate::features::trade_listing::subscriber::subscriber(
                 client.clone(),
                 keys.clone(),
+                result_keys.clone(),
+                Arc::clone(&trade_cfg),
+                Arc::clone(&registry),
+                &subscriber_cfg,
+                &runtime,
+                &shared,
                 stop_rx.clone(),
             )
             .await;
@@ -82,24 +134,62 @@ pub async fn start_subscriber(
                 tracing::error!("Error on job request subscription: {e}");
             } else {
                 backoff.reset();
+                attempt = 0;
             }
 
-            if *stop_rx.borrow() {
+            if *stop_rx.borrow() || (runtime.once && !failed) {
                 break;
             }
 
             if failed {
-                let delay = backoff.next_delay();
+                let (delay, status) = next_retry(&mut attempt, &mut backoff);
+                status_tx.send_replace(status);
                 tokio::select! {
                     _ = tokio::time::sleep(delay) => {}
                     _ = stop_rx.changed() => break,
                 }
             }
         }
+        status_tx.send_replace(RhiStatus::Stopped);
     });
 
     RhiHandle {
         stop_tx: Arc::new(Mutex::new(Some(stop_tx))),
+        status_rx,
+        relays_rx,
         join: Some(join),
     }
 }
+
+fn next_retry(attempt: &mut u32, backoff: &mut Backoff) -> (Duration, RhiStatus) {
+    *attempt = attempt.saturating_add(1);
+    let delay = backoff.next_delay();
+    let status = RhiStatus::Retrying {
+        attempt: *attempt,
+        next_delay: delay,
+    };
+    (delay, status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RhiStatus, next_retry};
+    use radroots_runtime::{Backoff, BackoffConfig};
+
+    #[test]
+    fn retry_status_advances_with_backoff() {
+        let mut backoff = Backoff::new(BackoffConfig::default());
+        let mut attempt = 0;
+
+        for expected in 1..=3 {
+            let (delay, status) = next_retry(&mut attempt, &mut backoff);
+            assert_eq!(
+                status,
+                RhiStatus::Retrying {
+                    attempt: expected,
+                    next_delay: delay,
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use nostr::{EventBuilder, Kind, Tag};
+use radroots_events::tags::{TAG_D, TAG_E_PREV, TAG_E_ROOT};
+use radroots_nostr::prelude::{
+    RadrootsNostrClient, RadrootsNostrEvent, RadrootsNostrKeys, radroots_nostr_build_event,
+};
+use radroots_trade::listing::{
+    dvm::{TradeListingEnvelope, TradeListingMessageType},
+    order::TradeOrderStatus,
+    tags::trade_listing_dvm_tags,
+};
+use rhi::config::{EncryptionMode, TradeConfig};
+use rhi::features::trade_listing::{
+    chain::TradeChainError,
+    envelope::encode_envelope,
+    handlers::{
+        dvm::{
+            TradeListingContext, TradeListingDvmError, TransitionHook, handle_error, handle_event,
+        },
+        registry::HandlerRegistry,
+    },
+    listing_cache::ListingCache,
+    state::{SharedTradeListingState, TradeOrderState},
+};
+use rhi::infra::{event_cache::EventFetchCache, journal::EventJournal, nostr::tag_lookup};
+use serde_json::{Value, json};
+
+type Transitions = Arc<Mutex<Vec<(TradeOrderStatus, TradeOrderStatus)>>>;
+
+// Collects the event journal, which in dry-run mode records every event the handlers
+// would have published.
+#[derive(Clone, Default)]
+struct Published(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Published {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Published {
+    fn events(&self) -> Vec<RadrootsNostrEvent> {
+        let journal = self.0.lock().unwrap();
+        String::from_utf8_lossy(&journal)
+            .lines()
+            .map(|line| {
+                let mut record: Value = serde_json::from_str(line).unwrap();
+                assert_eq!(record["direction"], "sent");
+                serde_json::from_value(record["event"].take()).unwrap()
+            })
+            .collect()
+    }
+}
+
+fn context(
+    rhi: &RadrootsNostrKeys,
+    published: &Published,
+    transitions: &Transitions,
+) -> TradeListingContext {
+    let mut trade = TradeConfig::default();
+    trade.emit_completion_reaction = false;
+    let hook: TransitionHook = {
+        let transitions = Arc::clone(transitions);
+        Arc::new(move |_: &TradeOrderState, from, to| {
+            transitions.lock().unwrap().push((from, to));
+        })
+    };
+    TradeListingContext {
+        client: RadrootsNostrClient::new(rhi.clone()),
+        keys: rhi.clone(),
+        result_keys: RadrootsNostrKeys::generate(),
+        state: Arc::new(SharedTradeListingState::default()),
+        listing_cache: Arc::new(ListingCache::new(&trade.listing_cache)),
+        config: Arc::new(trade),
+        registry: Arc::new(HandlerRegistry::default()),
+        store: None,
+        journal: Some(Arc::new(EventJournal::from_writer(published.clone()))),
+        outbox: None,
+        event_cache: Arc::new(EventFetchCache::default()),
+        rates: None,
+        invoices: None,
+        events: Vec::new(),
+        on_transition: Some(hook),
+        encryption: EncryptionMode::Nip04,
+        dry_run: true,
+    }
+}
+
+// `bin-1` holds a dozen at 0.50 each, so one bin costs 6.00.
+fn listing(seller: &RadrootsNostrKeys) -> RadrootsNostrEvent {
+    let content = json!({
+        "d_tag": "listing-1",
+        "farm": { "pubkey": seller.public_key().to_hex(), "d_tag": "farm-1" },
+        "product": { "key": "eggs", "title": "Eggs", "category": "eggs" },
+        "primary_bin_id": "bin-1",
+        "bins": [{
+            "bin_id": "bin-1",
+            "quantity": { "amount": "12", "unit": "each" },
+            "price_per_canonical_unit": {
+                "amount": { "amount": "0.50", "currency": "USD" },
+                "quantity": { "amount": "1", "unit": "each" }
+            }
+        }]
+    });
+    EventBuilder::new(Kind::Custom(30402), content.to_string())
+        .tag(Tag::identifier("listing-1"))
+        .sign_with_keys(seller)
+        .unwrap()
+}
+
+fn request(
+    from: &RadrootsNostrKeys,
+    rhi: &RadrootsNostrKeys,
+    message_type: TradeListingMessageType,
+    listing_addr: &str,
+    payload: Value,
+    chain: &[[&str; 2]],
+) -> RadrootsNostrEvent {
+    let envelope = TradeListingEnvelope::new(
+        message_type,
+        listing_addr.to_string(),
+        Some("order-1".to_string()),
+        payload,
+    );
+    let content = encode_envelope(&envelope).unwrap();
+    let mut tags =
+        trade_listing_dvm_tags(rhi.public_key().to_string(), listing_addr, Some("order-1"));
+    tags.extend(chain.iter().map(|tag| tag.map(str::to_string).to_vec()));
+    radroots_nostr_build_event(message_type.kind() as u32, content, tags)
+        .unwrap()
+        .sign_with_keys(from)
+        .unwrap()
+}
+
+async fn listed(ctx: &TradeListingContext, seller: &RadrootsNostrKeys) -> String {
+    let listing_addr = format!("30402:{}:listing-1", seller.public_key().to_hex());
+    ctx.listing_cache.insert(&listing_addr, listing(seller));
+    ctx.state
+        .listings_mut()
+        .await
+        .mark_listing_validated(&listing_addr);
+    listing_addr
+}
+
+fn order(listing_addr: &str, buyer: &RadrootsNostrKeys, seller: &RadrootsNostrKeys) -> Value {
+    json!({
+        "order_id": "order-1",
+        "listing_addr": listing_addr,
+        "buyer_pubkey": buyer.public_key().to_hex(),
+        "seller_pubkey": seller.public_key().to_hex(),
+        "items": [{ "bin_id": "bin-1", "bin_count": 1 }],
+        "total": "10.00",
+    })
+}
+
+#[tokio::test]
+async fn order_chain_reaches_completed_with_chained_results() {
+    let rhi = RadrootsNostrKeys::generate();
+    let buyer = RadrootsNostrKeys::generate();
+    let seller = RadrootsNostrKeys::generate();
+    let published = Published::default();
+    let transitions = Transitions::default();
+    let ctx = context(&rhi, &published, &transitions);
+    let listing_addr = listed(&ctx, &seller).await;
+
+    let steps = [
+        (
+            &buyer,
+            &seller,
+            TradeListingMessageType::OrderRequest,
+            order(&listing_addr, &buyer, &seller),
+        ),
+        (
+            &seller,
+            &buyer,
+            TradeListingMessageType::OrderResponse,
+            json!({ "accepted": true }),
+        ),
+        (
+            &seller,
+            &buyer,
+            TradeListingMessageType::FulfillmentUpdate,
+            json!({ "state": "shipped" }),
+        ),
+        (
+            &buyer,
+            &seller,
+            TradeListingMessageType::Receipt,
+            json!({ "total": "10.00" }),
+        ),
+    ];
+    let mut root: Option<String> = None;
+    let mut prev: Option<String> = None;
+    for (step, (from, to, message_type, payload)) in steps.into_iter().enumerate() {
+        // Every stage after the order request continues from the last relayed result.
+        let chain: Vec<[&str; 2]> = root
+            .as_deref()
+            .zip(prev.as_deref())
+            .map(|(root, prev)| vec![[TAG_E_ROOT, root], [TAG_E_PREV, prev]])
+            .unwrap_or_default();
+        let event = request(from, &rhi, message_type, &listing_addr, payload, &chain);
+        let root_id = root.get_or_insert_with(|| event.id.to_string()).clone();
+        let request_id = event.id.to_string();
+        let tags = event.tags.iter().cloned().collect();
+        handle_event(event, tags, &rhi, &ctx).await.unwrap();
+
+        // Each request is relayed as exactly one result, chained to the order request.
+        let events = published.events();
+        assert_eq!(events.len(), step + 1);
+        let relayed = &events[step];
+        assert_eq!(relayed.kind, Kind::Custom(message_type.kind()));
+        assert_eq!(relayed.pubkey, ctx.result_keys.public_key());
+        assert!(relayed.verify().is_ok());
+        assert_eq!(tag_lookup(relayed, "p"), Some(to.public_key().to_hex()));
+        assert_eq!(
+            tag_lookup(relayed, "a").as_deref(),
+            Some(listing_addr.as_str())
+        );
+        assert_eq!(tag_lookup(relayed, TAG_D).as_deref(), Some("order-1"));
+        assert_eq!(tag_lookup(relayed, TAG_E_ROOT), Some(root_id));
+        assert_eq!(tag_lookup(relayed, TAG_E_PREV), Some(request_id));
+        prev = Some(relayed.id.to_string());
+    }
+
+    let state = ctx.state.order_shard("order-1").read().await;
+    let stored = state.get_order("order-1").unwrap();
+    assert_eq!(stored.status, TradeOrderStatus::Completed);
+    assert_eq!(stored.root_event_id, root);
+    assert_eq!(stored.seen_event_ids.len(), 4);
+    assert_eq!(stored.total.as_deref(), Some("6.00"));
+    assert_eq!(
+        *transitions.lock().unwrap(),
+        vec![
+            (TradeOrderStatus::Requested, TradeOrderStatus::Accepted),
+            (TradeOrderStatus::Accepted, TradeOrderStatus::Fulfilled),
+            (TradeOrderStatus::Fulfilled, TradeOrderStatus::Completed),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn stage_requests_on_a_foreign_chain_are_rejected() {
+    let rhi = RadrootsNostrKeys::generate();
+    let buyer = RadrootsNostrKeys::generate();
+    let seller = RadrootsNostrKeys::generate();
+    let published = Published::default();
+    let transitions = Transitions::default();
+    let ctx = context(&rhi, &published, &transitions);
+    let listing_addr = listed(&ctx, &seller).await;
+
+    let order_request = request(
+        &buyer,
+        &rhi,
+        TradeListingMessageType::OrderRequest,
+        &listing_addr,
+        order(&listing_addr, &buyer, &seller),
+        &[],
+    );
+    let root = order_request.id.to_string();
+    let tags = order_request.tags.iter().cloned().collect();
+    handle_event(order_request, tags, &rhi, &ctx).await.unwrap();
+    let relayed = published.events()[0].id.to_string();
+
+    // A result rooted at some other order, planted where the handler will look it up.
+    let foreign_root = "f".repeat(64);
+    let foreign = EventBuilder::new(Kind::Custom(6302), "")
+        .tag(Tag::identifier("order-1"))
+        .tag(Tag::parse([TAG_E_ROOT, foreign_root.as_str()]).unwrap())
+        .sign_with_keys(&seller)
+        .unwrap();
+    let foreign_id = foreign.id.to_string();
+    ctx.event_cache.insert(foreign);
+
+    // Neither a request naming another root nor one continuing a result off the
+    // order's chain reaches the stage handler.
+    let chains = [
+        vec![
+            [TAG_E_ROOT, foreign_root.as_str()],
+            [TAG_E_PREV, relayed.as_str()],
+        ],
+        vec![[TAG_E_PREV, foreign_id.as_str()]],
+        vec![
+            [TAG_E_ROOT, root.as_str()],
+            [TAG_E_PREV, foreign_id.as_str()],
+        ],
+    ];
+    for chain in chains {
+        let response = request(
+            &seller,
+            &rhi,
+            TradeListingMessageType::OrderResponse,
+            &listing_addr,
+            json!({ "accepted": true }),
+            &chain,
+        );
+        let tags = response.tags.iter().cloned().collect();
+        let err = handle_event(response, tags, &rhi, &ctx).await.unwrap_err();
+        assert!(matches!(err, TradeListingDvmError::Chain(_)), "{err}");
+    }
+
+    assert_eq!(published.events().len(), 1);
+    let state = ctx.state.order_shard("order-1").read().await;
+    assert_eq!(
+        state.get_order("order-1").unwrap().status,
+        TradeOrderStatus::Requested
+    );
+    assert!(transitions.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn stage_requests_continuing_an_unrooted_accept_are_rejected() {
+    let rhi = RadrootsNostrKeys::generate();
+    let buyer = RadrootsNostrKeys::generate();
+    let seller = RadrootsNostrKeys::generate();
+    let published = Published::default();
+    let transitions = Transitions::default();
+    let ctx = context(&rhi, &published, &transitions);
+    let listing_addr = listed(&ctx, &seller).await;
+
+    let order_request = request(
+        &buyer,
+        &rhi,
+        TradeListingMessageType::OrderRequest,
+        &listing_addr,
+        order(&listing_addr, &buyer, &seller),
+        &[],
+    );
+    let tags = order_request.tags.iter().cloned().collect();
+    handle_event(order_request, tags, &rhi, &ctx).await.unwrap();
+
+    // An accept that lost its root must not yield a result with an empty one.
+    let accept = EventBuilder::new(
+        Kind::Custom(TradeListingMessageType::OrderResponse.kind()),
+        "",
+    )
+    .tag(Tag::identifier("order-1"))
+    .sign_with_keys(&ctx.result_keys)
+    .unwrap();
+    let accept_id = accept.id.to_string();
+    ctx.event_cache.insert(accept);
+
+    let update = request(
+        &seller,
+        &rhi,
+        TradeListingMessageType::FulfillmentUpdate,
+        &listing_addr,
+        json!({ "state": "shipped" }),
+        &[[TAG_E_PREV, accept_id.as_str()]],
+    );
+    let tags = update.tags.iter().cloned().collect();
+    let err = handle_event(update, tags, &rhi, &ctx).await.unwrap_err();
+    match err {
+        TradeListingDvmError::Chain(TradeChainError::MissingRoot { event_id }) => {
+            assert_eq!(event_id, accept_id);
+        }
+        other => panic!("expected a missing root, got {other}"),
+    }
+    assert_eq!(published.events().len(), 1);
+}
+
+#[tokio::test]
+async fn job_feedback_is_signed_with_the_service_key() {
+    let rhi = RadrootsNostrKeys::generate();
+    let buyer = RadrootsNostrKeys::generate();
+    let seller = RadrootsNostrKeys::generate();
+    let published = Published::default();
+    let ctx = context(&rhi, &published, &Transitions::default());
+    let listing_addr = listed(&ctx, &seller).await;
+
+    let order_request = request(
+        &buyer,
+        &rhi,
+        TradeListingMessageType::OrderRequest,
+        &listing_addr,
+        order(&listing_addr, &buyer, &seller),
+        &[],
+    );
+    handle_error(TradeListingDvmError::InvalidOrder, &order_request, &ctx)
+        .await
+        .unwrap();
+
+    let feedback = &published.events()[0];
+    assert_eq!(feedback.kind, Kind::JobFeedback);
+    assert_eq!(feedback.pubkey, rhi.public_key());
+    assert_ne!(feedback.pubkey, ctx.result_keys.public_key());
+}